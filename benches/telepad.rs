@@ -0,0 +1,99 @@
+//! How fast a dump file restores through `telepad_file`, which maps the whole
+//! file and writes mapping contents straight out of it, against reading the
+//! same file through a `BufReader`. Each size is a `Synthetic` child with
+//! that much memory dumped to a file in the temp dir once, then restored over
+//! and over with the restored process killed in between, outside the timing.
+//!
+//! Sizes are in MB and default to 500, pick others with e.g.
+//! `TELEFORK_BENCH_MB=100,1000 cargo bench --bench telepad`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use telefork::harness::Synthetic;
+use telefork::{
+    teledump_with_config, telepad_attached, telepad_file_attached, Config, RestoredProcess,
+};
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::time::{Duration, Instant};
+
+const MB: usize = 1024 * 1024;
+
+fn sizes() -> Vec<usize> {
+    match std::env::var("TELEFORK_BENCH_MB") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|mb| {
+                mb.trim()
+                    .parse()
+                    .expect("TELEFORK_BENCH_MB is a list of sizes in MB")
+            })
+            .collect(),
+        Err(_) => vec![500],
+    }
+}
+
+/// Restore `iters` times with `restore`, timing only the restores
+fn time_restores(iters: u64, mut restore: impl FnMut() -> RestoredProcess) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..iters {
+        let start = Instant::now();
+        let restored = restore();
+        total += start.elapsed();
+        let pid = restored.pid();
+        drop(restored);
+        kill(pid, Signal::SIGKILL).unwrap();
+        waitpid(pid, None).unwrap();
+    }
+    total
+}
+
+fn telepad(c: &mut Criterion) {
+    let config = Config::default();
+    let mut group = c.benchmark_group("telepad");
+    // Each restore of 500MB takes a while, the default 100 samples is a wait
+    group.sample_size(10);
+    for mb in sizes() {
+        let path = std::env::temp_dir().join(format!("telefork-bench-{}MB.bin", mb));
+        let memory_bytes = {
+            let synthetic = Synthetic::spawn(mb * MB).expect("couldn't spawn synthetic process");
+            let mut out = File::create(&path).unwrap();
+            let stats =
+                teledump_with_config(synthetic.pid.as_raw(), &mut out, true, &config).unwrap();
+            stats.memory_bytes
+        };
+        group.throughput(Throughput::Bytes(memory_bytes as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("mmap", format!("{}MB", mb)),
+            path.as_path(),
+            |b, dump| {
+                b.iter_custom(|iters| {
+                    let file = File::open(dump).unwrap();
+                    time_restores(iters, || telepad_file_attached(&file, 0, &config).unwrap())
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("buffered", format!("{}MB", mb)),
+            path.as_path(),
+            |b, dump| {
+                b.iter_custom(|iters| {
+                    time_restores(iters, || {
+                        let mut file = BufReader::new(File::open(dump).unwrap());
+                        telepad_attached(&mut file, 0, &config).unwrap()
+                    })
+                })
+            },
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, telepad);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+//! A process that called `alarm(5)`, dumped two seconds in and restored.
+//! The restored process should get its `SIGALRM`, which kills it since
+//! handlers don't come along, about three seconds after the restore rather
+//! than never.
+
+mod common;
+
+use telefork::{teledump, telepad_file, Config};
+
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::fs::File;
+use std::time::{Duration, Instant};
+
+const ALARM: Duration = Duration::from_secs(5);
+const DUMP_AFTER: Duration = Duration::from_secs(2);
+/// Restores here take a moment, and the timer's only so precise
+const SLACK: Duration = Duration::from_millis(750);
+
+fn main() {
+    let child = common::spawn_ready(|| {
+        unsafe { libc::alarm(ALARM.as_secs() as u32) };
+    });
+    std::thread::sleep(DUMP_AFTER);
+    let path = std::env::temp_dir().join(format!("telefork-alarm-{}", child));
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), false).unwrap();
+    common::reap(child);
+
+    let restored = telepad_file(&File::open(&path).unwrap(), 0, &Config::default());
+    std::fs::remove_file(&path).unwrap();
+    let pid = restored.unwrap();
+    let restored_at = Instant::now();
+    let status = waitpid(pid, None).unwrap();
+    let lasted = restored_at.elapsed();
+
+    assert_eq!(
+        status,
+        WaitStatus::Signaled(pid, Signal::SIGALRM, false),
+        "the restored process didn't die of its alarm"
+    );
+    let expected = ALARM - DUMP_AFTER;
+    assert!(
+        lasted + SLACK >= expected && lasted <= expected + SLACK,
+        "the alarm went off {:?} after the restore instead of about {:?}",
+        lasted,
+        expected
+    );
+    println!("alarm went off {:?} after the restore", lasted);
+}
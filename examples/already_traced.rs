@@ -0,0 +1,37 @@
+mod common;
+
+use telefork::{teledump, AlreadyTraced};
+
+use std::convert::TryInto;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+fn main() {
+    // A helper stands in for gdb, tracing a process of its own
+    let (helper, target) = common::spawn_with(|ready| {
+        let target = unsafe {
+            Command::new("sleep")
+                .arg("30")
+                .pre_exec(|| {
+                    nix::sys::ptrace::traceme().map_err(|_| std::io::Error::last_os_error())
+                })
+                .spawn()
+                .unwrap()
+        };
+        ready.send(&target.id().to_ne_bytes());
+    });
+    let target = u32::from_ne_bytes(target[..].try_into().unwrap()) as i32;
+
+    let err = teledump(target, &mut std::io::sink(), true).unwrap_err();
+    println!("{}", err);
+    let traced = err.downcast_ref::<AlreadyTraced>().unwrap();
+    assert_eq!(traced.pid, target);
+    assert_eq!(traced.tracer, helper.as_raw());
+
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(target),
+        nix::sys::signal::SIGKILL,
+    )
+    .unwrap();
+    common::reap(helper);
+}
@@ -0,0 +1,82 @@
+use telefork::{
+    telefork, wait_for_exit, GroupFailed, ResumeBarrier, TeleforkLocation, TelepadBuilder,
+};
+
+use std::sync::{Arc, Mutex};
+
+/// CLOCK_MONOTONIC, which is the same for every process on the machine
+fn now() -> std::time::Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    std::time::Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join("telefork-barrier");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut dump = Vec::new();
+    if let TeleforkLocation::Child(n) = telefork(&mut dump).unwrap() {
+        // Say when we woke up, for comparing with when the other was ready
+        let woke = now().as_nanos().to_string();
+        std::fs::write(dir.join(format!("woke-{}", n)), woke).unwrap();
+        std::process::exit(n);
+    }
+    let dump = Arc::new(dump);
+
+    // Both are held until the slower one is restored too
+    let barrier = Arc::new(ResumeBarrier::new(2));
+    let ready = Arc::new(Mutex::new(Vec::new()));
+    let restores: Vec<_> = (1..=2)
+        .map(|n| {
+            let (dump, barrier, ready) = (dump.clone(), barrier.clone(), ready.clone());
+            std::thread::spawn(move || {
+                TelepadBuilder::new()
+                    .resume_barrier(barrier)
+                    .pre_resume(|_| {
+                        if n == 2 {
+                            std::thread::sleep(std::time::Duration::from_millis(300));
+                        }
+                        ready.lock().unwrap().push(now());
+                        Ok(())
+                    })
+                    .telepad(&mut &dump[..], n)
+                    .map_err(|e| e.to_string())
+            })
+        })
+        .collect();
+    for (n, restore) in (1..=2).zip(restores) {
+        let child = restore.join().unwrap().unwrap();
+        assert_eq!(wait_for_exit(child).unwrap(), n);
+    }
+    let all_ready = *ready.lock().unwrap().iter().max().unwrap();
+    for n in 1..=2 {
+        let woke = std::fs::read_to_string(dir.join(format!("woke-{}", n))).unwrap();
+        let woke = std::time::Duration::from_nanos(woke.parse().unwrap());
+        println!("{} woke {:?} after both were ready", n, woke - all_ready);
+        assert!(woke >= all_ready);
+    }
+
+    // One that fails takes the other down with it rather than leave it
+    // waiting, or running without its peer
+    let barrier = Arc::new(ResumeBarrier::new(2));
+    let good = {
+        let (dump, barrier) = (dump.clone(), barrier.clone());
+        std::thread::spawn(move || {
+            TelepadBuilder::new()
+                .resume_barrier(barrier)
+                .telepad(&mut &dump[..], 3)
+                .map_err(|e| e.is::<GroupFailed>())
+        })
+    };
+    let broken = TelepadBuilder::new()
+        .resume_barrier(barrier)
+        .telepad(&mut &b"not a dump"[..], 4);
+    assert!(broken.is_err());
+    assert_eq!(good.join().unwrap(), Err(true));
+    assert!(!dir.join("woke-3").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
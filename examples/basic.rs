@@ -1,3 +1,6 @@
+// `foo` is the demo variable every example uses
+#![allow(clippy::disallowed_names)]
+
 use telefork::{telefork, telepad, wait_for_exit, TeleforkLocation};
 
 use std::fs::File;
@@ -6,7 +9,7 @@ use std::sync::atomic::{AtomicU32, Ordering};
 // use nix::sys::signal::{raise, Signal};
 
 thread_local! {
-    static TEST_TLS : AtomicU32 = AtomicU32::new(8);
+    static TEST_TLS: AtomicU32 = const { AtomicU32::new(8) };
 }
 
 fn print_tls_val() {
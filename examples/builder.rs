@@ -0,0 +1,69 @@
+use telefork::{
+    wait_for_exit, Compression, MissingSyscall, TeleforkBuilder, TeleforkLocation, TelepadBuilder,
+};
+
+use nix::unistd::Pid;
+
+use std::fs::File;
+
+const EXTRA_FD: u32 = 100;
+/// Past the end of the x86_64 syscall table
+const NOT_A_SYSCALL: i64 = 1000;
+
+fn main() {
+    let fname = "builder.telefork.bin";
+    let archive = "builder.archive.telefork.bin";
+    let greeting = String::from("compressed and in one piece");
+    let loc = {
+        let mut output = File::create(fname).unwrap();
+        TeleforkBuilder::new()
+            .compression(Compression::Zstd(3))
+            .on_progress(|bytes| println!("sent {} bytes of state", bytes))
+            .also_write_to(archive)
+            .telefork(&mut output)
+            .unwrap()
+    };
+    match loc {
+        TeleforkLocation::Child(val) => {
+            println!("woke up passed {}, {}", val, greeting);
+            // The pre_resume hook below opened this one for us
+            let extra = std::fs::read_link(format!("/proc/self/fd/{}", EXTRA_FD));
+            println!("fd {} is {:?}", EXTRA_FD, extra);
+            std::process::exit(if extra.is_ok() { val } else { 1 })
+        }
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+    println!(
+        "dump is {} bytes compressed",
+        std::fs::metadata(fname).unwrap().len()
+    );
+
+    let status = wait_for_exit(restore(fname)).unwrap();
+    println!("child exited with status = {}", status);
+    // The archive is the same stream so it restores the same way
+    let archived = wait_for_exit(restore(archive)).unwrap();
+    println!("child from the archive exited with status = {}", archived);
+    assert_eq!(status, archived);
+}
+
+fn restore(fname: &str) -> Pid {
+    let mut input = File::open(fname).unwrap();
+    let mut received = 0;
+    let child = TelepadBuilder::new()
+        .compression(Compression::Zstd(3))
+        .on_progress(|bytes| received = bytes)
+        .pre_resume(|restored| {
+            // What a syscall from a newer kernel looks like on an older one
+            match restored.syscall(NOT_A_SYSCALL, [0; 6]) {
+                Err(e) if e.downcast_ref::<MissingSyscall>().is_some() => {}
+                other => return Err(format!("expected ENOSYS, got {:?}", other).into()),
+            }
+            let fd = restored.open("/dev/null", 0, 0)?;
+            restored.dup2(fd, EXTRA_FD)?;
+            restored.close(fd)
+        })
+        .telepad(&mut input, 9)
+        .unwrap();
+    println!("received {} bytes of state from {}", received, fname);
+    child
+}
@@ -0,0 +1,48 @@
+/*
+ * A C program that teleforks itself over a pipe and restores the copy.
+ *
+ *   cargo build
+ *   cc -Iinclude examples/c/self_telefork.c -Ltarget/debug -ltelefork -o self_telefork
+ *   LD_LIBRARY_PATH=target/debug ./self_telefork
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+#include <sys/wait.h>
+
+#include "telefork.h"
+
+int main(void) {
+    int fds[2];
+    if (pipe(fds) != 0) {
+        perror("pipe");
+        return 1;
+    }
+
+    int foo = 103;
+    pid_t writer = fork();
+    if (writer == 0) {
+        /* Stream ourselves into the pipe from a separate process so the
+         * reading end below doesn't deadlock on a full pipe buffer. */
+        close(fds[0]);
+        int res = telefork_to_fd(fds[1]);
+        if (res == TELEFORK_PARENT) {
+            exit(0);
+        } else if (res == TELEFORK_ERROR) {
+            fprintf(stderr, "telefork failed\n");
+            exit(1);
+        }
+        printf("woke up in the restored process with pass=%d and foo=%d\n", res, foo);
+        exit(foo);
+    }
+    close(fds[1]);
+
+    int child = telepad_from_fd(fds[0], 7);
+    if (child == TELEFORK_ERROR) {
+        fprintf(stderr, "telepad failed\n");
+        return 1;
+    }
+    waitpid(writer, NULL, 0);
+    printf("restored child exited with status %d\n", telefork_wait_for_exit(child));
+    return 0;
+}
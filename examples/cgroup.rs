@@ -0,0 +1,77 @@
+//! A process in a cgroup of its own, dumped and restored. The restored one
+//! should be moved into the same cgroup rather than staying in ours, once
+//! with the cgroup still there and once after it's been removed, which
+//! `Config::create_cgroups` makes again. Needs to run as root on a machine
+//! with cgroup v2.
+
+mod common;
+
+use telefork::{teledump, telepad_file_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::fs::File;
+use std::path::PathBuf;
+
+fn cgroup2_mount() -> PathBuf {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap();
+    mountinfo
+        .lines()
+        .find_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            fs.starts_with("cgroup2 ")
+                .then(|| PathBuf::from(mount.split_whitespace().nth(4).unwrap()))
+        })
+        .expect("cgroup v2 isn't mounted")
+}
+
+fn cgroup_of(pid: Pid) -> String {
+    let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap();
+    cgroups
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .unwrap()
+        .to_string()
+}
+
+fn main() {
+    let name = format!("telefork-cgroup-{}", std::process::id());
+    let dir = cgroup2_mount().join(&name);
+    std::fs::create_dir(&dir).unwrap();
+
+    let child = common::spawn_ready(|| {});
+    std::fs::write(dir.join("cgroup.procs"), child.to_string()).unwrap();
+    let cgroup = cgroup_of(child);
+    assert_eq!(cgroup, format!("/{}", name));
+    let path = std::env::temp_dir().join(format!("telefork-cgroup-{}", child));
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    common::reap(child);
+
+    for create_cgroups in [false, true] {
+        if create_cgroups {
+            std::fs::remove_dir(&dir).unwrap();
+        }
+        let config = Config {
+            create_cgroups,
+            ..Config::default()
+        };
+        let restored = telepad_file_attached(&File::open(&path).unwrap(), 0, &config).unwrap();
+        let pid = restored.pid();
+        let got = cgroup_of(pid);
+        assert!(restored.skipped().is_empty(), "{:?}", restored.skipped());
+        drop(restored);
+        common::reap(pid);
+        assert_eq!(got, cgroup, "the restored process is in the wrong cgroup");
+        println!(
+            "restored into {}{}",
+            got,
+            if create_cgroups {
+                " after making it"
+            } else {
+                ""
+            }
+        );
+    }
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+}
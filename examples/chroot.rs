@@ -0,0 +1,64 @@
+//! A process that chrooted into a directory, moved into a subdirectory of it
+//! and opened a file there by a relative path. The restored one should be
+//! chrooted to the same place, have the fd on that file rather than on one
+//! of the same name wherever `telepad` runs, and resolve a fresh relative
+//! open inside the chroot. Chrooting needs root, without it there's nothing
+//! to try.
+
+mod common;
+
+use telefork::{telepad, Config};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+fn main() {
+    if !nix::unistd::geteuid().is_root() {
+        println!("chroot needs root, nothing to try");
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("telefork-chroot-{}", std::process::id()));
+    let (root, elsewhere) = (dir.join("root"), dir.join("elsewhere"));
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::create_dir_all(elsewhere.join("sub")).unwrap();
+    std::fs::write(root.join("sub/data"), "inside").unwrap();
+    std::fs::write(root.join("sub/other"), "inside too").unwrap();
+    std::fs::write(elsewhere.join("sub/data"), "outside").unwrap();
+    std::fs::write(elsewhere.join("sub/other"), "outside too").unwrap();
+    // Seen from inside the chroot these are `/go` and `/out`
+    let (go, out) = (root.join("go"), root.join("out"));
+
+    let (child, _) = common::spawn_with(|ready| {
+        nix::unistd::chroot(&root).unwrap();
+        std::env::set_current_dir("/sub").unwrap();
+        let mut data = File::open("data").unwrap();
+        ready.send(&[]);
+        common::wait_for(Path::new("/go"));
+        let mut contents = String::new();
+        data.read_to_string(&mut contents).unwrap();
+        let other = std::fs::read_to_string("other").unwrap_or_default();
+        std::fs::write("/out", format!("{} and {}", contents, other)).unwrap();
+        std::process::exit(0);
+    });
+
+    let dump = common::dump(child, &Config::default());
+
+    // Somewhere the same relative paths find the wrong files if the root
+    // isn't put back
+    std::env::set_current_dir(&elsewhere).unwrap();
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(
+        got, "inside and inside too",
+        "the restored process lost its root or resolved paths outside it"
+    );
+    println!("restored inside the chroot: {}", got);
+}
@@ -0,0 +1,70 @@
+//! `telefork dump --compress zstd --encrypt --key-file` and then `telefork
+//! restore --key-file` with the same key. Restoring without a key or with a
+//! different one should fail and say why, rather than with whatever garbage
+//! the undecrypted stream decodes to.
+
+mod common;
+
+use std::path::Path;
+
+/// Run the `telefork` CLI, returning whether it worked and what it printed
+/// to stderr
+fn run(args: &[&str]) -> (bool, String) {
+    let out = common::cli(args);
+    let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+    (out.status.success(), stderr)
+}
+
+fn main() {
+    let dir = common::temp_dir("cli_transport");
+    let (go, out) = (dir.join("go"), dir.join("out"));
+    let (child, _) = common::spawn_with(|ready| {
+        ready.send(&[]);
+        common::wait_for(&go);
+        std::fs::write(&out, "restored").unwrap();
+        std::process::exit(0);
+    });
+    common::wait_asleep(child);
+
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let (dump, key, wrong_key) = (path("dump.bin"), path("key"), path("wrong_key"));
+    std::fs::write(&key, "ab".repeat(32)).unwrap();
+    std::fs::write(&wrong_key, "cd".repeat(32)).unwrap();
+    let (dumped, stderr) = run(&[
+        "dump",
+        &child.to_string(),
+        &dump,
+        "--compress",
+        "zstd:5",
+        "--encrypt",
+        "--key-file",
+        &key,
+    ]);
+    assert!(dumped, "telefork dump failed: {}", stderr);
+    // It was killed by the dump since it wasn't left running
+    nix::sys::wait::waitpid(child, None).unwrap();
+    std::fs::write(&go, "").unwrap();
+
+    let (restored, stderr) = run(&["restore", &dump]);
+    assert!(!restored, "restored an encrypted dump without a key");
+    assert!(
+        stderr.contains("restore it with --key-file"),
+        "the error doesn't ask for a key: {}",
+        stderr
+    );
+    let (restored, stderr) = run(&["restore", &dump, "--key-file", &wrong_key]);
+    assert!(!restored, "restored an encrypted dump with the wrong key");
+    assert!(
+        stderr.contains("wrong key?"),
+        "the error doesn't say the key might be wrong: {}",
+        stderr
+    );
+    println!("refused without a key and with the wrong key");
+
+    let (restored, stderr) = run(&["restore", &dump, "--key-file", &key]);
+    let wrote = std::fs::read_to_string(Path::new(&out)).ok();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(restored, "telefork restore failed: {}", stderr);
+    assert_eq!(wrote.as_deref(), Some("restored"));
+    println!("restored from a compressed and encrypted dump");
+}
@@ -0,0 +1,127 @@
+//! The setup most of the examples share: a forked child that gets itself into
+//! whatever state the example is about, says when it's ready to be dumped and
+//! then waits around to be killed or restored. Each example only uses some of
+//! this.
+#![allow(dead_code)]
+
+use telefork::{teledump_with_config, Config};
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, ForkResult, Pid};
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+
+/// The child's end of the pipe it says it's ready on
+pub struct Ready(File);
+
+impl Ready {
+    /// Tell the parent we're set up, handing it `msg`, like an address or fd
+    /// number it wants to check against the restored process
+    pub fn send(mut self, msg: &[u8]) {
+        // Prefixed with its length since anything the child forks shares the
+        // pipe, so we can't wait for the end of it
+        self.0.write_all(&(msg.len() as u32).to_le_bytes()).unwrap();
+        self.0.write_all(msg).unwrap();
+    }
+}
+
+/// Fork a child that dies along with us and run `child` in it, returning once
+/// it has called `Ready::send` with whatever it sent. That's empty if it died
+/// or returned without sending. If `child` returns the process pauses until
+/// it's killed, holding on to what it returned, like a file it has to keep
+/// open. Otherwise it's up to `child` to exit.
+pub fn spawn_with<T>(child: impl FnOnce(Ready) -> T) -> (Pid, Vec<u8>) {
+    let (ready_read, ready_write) = nix::unistd::pipe().unwrap();
+    let pid = match fork().unwrap() {
+        ForkResult::Child => {
+            // Don't outlive a failed check
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+            nix::unistd::close(ready_read).unwrap();
+            let _kept = child(Ready(unsafe { File::from_raw_fd(ready_write) }));
+            loop {
+                unsafe { libc::pause() };
+            }
+        }
+        ForkResult::Parent { child } => child,
+    };
+    // So we see end of file if it dies before it's ready
+    nix::unistd::close(ready_write).unwrap();
+    let mut ready = unsafe { File::from_raw_fd(ready_read) };
+    let mut len = [0u8; 4];
+    if ready.read_exact(&mut len).is_err() {
+        return (pid, Vec::new());
+    }
+    let mut msg = vec![0u8; u32::from_le_bytes(len) as usize];
+    ready.read_exact(&mut msg).unwrap();
+    (pid, msg)
+}
+
+/// Fork a child that runs `setup` and then waits to be dumped, holding on to
+/// what `setup` returned. Returns once the child is asleep.
+pub fn spawn_ready<T>(setup: impl FnOnce() -> T) -> Pid {
+    let (child, _) = spawn_with(|ready| {
+        let kept = setup();
+        ready.send(&[]);
+        kept
+    });
+    wait_asleep(child);
+    child
+}
+
+/// Wait for `pid` to be asleep, like in the `pause` it waits to be dumped in.
+/// Dumping it before then catches it partway through getting there.
+pub fn wait_asleep(pid: Pid) {
+    while !std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .unwrap()
+        .contains(") S ")
+    {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+/// Wait for `path` to exist, which is how the examples tell a restored
+/// process to go on
+pub fn wait_for(path: &Path) {
+    while !path.exists() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+/// Dump `child` with `config` into memory, then kill it so only the dump is
+/// left
+pub fn dump(child: Pid, config: &Config) -> Vec<u8> {
+    let mut dump = Vec::new();
+    teledump_with_config(child.as_raw(), &mut dump, true, config).unwrap();
+    reap(child);
+    dump
+}
+
+/// Kill `pid` and wait for it to be gone
+pub fn reap(pid: Pid) {
+    kill(pid, Signal::SIGKILL).unwrap();
+    waitpid(pid, None).unwrap();
+}
+
+/// Run the `telefork` command line tool with `args`, the one built alongside
+/// the examples
+pub fn cli(args: &[&str]) -> std::process::Output {
+    let exe = std::env::current_exe().unwrap();
+    let bin = exe.parent().unwrap().parent().unwrap().join("telefork");
+    assert!(
+        bin.exists(),
+        "there's no {}, cargo build it first",
+        bin.display()
+    );
+    std::process::Command::new(bin).args(args).output().unwrap()
+}
+
+/// A fresh directory under the temp dir for the example called `name`
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("telefork-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
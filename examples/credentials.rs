@@ -0,0 +1,59 @@
+//! A root process that dropped to `nobody`, its groups first and then its
+//! real, effective and saved ids, like a daemon does after binding its
+//! ports. The restored process should be `nobody` again rather than keep
+//! the root ids of the `telepad` that forked it. Needs to run as root.
+
+mod common;
+
+use telefork::{telepad_attached, Config};
+
+use nix::unistd::Pid;
+
+const NOBODY: u32 = 65534;
+
+/// The `Uid`, `Gid` and `Groups` lines of the process's status
+fn ids(pid: Pid) -> Vec<String> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    status
+        .lines()
+        .filter(|l| l.starts_with("Uid:") || l.starts_with("Gid:") || l.starts_with("Groups:"))
+        .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect()
+}
+
+fn main() {
+    if !nix::unistd::geteuid().is_root() {
+        println!("changing ids needs root, nothing to try");
+        return;
+    }
+
+    let child = common::spawn_ready(|| unsafe {
+        assert_eq!(libc::setgroups(1, &NOBODY), 0);
+        assert_eq!(libc::setresgid(NOBODY, NOBODY, NOBODY), 0);
+        assert_eq!(libc::setresuid(NOBODY, NOBODY, NOBODY), 0);
+    });
+    let dumped = ids(child);
+    assert_eq!(
+        dumped,
+        vec![
+            "Uid: 65534 65534 65534 65534",
+            "Gid: 65534 65534 65534 65534",
+            "Groups: 65534",
+        ],
+        "the process didn't drop to nobody"
+    );
+
+    let dump = common::dump(child, &Config::default());
+
+    let restored = telepad_attached(&mut &dump[..], 0, &Config::default()).unwrap();
+    let pid = restored.pid();
+    let got = ids(pid);
+    drop(restored);
+    common::reap(pid);
+
+    assert_eq!(
+        got, dumped,
+        "the restored process doesn't have the original's ids"
+    );
+    println!("restored process runs as nobody: {}", got.join(", "));
+}
@@ -0,0 +1,112 @@
+//! `dump --cuda` against a stand-in for `cuda-checkpoint` that only keeps
+//! track of the state and writes down each toggle, along with how much of
+//! the dump had been written when it happened. The GPU state has to be
+//! checkpointed before anything is dumped and put back only after all of it
+//! is. A dump that fails partway has to put it back too, rather than leave
+//! the process with its GPU state copied out.
+
+mod common;
+
+use telefork::cmd::{dump, Meta, Transport};
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Answers `--get-state` from the state file and logs `--toggle`s
+fn write_stub(stub: &Path, state: &Path, log: &Path, dump: &Path) {
+    let script = format!(
+        r#"#!/bin/sh
+state=$(cat '{state}' 2>/dev/null || echo running)
+case "$1" in
+--get-state) echo "$state" ;;
+--toggle)
+    echo "toggle $state $(stat -c %s '{dump}' 2>/dev/null || echo none)" >> '{log}'
+    if [ "$state" = running ]; then echo checkpointed; else echo running; fi > '{state}'
+    ;;
+esac
+"#,
+        state = state.display(),
+        log = log.display(),
+        dump = dump.display(),
+    );
+    std::fs::write(stub, script).unwrap();
+    std::fs::set_permissions(stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// The logged toggles, as what it was toggled from and whether the dump had
+/// anything in it by then
+fn toggles(log: &Path) -> Vec<(String, bool)> {
+    let log = std::fs::read_to_string(log).unwrap_or_default();
+    let toggles = log.lines().map(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let dumped = fields[2] != "none" && fields[2] != "0";
+        (fields[1].to_string(), dumped)
+    });
+    toggles.collect()
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-cuda-order-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (stub, state, log, path) = (
+        dir.join("cuda-checkpoint"),
+        dir.join("state"),
+        dir.join("log"),
+        dir.join("dump"),
+    );
+    write_stub(&stub, &state, &log, &path);
+    std::env::set_var("CUDA_CHECKPOINT", &stub);
+
+    let child = common::spawn_ready(|| {});
+
+    let dumped = dump(
+        child.as_raw(),
+        &path,
+        true,
+        true,
+        Meta::None,
+        Transport::default(),
+        0,
+    );
+    common::reap(child);
+    dumped.unwrap();
+    let got = toggles(&log);
+    assert_eq!(
+        got,
+        vec![
+            ("running".to_string(), false),
+            ("checkpointed".to_string(), true)
+        ],
+        "checkpointed after dumping started or put back before it finished"
+    );
+    println!("checkpointed before the dump and put back after it");
+
+    // It's gone now, so the dump fails once the GPU state is checkpointed
+    std::fs::remove_file(&log).unwrap();
+    let failed = dump(
+        child.as_raw(),
+        &path,
+        true,
+        true,
+        Meta::None,
+        Transport::default(),
+        0,
+    );
+    let got = toggles(&log);
+    let state_after = std::fs::read_to_string(&state).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(failed.is_err(), "dumping a process that's gone worked");
+    assert_eq!(
+        got.iter()
+            .map(|(from, _)| from.as_str())
+            .collect::<Vec<_>>(),
+        ["running", "checkpointed"],
+        "the GPU state wasn't put back after the dump failed"
+    );
+    assert_eq!(state_after.trim(), "running");
+    println!(
+        "put the GPU state back after the dump failed: {}",
+        failed.unwrap_err()
+    );
+}
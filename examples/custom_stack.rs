@@ -0,0 +1,74 @@
+//! Round trip while running on a stack we mapped ourselves, like green
+//! thread runtimes do, rather than the `[stack]` the kernel gave us.
+
+use telefork::harness::round_trip;
+
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+static STACK: AtomicUsize = AtomicUsize::new(0);
+static STATUS: AtomicI32 = AtomicI32::new(-1);
+
+/// Where our stack is right now
+fn stack_pointer() -> usize {
+    let local = 0u8;
+    std::hint::black_box(&local) as *const u8 as usize
+}
+
+fn on_stack(addr: usize) -> bool {
+    let stack = STACK.load(Ordering::SeqCst);
+    stack <= addr && addr < stack + STACK_SIZE
+}
+
+extern "C" fn run() {
+    assert!(on_stack(stack_pointer()), "didn't switch stacks");
+    let trip = round_trip(|| {
+        // Deeper than where it was dumped, on memory that has to have come back
+        let deeper = vec![7u8; 64 * 1024];
+        let sum: usize = std::hint::black_box(&deeper)
+            .iter()
+            .map(|b| *b as usize)
+            .sum();
+        if on_stack(stack_pointer()) && sum == 7 * 64 * 1024 {
+            42
+        } else {
+            1
+        }
+    });
+    // Panicking can't unwind out of here, so leave it for main
+    match trip {
+        Ok(trip) => STATUS.store(trip.status, Ordering::SeqCst),
+        Err(e) => eprintln!("round trip failed: {}", e),
+    }
+}
+
+fn main() {
+    unsafe {
+        let stack = libc::mmap(
+            std::ptr::null_mut(),
+            STACK_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        assert_ne!(stack, libc::MAP_FAILED);
+        STACK.store(stack as usize, Ordering::SeqCst);
+
+        let mut main_ctx: libc::ucontext_t = std::mem::zeroed();
+        let mut ctx: libc::ucontext_t = std::mem::zeroed();
+        assert_eq!(libc::getcontext(&mut ctx), 0);
+        ctx.uc_stack.ss_sp = stack;
+        ctx.uc_stack.ss_size = STACK_SIZE;
+        ctx.uc_link = &mut main_ctx;
+        libc::makecontext(&mut ctx, run, 0);
+        assert_eq!(libc::swapcontext(&mut main_ctx, &ctx), 0);
+    }
+    let status = STATUS.load(Ordering::SeqCst);
+    assert_eq!(
+        status, 42,
+        "the restored process didn't carry on from the custom stack"
+    );
+    println!("round trip on a custom stack ok");
+}
@@ -0,0 +1,73 @@
+//! A process that changed directory, opened a file there by a relative path
+//! and set a umask of its own. The restored one should be in the same
+//! directory with the same umask, and the fd should be the file from that
+//! directory rather than one of the same name wherever `telepad` is.
+//!
+//! This only checks the working directory and umask themselves. Fds are
+//! reopened by their absolute path and never with `O_CREAT`, so they come
+//! out the same whichever order these go in. The root is the one fds do
+//! depend on, which the `chroot` example checks.
+
+mod common;
+
+use telefork::{telepad, Config};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+
+const UMASK: u32 = 0o027;
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-cwd-{}", std::process::id()));
+    let (elsewhere, cwd) = (dir.join("elsewhere"), dir.join("cwd"));
+    std::fs::create_dir_all(&elsewhere).unwrap();
+    std::fs::create_dir_all(&cwd).unwrap();
+    std::fs::write(cwd.join("data"), "the right one").unwrap();
+    std::fs::write(elsewhere.join("data"), "the wrong one").unwrap();
+    let (go, out) = (dir.join("go"), dir.join("out"));
+
+    let (child, _) = common::spawn_with(|ready| {
+        std::env::set_current_dir(&cwd).unwrap();
+        unsafe { libc::umask(UMASK) };
+        let mut data = File::open("data").unwrap();
+        ready.send(&[]);
+        common::wait_for(&go);
+        let mut contents = String::new();
+        data.read_to_string(&mut contents).unwrap();
+        let here = std::env::current_dir().unwrap();
+        File::create("made").unwrap();
+        let mode = std::fs::metadata("made").unwrap().permissions().mode() & 0o777;
+        std::fs::write(
+            &out,
+            format!("{} in {} making {:o}", contents, here.display(), mode),
+        )
+        .unwrap();
+        std::process::exit(0);
+    });
+
+    let dump = common::dump(child, &Config::default());
+
+    // Somewhere the restored process would see the wrong file if it kept
+    // our working directory
+    std::env::set_current_dir(&elsewhere).unwrap();
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(
+        got,
+        format!(
+            "the right one in {} making {:o}",
+            cwd.display(),
+            0o666 & !UMASK
+        ),
+        "the restored process lost its working directory or umask"
+    );
+    println!("restored with {}", got);
+}
@@ -0,0 +1,47 @@
+use telefork::{telefork, FaultReport, TeleforkLocation, TelepadBuilder, FIRST_FAULT_WINDOW};
+
+use std::fs::File;
+
+fn main() {
+    let fname = "debug_fault.telefork.bin";
+    // A page of garbage that isn't even executable, standing in for code
+    // that got mangled on the way over
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(page, libc::MAP_FAILED);
+    unsafe { std::ptr::write_bytes(page as *mut u8, 0xff, 4096) };
+    let loc = {
+        let mut output = File::create(fname).unwrap();
+        telefork(&mut output).unwrap()
+    };
+    match loc {
+        TeleforkLocation::Child(_) => {
+            let mangled: extern "C" fn() = unsafe { std::mem::transmute(page) };
+            mangled();
+            std::process::exit(1)
+        }
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+
+    let mut input = File::open(fname).unwrap();
+    let mut restored = TelepadBuilder::new()
+        .telepad_attached(&mut input, 0)
+        .unwrap();
+    let err = restored.watch_first_fault(FIRST_FAULT_WINDOW).unwrap_err();
+    println!("{}", err);
+    let report = err.downcast_ref::<FaultReport>().unwrap();
+    let start = format!("{:x}-", page as usize);
+    assert!(report.rip_mapping.as_ref().unwrap().starts_with(&start));
+    let pid = restored.pid();
+    drop(restored.detach_stopped());
+    nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL).unwrap();
+    nix::sys::wait::waitpid(pid, None).unwrap();
+}
@@ -0,0 +1,71 @@
+//! A process with a small file open, restored after the file has been
+//! deleted, like on a machine that never had it. The dump carries small
+//! files' contents, so the restored fd should still read what the file had
+//! in it, from a memfd, at the same offset.
+
+mod common;
+
+use telefork::{telepad_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+
+const CONTENTS: &str = "a small config file the process read part of";
+const OFFSET: usize = 8;
+
+/// The `pos` line of the fd's `/proc/pid/fdinfo`
+fn offset(pid: Pid, fd: u32) -> u64 {
+    let info = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd)).unwrap();
+    let pos = info.lines().find(|l| l.starts_with("pos:")).unwrap();
+    pos.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+fn main() {
+    let dir = common::temp_dir("deleted_file");
+    let path = dir.join("config");
+    std::fs::write(&path, CONTENTS).unwrap();
+
+    let (child, fd) = common::spawn_with(|ready| {
+        let mut file = File::open(&path).unwrap();
+        file.read_exact(&mut [0u8; OFFSET]).unwrap();
+        let fd = file.as_raw_fd();
+        ready.send(&fd.to_le_bytes());
+        file
+    });
+    let fd = u32::from_le_bytes(fd[..].try_into().unwrap());
+    let dump = common::dump(child, &Config::default());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let restored = telepad_attached(&mut &dump[..], 0, &Config::default()).unwrap();
+    let pid = restored.pid();
+    let target = std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd));
+    let pos = offset(pid, fd);
+    // Opening it through /proc gets the same file at its own offset
+    let contents = std::fs::read_to_string(format!("/proc/{}/fd/{}", pid, fd));
+    drop(restored);
+    common::reap(pid);
+
+    let target = target.expect("fd wasn't restored");
+    assert!(
+        target.to_string_lossy().starts_with("/memfd:"),
+        "fd {} is {} rather than a memfd",
+        fd,
+        target.display()
+    );
+    assert_eq!(
+        contents.unwrap(),
+        CONTENTS,
+        "the memfd has the wrong contents"
+    );
+    assert_eq!(pos, OFFSET as u64, "the fd is at the wrong offset");
+    println!(
+        "fd {} came back as {} at offset {} with the deleted file's contents",
+        fd,
+        target.display(),
+        pos
+    );
+}
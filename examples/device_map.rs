@@ -0,0 +1,151 @@
+//! A process with a DRM dumb buffer mapped from `/dev/dri/card*`. Dumping it
+//! should fail with an `Unsupported` naming the device, and with
+//! `Config::skip_device_maps` it should dump, restore, and find zeroes where
+//! the buffer was. Needs a card that can make dumb buffers, like vkms or
+//! virtio-gpu, and does nothing without one.
+
+mod common;
+
+use telefork::{teledump_with_config, telepad, Config, Unsupported};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+const DRM_IOCTL_MODE_CREATE_DUMB: libc::c_ulong = 0xc020_64b2;
+const DRM_IOCTL_MODE_MAP_DUMB: libc::c_ulong = 0xc010_64b3;
+
+#[repr(C)]
+#[derive(Default)]
+struct CreateDumb {
+    height: u32,
+    width: u32,
+    bpp: u32,
+    flags: u32,
+    handle: u32,
+    pitch: u32,
+    size: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapDumb {
+    handle: u32,
+    pad: u32,
+    offset: u64,
+}
+
+fn find_card() -> Option<PathBuf> {
+    let mut cards: Vec<_> = std::fs::read_dir("/dev/dri")
+        .ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("card"))
+        .collect();
+    cards.sort();
+    cards.into_iter().next()
+}
+
+/// Make a dumb buffer on the card and map it, returning where and how big
+fn map_dumb_buffer(card: &File) -> std::io::Result<(*mut u8, usize)> {
+    let mut create = CreateDumb {
+        height: 64,
+        width: 64,
+        bpp: 32,
+        ..CreateDumb::default()
+    };
+    if unsafe { libc::ioctl(card.as_raw_fd(), DRM_IOCTL_MODE_CREATE_DUMB, &mut create) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut map = MapDumb {
+        handle: create.handle,
+        ..MapDumb::default()
+    };
+    if unsafe { libc::ioctl(card.as_raw_fd(), DRM_IOCTL_MODE_MAP_DUMB, &mut map) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let size = create.size as usize;
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            card.as_raw_fd(),
+            map.offset as libc::off_t,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((addr as *mut u8, size))
+}
+
+fn main() {
+    let card = match find_card() {
+        Some(card) => card,
+        None => {
+            println!("no /dev/dri/card*, nothing to try");
+            return;
+        }
+    };
+    let dir = std::env::temp_dir().join(format!("telefork-device-map-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (go, out) = (dir.join("go"), dir.join("out"));
+    let (child, ready) = common::spawn_with(|ready| {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&card)
+            .unwrap();
+        let (buffer, size) = match map_dumb_buffer(&file) {
+            Ok(mapped) => mapped,
+            Err(e) => {
+                eprintln!("{} can't make dumb buffers: {}", card.display(), e);
+                std::process::exit(2);
+            }
+        };
+        let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, size) };
+        buffer.fill(0xab);
+        ready.send(&[1]);
+        common::wait_for(&go);
+        let zeroes = buffer.iter().all(|&b| b == 0);
+        std::fs::write(&out, if zeroes { "zeroes" } else { "not zeroes" }).unwrap();
+        std::process::exit(0);
+    });
+    if ready.is_empty() {
+        waitpid(child, None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        println!("the card couldn't map a buffer, nothing to try");
+        return;
+    }
+
+    let err = teledump_with_config(child.as_raw(), &mut Vec::new(), true, &Config::default())
+        .expect_err("a process with a device mapped was dumped");
+    let unsupported = err
+        .downcast_ref::<Unsupported>()
+        .unwrap_or_else(|| panic!("failed some other way: {}", err));
+    assert!(
+        unsupported.0.contains(&*card.to_string_lossy()),
+        "the error doesn't say which device: {}",
+        unsupported
+    );
+    println!("refused by default: {}", unsupported);
+
+    let config = Config {
+        skip_device_maps: true,
+        ..Config::default()
+    };
+    let dump = common::dump(child, &config);
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(got, "zeroes", "the skipped device mapping isn't zeroed");
+    println!("with skip_device_maps it came back as zeroes");
+}
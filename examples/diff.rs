@@ -0,0 +1,57 @@
+mod common;
+
+use telefork::{diff_dumps, teledump};
+
+use nix::sys::uio;
+
+use std::fs::File;
+
+const PAGE_SIZE: usize = 4096;
+
+fn main() {
+    // Mapped before forking so we know where it is in the child too
+    let len = 4 * PAGE_SIZE;
+    let mem = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(mem, libc::MAP_FAILED);
+    let addr = mem as usize;
+    unsafe { std::ptr::write_bytes(mem as *mut u8, 1, len) };
+
+    let child = common::spawn_ready(|| {});
+
+    let dir = std::env::temp_dir();
+    let (a, b) = (dir.join("telefork-diff-a"), dir.join("telefork-diff-b"));
+    teledump(child.as_raw(), &mut File::create(&a).unwrap(), true).unwrap();
+    // Change just the second page, from out here so the child doesn't run
+    let page = [2u8; PAGE_SIZE];
+    uio::process_vm_writev(
+        child,
+        &[uio::IoVec::from_slice(&page)],
+        &[uio::RemoteIoVec {
+            base: addr + PAGE_SIZE,
+            len: PAGE_SIZE,
+        }],
+    )
+    .unwrap();
+    teledump(child.as_raw(), &mut File::create(&b).unwrap(), true).unwrap();
+    common::reap(child);
+
+    let diff = diff_dumps(&a, &b).unwrap();
+    print!("{}", diff);
+    assert!(diff.added.is_empty());
+    assert!(diff.removed.is_empty());
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].after.addr, addr);
+    assert_eq!(diff.changed[0].pages, vec![addr + PAGE_SIZE]);
+    assert!(diff_dumps(&a, &a).unwrap().is_empty());
+    std::fs::remove_file(a).unwrap();
+    std::fs::remove_file(b).unwrap();
+}
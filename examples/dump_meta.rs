@@ -0,0 +1,41 @@
+//! `telefork dump --meta` writes a JSON summary next to the dump for scripts
+//! to pick up. It should parse as JSON and describe the dump it sits next to.
+
+mod common;
+
+fn main() {
+    let child = common::spawn_ready(|| ());
+    let dir = common::temp_dir("dump_meta");
+    let path = dir.join("dump.bin");
+    let out = common::cli(&[
+        "dump",
+        "--leave-running",
+        &child.to_string(),
+        path.to_str().unwrap(),
+        // Last since it takes an optional path
+        "--meta",
+    ]);
+    common::reap(child);
+    assert!(out.status.success(), "telefork dump failed: {:?}", out);
+
+    let dump = std::fs::read(&path).unwrap();
+    let meta = std::fs::read(dir.join("dump.bin.meta.json")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let meta: serde_json::Value = serde_json::from_slice(&meta).unwrap();
+    println!("{}", meta);
+
+    assert_eq!(meta["pid"], child.as_raw());
+    assert_eq!(
+        meta["bytes"],
+        dump.len() as u64,
+        "the size doesn't match the dump"
+    );
+    assert_eq!(
+        meta["crc32"],
+        format!("{:08x}", crc32fast::hash(&dump)),
+        "the checksum doesn't match the dump"
+    );
+    let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap();
+    assert_eq!(meta["kernel"], kernel.trim());
+    assert!(meta["memory_bytes"].as_u64().unwrap() > 0);
+}
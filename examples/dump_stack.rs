@@ -0,0 +1,67 @@
+//! Dumping a process that's left running injects a few syscalls into it to
+//! ask about things `/proc` doesn't say. They get a stack of their own, so
+//! the process's stack, including whatever is below its stack pointer,
+//! should be exactly the same afterwards. The process sits blocked in a
+//! `read` the whole time so it doesn't change its stack itself.
+
+mod common;
+
+use telefork::teledump;
+
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+
+/// Where the `[stack]` mapping is
+fn stack_of(pid: Pid) -> (usize, usize) {
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap();
+    let line = maps.lines().find(|l| l.ends_with("[stack]")).unwrap();
+    let (start, end) = line.split(' ').next().unwrap().split_once('-').unwrap();
+    let start = usize::from_str_radix(start, 16).unwrap();
+    (start, usize::from_str_radix(end, 16).unwrap() - start)
+}
+
+fn read_stack(pid: Pid) -> Vec<u8> {
+    let (base, len) = stack_of(pid);
+    let mut bytes = vec![0u8; len];
+    let read = process_vm_readv(
+        pid,
+        &[IoVec::from_mut_slice(&mut bytes)],
+        &[RemoteIoVec { base, len }],
+    )
+    .unwrap();
+    assert_eq!(read, len);
+    bytes
+}
+
+fn main() {
+    // Never written to, the child just waits on it
+    let (wake_read, _wake_write) = nix::unistd::pipe().unwrap();
+    let (child, _) = common::spawn_with(|ready| {
+        ready.send(&[]);
+        let _ = unsafe { File::from_raw_fd(wake_read) }.read(&mut [0]);
+        std::process::exit(0);
+    });
+    // Long enough for it to get into the read
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let before = read_stack(child);
+    let maps_before = std::fs::read_to_string(format!("/proc/{}/maps", child)).unwrap();
+    teledump(child.as_raw(), &mut Vec::new(), true).unwrap();
+    let after = read_stack(child);
+    let maps_after = std::fs::read_to_string(format!("/proc/{}/maps", child)).unwrap();
+    let changed = before.iter().zip(&after).filter(|(a, b)| a != b).count();
+
+    common::reap(child);
+
+    assert_eq!(before.len(), after.len(), "the stack changed size");
+    assert_eq!(changed, 0, "dumping changed {} bytes of the stack", changed);
+    assert_eq!(
+        maps_before, maps_after,
+        "dumping left something mapped in the process"
+    );
+    println!("the {} byte stack was left alone", before.len());
+}
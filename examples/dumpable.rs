@@ -0,0 +1,56 @@
+//! A root process that moved its fsuid to `nobody` and then marked itself
+//! dumpable again, since changing fs ids resets the flag to
+//! `fs.suid_dumpable`. The restored one should still be dumpable, which means
+//! the flag has to go back after its fs ids do. With `fs.suid_dumpable` set
+//! to 1 it'd come back dumpable either way, so there's nothing to tell apart.
+//! Needs to run as root.
+
+mod common;
+
+use telefork::{telepad, Config};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+const NOBODY: u32 = 65534;
+
+fn main() {
+    if !nix::unistd::geteuid().is_root() {
+        println!("changing fs ids needs root, nothing to try");
+        return;
+    }
+    let suid_dumpable = std::fs::read_to_string("/proc/sys/fs/suid_dumpable").unwrap();
+    if suid_dumpable.trim() == "1" {
+        println!("fs.suid_dumpable is 1, nothing to tell apart");
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("telefork-dumpable-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let go = dir.join("go");
+
+    let (child, dumpable) = common::spawn_with(|ready| {
+        unsafe {
+            libc::setfsuid(NOBODY);
+            libc::prctl(libc::PR_SET_DUMPABLE, 1);
+        }
+        let dumpable = unsafe { libc::prctl(libc::PR_GET_DUMPABLE) };
+        ready.send(&[dumpable as u8]);
+        common::wait_for(&go);
+        // Can't write a file as nobody, the exit status will do
+        std::process::exit(unsafe { libc::prctl(libc::PR_GET_DUMPABLE) });
+    });
+    assert_eq!(dumpable[0], 1, "couldn't make the process dumpable again");
+
+    let dump = common::dump(child, &Config::default());
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        status,
+        WaitStatus::Exited(pid, 1),
+        "the restored process isn't dumpable, restoring its fs ids reset it"
+    );
+    println!("restored process is still dumpable");
+}
@@ -0,0 +1,56 @@
+//! A `sleep` started with an environment of its own, nothing like ours. The
+//! restored one is a child of ours, so unless its environment block is
+//! pointed back at the restored stack `/proc/pid/environ` shows our
+//! environment instead of the one it started with.
+
+use telefork::{teledump, telepad_attached, Config};
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::Pid;
+
+use std::process::Command;
+
+fn environ(pid: Pid) -> Vec<u8> {
+    std::fs::read(format!("/proc/{}/environ", pid)).unwrap()
+}
+
+fn main() {
+    let mut sleep = Command::new("sleep")
+        .arg("1000")
+        .env_clear()
+        .env("TELEFORK_ENVIRON", "from the original")
+        .spawn()
+        .unwrap();
+    let child = Pid::from_raw(sleep.id() as i32);
+    // Until the exec is done it still has ours
+    let dumped = loop {
+        let env = environ(child);
+        if env.starts_with(b"TELEFORK_ENVIRON=") {
+            break env;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+
+    let mut dump = Vec::new();
+    teledump(child.as_raw(), &mut dump, true).unwrap();
+    sleep.kill().unwrap();
+    sleep.wait().unwrap();
+
+    let restored = telepad_attached(&mut &dump[..], 0, &Config::default()).unwrap();
+    let pid = restored.pid();
+    let got = environ(pid);
+    drop(restored);
+    kill(pid, Signal::SIGKILL).unwrap();
+    waitpid(pid, None).unwrap();
+
+    assert_eq!(
+        String::from_utf8_lossy(&got),
+        String::from_utf8_lossy(&dumped),
+        "/proc/pid/environ doesn't show the original environment"
+    );
+    println!(
+        "restored with environment {:?}",
+        String::from_utf8_lossy(&got)
+    );
+}
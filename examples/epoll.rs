@@ -0,0 +1,88 @@
+//! Processes caught blocked in a wait. One in `poll` with no fds gets the
+//! call re-issued on restore, so the dump says it's restarting a syscall.
+//! One in `epoll_wait` can't, since epoll fds aren't restored and waiting on
+//! one again would fail with `EBADF`. It should get `EINTR` back instead,
+//! as if a signal had interrupted the wait.
+
+mod common;
+
+use telefork::{read_indexes, telepad, Config};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+
+use std::io::Cursor;
+use std::path::Path;
+
+/// Fork a process that does `wait` once it's said it's ready, then writes
+/// what it got back and its errno to `out`
+fn blocked_in(wait: fn() -> i32, out: &Path) -> Pid {
+    let (child, _) = common::spawn_with(|ready| {
+        ready.send(&[]);
+        let res = wait();
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        std::fs::write(out, format!("{} {}", res, errno)).unwrap();
+        std::process::exit(0);
+    });
+    // Long enough for it to get into the wait
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    child
+}
+
+/// Dump it and say whether the dump restarts a syscall
+fn dump(child: Pid) -> (Vec<u8>, bool) {
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let dump = common::dump(child, &config);
+    let indexes = read_indexes(&mut Cursor::new(&dump)).unwrap();
+    let restarts = indexes[0].1.commands.contains_key("RestartSyscall");
+    (dump, restarts)
+}
+
+fn epoll_wait() -> i32 {
+    unsafe {
+        let epoll = libc::epoll_create1(0);
+        let mut pipe = [0; 2];
+        libc::pipe(pipe.as_mut_ptr());
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: 0,
+        };
+        libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, pipe[0], &mut event);
+        libc::epoll_wait(epoll, &mut event, 1, -1)
+    }
+}
+
+fn poll_nothing() -> i32 {
+    unsafe { libc::poll(std::ptr::null_mut(), 0, -1) }
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-epoll-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("out");
+
+    let (_, restarts) = dump(blocked_in(poll_nothing, &out));
+    assert!(restarts, "the poll isn't re-issued");
+    println!("poll will be re-issued");
+
+    let (dump, restarts) = dump(blocked_in(epoll_wait, &out));
+    assert!(
+        !restarts,
+        "the epoll_wait is re-issued on an fd that won't exist"
+    );
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(
+        got,
+        format!("-1 {}", libc::EINTR),
+        "the restored epoll_wait didn't come back interrupted"
+    );
+    println!("epoll_wait came back with EINTR");
+}
@@ -0,0 +1,74 @@
+//! `estimate_dump_size` on a process with a big mapping it's only touched a
+//! quarter of should come to what dumping it actually streams, with the
+//! untouched part showing up as not resident.
+
+mod common;
+
+use telefork::{estimate_dump_size, teledump};
+
+use std::fs::File;
+
+const MAPPING: usize = 16 * 1024 * 1024;
+const TOUCHED: usize = MAPPING / 4;
+/// Anything else the dump does on the way that the estimate can't see,
+/// like a page of the process's memory moving around
+const SLACK: usize = 16 * 4096;
+/// The commands around the memory, with the ELF auxv, environment and fds
+const OVERHEAD: u64 = 1024 * 1024;
+
+fn main() {
+    let child = common::spawn_ready(|| {
+        let mem = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                MAPPING,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        } as *mut u8;
+        assert!(mem as *mut libc::c_void != libc::MAP_FAILED);
+        for i in (0..TOUCHED).step_by(4096) {
+            unsafe { *mem.add(i) = 1 };
+        }
+    });
+
+    let estimate = estimate_dump_size(child.as_raw()).unwrap();
+    let path = std::env::temp_dir().join(format!("telefork-estimate-{}", child));
+    let stats = teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    let file_size = std::fs::metadata(&path).unwrap().len();
+    common::reap(child);
+    std::fs::remove_file(&path).unwrap();
+    println!(
+        "estimated {:?}, dumped {:?} in {} bytes",
+        estimate, stats, file_size
+    );
+
+    assert_eq!(
+        estimate.mappings, stats.mappings,
+        "counted different mappings"
+    );
+    assert!(
+        estimate.total_bytes.abs_diff(stats.memory_bytes) <= SLACK,
+        "estimated {} bytes of memory but {} were dumped",
+        estimate.total_bytes,
+        stats.memory_bytes
+    );
+    assert!(
+        file_size >= estimate.total_bytes as u64
+            && file_size - (estimate.total_bytes as u64) <= OVERHEAD + SLACK as u64,
+        "estimated {} bytes of memory but the dump is {}",
+        estimate.total_bytes,
+        file_size
+    );
+    assert!(
+        estimate.resident_bytes >= TOUCHED
+            && estimate.resident_bytes + (MAPPING - TOUCHED) <= estimate.total_bytes,
+        "{} of {} bytes estimated resident, with {} untouched",
+        estimate.resident_bytes,
+        estimate.total_bytes,
+        MAPPING - TOUCHED
+    );
+    println!("estimate matched the dump");
+}
@@ -0,0 +1,30 @@
+//! A restore from a dump that's cut off halfway, so it fails well after the
+//! process it was restoring into has been forked. That half restored process
+//! should be killed and reaped before `telepad` returns, leaving us with no
+//! children at all rather than a stuck, traced leftover.
+
+mod common;
+
+use telefork::{telepad, Config};
+
+use nix::errno::Errno;
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::Pid;
+
+fn main() {
+    let child = common::spawn_ready(|| {});
+
+    let mut dump = common::dump(child, &Config::default());
+    dump.truncate(dump.len() / 2);
+
+    let err = telepad(&mut &dump[..], 0).unwrap_err();
+    println!("restore failed: {}", err);
+    // Anything left, even a zombie, would still be ours to wait for
+    let left = waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG));
+    assert_eq!(
+        left.map_err(|e| e.as_errno()),
+        Err(Some(Errno::ECHILD)),
+        "the failed restore left a child behind"
+    );
+    println!("nothing was left behind");
+}
@@ -0,0 +1,49 @@
+//! `scan_file_descriptors` on a process that's opening and closing files as
+//! fast as it can, so some of the fds it lists are gone by the time it looks
+//! at them. Every scan should still work and find the one file it keeps
+//! open the whole time, and a dump of it should too.
+
+mod common;
+
+use telefork::{scan_file_descriptors, Config, Connection};
+
+use std::fs::File;
+
+const SCANS: usize = 2000;
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-fd-race-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (kept, churned) = (dir.join("kept"), dir.join("churned"));
+    std::fs::write(&kept, "kept").unwrap();
+    std::fs::write(&churned, "churned").unwrap();
+
+    let (child, _) = common::spawn_with(|ready| {
+        let _kept = File::open(&kept).unwrap();
+        ready.send(&[]);
+        loop {
+            let files: Vec<File> = (0..32).map(|_| File::open(&churned).unwrap()).collect();
+            drop(files);
+        }
+    });
+    let kept_path = kept.to_string_lossy().to_string();
+    let has_kept = |fds: &telefork::ConnectionMap| {
+        fds.values()
+            .any(|c| matches!(c, Connection::File(f) if f.path == kept_path))
+    };
+
+    let mut churned_seen = 0;
+    for _ in 0..SCANS {
+        let fds = scan_file_descriptors(child.as_raw()).unwrap();
+        assert!(has_kept(&fds), "lost the file that stayed open");
+        churned_seen += fds.values().filter(|c| c.path().is_some()).count() - 1;
+    }
+    println!(
+        "{} scans of a process closing fds under them all worked, seeing {} of the churned ones",
+        SCANS, churned_seen
+    );
+
+    common::dump(child, &Config::default());
+    std::fs::remove_dir_all(&dir).unwrap();
+    println!("and dumping it did too");
+}
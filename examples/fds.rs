@@ -0,0 +1,82 @@
+//! `telefork fds` on a process with a file, a pipe and a TCP socket open. The
+//! file should be listed with its path and offset as one it'd restore, and
+//! the pipe and socket as ones it'd drop, with what they are.
+
+mod common;
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+
+const OFFSET: u64 = 5;
+
+/// What `/proc/pid/fd/fd` points at, like `pipe:[1234]`
+fn target(pid: Pid, fd: u32) -> String {
+    let link = std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).unwrap();
+    link.to_string_lossy().to_string()
+}
+
+fn main() {
+    let dir = common::temp_dir("fds");
+    let path = dir.join("data");
+    std::fs::write(&path, "some file contents").unwrap();
+
+    let (child, fds) = common::spawn_with(|ready| {
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(OFFSET)).unwrap();
+        let (pipe_read, pipe_write) = nix::unistd::pipe().unwrap();
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fds: Vec<u8> = [file.as_raw_fd(), pipe_read, socket.as_raw_fd()]
+            .iter()
+            .flat_map(|fd| fd.to_le_bytes())
+            .collect();
+        ready.send(&fds);
+        (file, pipe_write, socket)
+    });
+    let fds: Vec<u32> = fds
+        .chunks(4)
+        .map(|fd| u32::from_le_bytes(fd.try_into().unwrap()))
+        .collect();
+    let (file, pipe, socket) = (fds[0], fds[1], fds[2]);
+    let pipe_target = target(child, pipe);
+    let socket_target = target(child, socket);
+
+    let out = common::cli(&["fds", &child.to_string()]);
+    common::reap(child);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(out.status.success(), "telefork fds failed: {:?}", out);
+    let listed = String::from_utf8(out.stdout).unwrap();
+    print!("{}", listed);
+
+    let line = |fd: u32| {
+        listed
+            .lines()
+            .find(|l| l.split_whitespace().next() == Some(&fd.to_string()))
+            .unwrap_or_else(|| panic!("fd {} isn't listed", fd))
+    };
+    assert_eq!(
+        line(file),
+        format!(
+            "{:>4}  restore  file {} at offset {}",
+            file,
+            path.display(),
+            OFFSET
+        )
+    );
+    assert_eq!(
+        line(pipe),
+        format!("{:>4}  drop     unsupported  ({})", pipe, pipe_target)
+    );
+    assert_eq!(
+        line(socket),
+        format!(
+            "{:>4}  drop     socket {}  ({})",
+            socket, socket_target, socket_target
+        )
+    );
+    println!("the file, pipe and socket are all listed as expected");
+}
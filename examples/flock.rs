@@ -0,0 +1,64 @@
+//! A process holding an `flock` on a lockfile, like a daemon making sure
+//! it's the only copy. The dump should carry the lock. Restoring while
+//! something else holds the lock should fail rather than let the process
+//! carry on unlocked, and once that's let go the restored process should
+//! hold it again so nobody else can take it.
+
+mod common;
+
+use telefork::{read_indexes, telepad, Config};
+
+use std::fs::File;
+use std::io::Cursor;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Try to take an exclusive lock on `path` without waiting, through a new
+/// open file of our own
+fn try_lock(path: &Path) -> Option<File> {
+    let file = File::open(path).unwrap();
+    let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    (res == 0).then_some(file)
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("telefork-flock-{}", std::process::id()));
+    std::fs::write(&path, "").unwrap();
+
+    let child = common::spawn_ready(|| {
+        let lock = File::open(&path).unwrap();
+        let res = unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) };
+        assert_eq!(res, 0);
+        lock
+    });
+    assert!(try_lock(&path).is_none(), "the child doesn't hold the lock");
+
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let dump = common::dump(child, &config);
+    let indexes = read_indexes(&mut Cursor::new(&dump)).unwrap();
+    assert!(
+        indexes[0].1.commands.contains_key("FileLocks"),
+        "the dump doesn't have the lock"
+    );
+
+    let ours = try_lock(&path).expect("the lock wasn't let go with the child");
+    let err = telepad(&mut &dump[..], 0).unwrap_err();
+    assert!(
+        err.to_string().contains("file lock"),
+        "failed some other way while we held the lock: {}",
+        err
+    );
+    println!("restoring while we held the lock: {}", err);
+    drop(ours);
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    let held = try_lock(&path).is_none();
+    common::reap(pid);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(held, "the restored process doesn't hold the lock");
+    println!("the restored process holds the lock again");
+}
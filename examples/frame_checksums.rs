@@ -0,0 +1,152 @@
+//! A dump made with `Config::frame_checksums` has a CRC32 after every frame,
+//! so flipping a byte in one command fails reading it right there naming
+//! that frame. The resumable protocol has one per chunk too, and asks for
+//! a corrupted chunk again instead of failing.
+
+mod common;
+
+use telefork::resumable::{ResumableReader, ResumableWriter};
+use telefork::{diff_dumps, teledump_with_config, telepad, Config, CorruptFrame, DumpDir};
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+
+/// Magic, version and header flags
+const HEADER_LEN: usize = 12;
+/// A frame is its command's length and whether it's optional, then the CRC
+const FRAME_LEN: usize = 4 + 1 + 4;
+
+/// Where the command in frame `n` starts
+fn command_offset(dump: &[u8], n: usize) -> usize {
+    let mut offset = HEADER_LEN;
+    // The first few are small commands with nothing after their frames
+    for _ in 0..n {
+        let len = u32::from_le_bytes(dump[offset..offset + 4].try_into().unwrap());
+        offset += FRAME_LEN + len as usize;
+    }
+    offset + FRAME_LEN
+}
+
+fn corrupt_frame() {
+    let child = common::spawn_ready(|| {});
+    let config = Config {
+        frame_checksums: true,
+        ..Config::default()
+    };
+    let tmp = std::env::temp_dir();
+    let (good, bad) = (
+        tmp.join("telefork-frame-checksums-good"),
+        tmp.join("telefork-frame-checksums-bad"),
+    );
+    let dir = tmp.join("telefork-frame-checksums-dir");
+    teledump_with_config(
+        child.as_raw(),
+        &mut File::create(&good).unwrap(),
+        true,
+        &config,
+    )
+    .unwrap();
+    let mut split = DumpDir::create(&dir).unwrap();
+    teledump_with_config(child.as_raw(), &mut split, true, &config).unwrap();
+    split.finish().unwrap();
+    common::reap(child);
+
+    let mut dump = std::fs::read(&good).unwrap();
+    assert_eq!(&dump[4..8], &2u32.to_le_bytes(), "checksums need version 2");
+    let at = command_offset(&dump, 2);
+    dump[at] ^= 0xff;
+    std::fs::write(&bad, &dump).unwrap();
+
+    // Both split into a directory and not, they read back fine untouched
+    assert!(diff_dumps(&good, &good).unwrap().is_empty());
+    assert!(diff_dumps(&good, &dir).is_ok());
+    let err = diff_dumps(&good, &bad).unwrap_err();
+    match err.downcast_ref::<CorruptFrame>() {
+        Some(corrupt) => assert_eq!(corrupt.frame, 2, "blamed the wrong frame: {}", corrupt),
+        None => panic!("corruption wasn't caught by the checksum: {}", err),
+    }
+    println!("caught: {}", err);
+
+    let err = telepad(&mut &dump[..], 0).unwrap_err();
+    match err.downcast_ref::<CorruptFrame>() {
+        Some(corrupt) => assert_eq!(corrupt.frame, 2, "blamed the wrong frame: {}", corrupt),
+        None => panic!("telepad didn't catch the corruption: {}", err),
+    }
+    std::fs::remove_file(good).unwrap();
+    std::fs::remove_file(bad).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Flips one byte partway through the first chunk's data on its way out
+struct Corrupting {
+    inner: UnixStream,
+    written: usize,
+    at: usize,
+}
+
+impl Write for Corrupting {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buf = buf.to_vec();
+        if (self.written..self.written + buf.len()).contains(&self.at) {
+            buf[self.at - self.written] ^= 0xff;
+        }
+        let wrote = self.inner.write(&buf)?;
+        self.written += wrote;
+        Ok(wrote)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Read for Corrupting {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+fn corrupt_chunk() {
+    let (ours, theirs) = UnixStream::pair().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let receiver = std::thread::spawn(move || {
+        let mut theirs = Some(theirs);
+        let mut inp = ResumableReader::new(move || {
+            theirs
+                .take()
+                .ok_or_else(|| io::Error::other("the sender should never have to reconnect"))
+        })
+        .unwrap();
+        let mut got = Vec::new();
+        inp.read_to_end(&mut got).unwrap();
+        got
+    });
+
+    let mut ours = Some(Corrupting {
+        inner: ours,
+        written: 0,
+        // Past the magic, session id and first frame header
+        at: 4 + 8 + 16 + 1000,
+    });
+    let mut out = ResumableWriter::new(move || {
+        ours.take()
+            .ok_or_else(|| io::Error::other("the receiver should never have to reconnect"))
+    })
+    .unwrap();
+    out.write_all(&data).unwrap();
+    out.finish().unwrap();
+    assert!(
+        receiver.join().unwrap() == expected,
+        "the corrupted chunk got through"
+    );
+}
+
+fn main() {
+    corrupt_chunk();
+    println!("corrupted chunk was resent");
+    corrupt_frame();
+    println!("corrupted frame was caught");
+}
@@ -0,0 +1,170 @@
+//! Going through a dump a frame at a time with `FrameReader`. With
+//! `Config::contents_lengths` every frame says how much follows it, so the
+//! whole dump can be walked without making anything of the commands, even
+//! one from a newer version that a restore skips along with its contents.
+
+mod common;
+
+use telefork::{
+    diff_dumps, teledump_with_config, Config, DumpDir, FrameReader, OversizedFrame, RawFrame,
+    MAX_FRAME_LEN,
+};
+
+use std::error::Error;
+use std::io::{self, Read};
+
+/// Magic, version and header flags
+const HEADER_LEN: usize = 12;
+/// A frame is its command's length and whether it's optional, then how many
+/// bytes follow the command
+const FRAME_LEN: usize = 4 + 1 + 8;
+
+/// Hands out one byte per read, like a slow socket
+struct Trickle<'a>(&'a [u8]);
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.0.is_empty() || buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = self.0[0];
+        self.0 = &self.0[1..];
+        Ok(1)
+    }
+}
+
+/// Every frame in a dump, skipping all the contents
+fn frames(inp: impl Read) -> Result<Vec<RawFrame>, Box<dyn Error>> {
+    Ok(frames_and_contents(inp)?
+        .into_iter()
+        .map(|(f, _)| f)
+        .collect())
+}
+
+/// Along with how much follows each, whether the dump said or not
+fn frames_and_contents(inp: impl Read) -> Result<Vec<(RawFrame, u64)>, Box<dyn Error>> {
+    let mut reader = FrameReader::new(inp)?;
+    let mut frames = Vec::new();
+    while let Some(frame) = reader.next_frame()? {
+        let contents = reader.remaining();
+        frames.push((frame, contents));
+    }
+    Ok(frames)
+}
+
+fn main() {
+    let child = common::spawn_ready(|| {});
+    // Once with the lengths and once without, of the same process so they
+    // have the same frames
+    let mut dumps = [true, false].map(|contents_lengths| {
+        let config = Config {
+            contents_lengths,
+            ..Config::default()
+        };
+        let mut dump = Vec::new();
+        teledump_with_config(child.as_raw(), &mut dump, true, &config).unwrap();
+        dump
+    });
+    // And split into a directory, which has to follow the frames too
+    let dir = std::env::temp_dir().join("telefork-frames-dir");
+    let mut split = DumpDir::create(&dir).unwrap();
+    let config = Config {
+        contents_lengths: true,
+        ..Config::default()
+    };
+    teledump_with_config(child.as_raw(), &mut split, true, &config).unwrap();
+    split.finish().unwrap();
+    common::reap(child);
+    let [dump, plain] = std::mem::take(&mut dumps);
+
+    // Skipping by length lands exactly on the end
+    let all = frames(&dump[..]).unwrap();
+    let total: u64 = all
+        .iter()
+        .map(|f| (FRAME_LEN + f.command.len()) as u64 + f.contents_len.unwrap())
+        .sum();
+    assert_eq!(HEADER_LEN as u64 + total, dump.len() as u64);
+    assert!(all.iter().any(|f| f.contents_len.unwrap() > 0));
+    println!("skipped {} frames by their lengths", all.len());
+
+    // However the reads come back split
+    let trickled = frames(Trickle(&dump)).unwrap();
+    assert_eq!(trickled.len(), all.len());
+    for (a, b) in all.iter().zip(&trickled) {
+        assert_eq!(
+            (a.index, &a.command, a.contents_len),
+            (b.index, &b.command, b.contents_len)
+        );
+    }
+
+    // Without the lengths it works them out from the commands instead, and
+    // gets to the end the same. That dump is version 1 without any flags.
+    let plain_frames = frames_and_contents(&plain[..]).unwrap();
+    assert!(plain_frames.iter().all(|(f, _)| f.contents_len.is_none()));
+    let total: u64 = plain_frames
+        .iter()
+        .map(|(f, contents)| (4 + 1 + f.command.len()) as u64 + contents)
+        .sum();
+    assert_eq!(8 + total, plain.len() as u64);
+
+    // A command from some newer version, optional and with a page after it
+    let mut bogus = 4u32.to_le_bytes().to_vec();
+    bogus.push(1);
+    bogus.extend_from_slice(&4096u64.to_le_bytes());
+    bogus.extend_from_slice(&9999u32.to_le_bytes());
+    bogus.extend_from_slice(&[0xaa; 4096]);
+    let mut newer = dump[..HEADER_LEN].to_vec();
+    newer.extend_from_slice(&bogus);
+    newer.extend_from_slice(&dump[HEADER_LEN..]);
+    let skipped = frames(&newer[..]).unwrap();
+    assert_eq!(skipped.len(), all.len() + 1);
+    assert_eq!(skipped[0].contents_len, Some(4096));
+    let tmp = std::env::temp_dir();
+    let (ours, theirs) = (
+        tmp.join("telefork-frames-ours"),
+        tmp.join("telefork-frames-newer"),
+    );
+    std::fs::write(&ours, &dump).unwrap();
+    std::fs::write(&theirs, &newer).unwrap();
+    let diff = diff_dumps(&ours, &theirs);
+    assert!(
+        diff_dumps(&ours, &dir).is_ok(),
+        "couldn't read the directory back"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&ours).unwrap();
+    std::fs::remove_file(&theirs).unwrap();
+    assert!(
+        diff.unwrap().is_empty(),
+        "reading the dump back didn't skip the unknown command"
+    );
+    println!("skipped a command from a newer version along with its contents");
+
+    // Caught before making room for it
+    let mut oversized = dump[..HEADER_LEN].to_vec();
+    oversized.extend_from_slice(&(MAX_FRAME_LEN + 1).to_le_bytes());
+    oversized.push(0);
+    oversized.extend_from_slice(&0u64.to_le_bytes());
+    let err = frames(&oversized[..]).unwrap_err();
+    match err.downcast_ref::<OversizedFrame>() {
+        Some(e) => assert_eq!(e.frame, 0),
+        None => panic!("an oversized frame failed some other way: {}", err),
+    }
+    println!("caught: {}", err);
+
+    // Cut off partway through a frame, or through the contents after one
+    let with_contents = all.iter().position(|f| f.contents_len != Some(0)).unwrap();
+    let contents_start = HEADER_LEN
+        + all[..=with_contents]
+            .iter()
+            .map(|f| FRAME_LEN + f.command.len())
+            .sum::<usize>();
+    for cut in [HEADER_LEN + 3, contents_start + 10] {
+        let err = frames(&dump[..cut]).unwrap_err();
+        match err.downcast_ref::<io::Error>() {
+            Some(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            None => panic!("a truncated dump failed some other way: {}", err),
+        }
+    }
+    println!("truncated dumps fail cleanly");
+}
@@ -0,0 +1,66 @@
+//! A root process that moved its fsuid and fsgid to `nobody`, so files only
+//! root can read are closed to it even though its effective ids are still
+//! root's. The restored one should have the same fs ids, and so the same
+//! file access, rather than having them reset to its effective ones. Needs
+//! to run as root.
+
+mod common;
+
+use telefork::{teledump, telepad_file_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::fs::File;
+use std::os::unix::fs::PermissionsExt;
+
+const NOBODY: u32 = 65534;
+
+/// Effective and fs ids, from the `Uid`/`Gid` lines
+fn ids(pid: Pid) -> [(u32, u32); 2] {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    ["Uid:", "Gid:"].map(|field| {
+        let line = status.lines().find(|l| l.starts_with(field)).unwrap();
+        let ids: Vec<u32> = line[field.len()..]
+            .split_whitespace()
+            .map(|id| id.parse().unwrap())
+            .collect();
+        (ids[1], ids[3])
+    })
+}
+
+fn main() {
+    let secret = std::env::temp_dir().join(format!("telefork-fsuid-{}", std::process::id()));
+    std::fs::write(&secret, "root only").unwrap();
+    std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+    let (child, shut_out) = common::spawn_with(|ready| {
+        unsafe {
+            libc::setfsgid(NOBODY);
+            libc::setfsuid(NOBODY);
+        }
+        // Shut out even though it's root, since the fsuid isn't
+        let shut_out = File::open(&secret).is_err();
+        ready.send(&[shut_out as u8]);
+    });
+    assert_eq!(shut_out[0], 1, "the fsuid didn't keep it out of the file");
+    let dumped = ids(child);
+    assert_eq!(dumped, [(0, NOBODY), (0, NOBODY)]);
+
+    let path = secret.with_extension("dump");
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    common::reap(child);
+    let restored = telepad_file_attached(&File::open(&path).unwrap(), 0, &Config::default());
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&secret).unwrap();
+    let restored = restored.unwrap();
+    let pid = restored.pid();
+    let got = ids(pid);
+    drop(restored);
+    common::reap(pid);
+
+    assert_eq!(
+        got, dumped,
+        "the restored process checks files as someone else"
+    );
+    println!("restored with effective and fs ids {:?}", got);
+}
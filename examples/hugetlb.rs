@@ -0,0 +1,54 @@
+use telefork::{telefork, telepad, wait_for_exit, TeleforkLocation};
+
+const SIZE: usize = 2 * 1024 * 1024;
+
+/// The `KernelPageSize` of our mapping at `addr`, in kB
+fn kernel_page_size(addr: usize) -> Option<usize> {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").ok()?;
+    let start = format!("{:x}-", addr);
+    let mut lines = smaps.lines().skip_while(|l| !l.starts_with(&start));
+    lines.next()?;
+    lines
+        .find_map(|l| l.strip_prefix("KernelPageSize:"))
+        .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+fn main() {
+    // Needs some reserved, like with `echo 4 > /proc/sys/vm/nr_hugepages`
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        println!("no 2 MB huge pages reserved, skipping");
+        return;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, SIZE) };
+    bytes
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = (i % 241) as u8);
+
+    let mut dump = Vec::new();
+    match telefork(&mut dump).unwrap() {
+        TeleforkLocation::Child(val) => {
+            let intact = bytes.iter().enumerate().all(|(i, b)| *b == (i % 241) as u8);
+            let huge = kernel_page_size(addr as usize) == Some(2048);
+            std::process::exit(if intact && huge { val } else { 1 })
+        }
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+    // It's the forked copy's that went into the dump, ours can go
+    unsafe { libc::munmap(addr, SIZE) };
+
+    let child = telepad(&mut &dump[..], 42).unwrap();
+    let status = wait_for_exit(child).unwrap();
+    println!("child exited with status = {}", status);
+    assert_eq!(status, 42);
+}
@@ -0,0 +1,82 @@
+//! A process watching a directory with inotify, like a daemon picking up new
+//! config files. The scan should find the watch by its path, and the
+//! restored process should still get an event for a file created in the
+//! directory after it's restored, under the same watch descriptor.
+
+mod common;
+
+use telefork::{scan_file_descriptors, telepad, Config, Connection};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-inotify-{}", std::process::id()));
+    // Watched, so the files for getting it going go next to it instead
+    let watched = dir.join("watched");
+    std::fs::create_dir_all(&watched).unwrap();
+    let (go, out) = (dir.join("go"), dir.join("out"));
+
+    let (child, wd) = common::spawn_with(|ready| {
+        let path = CString::new(watched.as_os_str().as_bytes()).unwrap();
+        let inotify = unsafe { libc::inotify_init1(0) };
+        let wd = unsafe { libc::inotify_add_watch(inotify, path.as_ptr(), libc::IN_CREATE) };
+        ready.send(&wd.to_le_bytes());
+        common::wait_for(&go);
+        let mut events = unsafe { File::from_raw_fd(inotify) };
+        let mut buf = [0u8; 4096];
+        let len = events.read(&mut buf).unwrap();
+        let event: libc::inotify_event =
+            unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const _) };
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let name = &buf[header..(header + event.len as usize).min(len)];
+        let name = String::from_utf8_lossy(name);
+        std::fs::write(
+            &out,
+            format!("{} {}", event.wd, name.trim_end_matches('\0')),
+        )
+        .unwrap();
+        std::process::exit(0);
+    });
+    let wd = i32::from_le_bytes(wd[..].try_into().unwrap());
+
+    let fds = scan_file_descriptors(child.as_raw()).unwrap();
+    let watches: Vec<(i32, String)> = fds
+        .values()
+        .filter_map(|c| match c {
+            Connection::Inotify(inotify) => Some(&inotify.watches),
+            _ => None,
+        })
+        .flatten()
+        .map(|w| (w.wd, w.path.clone()))
+        .collect();
+    assert_eq!(
+        watches,
+        vec![(wd, watched.to_string_lossy().into_owned())],
+        "the scan didn't find the watch on the directory"
+    );
+    println!("found watch {} on {}", wd, watched.display());
+
+    let dump = common::dump(child, &Config::default());
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(watched.join("created"), "").unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(
+        got,
+        format!("{} created", wd),
+        "the restored process didn't see the file get created"
+    );
+    println!("restored process saw the file get created");
+}
@@ -0,0 +1,39 @@
+use telefork::{telefork, wait_for_exit, TeleforkLocation, TelepadBuilder};
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+fn main() {
+    let fname = "keep_going.telefork.bin";
+    let doomed = std::env::temp_dir().join("telefork-keep-going.txt");
+    let file = File::create(&doomed).unwrap();
+    let fd = file.as_raw_fd();
+    let loc = {
+        let mut output = File::create(fname).unwrap();
+        telefork(&mut output).unwrap()
+    };
+    match loc {
+        TeleforkLocation::Child(val) => {
+            // Its file was gone by the time we got restored
+            let gone = std::fs::read_link(format!("/proc/self/fd/{}", fd)).is_err();
+            std::process::exit(if gone { val } else { 1 })
+        }
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+    std::fs::remove_file(&doomed).unwrap();
+
+    let mut input = File::open(fname).unwrap();
+    let mut restored = TelepadBuilder::new()
+        .keep_going(true)
+        .telepad_attached(&mut input, 42)
+        .unwrap();
+    for skipped in restored.skipped() {
+        println!("skipped {}: {}", skipped.what, skipped.error);
+    }
+    let what = format!("fd {}", fd);
+    assert!(restored.skipped().iter().any(|s| s.what.ends_with(&what)));
+    restored.resume().unwrap();
+    let status = wait_for_exit(restored.detach().unwrap()).unwrap();
+    println!("child exited with status = {}", status);
+    assert_eq!(status, 42);
+}
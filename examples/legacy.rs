@@ -0,0 +1,37 @@
+use telefork::{
+    telefork_with_config, telepad, wait_for_exit, Config, FrameReader, TeleforkLocation,
+    DUMP_MAGIC, DUMP_VERSION,
+};
+
+use std::io::Read;
+
+fn main() {
+    // Saying how much follows each frame lets us take the frames apart
+    // without knowing what's in them
+    let config = Config {
+        contents_lengths: true,
+        ..Config::default()
+    };
+    let mut dump = Vec::new();
+    match telefork_with_config(&mut dump, &config).unwrap() {
+        TeleforkLocation::Child(val) => std::process::exit(val),
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+    assert_eq!(dump[..4], DUMP_MAGIC);
+    assert_eq!(dump[4..8], DUMP_VERSION.to_le_bytes());
+
+    // Before the header a dump was just the commands, each followed by its
+    // contents with no frame in front
+    let mut legacy = Vec::new();
+    let mut frames = FrameReader::new(&dump[..]).unwrap();
+    while let Some(frame) = frames.next_frame().unwrap() {
+        legacy.extend_from_slice(&frame.command);
+        frames.read_to_end(&mut legacy).unwrap();
+    }
+    assert_ne!(legacy[..4], DUMP_MAGIC);
+
+    let child = telepad(&mut &legacy[..], 7).unwrap();
+    let status = wait_for_exit(child).unwrap();
+    println!("child restored from a legacy dump exited with {}", status);
+    assert_eq!(status, 7);
+}
@@ -0,0 +1,35 @@
+use telefork::{telefork, telepad_many, wait_for_exit, Config, TeleforkLocation};
+
+use std::fs::File;
+
+const COPIES: usize = 3;
+
+fn main() {
+    let fname = "many.telefork.bin";
+    let mut state = vec![10];
+    let loc = {
+        let mut output = File::create(fname).unwrap();
+        telefork(&mut output).unwrap()
+    };
+    match loc {
+        TeleforkLocation::Child(val) => {
+            // Each copy has its own, so none of the others' pushes show up
+            state.push(val);
+            std::process::exit(state.iter().sum())
+        }
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+
+    let dump = std::fs::read(fname).unwrap();
+    let restored = telepad_many(&dump, COPIES, 1, &Config::default()).unwrap();
+    let mut children = Vec::new();
+    for mut copy in restored {
+        copy.resume().unwrap();
+        children.push(copy.detach().unwrap());
+    }
+    for (i, child) in children.into_iter().enumerate() {
+        let status = wait_for_exit(child).unwrap();
+        println!("copy {} exited with status = {}", i + 1, status);
+        assert_eq!(status, 10 + i as i32 + 1);
+    }
+}
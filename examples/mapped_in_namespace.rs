@@ -0,0 +1,94 @@
+//! A process in its own mount namespace with a shared mapping of a file
+//! that's only there because of a bind mount inside it. Restored with
+//! `Config::mount_namespace` the file is found where the process sees it
+//! rather than where we would, so it gets mapped again instead of turning
+//! into zeroed memory, and writes through the mapping still reach the file.
+//! Needs to run as root to make the namespace.
+
+mod common;
+
+use telefork::{teledump, telepad_file, Config};
+
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+
+const LEN: usize = 4096;
+
+fn main() {
+    if !nix::unistd::geteuid().is_root() {
+        println!("making a mount namespace needs root, nothing to try");
+        return;
+    }
+    let base = std::env::temp_dir().join(format!("telefork-mapped-ns-{}", std::process::id()));
+    let (hidden, visible) = (base.join("hidden"), base.join("visible"));
+    std::fs::create_dir_all(&hidden).unwrap();
+    std::fs::create_dir_all(&visible).unwrap();
+    let mut contents = b"from before".to_vec();
+    contents.resize(LEN, 0);
+    std::fs::write(hidden.join("data"), &contents).unwrap();
+    let go = base.join("go");
+
+    let (child, _) = common::spawn_with(|ready| {
+        unshare(CloneFlags::CLONE_NEWNS).unwrap();
+        // Keep the bind mount from propagating back out to the host
+        mount::<str, str, str, str>(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None)
+            .unwrap();
+        mount::<_, _, str, str>(Some(&hidden), &visible, None, MsFlags::MS_BIND, None).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(visible.join("data"))
+            .unwrap();
+        let mapped = unsafe {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            );
+            std::slice::from_raw_parts_mut(addr as *mut u8, LEN)
+        };
+        drop(file);
+        ready.send(&[]);
+        common::wait_for(&go);
+        // Zeroed memory instead of the file would lose this
+        let still_mapped = mapped.starts_with(b"from before");
+        mapped[..b"from after".len()].copy_from_slice(b"from after");
+        std::process::exit(if still_mapped { 0 } else { 1 });
+    });
+    assert!(
+        !visible.join("data").exists(),
+        "the bind mount leaked out of the namespace"
+    );
+
+    let path = base.join("dump");
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    let config = Config {
+        mount_namespace: Some(format!("/proc/{}/ns/mnt", child).into()),
+        ..Config::default()
+    };
+    let restored = telepad_file(&File::open(&path).unwrap(), 0, &config);
+    common::reap(child);
+    let pid = restored.unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let on_disk = std::fs::read(hidden.join("data")).unwrap();
+    let _ = std::fs::remove_dir_all(&base);
+
+    assert_eq!(
+        status,
+        WaitStatus::Exited(pid, 0),
+        "the restored mapping didn't have the file's contents"
+    );
+    assert!(
+        on_disk.starts_with(b"from after"),
+        "a write through the restored mapping didn't reach the file"
+    );
+    println!("restored the mapping of a file only visible in the namespace");
+}
@@ -0,0 +1,109 @@
+//! `apply_memory_image` into a stub process we stopped ourselves. Afterwards
+//! it should have the same mappings as the dumped process, with the same
+//! contents, and the dumped stack pointer, while its pid and fds are still
+//! its own.
+
+mod common;
+
+use telefork::{apply_memory_image, teledump};
+
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+const MAPPING: usize = 64 * 4096;
+
+fn fill_a_mapping() {
+    let mem = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            MAPPING,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    } as *mut u8;
+    assert!(mem as *mut libc::c_void != libc::MAP_FAILED);
+    for i in 0..MAPPING {
+        unsafe { *mem.add(i) = (i * 7 % 251) as u8 };
+    }
+}
+
+/// Start, end and name of each mapping, leaving out the kernel's own which
+/// the stub keeps
+fn maps(pid: Pid) -> Vec<(usize, usize, String)> {
+    std::fs::read_to_string(format!("/proc/{}/maps", pid))
+        .unwrap()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let name = fields.nth(4).unwrap_or("").to_string();
+            if name.starts_with("[v") {
+                return None;
+            }
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+                name,
+            ))
+        })
+        .collect()
+}
+
+fn read_mem(pid: Pid, start: usize, end: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; end - start];
+    let mem = File::open(format!("/proc/{}/mem", pid)).ok()?;
+    mem.read_exact_at(&mut buf, start as u64).ok()?;
+    Some(buf)
+}
+
+fn main() {
+    let source = common::spawn_ready(fill_a_mapping);
+    let mut dump = Vec::new();
+    teledump(source.as_raw(), &mut dump, true).unwrap();
+
+    let stub = common::spawn_ready(|| {});
+    ptrace::attach(stub).unwrap();
+    match waitpid(stub, None).unwrap() {
+        WaitStatus::Stopped(_, Signal::SIGSTOP) => {}
+        status => panic!("stub didn't stop: {:?}", status),
+    }
+    let fds_before = std::fs::read_dir(format!("/proc/{}/fd", stub))
+        .unwrap()
+        .count();
+    let report = apply_memory_image(stub, &mut &dump[..]).unwrap();
+    println!("applied {} mappings", report.mappings);
+
+    let (ours, theirs) = (maps(stub), maps(source));
+    assert_eq!(ours, theirs, "the stub has different mappings");
+    let mut compared = 0;
+    for (start, end, _) in &theirs {
+        // Some like guard pages can't be read in either
+        if let (Some(a), Some(b)) = (read_mem(stub, *start, *end), read_mem(source, *start, *end)) {
+            assert!(a == b, "the mapping at {:x} has different contents", start);
+            compared += end - start;
+        }
+    }
+    assert!(compared >= MAPPING);
+    let rsp = ptrace::getregs(stub).unwrap().rsp as usize;
+    let stack = theirs.iter().find(|m| m.2 == "[stack]").unwrap();
+    assert!(stack.0 <= rsp && rsp < stack.1, "rsp isn't on the stack");
+    assert_eq!(
+        std::fs::read_dir(format!("/proc/{}/fd", stub))
+            .unwrap()
+            .count(),
+        fds_before,
+        "the stub's fds changed"
+    );
+    println!("stub has the same {} bytes of memory", compared);
+
+    for pid in [stub, source] {
+        common::reap(pid);
+    }
+}
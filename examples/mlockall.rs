@@ -0,0 +1,61 @@
+mod common;
+
+use telefork::{teledump, telepad, wait_for_exit};
+
+use std::fs::File;
+
+fn main() {
+    let fname = "mlockall.telefork.bin";
+    // Locks don't survive a fork, so this dumps a separate process that
+    // took them itself rather than teleforking
+    let (pid, ready) = common::spawn_with(|ready| locked_child(ready));
+    assert_eq!(
+        ready,
+        [1],
+        "mlockall failed, RLIMIT_MEMLOCK is probably too low"
+    );
+
+    {
+        let mut output = File::create(fname).unwrap();
+        teledump(pid.as_raw(), &mut output, true).unwrap();
+    }
+    common::reap(pid);
+
+    let mut input = File::open(fname).unwrap();
+    let child = telepad(&mut input, 0).unwrap();
+    let status = wait_for_exit(child).unwrap();
+    println!("child exited with status = {}", status);
+    assert_eq!(status, 42, "memory mapped after the restore wasn't locked");
+}
+
+fn locked_child(ready: common::Ready) -> ! {
+    let original = std::process::id();
+    let locked = unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } == 0;
+    ready.send(&[locked as u8]);
+    // Until we wake up restored as a different process
+    while std::process::id() == original {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap_or_default();
+    let mut ours = false;
+    let mut locked = false;
+    for line in smaps.lines() {
+        if line.starts_with(&format!("{:x}-", page as usize)) {
+            ours = true;
+        } else if let (true, Some(flags)) = (ours, line.strip_prefix("VmFlags:")) {
+            locked = flags.split_whitespace().any(|f| f == "lo");
+            break;
+        }
+    }
+    std::process::exit(if locked { 42 } else { 1 })
+}
@@ -0,0 +1,80 @@
+//! A process in its own mount namespace with a file open that's only there
+//! because of a bind mount inside it, like a container's volume. Restoring
+//! it outside the namespace can't find the file, restoring it with
+//! `Config::mount_namespace` pointed at the namespace opens the same one.
+//! Needs to run as root to make the namespace.
+
+mod common;
+
+use telefork::{teledump, telepad_file, Config, RemoteSyscallError};
+
+use nix::errno::Errno;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::IntoRawFd;
+
+fn main() {
+    let base = std::env::temp_dir().join(format!("telefork-mntns-{}", std::process::id()));
+    let (hidden, visible) = (base.join("hidden"), base.join("visible"));
+    std::fs::create_dir_all(&hidden).unwrap();
+    std::fs::create_dir_all(&visible).unwrap();
+    std::fs::write(hidden.join("secret"), "only in the namespace").unwrap();
+    let ino = std::fs::metadata(hidden.join("secret")).unwrap().ino();
+
+    let (child, fd) = common::spawn_with(|ready| {
+        unshare(CloneFlags::CLONE_NEWNS).unwrap();
+        // Keep the bind mount from propagating back out to the host
+        mount::<str, str, str, str>(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None)
+            .unwrap();
+        mount::<_, _, str, str>(Some(&hidden), &visible, None, MsFlags::MS_BIND, None).unwrap();
+        let fd = File::open(visible.join("secret")).unwrap().into_raw_fd();
+        ready.send(&fd.to_le_bytes());
+    });
+    let fd = i32::from_le_bytes(fd[..].try_into().unwrap());
+    assert!(
+        !visible.join("secret").exists(),
+        "the bind mount leaked out of the namespace"
+    );
+    common::wait_asleep(child);
+
+    let path = base.join("dump");
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    let dump = File::open(&path).unwrap();
+
+    let err = telepad_file(&dump, 0, &Config::default()).unwrap_err();
+    match err.downcast_ref::<RemoteSyscallError>() {
+        Some(e) => assert_eq!(e.errno, Errno::ENOENT, "failed some other way: {}", e),
+        None => panic!("failed some other way outside the namespace: {}", err),
+    }
+    println!("outside the namespace: {}", err);
+
+    let ns = format!("/proc/{}/ns/mnt", child);
+    let config = Config {
+        mount_namespace: Some(ns.clone().into()),
+        ..Config::default()
+    };
+    let restored = telepad_file(&dump, 0, &config).unwrap();
+    let restored_ino = std::fs::metadata(format!("/proc/{}/fd/{}", restored, fd))
+        .unwrap()
+        .ino();
+    let restored_ns = std::fs::read_link(format!("/proc/{}/ns/mnt", restored)).unwrap();
+    let result = (
+        restored_ino,
+        restored_ns == std::fs::read_link(&ns).unwrap(),
+    );
+
+    for pid in [restored, child] {
+        common::reap(pid);
+    }
+    let _ = std::fs::remove_dir_all(&base);
+    assert_eq!(
+        result.0, ino,
+        "the restored fd isn't the file in the namespace"
+    );
+    assert!(result.1, "the restored process isn't in the namespace");
+    println!("restored inside the namespace with the same file open");
+}
@@ -0,0 +1,101 @@
+//! A process with two POSIX message queues open, one it answers messages on
+//! and one it answers into that already has a few messages waiting.
+//! Both queues are unlinked once it's dumped, so the restore has to make
+//! them again, with the waiting messages put back, and the restored process
+//! should still be answering.
+
+mod common;
+
+use telefork::{scan_file_descriptors, teledump, telepad_file_attached, Config, Connection};
+
+use std::ffi::CString;
+use std::fs::File;
+
+const WAITING: [(&[u8], u32); 3] = [(b"low", 1), (b"high", 5), (b"also low", 1)];
+
+fn mq_open(name: &CString, flags: i32) -> libc::mqd_t {
+    let mut attr: libc::mq_attr = unsafe { std::mem::zeroed() };
+    attr.mq_maxmsg = 4;
+    attr.mq_msgsize = 64;
+    let mq = unsafe { libc::mq_open(name.as_ptr(), flags | libc::O_CREAT, 0o600, &attr) };
+    assert!(mq >= 0, "mq_open: {}", std::io::Error::last_os_error());
+    mq
+}
+
+fn receive(mq: libc::mqd_t) -> (Vec<u8>, u32) {
+    let mut buf = [0u8; 64];
+    let mut priority = 0;
+    let len = unsafe { libc::mq_receive(mq, buf.as_mut_ptr() as *mut _, buf.len(), &mut priority) };
+    assert!(len >= 0, "mq_receive: {}", std::io::Error::last_os_error());
+    (buf[..len as usize].to_vec(), priority)
+}
+
+fn send(mq: libc::mqd_t, data: &[u8], priority: u32) {
+    let res = unsafe { libc::mq_send(mq, data.as_ptr() as *const _, data.len(), priority) };
+    assert_eq!(res, 0, "mq_send: {}", std::io::Error::last_os_error());
+}
+
+fn main() {
+    let id = std::process::id();
+    let requests = CString::new(format!("/telefork-requests-{}", id)).unwrap();
+    let replies = CString::new(format!("/telefork-replies-{}", id)).unwrap();
+    let (child, _) = common::spawn_with(|ready| {
+        let requests = mq_open(&requests, libc::O_RDONLY);
+        let replies = mq_open(&replies, libc::O_WRONLY);
+        for (data, priority) in WAITING {
+            send(replies, data, priority);
+        }
+        ready.send(&[]);
+        loop {
+            let (mut data, priority) = receive(requests);
+            data.reverse();
+            send(replies, &data, priority);
+        }
+    });
+
+    let fds = scan_file_descriptors(child.as_raw()).unwrap();
+    let queues: Vec<_> = fds
+        .values()
+        .filter_map(|c| match c {
+            Connection::Mqueue(mq) => Some(mq),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(queues.len(), 2, "didn't see both queues: {:?}", fds);
+    let path = std::env::temp_dir().join(format!("telefork-mqueue-{}", child));
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    common::reap(child);
+    unsafe {
+        libc::mq_unlink(requests.as_ptr());
+        libc::mq_unlink(replies.as_ptr());
+    }
+
+    let restored = telepad_file_attached(&File::open(&path).unwrap(), 0, &Config::default());
+    std::fs::remove_file(&path).unwrap();
+    let restored = restored.unwrap();
+    let pid = restored.pid();
+    drop(restored);
+
+    let replies_mq = mq_open(&replies, libc::O_RDONLY);
+    let mut got: Vec<_> = (0..WAITING.len()).map(|_| receive(replies_mq)).collect();
+    let requests_mq = mq_open(&requests, libc::O_WRONLY);
+    send(requests_mq, b"ping", 3);
+    got.push(receive(replies_mq));
+    common::reap(pid);
+    unsafe {
+        libc::mq_unlink(requests.as_ptr());
+        libc::mq_unlink(replies.as_ptr());
+    }
+
+    let expected: Vec<(Vec<u8>, u32)> = [
+        (&b"high"[..], 5),
+        (b"low", 1),
+        (b"also low", 1),
+        (b"gnip", 3),
+    ]
+    .iter()
+    .map(|&(data, priority)| (data.to_vec(), priority))
+    .collect();
+    assert_eq!(got, expected);
+    println!("restored both queues, with the waiting messages, and it still answers");
+}
@@ -0,0 +1,24 @@
+use telefork::{teledump, NotLeader};
+
+use std::sync::mpsc;
+
+fn main() {
+    // Any thread but the main one has a tid that isn't the process's pid
+    let (tx, rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let thread = std::thread::spawn(move || {
+        tx.send(nix::unistd::gettid().as_raw()).unwrap();
+        done_rx.recv().unwrap();
+    });
+    let tid = rx.recv().unwrap();
+    let pid = std::process::id() as i32;
+
+    let err = teledump(tid, &mut std::io::sink(), true).unwrap_err();
+    println!("{}", err);
+    let not_leader = err.downcast_ref::<NotLeader>().unwrap();
+    assert_eq!(not_leader.tid, tid);
+    assert_eq!(not_leader.tgid, pid);
+
+    done_tx.send(()).unwrap();
+    thread.join().unwrap();
+}
@@ -0,0 +1,85 @@
+//! A process that asked for a mapping of its own to prefer NUMA node 0 with
+//! `mbind`. The dump should carry the policy along and the restored mapping
+//! should have it again, as `/proc/pid/numa_maps` shows it. Node 0 is there
+//! on anything with NUMA support, even a single node machine, but kernels
+//! without it have nothing to check.
+
+mod common;
+
+use telefork::{read_indexes, telepad_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::io::Cursor;
+
+const MPOL_PREFERRED: i64 = 1;
+const LEN: usize = 16 * 4096;
+
+/// The policy `numa_maps` shows for the mapping at `addr`
+fn policy(pid: Pid, addr: usize) -> String {
+    let numa_maps = std::fs::read_to_string(format!("/proc/{}/numa_maps", pid)).unwrap();
+    let line = numa_maps
+        .lines()
+        .find(|l| l.starts_with(&format!("{:x} ", addr)))
+        .unwrap_or_default();
+    line.split(' ').nth(1).unwrap_or_default().to_string()
+}
+
+fn main() {
+    if !std::path::Path::new("/proc/self/numa_maps").exists() {
+        println!("this kernel doesn't have NUMA support, nothing to check");
+        return;
+    }
+    let (child, addr) = common::spawn_with(|ready| {
+        let addr = unsafe {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            let node0: u64 = 1;
+            let res = libc::syscall(
+                libc::SYS_mbind,
+                addr,
+                LEN,
+                MPOL_PREFERRED,
+                &node0 as *const u64,
+                2,
+                0,
+            );
+            assert_eq!(res, 0, "mbind failed");
+            std::ptr::write_bytes(addr as *mut u8, 7, LEN);
+            addr as usize
+        };
+        ready.send(&addr.to_le_bytes());
+    });
+    let addr = usize::from_le_bytes(addr[..].try_into().unwrap());
+    let dumped = policy(child, addr);
+    assert_eq!(dumped, "prefer:0", "mbind didn't set the policy");
+
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let dump = common::dump(child, &config);
+    let indexes = read_indexes(&mut Cursor::new(&dump)).unwrap();
+    let policies = indexes[0]
+        .1
+        .commands
+        .get("MemoryPolicy")
+        .map_or(0, Vec::len);
+    assert_eq!(policies, 1, "the dump should have the one policy");
+
+    let restored = telepad_attached(&mut &dump[..], 0, &Config::default()).unwrap();
+    let pid = restored.pid();
+    let got = policy(pid, addr);
+    drop(restored);
+    common::reap(pid);
+
+    assert_eq!(got, dumped, "the restored mapping lost its NUMA policy");
+    println!("restored mapping at {:x} with policy {}", addr, got);
+}
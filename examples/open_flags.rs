@@ -0,0 +1,71 @@
+//! A process with a file open `O_DIRECT | O_SYNC`, flags that can only be
+//! picked when the file is opened and change how its I/O behaves. The
+//! restored fd should have them again, as `fdinfo` shows them. Filesystems
+//! that don't do `O_DIRECT` have nothing to check.
+
+mod common;
+
+use telefork::{telepad_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::IntoRawFd;
+use std::path::Path;
+
+const FLAGS: i32 = libc::O_DIRECT | libc::O_SYNC;
+
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(FLAGS)
+        .open(path)
+}
+
+/// The open flags `fdinfo` shows for `fd`, less the ones that don't matter
+/// here like `O_LARGEFILE`
+fn flags(pid: Pid, fd: i32) -> i32 {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd)).unwrap();
+    let flags = fdinfo
+        .lines()
+        .find_map(|l| l.strip_prefix("flags:"))
+        .unwrap();
+    i32::from_str_radix(flags.trim(), 8).unwrap() & (FLAGS | libc::O_ACCMODE)
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("telefork-open-flags-{}", std::process::id()));
+    std::fs::write(&path, "").unwrap();
+    if open(&path).is_err() {
+        std::fs::remove_file(&path).unwrap();
+        println!("the temp dir's filesystem doesn't do O_DIRECT, nothing to check");
+        return;
+    }
+
+    let (child, fd) = common::spawn_with(|ready| {
+        let fd = open(&path).unwrap().into_raw_fd();
+        ready.send(&fd.to_le_bytes());
+    });
+    let fd = i32::from_le_bytes(fd[..].try_into().unwrap());
+    let dumped = flags(child, fd);
+    assert_eq!(dumped, FLAGS | libc::O_RDWR);
+
+    let dump = common::dump(child, &Config::default());
+
+    let restored = telepad_attached(&mut &dump[..], 0, &Config::default()).unwrap();
+    let pid = restored.pid();
+    let got = flags(pid, fd);
+    drop(restored);
+    common::reap(pid);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        got, dumped,
+        "the restored fd has flags {:o} instead of {:o}",
+        got, dumped
+    );
+    println!("restored fd {} with flags {:o}", fd, got);
+}
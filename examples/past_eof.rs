@@ -0,0 +1,82 @@
+//! A private, read-only mapping of a file that's shorter than the mapping,
+//! so the pages past the end of the file are a SIGBUS to touch. Dumping it
+//! can't read those pages, and the restored process should have the same
+//! contents where there's file and still not be able to touch the rest.
+
+mod common;
+
+use telefork::{teledump, telepad_file_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+const PAGE_SIZE: usize = 4096;
+const FILE_LEN: usize = PAGE_SIZE + PAGE_SIZE / 2;
+const MAPPING: usize = 4 * PAGE_SIZE;
+
+/// Each page of the mapping at `addr`, or `None` for the ones that can't be
+/// read
+fn pages(pid: Pid, addr: usize) -> Vec<Option<Vec<u8>>> {
+    let mem = File::open(format!("/proc/{}/mem", pid)).unwrap();
+    (0..MAPPING / PAGE_SIZE)
+        .map(|i| {
+            let mut page = vec![0u8; PAGE_SIZE];
+            mem.read_exact_at(&mut page, (addr + i * PAGE_SIZE) as u64)
+                .ok()
+                .map(|_| page)
+        })
+        .collect()
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("telefork-past-eof-{}", std::process::id()));
+    let contents: Vec<u8> = (0..FILE_LEN).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&path, &contents).unwrap();
+
+    let (child, addr) = common::spawn_with(|ready| {
+        let file = File::open(&path).unwrap();
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                MAPPING,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        assert!(addr != libc::MAP_FAILED);
+        ready.send(&(addr as usize).to_le_bytes());
+        file
+    });
+    let addr = usize::from_le_bytes(addr[..].try_into().unwrap());
+    let original = pages(child, addr);
+    assert_eq!(
+        original.iter().map(Option::is_some).collect::<Vec<_>>(),
+        [true, true, false, false]
+    );
+
+    let dump_path = path.with_extension("dump");
+    let stats = teledump(child.as_raw(), &mut File::create(&dump_path).unwrap(), true).unwrap();
+    common::reap(child);
+    println!("dumped {} bytes of memory", stats.memory_bytes);
+
+    let restored = telepad_file_attached(&File::open(&dump_path).unwrap(), 0, &Config::default());
+    std::fs::remove_file(&dump_path).unwrap();
+    let restored = restored.unwrap();
+    let pid = restored.pid();
+    let got = pages(pid, addr);
+    drop(restored);
+    common::reap(pid);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(
+        got == original,
+        "the restored mapping doesn't match where it's readable"
+    );
+    println!("restored with the file's contents and the end still past it");
+}
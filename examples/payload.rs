@@ -0,0 +1,71 @@
+//! Handing the woken process a struct with two fds in it through
+//! `telepad_with_payload`, rather than the single i32 `telepad` passes. It
+//! reads the struct back with `read_payload`, then copies what it can read
+//! from one fd into the other.
+
+mod common;
+
+use telefork::{read_payload, telefork, telepad_with_payload, wait_for_exit, TeleforkLocation};
+
+use serde::{Deserialize, Serialize};
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const CONTENTS: &str = "passed along by fd";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Startup {
+    input: i32,
+    output: i32,
+    greeting: String,
+}
+
+fn main() {
+    let dir = common::temp_dir("payload");
+    let (input_path, output_path) = (dir.join("input"), dir.join("output"));
+    std::fs::write(&input_path, CONTENTS).unwrap();
+    // Open before teleforking so the restored process has them too
+    let input = File::open(&input_path).unwrap();
+    let output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&output_path)
+        .unwrap();
+    let startup = Startup {
+        input: input.as_raw_fd(),
+        output: output.as_raw_fd(),
+        greeting: "hello from the parent".to_string(),
+    };
+
+    let mut dump = Vec::new();
+    match telefork(&mut dump).unwrap() {
+        TeleforkLocation::Child(arg) => {
+            let got: Startup = unsafe { read_payload(arg) }.unwrap();
+            println!("woke up with {:?}", got);
+            let mut contents = String::new();
+            unsafe { File::from_raw_fd(got.input) }
+                .read_to_string(&mut contents)
+                .unwrap();
+            let mut out = unsafe { File::from_raw_fd(got.output) };
+            write!(out, "{} {}", got.greeting, contents).unwrap();
+            std::process::exit(0);
+        }
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+    drop((input, output));
+
+    let child = telepad_with_payload(&mut &dump[..], &startup).unwrap();
+    let status = wait_for_exit(child).unwrap();
+    let written = std::fs::read_to_string(&output_path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, 0);
+    assert_eq!(written, format!("{} {}", startup.greeting, CONTENTS));
+    println!(
+        "the restored process wrote {:?} through the payload's fds",
+        written
+    );
+}
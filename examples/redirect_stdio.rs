@@ -0,0 +1,65 @@
+//! Restoring with `TelepadBuilder::redirect_stdio` pointing the process's
+//! stdout at a pipe of ours. What it prints after waking up should come out
+//! of that pipe rather than out of the stdout of whoever restored it.
+
+mod common;
+
+use telefork::{wait_for_exit, Config, TelepadBuilder};
+
+use nix::unistd::{close, dup, dup2, pipe};
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+
+const LINE: &str = "printed after the restore\n";
+
+/// Everything that can be read from `fd` until every write end is closed
+fn read_all(fd: i32) -> String {
+    let mut contents = String::new();
+    unsafe { File::from_raw_fd(fd) }
+        .read_to_string(&mut contents)
+        .unwrap();
+    contents
+}
+
+fn main() {
+    let go = common::temp_dir("redirect_stdio").join("go");
+    let (child, _) = common::spawn_with(|ready| {
+        ready.send(&[]);
+        common::wait_for(&go);
+        let mut stdout = std::io::stdout();
+        stdout.write_all(LINE.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+        std::process::exit(0);
+    });
+    let dump = common::dump(child, &Config::default());
+
+    let (captured_read, captured_write) = pipe().unwrap();
+    // Our own stdout goes somewhere we can look at for the restore, so we'd
+    // see it if the line ended up there
+    let (ours_read, ours_write) = pipe().unwrap();
+    std::io::stdout().flush().unwrap();
+    let saved_stdout = dup(1).unwrap();
+    dup2(ours_write, 1).unwrap();
+    close(ours_write).unwrap();
+
+    let restored = TelepadBuilder::new()
+        .redirect_stdio(1, captured_write)
+        .telepad(&mut &dump[..], 0);
+    dup2(saved_stdout, 1).unwrap();
+    close(saved_stdout).unwrap();
+    close(captured_write).unwrap();
+    let pid = restored.unwrap();
+
+    std::fs::write(&go, "").unwrap();
+    let status = wait_for_exit(pid).unwrap();
+    std::fs::remove_dir_all(go.parent().unwrap()).unwrap();
+    let captured = read_all(captured_read);
+    let leaked = read_all(ours_read);
+
+    assert_eq!(status, 0);
+    assert_eq!(captured, LINE, "the pipe didn't get what it printed");
+    assert_eq!(leaked, "", "it printed to our stdout");
+    println!("the restored process printed {:?} into the pipe", captured);
+}
@@ -0,0 +1,40 @@
+//! Restoring with `telepad_attached` leaves the process stopped, with the
+//! registers it'll resume with there to look at before letting it go. For a
+//! process dumped asleep in `pause` that's the rip it was dumped at, moved
+//! back onto the `syscall` instruction so the pause gets issued again.
+
+mod common;
+
+use telefork::{telepad_attached, Config};
+
+use nix::unistd::Pid;
+
+/// Where `pid` will carry on from once the syscall it's blocked in returns,
+/// the last field of its `/proc/pid/syscall`
+fn syscall_pc(pid: Pid) -> u64 {
+    let syscall = std::fs::read_to_string(format!("/proc/{}/syscall", pid)).unwrap();
+    let pc = syscall.split_whitespace().last().unwrap();
+    u64::from_str_radix(pc.trim_start_matches("0x"), 16).unwrap()
+}
+
+fn main() {
+    let child = common::spawn_ready(|| ());
+    // Just past the 2 byte syscall instruction
+    let dumped_rip = syscall_pc(child) - 2;
+    let dump = common::dump(child, &Config::default());
+
+    let mut restored = telepad_attached(&mut &dump[..], 0, &Config::default()).unwrap();
+    let pid = restored.pid();
+    let rip = restored.registers().unwrap().rip;
+    assert_eq!(
+        rip, dumped_rip,
+        "the restored process would resume at {:#x} instead of {:#x}",
+        rip, dumped_rip
+    );
+    restored.resume().unwrap();
+    restored.detach().unwrap();
+    // Back in its pause
+    common::wait_asleep(pid);
+    common::reap(pid);
+    println!("restored process had rip {:#x} before resuming", rip);
+}
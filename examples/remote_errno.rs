@@ -0,0 +1,55 @@
+//! A syscall injected into a restored process that the kernel refuses. Here
+//! that's an mmap far bigger than the address space, which should come back
+//! as a `RemoteSyscallError` saying ENOMEM rather than just that it failed.
+
+mod common;
+
+use telefork::{Config, RemoteSyscallError, TelepadBuilder};
+
+use nix::errno::Errno;
+
+/// More than a 57 bit address space could ever fit
+const ABSURD: u64 = 1 << 60;
+
+fn main() {
+    let child = common::spawn_ready(|| ());
+    let dump = common::dump(child, &Config::default());
+
+    let mut refused = None;
+    let restored = TelepadBuilder::new()
+        .pre_resume(|restored| {
+            let res = restored.syscall(
+                libc::SYS_mmap,
+                [
+                    0,
+                    ABSURD,
+                    libc::PROT_READ as u64,
+                    (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64,
+                    -1i64 as u64,
+                    0,
+                ],
+            );
+            refused = Some(res);
+            Ok(())
+        })
+        .telepad_attached(&mut &dump[..], 0)
+        .unwrap();
+    let pid = restored.pid();
+    drop(restored);
+    common::reap(pid);
+
+    let err = match refused.expect("the hook didn't run") {
+        Ok(addr) => panic!("mapping {} bytes worked, at {:#x}", ABSURD, addr),
+        Err(e) => e,
+    };
+    let remote = err
+        .downcast_ref::<RemoteSyscallError>()
+        .unwrap_or_else(|| panic!("not a RemoteSyscallError: {}", err));
+    assert_eq!(remote.errno, Errno::ENOMEM);
+    assert!(
+        err.to_string().contains("ENOMEM"),
+        "the error doesn't say ENOMEM: {}",
+        err
+    );
+    println!("{}", err);
+}
@@ -0,0 +1,47 @@
+use telefork::{telefork, wait_for_exit, TeleforkLocation, TelepadBuilder, VdsoRestore};
+
+use std::fs::File;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+
+fn main() {
+    let fname = "report.telefork.bin";
+    // Sockets other than socketpairs don't come along, it has to say so
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let fd = listener.as_raw_fd();
+    // Enough CPU time to show up in clock ticks
+    let started = std::time::Instant::now();
+    let mut spun = 0u64;
+    while started.elapsed() < std::time::Duration::from_millis(200) {
+        spun = std::hint::black_box(spun.wrapping_add(1));
+    }
+    let loc = {
+        let mut output = File::create(fname).unwrap();
+        telefork(&mut output).unwrap()
+    };
+    match loc {
+        TeleforkLocation::Child(val) => std::process::exit(val),
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+
+    let mut input = File::open(fname).unwrap();
+    let mut restored = TelepadBuilder::new()
+        .telepad_attached(&mut input, 42)
+        .unwrap();
+    let report = restored.report().clone();
+    println!("{}", report);
+    assert!(report.mappings > 0);
+    assert_eq!(report.mappings_skipped, 0);
+    assert_eq!(report.fds_skipped, 1);
+    let what = format!("socket fd {}", fd);
+    assert!(report.skipped.iter().any(|s| s.what == what));
+    assert_eq!(report.vdso, VdsoRestore::Remapped);
+    assert!(report.tls);
+    let cpu = report.cpu.unwrap();
+    assert!(cpu.user + cpu.system >= std::time::Duration::from_millis(100));
+    assert_eq!(report.monotonic_behind, None);
+    restored.resume().unwrap();
+    let status = wait_for_exit(restored.detach().unwrap()).unwrap();
+    println!("child exited with status = {}", status);
+    assert_eq!(status, 42);
+}
@@ -0,0 +1,145 @@
+//! A dump sent with the resumable protocol over a connection that drops
+//! partway. The sender should reconnect and carry on, the receiver should end
+//! up with exactly the bytes that were written into the sending end, and
+//! those should restore into a process that still works.
+
+mod common;
+
+use telefork::resumable::{ResumableReader, ResumableWriter};
+use telefork::{teledump, telepad};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// How far into the first connection it breaks
+const DROP_AFTER: usize = 300 * 1024;
+
+/// A connection that breaks for good once `left` more bytes have been
+/// written to it
+struct Flaky {
+    stream: UnixStream,
+    left: Option<usize>,
+}
+
+impl Read for Flaky {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Flaky {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match self.left {
+            Some(0) => {
+                // So the receiver sees it go too
+                let _ = self.stream.shutdown(Shutdown::Both);
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "dropped"));
+            }
+            Some(left) => buf.len().min(left),
+            None => buf.len(),
+        };
+        let len = self.stream.write(&buf[..len])?;
+        if let Some(left) = &mut self.left {
+            *left -= len;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Keeps a copy of everything written through it
+struct Tee<W> {
+    inner: W,
+    copy: Vec<u8>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.copy.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-resumable-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (sock, go, out) = (dir.join("sock"), dir.join("go"), dir.join("out"));
+
+    let (child, _) = common::spawn_with(|ready| {
+        // Enough that the dump is well past the drop
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        ready.send(&[]);
+        common::wait_for(&go);
+        let sum: u64 = data.iter().map(|&b| b as u64).sum();
+        std::fs::write(&out, sum.to_string()).unwrap();
+        std::process::exit(0);
+    });
+
+    let listener = UnixListener::bind(&sock).unwrap();
+    let receiver = std::thread::spawn(move || {
+        let mut inp = ResumableReader::new(|| listener.accept().map(|(s, _)| s)).unwrap();
+        let mut received = Vec::new();
+        inp.read_to_end(&mut received).unwrap();
+        received
+    });
+
+    let mut connections = 0;
+    let connect = || {
+        connections += 1;
+        let stream = UnixStream::connect(&sock)?;
+        let left = if connections == 1 {
+            Some(DROP_AFTER)
+        } else {
+            None
+        };
+        Ok(Flaky { stream, left })
+    };
+    let mut tee = Tee {
+        inner: ResumableWriter::new(connect).unwrap(),
+        copy: Vec::new(),
+    };
+    teledump(child.as_raw(), &mut tee, true).unwrap();
+    let Tee { inner, copy: sent } = tee;
+    inner.finish().unwrap();
+    common::reap(child);
+    let received = receiver.join().unwrap();
+
+    assert!(
+        connections > 1,
+        "the connection never dropped, the dump is smaller than {} bytes",
+        DROP_AFTER
+    );
+    assert_eq!(received.len(), sent.len(), "received a different length");
+    assert!(received == sent, "received different bytes than were sent");
+    println!(
+        "{} byte dump arrived intact over {} connections",
+        sent.len(),
+        connections
+    );
+
+    let pid = telepad(&mut &received[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    let expected: u64 = (0..4 * 1024 * 1024u64).map(|i| i % 251).sum();
+    assert_eq!(
+        got,
+        expected.to_string(),
+        "the restored process's data changed"
+    );
+    println!("restored process summed its data to {}", got);
+}
@@ -0,0 +1,36 @@
+use telefork::harness::{round_trip, round_trip_dir};
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static GLOBAL: AtomicI32 = AtomicI32::new(1);
+
+fn main() {
+    GLOBAL.store(42, Ordering::SeqCst);
+    let trip = round_trip(|| {
+        let val = GLOBAL.load(Ordering::SeqCst);
+        println!("restored process sees GLOBAL={}", val);
+        val
+    })
+    .unwrap();
+
+    println!(
+        "{} maps and {} fds before, {} maps and {} fds after",
+        trip.before.maps.len(),
+        trip.before.fds.len(),
+        trip.after.maps.len(),
+        trip.after.fds.len()
+    );
+    assert_eq!(trip.status, 42, "the changed global didn't make it across");
+    println!("round trip ok");
+
+    // Same again but split into a file per mapping on disk
+    let dir = std::env::temp_dir().join(format!("telefork-round-trip-{}", std::process::id()));
+    GLOBAL.store(43, Ordering::SeqCst);
+    let dir_trip = round_trip_dir(&dir, || GLOBAL.load(Ordering::SeqCst));
+    let mappings = std::fs::read_dir(&dir).map(|d| d.count()).unwrap_or(0);
+    let _ = std::fs::remove_dir_all(&dir);
+    let dir_trip = dir_trip.unwrap();
+    println!("directory dump had {} files", mappings);
+    assert_eq!(dir_trip.status, 43, "the directory dump lost the global");
+    println!("directory round trip ok");
+}
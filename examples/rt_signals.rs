@@ -0,0 +1,106 @@
+//! A process with real-time signals blocked and queued up on it, two of the
+//! same one with different values and one of another. After a restore they
+//! should all still be there with their values, not just one of each.
+
+mod common;
+
+use telefork::{telepad, Config};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::convert::TryInto;
+
+/// Queued in this order, as signal offsets from SIGRTMIN and values
+const QUEUED: [(i32, u64); 3] = [(0, 1111), (0, 2222), (1, 3333)];
+const SI_QUEUE: i32 = -1;
+
+fn rt_signals() -> libc::sigset_t {
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        for (offset, _) in QUEUED {
+            libc::sigaddset(&mut set, libc::SIGRTMIN() + offset);
+        }
+    }
+    set
+}
+
+/// `sigqueue` to ourselves, by hand with a raw `siginfo_t`
+fn queue(sig: i32, value: u64) {
+    let mut info = [0u8; 128];
+    info[..4].copy_from_slice(&sig.to_ne_bytes());
+    info[8..12].copy_from_slice(&SI_QUEUE.to_ne_bytes());
+    info[16..20].copy_from_slice(&(std::process::id() as i32).to_ne_bytes());
+    info[20..24].copy_from_slice(&unsafe { libc::getuid() }.to_ne_bytes());
+    info[24..32].copy_from_slice(&value.to_ne_bytes());
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_rt_sigqueueinfo,
+            std::process::id(),
+            sig,
+            info.as_ptr(),
+        )
+    };
+    assert_eq!(res, 0, "{}", std::io::Error::last_os_error());
+}
+
+/// Take everything queued, as signal offsets and values
+fn take_queued() -> Vec<(i32, u64)> {
+    let set = rt_signals();
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let mut taken = Vec::new();
+    loop {
+        let mut info = [0u8; 128];
+        let sig = unsafe {
+            libc::syscall(
+                libc::SYS_rt_sigtimedwait,
+                &set,
+                info.as_mut_ptr(),
+                &timeout,
+                8,
+            )
+        };
+        if sig < 0 {
+            return taken;
+        }
+        let value = u64::from_ne_bytes(info[24..32].try_into().unwrap());
+        taken.push((sig as i32 - libc::SIGRTMIN(), value));
+    }
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-rt-signals-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (go, out) = (dir.join("go"), dir.join("out"));
+    let (child, _) = common::spawn_with(|ready| {
+        let set = rt_signals();
+        unsafe { libc::sigprocmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        for (offset, value) in QUEUED {
+            queue(libc::SIGRTMIN() + offset, value);
+        }
+        ready.send(&[]);
+        common::wait_for(&go);
+        let taken = take_queued();
+        std::fs::write(&out, format!("{:?}", taken)).unwrap();
+        std::process::exit(0);
+    });
+
+    let dump = common::dump(child, &Config::default());
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(
+        got,
+        format!("{:?}", QUEUED.to_vec()),
+        "the restored process didn't get every signal with its value"
+    );
+    println!("all {} queued signals came back: {}", QUEUED.len(), got);
+}
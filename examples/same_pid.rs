@@ -0,0 +1,59 @@
+//! Restoring with `Config::same_pid` while the original is still running and
+//! has the pid. `Require` should fail with a `PidConflict` for that pid and
+//! `Prefer` should restore it as some other pid and say so in the report.
+//! Once the original is gone `Require` should get it the pid back. Asking
+//! for a pid needs root, or `CAP_CHECKPOINT_RESTORE`, and Linux 5.5.
+
+mod common;
+
+use telefork::{teledump, telepad_attached, Config, PidConflict, RestoredProcess, SamePid};
+
+fn restore(dump: &[u8], same_pid: SamePid) -> Result<RestoredProcess, Box<dyn std::error::Error>> {
+    let config = Config {
+        same_pid,
+        ..Config::default()
+    };
+    telepad_attached(&mut &dump[..], 0, &config)
+}
+
+fn finish(restored: RestoredProcess) {
+    let pid = restored.pid();
+    drop(restored);
+    common::reap(pid);
+}
+
+fn main() {
+    let child = common::spawn_ready(|| {});
+    let mut dump = Vec::new();
+    teledump(child.as_raw(), &mut dump, true).unwrap();
+
+    // The original still has it
+    let err = restore(&dump, SamePid::Require).expect_err("restored as a pid that's in use");
+    let conflict = err
+        .downcast_ref::<PidConflict>()
+        .unwrap_or_else(|| panic!("failed some other way: {}", err));
+    assert_eq!(conflict.pid, child.as_raw());
+    println!("Require: {}", conflict);
+
+    let restored = restore(&dump, SamePid::Prefer).unwrap();
+    assert_ne!(restored.pid(), child);
+    assert_eq!(restored.report().original_pid, Some(child.as_raw()));
+    let what = format!("pid {}", child);
+    assert!(
+        restored.skipped().iter().any(|s| s.what == what),
+        "the report doesn't say it didn't get its pid: {:?}",
+        restored.skipped()
+    );
+    println!(
+        "Prefer: restored as {} instead of {}",
+        restored.pid(),
+        child
+    );
+    finish(restored);
+
+    common::reap(child);
+    let restored = restore(&dump, SamePid::Require).unwrap();
+    assert_eq!(restored.pid(), child);
+    println!("Require: got {} back once it was free", restored.pid());
+    finish(restored);
+}
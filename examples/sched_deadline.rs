@@ -0,0 +1,98 @@
+//! A `SCHED_DEADLINE` process should come back with the same runtime,
+//! deadline and period, or if this machine won't admit it, come back running
+//! normally with the restore saying what it carried on without. Setting a
+//! deadline policy needs root, so where that's refused it tries a plain
+//! `SCHED_BATCH` with a nice value instead, which anyone can.
+
+mod common;
+
+use telefork::{teledump, telepad_file_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::File;
+
+const SCHED_BATCH: u32 = 3;
+const SCHED_DEADLINE: u32 = 6;
+const SCHED_ATTR_SIZE: usize = 48;
+
+/// Policy, nice, runtime, deadline and period
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Attr(u32, i32, u64, u64, u64);
+
+fn getattr(pid: i32) -> Attr {
+    let mut attr = [0u8; SCHED_ATTR_SIZE];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_sched_getattr,
+            pid,
+            attr.as_mut_ptr(),
+            SCHED_ATTR_SIZE,
+            0,
+        )
+    };
+    assert_eq!(res, 0, "sched_getattr failed");
+    let u64_at = |i: usize| u64::from_ne_bytes(attr[i..i + 8].try_into().unwrap());
+    Attr(
+        u32::from_ne_bytes(attr[4..8].try_into().unwrap()),
+        i32::from_ne_bytes(attr[16..20].try_into().unwrap()),
+        u64_at(24),
+        u64_at(32),
+        u64_at(40),
+    )
+}
+
+/// On ourselves, so it's ours and not the restorer's
+fn setattr(Attr(policy, nice, runtime, deadline, period): Attr) -> bool {
+    let mut attr = Vec::new();
+    attr.extend_from_slice(&(SCHED_ATTR_SIZE as u32).to_ne_bytes());
+    attr.extend_from_slice(&policy.to_ne_bytes());
+    attr.extend_from_slice(&0u64.to_ne_bytes());
+    attr.extend_from_slice(&nice.to_ne_bytes());
+    attr.extend_from_slice(&0u32.to_ne_bytes());
+    for v in [runtime, deadline, period] {
+        attr.extend_from_slice(&v.to_ne_bytes());
+    }
+    unsafe { libc::syscall(libc::SYS_sched_setattr, 0, attr.as_ptr(), 0) == 0 }
+}
+
+fn main() {
+    let wanted = [
+        Attr(SCHED_DEADLINE, 0, 10_000_000, 30_000_000, 100_000_000),
+        Attr(SCHED_BATCH, 7, 0, 0, 0),
+    ];
+    let (child, which) = common::spawn_with(|ready| {
+        let which = wanted.iter().position(|&attr| setattr(attr)).unwrap() as u8;
+        ready.send(&[which]);
+    });
+    let attr = wanted[which[0] as usize];
+    assert_eq!(getattr(child.as_raw()), attr);
+    if attr.0 != SCHED_DEADLINE {
+        println!("not allowed SCHED_DEADLINE here, trying {:?} instead", attr);
+    }
+
+    let path = std::env::temp_dir().join(format!("telefork-sched-{}", child));
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    common::reap(child);
+    let restored = telepad_file_attached(&File::open(&path).unwrap(), 0, &Config::default());
+    std::fs::remove_file(&path).unwrap();
+    let restored = restored.unwrap();
+    let pid: Pid = restored.pid();
+    let got = getattr(pid.as_raw());
+    let skipped = restored.report().skipped.clone();
+    drop(restored);
+    common::reap(pid);
+
+    if got == attr {
+        println!("restored with {:?}", got);
+    } else {
+        // Only allowed when this machine couldn't take it
+        let reason = skipped
+            .iter()
+            .find(|s| s.what == "SCHED_DEADLINE")
+            .unwrap_or_else(|| panic!("restored with {:?} instead of {:?}", got, attr));
+        assert_eq!(got.0, 0, "should have fallen back to SCHED_OTHER");
+        println!("ran normally instead: {}", reason.error);
+    }
+}
@@ -0,0 +1,121 @@
+//! Two snapshots of a process in one file, written with `Config::index`, and
+//! the second one restored with `telepad_seek`. The process changes its
+//! state between them, so the restored one should have the second state, and
+//! nothing of the first snapshot but its index should have been read to get
+//! there.
+
+mod common;
+
+use telefork::{read_indexes, teledump_with_config, telepad_seek, BadStream, Config};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// A file that remembers the lowest offset anything was read from
+struct Watched {
+    file: File,
+    lowest: u64,
+}
+
+impl Read for Watched {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let at = self.file.stream_position()?;
+        let n = self.file.read(buf)?;
+        if n > 0 {
+            self.lowest = self.lowest.min(at);
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for Watched {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-seek-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (next, changed) = (dir.join("next"), dir.join("changed"));
+    let (go, out) = (dir.join("go"), dir.join("out"));
+    let (child, _) = common::spawn_with(|ready| {
+        let mut state = "first".to_string();
+        ready.send(&[]);
+        common::wait_for(&next);
+        state.replace_range(.., "second");
+        std::fs::write(&changed, "").unwrap();
+        common::wait_for(&go);
+        std::fs::write(&out, &state).unwrap();
+        std::process::exit(0);
+    });
+
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let path = dir.join("snapshots");
+    let mut snapshots = File::create(&path).unwrap();
+    teledump_with_config(child.as_raw(), &mut snapshots, true, &config).unwrap();
+    std::fs::write(&next, "").unwrap();
+    common::wait_for(&changed);
+    teledump_with_config(child.as_raw(), &mut snapshots, true, &config).unwrap();
+    drop(snapshots);
+    common::reap(child);
+
+    let mut file = File::open(&path).unwrap();
+    let indexes = read_indexes(&mut file).unwrap();
+    assert_eq!(
+        indexes.len(),
+        2,
+        "there should be an index for each snapshot"
+    );
+    let first_end = indexes[0].0 + indexes[0].1.len;
+    let (second, index) = &indexes[1];
+    assert!(*second > 0 && index.commands.contains_key("Mapping"));
+    println!(
+        "second snapshot is {} bytes at {} with {} mappings",
+        index.len,
+        second,
+        index.commands["Mapping"].len()
+    );
+
+    // An index that says it's longer or shorter than it is gets caught
+    // rather than read into the footer or the dump before it
+    let bytes = std::fs::read(&path).unwrap();
+    let len_at = bytes.len() - 12;
+    let len = u64::from_le_bytes(bytes[len_at..len_at + 8].try_into().unwrap());
+    for wrong in [len - 1, len + 1] {
+        let mut corrupt = bytes.clone();
+        corrupt[len_at..len_at + 8].copy_from_slice(&wrong.to_le_bytes());
+        let err = read_indexes(&mut Cursor::new(&corrupt)).unwrap_err();
+        assert!(
+            err.downcast_ref::<BadStream>().is_some(),
+            "an index with the wrong length failed some other way: {}",
+            err
+        );
+    }
+
+    let mut watched = Watched {
+        file,
+        lowest: u64::MAX,
+    };
+    let restored = telepad_seek(&mut watched, 1, 0, &Config::default());
+    assert!(
+        watched.lowest >= first_end,
+        "read from {} which is in the first snapshot",
+        watched.lowest
+    );
+    let pid = restored.unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(got, "second", "restored the wrong snapshot");
+    println!("restored the second snapshot without reading the first");
+}
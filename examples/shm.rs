@@ -0,0 +1,101 @@
+//! A process with a SysV shared memory segment attached, restored after the
+//! segment has been removed, like on another machine or after a reboot. The
+//! restore should make a new segment under the same key with the dumped
+//! contents in it and attach that at the same address.
+
+mod common;
+
+use telefork::{read_indexes, telepad_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Cursor;
+use std::os::unix::fs::FileExt;
+
+const PATTERN: &[u8] = b"telefork shared memory ";
+const LEN: usize = 4 * 4096;
+
+/// The shmid of the segment attached at `addr`, which the maps have as the
+/// mapping's inode
+fn attached_shmid(pid: Pid, addr: usize) -> Option<i32> {
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap();
+    maps.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let start = usize::from_str_radix(fields[0].split('-').next()?, 16).ok()?;
+        if start != addr || !fields.get(5)?.starts_with("/SYSV") {
+            return None;
+        }
+        fields[4].parse().ok()
+    })
+}
+
+fn main() {
+    let key = 0x7e1e_0000 | (std::process::id() as i32 & 0xffff);
+    let shmid = unsafe { libc::shmget(key, LEN, libc::IPC_CREAT | libc::IPC_EXCL | 0o600) };
+    if shmid < 0 {
+        println!("couldn't make a SysV shared memory segment, nothing to check");
+        return;
+    }
+
+    let (child, addr) = common::spawn_with(|ready| {
+        let addr = unsafe { libc::shmat(shmid, std::ptr::null(), 0) };
+        let bytes = unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, LEN) };
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = PATTERN[i % PATTERN.len()];
+        }
+        ready.send(&(addr as usize).to_le_bytes());
+    });
+    let addr = usize::from_le_bytes(addr[..].try_into().unwrap());
+    assert_eq!(attached_shmid(child, addr), Some(shmid));
+
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let dump = common::dump(child, &config);
+    let indexes = read_indexes(&mut Cursor::new(&dump)).unwrap();
+    assert!(
+        indexes[0].1.commands.contains_key("SharedMemory"),
+        "the dump doesn't have the shared memory segment"
+    );
+    // Nothing has it attached anymore, so it's gone straight away
+    let res = unsafe { libc::shmctl(shmid, libc::IPC_RMID, std::ptr::null_mut()) };
+    assert_eq!(res, 0, "couldn't remove the segment");
+
+    let restored = telepad_attached(&mut &dump[..], 0, &Config::default());
+    // Whatever the restore made goes too, even if it failed partway
+    let new_shmid = unsafe { libc::shmget(key, 0, 0) };
+    if new_shmid >= 0 {
+        unsafe { libc::shmctl(new_shmid, libc::IPC_RMID, std::ptr::null_mut()) };
+    }
+    let restored = restored.unwrap();
+    let pid = restored.pid();
+    let attached = attached_shmid(pid, addr);
+    let mut contents = vec![0u8; LEN];
+    File::open(format!("/proc/{}/mem", pid))
+        .unwrap()
+        .read_exact_at(&mut contents, addr as u64)
+        .unwrap();
+    drop(restored);
+    common::reap(pid);
+
+    assert!(new_shmid >= 0, "there's no segment under key {:#x}", key);
+    assert_eq!(
+        attached,
+        Some(new_shmid),
+        "the new segment isn't attached at {:x}",
+        addr
+    );
+    let wrong = contents
+        .iter()
+        .enumerate()
+        .filter(|(i, b)| **b != PATTERN[i % PATTERN.len()])
+        .count();
+    assert_eq!(wrong, 0, "{} bytes of the segment came back wrong", wrong);
+    println!(
+        "restored segment {:#x} as shmid {} instead of {}",
+        key, new_shmid, shmid
+    );
+}
@@ -0,0 +1,46 @@
+//! A dump where one mapping's contents came out short, the way a producer
+//! writing the wrong amount would leave it. Restoring it should fail right
+//! at that mapping with a `BadStream` saying which one it was, rather than
+//! reading the rest of the dump out of step and failing somewhere confusing.
+
+mod common;
+
+use telefork::{read_indexes, teledump_with_config, telepad, BadStream, Config};
+
+use std::io::Cursor;
+
+/// How many bytes of contents go missing
+const SHORT_BY: usize = 16;
+
+fn main() {
+    let child = common::spawn_ready(|| {});
+
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let mut dump = Vec::new();
+    teledump_with_config(child.as_raw(), &mut dump, true, &config).unwrap();
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", child)).unwrap();
+    common::reap(child);
+
+    // The contents of the mapping are right before its end marker
+    let indexes = read_indexes(&mut Cursor::new(&dump)).unwrap();
+    let end = indexes[0].1.commands["MappingEnd"][0] as usize;
+    dump.drain(end - SHORT_BY..end);
+
+    let err = telepad(&mut &dump[..], 0).unwrap_err();
+    let bad = match err.downcast_ref::<BadStream>() {
+        Some(bad) => bad,
+        None => panic!("failed some other way: {}", err),
+    };
+    let named = maps.lines().any(|line| {
+        let start = line.split('-').next().unwrap();
+        bad.0.contains(&format!(
+            "mapping at {:#x}",
+            usize::from_str_radix(start, 16).unwrap()
+        ))
+    });
+    assert!(named, "the error doesn't say which mapping: {}", bad);
+    println!("failed at the short mapping: {}", bad);
+}
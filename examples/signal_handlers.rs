@@ -0,0 +1,66 @@
+//! A process with a handler for SIGUSR1, like a daemon that reopens its logs
+//! on a signal. The dump should have every signal's action, and the restored
+//! process should run its handler when it gets SIGUSR1 rather than be
+//! killed by it.
+
+mod common;
+
+use telefork::{read_indexes, telepad, Config};
+
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static HANDLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_: libc::c_int) {
+    HANDLED.store(true, Ordering::SeqCst);
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-signal-handlers-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("out");
+
+    let (child, _) = common::spawn_with(|ready| {
+        let action = SigAction::new(
+            SigHandler::Handler(handle),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGUSR1, &action) }.unwrap();
+        ready.send(&[]);
+        while !HANDLED.load(Ordering::SeqCst) {
+            unsafe { libc::pause() };
+        }
+        std::fs::write(&out, "handled").unwrap();
+        std::process::exit(0);
+    });
+
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let dump = common::dump(child, &config);
+    let indexes = read_indexes(&mut Cursor::new(&dump)).unwrap();
+    assert!(
+        indexes[0].1.commands.contains_key("SignalActions"),
+        "the dump doesn't have the process's signal actions"
+    );
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    kill(pid, Signal::SIGUSR1).unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        status,
+        WaitStatus::Exited(pid, 0),
+        "the restored process didn't handle SIGUSR1"
+    );
+    assert_eq!(got, "handled");
+    println!("restored process handled SIGUSR1");
+}
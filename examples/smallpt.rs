@@ -131,10 +131,10 @@ fn render_scene(scene: &Scene, width: usize, height: usize, backbuffer: &mut [Ve
     // apparently rayon calls during setup. See
     // https://github.com/bminor/glibc/blob/5f72f9800b250410cad3abfeeb09469ef12b2438/sysdeps/unix/sysv/linux/getsysstats.c#L131
     // So this only works when either on the same kernel version or when using
-    // JANKY_VDSO_TELEPORT mode. So we might as well time it while we're at it
+    // `Config::janky_vdso` mode. So we might as well time it while we're at it
     let now = Instant::now();
     trace(
-        &scene,
+        scene,
         &camera,
         width,
         height,
@@ -147,17 +147,17 @@ fn render_scene(scene: &Scene, width: usize, height: usize, backbuffer: &mut [Ve
 
 fn save_png_file(width: usize, height: usize, backbuffer: &[Vec3]) {
     let mut data = vec![0u8; width * height * 3];
-    for i in 0..width * height {
-        let color = saturate(tonemap(backbuffer[i]));
+    for (pixel, rgb) in backbuffer.iter().zip(data.chunks_exact_mut(3)) {
+        let color = saturate(tonemap(*pixel));
 
-        data[i * 3 + 0] = (color.x * 255.0).round() as u8; // r
-        data[i * 3 + 1] = (color.y * 255.0).round() as u8; // g
-        data[i * 3 + 2] = (color.z * 255.0).round() as u8; // b
+        rgb[0] = (color.x * 255.0).round() as u8; // r
+        rgb[1] = (color.y * 255.0).round() as u8; // g
+        rgb[2] = (color.z * 255.0).round() as u8; // b
     }
 
     let path = Path::new("render.png");
     let file = File::create(path).unwrap();
-    let ref mut w = BufWriter::new(file);
+    let w = &mut BufWriter::new(file);
 
     let mut encoder = png::Encoder::new(w, width as u32, height as u32);
     encoder.set_color(png::ColorType::RGB);
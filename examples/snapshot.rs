@@ -0,0 +1,116 @@
+//! Snapshot fuzzing with `telepad_snapshot`. The process spins until it's
+//! given an input, scribbles it over some of a big buffer and stops with a
+//! `SIGTRAP`. Each round writes an input, runs it to the trap, checks what
+//! it did and resets, which should put the buffer and the input back the
+//! way they were and take far less time than restoring all over again.
+
+mod common;
+
+use telefork::{teledump, telepad_file_attached, telepad_snapshot, Config};
+
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, Instant};
+
+const BUFFER: usize = 64 * 1024 * 1024;
+const PAGE_SIZE: usize = 4096;
+/// How many pages of the buffer each run writes to
+const DIRTIED: usize = 16;
+const ROUNDS: usize = 100;
+
+static mut INPUT: u8 = 0;
+
+fn peek(pid: Pid, addr: usize) -> u8 {
+    let mut byte = [0u8];
+    File::open(format!("/proc/{}/mem", pid))
+        .unwrap()
+        .read_exact_at(&mut byte, addr as u64)
+        .unwrap();
+    byte[0]
+}
+
+fn poke(pid: Pid, addr: usize, byte: u8) {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(format!("/proc/{}/mem", pid))
+        .unwrap()
+        .write_all_at(&[byte], addr as u64)
+        .unwrap();
+}
+
+fn main() {
+    let (child, addrs) = common::spawn_with(|ready| {
+        let buffer = vec![1u8; BUFFER].leak();
+        let input = std::ptr::addr_of_mut!(INPUT);
+        let mut addrs = (buffer.as_ptr() as usize).to_le_bytes().to_vec();
+        addrs.extend_from_slice(&(input as usize).to_le_bytes());
+        ready.send(&addrs);
+        loop {
+            let input = unsafe { std::ptr::read_volatile(input) };
+            if input == 0 {
+                std::hint::spin_loop();
+                continue;
+            }
+            for page in buffer.chunks_mut(PAGE_SIZE * 7).take(DIRTIED) {
+                page[0] = input;
+            }
+            unsafe { libc::raise(libc::SIGTRAP) };
+        }
+    });
+    let buffer = usize::from_le_bytes(addrs[..8].try_into().unwrap());
+    let input = usize::from_le_bytes(addrs[8..].try_into().unwrap());
+
+    let path = std::env::temp_dir().join(format!("telefork-snapshot-{}", child));
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    common::reap(child);
+
+    let started = Instant::now();
+    let again = telepad_file_attached(&File::open(&path).unwrap(), 0, &Config::default());
+    let restore_took = started.elapsed();
+    let again = again.unwrap().pid();
+    common::reap(again);
+
+    let snapshot = telepad_snapshot(&mut File::open(&path).unwrap(), 0, &Config::default());
+    std::fs::remove_file(&path).unwrap();
+    let mut snapshot = snapshot.unwrap();
+    let pid = snapshot.pid();
+    let mut resets_took = Duration::ZERO;
+    for round in 0..ROUNDS {
+        let given = (round % 255) as u8 + 2;
+        poke(pid, input, given);
+        let status = snapshot.run().unwrap();
+        assert_eq!(status, WaitStatus::Stopped(pid, Signal::SIGTRAP));
+        assert_eq!(peek(pid, buffer), given, "it didn't run with the input");
+
+        let started = Instant::now();
+        let pages = snapshot.reset().unwrap();
+        resets_took += started.elapsed();
+        assert!(pages >= DIRTIED, "only {} pages were reset", pages);
+        assert_eq!(peek(pid, input), 0, "the input is still there");
+        for page in 0..DIRTIED {
+            assert_eq!(
+                peek(pid, buffer + page * PAGE_SIZE * 7),
+                1,
+                "page {} of the buffer kept what the last run wrote",
+                page
+            );
+        }
+    }
+    drop(snapshot);
+    common::reap(pid);
+
+    let reset_took = resets_took / ROUNDS as u32;
+    println!(
+        "{} rounds, resetting took {:?} against {:?} to restore",
+        ROUNDS, reset_took, restore_took
+    );
+    assert!(
+        reset_took < restore_took,
+        "resetting wasn't any faster than restoring again"
+    );
+}
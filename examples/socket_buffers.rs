@@ -0,0 +1,96 @@
+//! A process with data waiting on two socketpairs, a stream one and a
+//! datagram one. The stream one's bytes should be kept and come back on
+//! restore. The datagram one's messages shouldn't be glued together into one
+//! run of bytes, which would lose where each of them ended, so nothing is
+//! kept for it and the restored end has nothing to read.
+
+mod common;
+
+use telefork::{scan_file_descriptors, telepad, Config, Connection};
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+fn socketpair(sock_type: i32) -> [i32; 2] {
+    let mut fds = [0; 2];
+    let res = unsafe { libc::socketpair(libc::AF_UNIX, sock_type, 0, fds.as_mut_ptr()) };
+    assert_eq!(res, 0);
+    fds
+}
+
+/// Whatever one `recv` gets without waiting, or the errno
+fn recv_now(fd: i32) -> Result<Vec<u8>, i32> {
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        libc::recv(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(0));
+    }
+    Ok(buf[..len as usize].to_vec())
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-socket-buffers-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (go, out) = (dir.join("go"), dir.join("out"));
+
+    let (child, _) = common::spawn_with(|ready| {
+        let stream = socketpair(libc::SOCK_STREAM);
+        let dgram = socketpair(libc::SOCK_DGRAM);
+        let send = |fd: i32, bytes: &[u8]| unsafe {
+            libc::send(fd, bytes.as_ptr() as *const libc::c_void, bytes.len(), 0)
+        };
+        send(stream[1], b"hello world");
+        send(dgram[1], b"a");
+        send(dgram[1], b"bb");
+        ready.send(&[]);
+        common::wait_for(&go);
+        let stream = recv_now(stream[0]);
+        let dgram = recv_now(dgram[0]);
+        std::fs::write(&out, format!("stream {:?} dgram {:?}", stream, dgram)).unwrap();
+        std::process::exit(0);
+    });
+
+    let fds = scan_file_descriptors(child.as_raw()).unwrap();
+    let mut buffered: Vec<(i32, Vec<u8>)> = fds
+        .values()
+        .filter_map(|c| match c {
+            Connection::UnixPair(pair) if !pair.buffered.is_empty() => {
+                Some((pair.sock_type, pair.buffered.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    buffered.sort();
+    assert_eq!(
+        buffered,
+        vec![(libc::SOCK_STREAM, b"hello world".to_vec())],
+        "only the stream socket's bytes should be kept"
+    );
+    println!("kept the stream socket's bytes and none of the datagrams");
+
+    let dump = common::dump(child, &Config::default());
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    let expected = format!(
+        "stream {:?} dgram {:?}",
+        Ok::<_, i32>(b"hello world".to_vec()),
+        Err::<Vec<u8>, _>(libc::EAGAIN)
+    );
+    assert_eq!(
+        got, expected,
+        "the restored sockets had the wrong data waiting"
+    );
+    println!("restored with {}", got);
+}
@@ -0,0 +1,53 @@
+//! A process someone already stopped with SIGSTOP before dumping it, like
+//! tooling that lines processes up first. Dumping it and leaving it running
+//! should leave it how we found it, still stopped, and a SIGCONT should
+//! still carry it on from there.
+
+mod common;
+
+use telefork::teledump;
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+/// The state letter from `/proc/pid/stat`
+fn state(pid: Pid) -> char {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+    let after = &stat[stat.rfind(')').unwrap() + 2..];
+    after.chars().next().unwrap()
+}
+
+/// Wait a little while for it to get to `want`
+fn wait_for_state(pid: Pid, want: char) -> char {
+    for _ in 0..100 {
+        if state(pid) == want {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    state(pid)
+}
+
+fn main() {
+    let child = common::spawn_ready(|| {});
+
+    kill(child, Signal::SIGSTOP).unwrap();
+    assert_eq!(wait_for_state(child, 'T'), 'T', "SIGSTOP didn't stop it");
+
+    let mut dump = Vec::new();
+    teledump(child.as_raw(), &mut dump, true).unwrap();
+    // Long enough for it to have started running again if it was going to
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let after_dump = state(child);
+    kill(child, Signal::SIGCONT).unwrap();
+    let after_cont = wait_for_state(child, 'S');
+    common::reap(child);
+
+    assert!(!dump.is_empty(), "nothing was dumped");
+    assert_eq!(after_dump, 'T', "dumping it left it running");
+    assert_eq!(after_cont, 'S', "it didn't carry on after a SIGCONT");
+    println!(
+        "dumped {} bytes of a stopped process and it stayed stopped",
+        dump.len()
+    );
+}
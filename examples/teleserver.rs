@@ -5,7 +5,7 @@ use std::os::unix::io::AsRawFd;
 
 fn handle_client(mut stream: TcpStream) {
     println!("TELESERVER: starting to receive process!");
-    let fd = stream.as_raw_fd() as i32;
+    let fd = stream.as_raw_fd();
     let child = telepad(&mut stream, fd).unwrap();
     println!(
         "TELESERVER: received child to pid = {} and passed TCP fd={}",
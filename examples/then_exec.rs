@@ -0,0 +1,76 @@
+//! A process with a file open on fd 5 and a variable in its environment,
+//! restored with `telepad_then_exec` into a shell instead of itself. The
+//! shell should find fd 5 open on the same file, still at the offset the
+//! original had read it to, and the same environment.
+
+use telefork::{teledump, telepad_then_exec, Config};
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-then-exec-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (state, out) = (dir.join("state"), dir.join("out"));
+    std::fs::write(&state, "already read\nhanded over\n").unwrap();
+
+    // The original reads the first line through fd 5 and then waits
+    let file = File::open(&state).unwrap();
+    let fd = file.as_raw_fd();
+    let mut original = unsafe {
+        Command::new("sh")
+            .args(["-c", "read line <&5; echo ready; exec sleep 1000"])
+            .env("TELEFORK_STATE", "kept")
+            .stdout(Stdio::piped())
+            .pre_exec(move || {
+                if libc::dup2(fd, 5) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+            .spawn()
+            .unwrap()
+    };
+    drop(file);
+    let mut ready = String::new();
+    BufReader::new(original.stdout.take().unwrap())
+        .read_line(&mut ready)
+        .unwrap();
+    // Give the exec of sleep a moment to be done with
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let pid = original.id() as i32;
+    let mut dump = Vec::new();
+    teledump(pid, &mut dump, true).unwrap();
+    kill(Pid::from_raw(pid), Signal::SIGKILL).unwrap();
+    original.wait().unwrap();
+
+    let script = format!(
+        "read line <&5; echo \"$line $TELEFORK_STATE\" > {}",
+        out.display()
+    );
+    let pid = telepad_then_exec(
+        &mut &dump[..],
+        "/bin/sh",
+        &["sh", "-c", &script],
+        &Config::default(),
+    )
+    .unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(
+        got, "handed over kept\n",
+        "the new program didn't get the fd and environment"
+    );
+    println!("the new program read {:?} from the fd it was handed", got);
+}
@@ -0,0 +1,109 @@
+//! A process that asked for transparent huge pages on a big mapping with
+//! `madvise(MADV_HUGEPAGE)`. The restored mapping should have the same
+//! contents and be marked for huge pages again, which shows up as `hg` in
+//! its `VmFlags`. Whether the kernel has actually collapsed it into huge
+//! pages yet is up to it, so that isn't checked. Kernels without THP have
+//! nothing to check.
+
+mod common;
+
+use telefork::{read_indexes, telepad_attached, Config};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Cursor;
+use std::os::unix::fs::FileExt;
+
+const LEN: usize = 4 * 1024 * 1024;
+
+/// The `VmFlags` of the mapping `addr` is in
+fn vm_flags(pid: Pid, addr: usize) -> Vec<String> {
+    let smaps = std::fs::read_to_string(format!("/proc/{}/smaps", pid)).unwrap();
+    let mut inside = false;
+    for line in smaps.lines() {
+        let range = line
+            .split_whitespace()
+            .next()
+            .and_then(|r| r.split_once('-'));
+        if let Some((start, end)) = range {
+            let start = usize::from_str_radix(start, 16);
+            let end = usize::from_str_radix(end, 16);
+            if let (Ok(start), Ok(end)) = (start, end) {
+                inside = (start..end).contains(&addr);
+                continue;
+            }
+        }
+        if let Some(flags) = line.strip_prefix("VmFlags:").filter(|_| inside) {
+            return flags.split_whitespace().map(String::from).collect();
+        }
+    }
+    Vec::new()
+}
+
+fn main() {
+    if !std::path::Path::new("/sys/kernel/mm/transparent_hugepage").exists() {
+        println!("this kernel doesn't have transparent huge pages, nothing to check");
+        return;
+    }
+    let (child, addr) = common::spawn_with(|ready| {
+        let bytes = unsafe {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            let res = libc::madvise(addr, LEN, libc::MADV_HUGEPAGE);
+            assert_eq!(res, 0, "madvise failed");
+            std::slice::from_raw_parts_mut(addr as *mut u8, LEN)
+        };
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        ready.send(&(bytes.as_ptr() as usize).to_le_bytes());
+    });
+    let addr = usize::from_le_bytes(addr[..].try_into().unwrap());
+    assert!(
+        vm_flags(child, addr).iter().any(|f| f == "hg"),
+        "madvise didn't mark the mapping"
+    );
+
+    let config = Config {
+        index: true,
+        ..Config::default()
+    };
+    let dump = common::dump(child, &config);
+    let indexes = read_indexes(&mut Cursor::new(&dump)).unwrap();
+    assert!(
+        indexes[0].1.commands.contains_key("HugePages"),
+        "the dump doesn't say the mapping used huge pages"
+    );
+
+    let restored = telepad_attached(&mut &dump[..], 0, &Config::default()).unwrap();
+    let pid = restored.pid();
+    let flags = vm_flags(pid, addr);
+    let mut contents = vec![0u8; LEN];
+    File::open(format!("/proc/{}/mem", pid))
+        .unwrap()
+        .read_exact_at(&mut contents, addr as u64)
+        .unwrap();
+    drop(restored);
+    common::reap(pid);
+
+    let wrong = contents
+        .iter()
+        .enumerate()
+        .filter(|(i, b)| **b != (i % 251) as u8)
+        .count();
+    assert_eq!(wrong, 0, "{} bytes of the mapping came back wrong", wrong);
+    assert!(
+        flags.iter().any(|f| f == "hg"),
+        "the restored mapping isn't marked for huge pages, it has {:?}",
+        flags
+    );
+    println!("restored {} bytes marked for huge pages", LEN);
+}
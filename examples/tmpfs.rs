@@ -0,0 +1,64 @@
+use telefork::{telefork, telepad_file, wait_for_exit, Config, TeleforkLocation};
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+const SIZE: usize = 8 * 4096;
+
+fn main() {
+    let fname = "tmpfs.telefork.bin";
+    let shm_path = format!("/dev/shm/telefork-tmpfs-{}", std::process::id());
+    let shm = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&shm_path)
+        .unwrap();
+    shm.set_len(SIZE as u64).unwrap();
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            shm.as_raw_fd(),
+            0,
+        )
+    };
+    assert_ne!(addr, libc::MAP_FAILED);
+    drop(shm);
+    let bytes = unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, SIZE) };
+    bytes
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = (i % 241) as u8);
+
+    let loc = {
+        let mut output = File::create(fname).unwrap();
+        telefork(&mut output).unwrap()
+    };
+    match loc {
+        TeleforkLocation::Child(val) => {
+            let intact = bytes.iter().enumerate().all(|(i, b)| *b == (i % 241) as u8);
+            // Still shared with the file, not a private copy of it
+            let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+            let shared = maps.lines().any(|l| {
+                l.starts_with(&format!("{:x}-", addr as usize))
+                    && l.contains("rw-s")
+                    && l.ends_with(&shm_path)
+            });
+            println!("contents intact: {}, still shared: {}", intact, shared);
+            std::process::exit(if intact && shared { val } else { 1 })
+        }
+        TeleforkLocation::Parent => println!("finished teleforking"),
+    };
+
+    // Like restoring on another machine, where it was never there
+    std::fs::remove_file(&shm_path).unwrap();
+    let input = File::open(fname).unwrap();
+    let child = telepad_file(&input, 42, &Config::default()).unwrap();
+    let status = wait_for_exit(child).unwrap();
+    println!("child exited with status = {}", status);
+    let _ = std::fs::remove_file(&shm_path);
+    assert_eq!(status, 42);
+}
@@ -0,0 +1,88 @@
+//! Scrubbing a secret out of a dump with `TeleforkBuilder::transform_pages`.
+//! The process keeps a secret that straddles a page boundary next to some
+//! ordinary data, and the transform zeroes just the secret's bytes. The dump
+//! shouldn't have the secret anywhere in it, and the restored process should
+//! find zeroes where it was and everything around it the same.
+
+mod common;
+
+use telefork::builder::TeleforkBuilder;
+use telefork::telepad;
+
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use std::convert::TryInto;
+
+const PAGE_SIZE: usize = 4096;
+/// How big the secret is, with the same amount of ordinary bytes either side
+const SECRET_LEN: usize = 8192;
+const ORDINARY: u8 = 0x5a;
+
+/// A byte of the secret, made up as it's needed so it isn't sitting in the
+/// binary or anywhere else in memory for the dump to pick up
+fn secret_byte(pid: u32, i: usize) -> u8 {
+    ((pid as usize).wrapping_mul(31).wrapping_add(i * 7) % 251) as u8 + 1
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("telefork-transform-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (go, out) = (dir.join("go"), dir.join("out"));
+    let (child, addr) = common::spawn_with(|ready| {
+        let buffer = vec![ORDINARY; SECRET_LEN * 3].leak();
+        // Halfway through a page, so it's split over two
+        let start = (buffer.as_ptr() as usize + SECRET_LEN).next_multiple_of(PAGE_SIZE)
+            - PAGE_SIZE / 2
+            - buffer.as_ptr() as usize;
+        let pid = std::process::id();
+        for (i, byte) in buffer[start..start + SECRET_LEN].iter_mut().enumerate() {
+            *byte = secret_byte(pid, i);
+        }
+        let secret = buffer[start..].as_ptr() as usize;
+        ready.send(&secret.to_le_bytes());
+        common::wait_for(&go);
+        let scrubbed = buffer[start..start + SECRET_LEN].iter().all(|&b| b == 0);
+        let kept = buffer[..start]
+            .iter()
+            .chain(&buffer[start + SECRET_LEN..])
+            .all(|&b| b == ORDINARY);
+        std::fs::write(&out, format!("scrubbed {} kept {}", scrubbed, kept)).unwrap();
+        std::process::exit(0);
+    });
+    let secret = usize::from_le_bytes(addr[..].try_into().unwrap());
+    let secret_end = secret + SECRET_LEN;
+
+    let mut dump = Vec::new();
+    let mut scrubbed = 0;
+    TeleforkBuilder::new()
+        .transform_pages(|addr, page| {
+            let from = secret.clamp(addr, addr + page.len());
+            let to = secret_end.clamp(addr, addr + page.len());
+            page[from - addr..to - addr].fill(0);
+            scrubbed += to - from;
+        })
+        .teledump(child.as_raw(), &mut dump, true)
+        .unwrap();
+    common::reap(child);
+    assert_eq!(scrubbed, SECRET_LEN, "the transform didn't see all of it");
+    let sample: Vec<u8> = (0..64)
+        .map(|i| secret_byte(child.as_raw() as u32, i))
+        .collect();
+    assert!(
+        !dump.windows(sample.len()).any(|w| w == sample),
+        "the secret is still in the dump"
+    );
+
+    let pid = telepad(&mut &dump[..], 0).unwrap();
+    std::fs::write(&go, "").unwrap();
+    let status = waitpid(pid, None).unwrap();
+    let got = std::fs::read_to_string(&out).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(status, WaitStatus::Exited(pid, 0));
+    assert_eq!(
+        got, "scrubbed true kept true",
+        "the restored process doesn't have the transformed memory"
+    );
+    println!("{} bytes of secret scrubbed, restored as zeroes", scrubbed);
+}
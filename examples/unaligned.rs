@@ -0,0 +1,55 @@
+use telefork::{telefork, DumpDir, TeleforkLocation};
+
+const PAGE_SIZE: usize = 4096;
+
+fn main() {
+    // The kernel rounds it up to two pages, all of which has to come across
+    // as exactly that many bytes or everything after it in the stream is off
+    let len = PAGE_SIZE + 100;
+    let mem = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(mem, libc::MAP_FAILED);
+    let addr = mem as usize;
+    unsafe { std::ptr::write_bytes(mem as *mut u8, 7, len) };
+
+    let dir = std::env::temp_dir().join("telefork-unaligned");
+    let mut out = DumpDir::create(&dir).unwrap();
+    if let TeleforkLocation::Child(_) = telefork(&mut out).unwrap() {
+        unreachable!("this dump is never restored");
+    }
+    // Splitting the stream needs every mapping's contents to be the length
+    // its command says, it fails on the next command if not
+    out.finish().unwrap();
+
+    // It might have merged with a neighbour, so look for whichever has it
+    let (start, end, sent) = std::fs::read_dir(&dir)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            let (start, end) = name.strip_suffix(".bin")?.split_once('-')?;
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            (start <= addr && addr < end).then(|| (start, end, std::fs::read(dir.join(name))))
+        })
+        .next()
+        .unwrap();
+    let sent = sent.unwrap();
+    assert_eq!(sent.len(), end - start);
+    let ours = &sent[addr - start..addr - start + 2 * PAGE_SIZE];
+    assert!(ours[..len].iter().all(|&b| b == 7));
+    assert!(ours[len..].iter().all(|&b| b == 0));
+    println!(
+        "{} bytes sent for the mapping with our {} bytes in it",
+        sent.len(),
+        len
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
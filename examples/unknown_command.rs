@@ -0,0 +1,99 @@
+//! A dump with a command from some newer version in it, one with a variant
+//! tag past the end of `Command`. If its frame says it's optional it should
+//! be skipped, contents and all, and the rest of the dump restored. If not
+//! there's no restoring the dump, and that should be an `Unsupported` error
+//! rather than the stream getting out of step. Both with and without
+//! `Config::contents_lengths`, since without it an unknown command can't
+//! have anything after it.
+
+use telefork::{
+    diff_dumps, telefork_with_config, telepad, wait_for_exit, Config, TeleforkLocation, Unsupported,
+};
+
+use std::error::Error;
+
+/// Far past the last variant
+const UNKNOWN_TAG: u32 = 9999;
+
+/// `dump` with a frame holding an unknown command added right after the
+/// header, followed by `contents`
+fn with_unknown(dump: &[u8], contents_lengths: bool, optional: bool, contents: &[u8]) -> Vec<u8> {
+    // Magic and version, then the header flags if there are any
+    let header_len = if contents_lengths { 12 } else { 8 };
+    let command = UNKNOWN_TAG.to_le_bytes();
+    let mut frame = (command.len() as u32).to_le_bytes().to_vec();
+    frame.push(optional as u8);
+    if contents_lengths {
+        frame.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+    }
+    frame.extend_from_slice(&command);
+    frame.extend_from_slice(contents);
+    let mut newer = dump[..header_len].to_vec();
+    newer.extend_from_slice(&frame);
+    newer.extend_from_slice(&dump[header_len..]);
+    newer
+}
+
+/// Read both dumps through to the end, see `diff_dumps`
+fn compare(ours: &[u8], theirs: &[u8]) -> Result<bool, Box<dyn Error>> {
+    let tmp = std::env::temp_dir();
+    let id = std::process::id();
+    let (a, b) = (
+        tmp.join(format!("telefork-unknown-ours-{}", id)),
+        tmp.join(format!("telefork-unknown-theirs-{}", id)),
+    );
+    std::fs::write(&a, ours)?;
+    std::fs::write(&b, theirs)?;
+    let diff = diff_dumps(&a, &b);
+    std::fs::remove_file(&a)?;
+    std::fs::remove_file(&b)?;
+    Ok(diff?.is_empty())
+}
+
+fn main() {
+    for contents_lengths in [true, false] {
+        let config = Config {
+            contents_lengths,
+            ..Config::default()
+        };
+        let mut dump = Vec::new();
+        match telefork_with_config(&mut dump, &config).unwrap() {
+            TeleforkLocation::Child(val) => std::process::exit(val),
+            TeleforkLocation::Parent => {}
+        };
+        // Only a dump that says how much follows each command can have
+        // something after one we don't know
+        let contents: &[u8] = if contents_lengths { &[0xaa; 4096] } else { &[] };
+
+        let optional = with_unknown(&dump, contents_lengths, true, contents);
+        assert!(
+            compare(&dump, &optional).unwrap(),
+            "reading the dump back didn't skip the optional command"
+        );
+        let child = telepad(&mut &optional[..], 5).unwrap();
+        let status = wait_for_exit(child).unwrap();
+        assert_eq!(status, 5);
+        println!(
+            "contents lengths {}: skipped an unknown optional command and restored",
+            contents_lengths
+        );
+
+        let required = with_unknown(&dump, contents_lengths, false, contents);
+        let err = compare(&dump, &required).unwrap_err();
+        assert!(
+            err.downcast_ref::<Unsupported>().is_some(),
+            "an unknown required command failed some other way: {}",
+            err
+        );
+        let err = telepad(&mut &required[..], 5).unwrap_err();
+        assert!(
+            err.downcast_ref::<Unsupported>().is_some(),
+            "restoring with an unknown required command failed some other way: {}",
+            err
+        );
+        println!(
+            "contents lengths {}: refused an unknown required command: {}",
+            contents_lengths, err
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! A process with a TCP connection open, which telefork can't bring back,
+//! restored with each `UnsupportedFdPolicy`. `Fail` refuses to restore it at
+//! all, `Warn` and `Drop` restore it without that fd and say which it was.
+
+mod common;
+
+use telefork::{teledump, telepad_file_attached, Config, Unsupported, UnsupportedFdPolicy};
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::IntoRawFd;
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (child, fd) = common::spawn_with(|ready| {
+        let fd = TcpStream::connect(addr).unwrap().into_raw_fd() as u32;
+        ready.send(&fd.to_le_bytes());
+    });
+    let _accepted = listener.accept().unwrap();
+    let tcp_fd = u32::from_le_bytes(fd[..].try_into().unwrap());
+
+    let path = std::env::temp_dir().join(format!("telefork-unsupported-fds-{}", child));
+    teledump(child.as_raw(), &mut File::create(&path).unwrap(), true).unwrap();
+    common::reap(child);
+    let dump = File::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    for policy in [
+        UnsupportedFdPolicy::Fail,
+        UnsupportedFdPolicy::Warn,
+        UnsupportedFdPolicy::Drop,
+    ] {
+        let config = Config {
+            unsupported_fds: policy,
+            ..Config::default()
+        };
+        let restored = telepad_file_attached(&dump, 0, &config);
+        if policy == UnsupportedFdPolicy::Fail {
+            let err = match restored {
+                Ok(_) => panic!("restored with a TCP fd anyway"),
+                Err(e) => e,
+            };
+            match err.downcast_ref::<Unsupported>() {
+                Some(_) => println!("{:?}: {}", policy, err),
+                None => panic!("failed some other way: {}", err),
+            }
+            continue;
+        }
+        let restored = restored.unwrap();
+        let pid = restored.pid();
+        let report = restored.report().clone();
+        let closed = !std::path::Path::new(&format!("/proc/{}/fd/{}", pid, tcp_fd)).exists();
+        drop(restored);
+        common::reap(pid);
+
+        assert_eq!(report.dropped_fds, [tcp_fd]);
+        assert!(closed, "the TCP fd is open in the restored process");
+        let listed = report
+            .skipped
+            .iter()
+            .any(|s| s.what.ends_with(&format!("fd {}", tcp_fd)));
+        assert_eq!(listed, policy == UnsupportedFdPolicy::Warn);
+        println!("{:?}: restored without fd {}", policy, tcp_fd);
+    }
+}
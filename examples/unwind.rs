@@ -0,0 +1,124 @@
+//! The restored process has to be able to unwind from the instruction it
+//! resumes at: the stack pointer, frame pointer and every return address
+//! above it have to be exactly what they were when it was dumped, or the
+//! first panic (or C++ exception) after a restore takes it down instead.
+
+use telefork::harness::MemoryChannel;
+use telefork::{telefork, telepad, wait_for_exit, TeleforkLocation};
+
+use std::arch::asm;
+use std::ffi::c_void;
+
+/// Not in `libc`, the unwinder from libgcc_s that panics already go through
+#[allow(non_camel_case_types)]
+type _Unwind_Trace_Fn = extern "C" fn(ctx: *mut c_void, arg: *mut c_void) -> i32;
+
+extern "C" {
+    fn _Unwind_Backtrace(trace: _Unwind_Trace_Fn, arg: *mut c_void) -> i32;
+    fn _Unwind_GetIP(ctx: *mut c_void) -> usize;
+    fn _Unwind_GetCFA(ctx: *mut c_void) -> usize;
+}
+
+/// Each frame's return address and canonical frame address, by DWARF CFI
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    ip: usize,
+    cfa: usize,
+}
+
+extern "C" fn record(ctx: *mut c_void, arg: *mut c_void) -> i32 {
+    let frames = unsafe { &mut *(arg as *mut Vec<Frame>) };
+    unsafe {
+        frames.push(Frame {
+            ip: _Unwind_GetIP(ctx),
+            cfa: _Unwind_GetCFA(ctx),
+        })
+    };
+    // _URC_NO_REASON
+    0
+}
+
+#[inline(never)]
+fn backtrace() -> Vec<Frame> {
+    let mut frames = Vec::new();
+    unsafe { _Unwind_Backtrace(record, &mut frames as *mut Vec<Frame> as *mut c_void) };
+    frames
+}
+
+#[inline(always)]
+fn stack_and_frame_pointers() -> (usize, usize) {
+    let (rsp, rbp): (usize, usize);
+    unsafe { asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp) };
+    (rsp, rbp)
+}
+
+/// Everything `check` needs is taken before the dump so it comes across
+/// with the stack and compared against in the restored process
+#[inline(never)]
+fn dump_and_check(out: &mut MemoryChannel) -> TeleforkLocation {
+    let (rsp, rbp) = stack_and_frame_pointers();
+    let before = backtrace();
+    let location = telefork(out).unwrap();
+    if let TeleforkLocation::Child(_) = location {
+        // Callee saved, so right after the call they're back to what they
+        // were before it no matter which process returned from it
+        let after = stack_and_frame_pointers();
+        if after != (rsp, rbp) {
+            eprintln!(
+                "rsp and rbp were {:x?} before the dump and {:x?} after",
+                (rsp, rbp),
+                after
+            );
+            std::process::exit(1);
+        }
+        std::process::exit(check(rsp, before));
+    }
+    location
+}
+
+/// The frames whose frame starts above `rsp`, which is `dump_and_check`'s
+/// and all its callers
+fn outer(frames: &[Frame], rsp: usize) -> Vec<&Frame> {
+    frames.iter().filter(|f| f.cfa > rsp).collect()
+}
+
+#[inline(never)]
+fn check(rsp: usize, before: Vec<Frame>) -> i32 {
+    // `dump_and_check` calls something else by now, so only its frame has
+    // to be in the same place, everything outside it has to match exactly
+    let after = backtrace();
+    let (outer_before, outer_after) = (outer(&before, rsp), outer(&after, rsp));
+    if outer_before.len() < 2
+        || outer_before.len() != outer_after.len()
+        || outer_before[0].cfa != outer_after[0].cfa
+        || outer_before[1..] != outer_after[1..]
+    {
+        eprintln!(
+            "unwound differently before the dump:\n{:x?}\nand after:\n{:x?}",
+            before, after
+        );
+        return 2;
+    }
+    std::panic::set_hook(Box::new(|_| {}));
+    let caught = std::panic::catch_unwind(|| panic!("thrown right after the restore"));
+    let _ = std::panic::take_hook();
+    match caught {
+        Err(payload) if payload.downcast_ref::<&str>().is_some() => 42,
+        _ => 3,
+    }
+}
+
+fn main() {
+    let mut channel = MemoryChannel::new();
+    if let TeleforkLocation::Parent = dump_and_check(&mut channel) {
+        let child = telepad(&mut channel, 0).unwrap();
+        let status = wait_for_exit(child).unwrap();
+        match status {
+            1 => panic!("the stack or frame pointer moved"),
+            2 => panic!("the return address chain changed"),
+            3 => panic!("couldn't catch a panic after the restore"),
+            _ => assert_eq!(status, 42, "the restored process died unwinding"),
+        }
+        println!("unwound cleanly after the restore");
+    }
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use telefork::{scan_file_descriptors, teledump, Connection};
+
+use nix::unistd::Pid;
+
+use std::convert::TryInto;
+
+const PAGE_SIZE: usize = 4096;
+const UFFDIO_API: libc::c_ulong = 0xc018_aa3f;
+const UFFDIO_REGISTER: libc::c_ulong = 0xc020_aa00;
+
+/// A child with four pages registered for missing faults with a userfaultfd,
+/// only the first of which it's touched. With `hand_off` the userfaultfd is
+/// only held open by a grandchild, like a handler in another process.
+fn spawn(hand_off: bool) -> (Pid, usize) {
+    let (child, addr) = common::spawn_with(|ready| unsafe {
+        let uffd = libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC) as i32;
+        let api: [u64; 3] = [0xaa, 0, 0];
+        let mem = libc::mmap(
+            std::ptr::null_mut(),
+            4 * PAGE_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        *(mem as *mut u8) = 1;
+        let register: [u64; 4] = [mem as u64, 4 * PAGE_SIZE as u64, 1, 0];
+        if uffd < 0
+            || libc::ioctl(uffd, UFFDIO_API, api.as_ptr()) != 0
+            || libc::ioctl(uffd, UFFDIO_REGISTER, register.as_ptr()) != 0
+        {
+            libc::_exit(1);
+        }
+        if hand_off {
+            if libc::fork() == 0 {
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                loop {
+                    libc::pause();
+                }
+            }
+            libc::close(uffd);
+        }
+        ready.send(&(mem as usize).to_ne_bytes());
+    });
+    assert_eq!(addr.len(), 8, "the child couldn't set up its userfaultfd");
+    (child, usize::from_ne_bytes(addr[..].try_into().unwrap()))
+}
+
+fn stop(child: Pid) {
+    common::reap(child);
+}
+
+fn main() {
+    let (child, addr) = spawn(false);
+    let cm = scan_file_descriptors(child.as_raw()).unwrap();
+    let uffd = cm
+        .values()
+        .find_map(|conn| match conn {
+            Connection::Userfaultfd(uffd) => Some(uffd.clone()),
+            _ => None,
+        })
+        .expect("the userfaultfd wasn't recognised");
+    println!("{:?}", uffd);
+    assert_eq!(uffd.features, Some(0));
+    assert_eq!(uffd.regions.len(), 1);
+    let region = &uffd.regions[0];
+    assert_eq!(
+        (region.addr, region.size, region.mode),
+        (addr, 4 * PAGE_SIZE, 1)
+    );
+    // The one it touched isn't missing anymore
+    assert_eq!(region.missing, vec![(addr + PAGE_SIZE, 3 * PAGE_SIZE)]);
+    teledump(child.as_raw(), &mut std::io::sink(), true).unwrap();
+    stop(child);
+
+    let (child, _) = spawn(true);
+    let err = teledump(child.as_raw(), &mut std::io::sink(), true).unwrap_err();
+    println!("{}", err);
+    assert!(err.to_string().contains("another process"));
+    stop(child);
+}
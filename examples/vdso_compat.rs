@@ -0,0 +1,58 @@
+use telefork::vdso::{compare, ours, VdsoLayout};
+use telefork::VdsoCompat;
+
+use std::convert::TryInto;
+
+fn main() {
+    let image = ours().unwrap();
+    let layout = VdsoLayout::parse(&image).unwrap();
+    println!(
+        "our vDSO is {} bytes with {:?}",
+        layout.size, layout.symbols
+    );
+    assert_eq!(compare(&image, &image), VdsoCompat::Compatible);
+
+    // Another build of the same kernel, some code changed in place
+    let (_, &first) = layout.symbols.iter().next().expect("the vDSO has symbols");
+    let mut rebuilt = image.clone();
+    rebuilt[first] ^= 0xff;
+    assert_eq!(compare(&rebuilt, &image), VdsoCompat::RemappableSameSize);
+
+    // A kernel that put its first function a little further along
+    let mut moved = image.clone();
+    let st_value = dynsym_values(&moved).next().unwrap();
+    let value = u64::from_ne_bytes(moved[st_value..st_value + 8].try_into().unwrap());
+    moved[st_value..st_value + 8].copy_from_slice(&(value + 16).to_ne_bytes());
+    let compat = compare(&moved, &image);
+    println!("with a moved symbol: {:?}", compat);
+    assert!(matches!(compat, VdsoCompat::Incompatible(_)));
+
+    // And one with an extra page on the end
+    let mut bigger = image.clone();
+    bigger.extend(std::iter::repeat_n(0, 4096));
+    let compat = compare(&bigger, &image);
+    println!("with an extra page: {:?}", compat);
+    assert!(matches!(compat, VdsoCompat::Incompatible(_)));
+}
+
+/// Where the `st_value` of each defined dynamic symbol is in the image
+fn dynsym_values(image: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let header: libc::Elf64_Ehdr = read(image, 0);
+    let dynsym = (0..header.e_shnum as usize)
+        .map(|i| read::<libc::Elf64_Shdr>(image, header.e_shoff as usize + i * 64))
+        .find(|s| s.sh_type == 11)
+        .expect("the vDSO has a dynamic symbol table");
+    let size = std::mem::size_of::<libc::Elf64_Sym>();
+    (0..dynsym.sh_size as usize / size)
+        .map(move |i| dynsym.sh_offset as usize + i * size)
+        .filter(move |&offset| {
+            let sym: libc::Elf64_Sym = read(image, offset);
+            sym.st_shndx != 0 && sym.st_value != 0
+        })
+        .map(|offset| offset + 8)
+}
+
+fn read<T>(image: &[u8], offset: usize) -> T {
+    assert!(offset + std::mem::size_of::<T>() <= image.len());
+    unsafe { std::ptr::read_unaligned(image[offset..].as_ptr() as *const T) }
+}
@@ -0,0 +1,112 @@
+//! Restoring a dump made on a kernel whose vDSO is a different size from
+//! ours. There's only one kernel to try it on here, so the dump's record of
+//! where its vDSO was gets patched to say it was a page bigger, then a page
+//! smaller. Either way ours has to stay where it is with its `[vvar]` next
+//! to it, the restored auxv has to point at it, and nothing else can move.
+
+mod common;
+
+use telefork::{telepad_file_attached, Config, VdsoRestore};
+
+use std::convert::TryInto;
+use std::fs::File;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Where the size of the `[vdso]` `Remap` is, which comes after its name
+/// and address
+fn remap_size_offset(dump: &[u8]) -> usize {
+    let mut name = 6u64.to_le_bytes().to_vec();
+    name.extend_from_slice(b"[vdso]");
+    let found: Vec<usize> = dump
+        .windows(name.len())
+        .enumerate()
+        .filter(|(_, w)| *w == &name[..])
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(found.len(), 1, "expected exactly one [vdso] remap");
+    found[0] + name.len() + 8
+}
+
+/// Start and end of each of the mappings with special names
+fn special_maps(pid: i32) -> Vec<(String, usize, usize)> {
+    std::fs::read_to_string(format!("/proc/{}/maps", pid))
+        .unwrap()
+        .lines()
+        .filter_map(|line| {
+            let name = line.split_whitespace().nth(5)?;
+            if !name.starts_with('[') || name == "[heap]" || name == "[stack]" {
+                return None;
+            }
+            let (start, end) = line.split_whitespace().next()?.split_once('-')?;
+            Some((
+                name.to_string(),
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ))
+        })
+        .collect()
+}
+
+fn auxv_entry(pid: i32, key: u64) -> Option<u64> {
+    let auxv = std::fs::read(format!("/proc/{}/auxv", pid)).unwrap();
+    auxv.chunks_exact(16)
+        .map(|pair| {
+            (
+                u64::from_ne_bytes(pair[..8].try_into().unwrap()),
+                u64::from_ne_bytes(pair[8..].try_into().unwrap()),
+            )
+        })
+        .find(|&(k, _)| k == key)
+        .map(|(_, v)| v)
+}
+
+/// Relative to the vDSO, where each special mapping is
+fn relative_to_vdso(maps: &[(String, usize, usize)]) -> Vec<(String, isize, usize)> {
+    let vdso = maps.iter().find(|m| m.0 == "[vdso]").unwrap().1;
+    maps.iter()
+        .filter(|m| m.0 != "[vsyscall]")
+        .map(|(name, start, end)| (name.clone(), *start as isize - vdso as isize, end - start))
+        .collect()
+}
+
+fn restore_with_vdso_size(dump: &[u8], size: usize) {
+    let mut dump = dump.to_vec();
+    let at = remap_size_offset(&dump);
+    dump[at..at + 8].copy_from_slice(&(size as u64).to_le_bytes());
+    let path = std::env::temp_dir().join(format!("telefork-vdso-size-{}", size));
+    std::fs::write(&path, &dump).unwrap();
+    let restored = telepad_file_attached(&File::open(&path).unwrap(), 0, &Config::default());
+    std::fs::remove_file(&path).unwrap();
+    let restored = restored.unwrap();
+    let pid = restored.pid();
+
+    assert_eq!(restored.report().vdso, VdsoRestore::KeptInPlace);
+    let maps = special_maps(pid.as_raw());
+    let vdso = maps.iter().find(|m| m.0 == "[vdso]").unwrap().1;
+    assert_eq!(
+        auxv_entry(pid.as_raw(), libc::AT_SYSINFO_EHDR),
+        Some(vdso as u64),
+        "the auxv doesn't point at the vDSO"
+    );
+    // Any process on this kernel has them laid out the same around it
+    assert_eq!(
+        relative_to_vdso(&maps),
+        relative_to_vdso(&special_maps(std::process::id() as i32)),
+        "the vDSO and its data got split up"
+    );
+
+    drop(restored);
+    common::reap(pid);
+    println!("kept our vDSO in place for a {} byte one", size);
+}
+
+fn main() {
+    let child = common::spawn_ready(|| {});
+    let dump = common::dump(child, &Config::default());
+
+    let at = remap_size_offset(&dump);
+    let ours = u64::from_le_bytes(dump[at..at + 8].try_into().unwrap()) as usize;
+    restore_with_vdso_size(&dump, ours + PAGE_SIZE);
+    restore_with_vdso_size(&dump, ours - PAGE_SIZE);
+}
@@ -0,0 +1,53 @@
+//! What `telefork dump` logs at each verbosity. Memory is streamed in chunks
+//! with a trace event for each, which `-vvv` should show and `-vv` shouldn't,
+//! or debugging anything at `-vv` means wading through one line per page.
+
+mod common;
+
+/// The log lines from dumping `pid` with `verbose`, like `-vv`
+fn dump_logs(pid: &str, verbose: &str, path: &str) -> Vec<String> {
+    let out = common::cli(&[verbose, "dump", "--leave-running", pid, path]);
+    assert!(out.status.success(), "telefork dump failed: {:?}", out);
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// The per-chunk events from `write_regular_map`
+fn chunk_events(logs: &[String]) -> usize {
+    logs.iter()
+        .filter(|l| l.contains("TRACE") && l.contains("read ") && l.contains(" bytes at "))
+        .count()
+}
+
+fn main() {
+    // Whatever's in the environment would win over the flags
+    std::env::remove_var("RUST_LOG");
+    let child = common::spawn_ready(|| ());
+    let pid = child.to_string();
+    let path = std::env::temp_dir().join(format!("telefork-verbosity-{}.bin", child));
+    let path = path.to_str().unwrap();
+
+    let trace = dump_logs(&pid, "-vvv", path);
+    let debug = dump_logs(&pid, "-vv", path);
+    common::reap(child);
+    std::fs::remove_file(path).unwrap();
+
+    assert!(
+        chunk_events(&trace) > 0,
+        "no per-chunk events at -vvv:\n{}",
+        trace.join("\n")
+    );
+    assert!(
+        debug.iter().any(|l| l.contains("DEBUG")),
+        "nothing logged at -vv"
+    );
+    assert_eq!(chunk_events(&debug), 0, "per-chunk events at -vv");
+    println!(
+        "-vvv logged {} lines with {} per-chunk events, -vv logged {} lines with none",
+        trace.len(),
+        chunk_events(&trace),
+        debug.len()
+    );
+}
@@ -0,0 +1,65 @@
+//! A dump with one byte of a mapping's contents flipped, the kind of damage
+//! nothing else in the stream notices. Restoring it normally goes through
+//! with the wrong byte in it, with `Config::verify_restore` it should fail
+//! with a `BadStream` naming the mapping that came out different.
+
+mod common;
+
+use telefork::{telepad_with_config, BadStream, Config};
+
+use std::convert::TryInto;
+
+const PATTERN: &[u8] = b"telefork verify restore ";
+const LEN: usize = 16 * 4096;
+
+fn main() {
+    let (child, addr) = common::spawn_with(|ready| {
+        let bytes = unsafe {
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            std::slice::from_raw_parts_mut(addr as *mut u8, LEN)
+        };
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = PATTERN[i % PATTERN.len()];
+        }
+        ready.send(&(bytes.as_ptr() as usize).to_le_bytes());
+    });
+    let addr = usize::from_le_bytes(addr[..].try_into().unwrap());
+
+    let mut dump = common::dump(child, &Config::default());
+
+    // Somewhere in the middle of the mapping's contents
+    let at = dump
+        .windows(PATTERN.len() * 4)
+        .position(|w| w.chunks(PATTERN.len()).all(|c| c == PATTERN))
+        .expect("the mapping's contents aren't in the dump");
+    dump[at + LEN / 2] ^= 0xff;
+
+    let config = Config {
+        verify_restore: true,
+        ..Config::default()
+    };
+    let err = telepad_with_config(&mut &dump[..], 0, &config).unwrap_err();
+    let bad = match err.downcast_ref::<BadStream>() {
+        Some(bad) => bad,
+        None => panic!("failed some other way: {}", err),
+    };
+    assert!(
+        bad.0.contains(&format!("at {:x}", addr)),
+        "the error doesn't name the mapping at {:x}: {}",
+        addr,
+        bad
+    );
+    println!("verified restore failed: {}", bad);
+
+    // Without checking, the damage goes unnoticed
+    let pid = telepad_with_config(&mut &dump[..], 0, &Config::default()).unwrap();
+    common::reap(pid);
+    println!("unverified restore went through");
+}
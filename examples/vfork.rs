@@ -0,0 +1,123 @@
+//! Dumping either side of a `vfork` before the child execs. The child is
+//! running on its parent's memory and the parent is stuck until it's done,
+//! so `teledump` waits for that to be over or fails with `TransientState`,
+//! and never writes a dump of the half-way state. Then a process spawning
+//! `/bin/true` as fast as it can, which is mostly in a `vfork` from
+//! `posix_spawn`, gets dumped over and over to catch it at any point.
+
+mod common;
+
+use telefork::{teledump, teledump_with_config, Config, FrameReader, TransientState};
+
+use nix::sys::wait::waitpid;
+use nix::unistd::Pid;
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+/// How long the vforked child holds its parent up for
+const CHILD_SLEEP: Duration = Duration::from_millis(600);
+
+extern "C" fn vfork_child(_: *mut libc::c_void) -> i32 {
+    // On its own stack, but everything else is its parent's
+    unsafe {
+        let ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: CHILD_SLEEP.as_nanos() as i64,
+        };
+        libc::nanosleep(&ts, std::ptr::null_mut());
+    }
+    0
+}
+
+/// Start `body` without waiting for it to get anywhere, since what it's in
+/// the middle of is what gets dumped
+fn spawn(body: fn()) -> Pid {
+    let (child, _) = common::spawn_with(|ready| {
+        ready.send(&[]);
+        body();
+    });
+    child
+}
+
+fn vfork_once() {
+    let mut stack = vec![0u8; 64 * 1024];
+    let top = unsafe { stack.as_mut_ptr().add(stack.len()) } as *mut libc::c_void;
+    let flags = libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD;
+    let child = unsafe { libc::clone(vfork_child, top, flags, std::ptr::null_mut()) };
+    assert!(child > 0);
+    waitpid(Pid::from_raw(child), None).unwrap();
+}
+
+fn spawn_forever() {
+    loop {
+        std::process::Command::new("/bin/true").status().unwrap();
+    }
+}
+
+/// Whether a dump reads all the way through
+fn check_dump(dump: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut frames = FrameReader::new(dump)?;
+    while frames.next_frame()?.is_some() {}
+    Ok(())
+}
+
+fn expect_transient(pid: Pid, what: &str) {
+    let config = Config {
+        settle_timeout: Duration::from_millis(50),
+        ..Config::default()
+    };
+    let err = teledump_with_config(pid.as_raw(), &mut Vec::new(), true, &config).unwrap_err();
+    match err.downcast_ref::<TransientState>() {
+        Some(_) => println!("{}: {}", what, err),
+        None => panic!("dumping the {} failed some other way: {}", what, err),
+    }
+}
+
+fn main() {
+    let parent = spawn(vfork_once);
+    let children = format!("/proc/{}/task/{}/children", parent, parent);
+    let child = loop {
+        let listed = std::fs::read_to_string(&children).unwrap();
+        let syscall = std::fs::read_to_string(format!("/proc/{}/syscall", parent)).unwrap();
+        match listed.split_whitespace().next() {
+            Some(child) if syscall.starts_with("56 ") => {
+                break Pid::from_raw(child.parse().unwrap())
+            }
+            _ => std::thread::sleep(Duration::from_millis(5)),
+        }
+    };
+    let started = Instant::now();
+    expect_transient(child, "vfork child");
+    expect_transient(parent, "vfork parent");
+
+    // Long enough to wait it out
+    let mut dump = Vec::new();
+    teledump(parent.as_raw(), &mut dump, true).unwrap();
+    assert!(
+        started.elapsed() >= CHILD_SLEEP / 2,
+        "dumped the parent before its child was done with it"
+    );
+    check_dump(&dump).unwrap();
+    println!("dumped the parent once its child exited");
+    common::reap(parent);
+
+    let spawner = spawn(spawn_forever);
+    let (mut dumped, mut refused) = (0, 0);
+    for _ in 0..40 {
+        let mut dump = Vec::new();
+        match teledump(spawner.as_raw(), &mut dump, true) {
+            Ok(_) => {
+                check_dump(&dump).unwrap();
+                dumped += 1;
+            }
+            Err(e) if e.is::<TransientState>() => refused += 1,
+            Err(e) => panic!("dumping the spawner failed some other way: {}", e),
+        }
+    }
+    common::reap(spawner);
+    println!(
+        "dumped the spawner {} times and it was mid-vfork too long {} times",
+        dumped, refused
+    );
+}
@@ -1,3 +1,6 @@
+// `foo` is the demo variable every example uses
+#![allow(clippy::disallowed_names)]
+
 fn main() {
     let args: Vec<String> = std::env::args().collect();
     let destination = args.get(1).expect("expected arg: address of teleserver");
@@ -1,3 +1,6 @@
+// `foo` is the demo variable every example uses
+#![allow(clippy::disallowed_names)]
+
 use telefork::{telefork, telepad, wait_for_exit, TeleforkLocation};
 
 use std::net::TcpStream;
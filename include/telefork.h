@@ -0,0 +1,46 @@
+/*
+ * C interface to telefork, see src/ffi.rs. Link against the cdylib built by
+ * `cargo build` (libtelefork.so).
+ *
+ * All functions take file descriptors owned by the caller, they are never
+ * closed by telefork.
+ */
+#ifndef TELEFORK_H
+#define TELEFORK_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Returned by telefork_to_fd in the original process. */
+#define TELEFORK_PARENT 0
+/* Returned by every function on failure. */
+#define TELEFORK_ERROR (-1)
+
+/*
+ * Stream the current process out over fd.
+ *
+ * Returns TELEFORK_PARENT in the original process. In the process woken up
+ * on the other end it returns the `pass` value given to telepad_from_fd, so
+ * pass a positive value there to be able to tell the two apart. Returns
+ * TELEFORK_ERROR on failure.
+ */
+int telefork_to_fd(int fd);
+
+/*
+ * Receive a process from fd and wake it up with telefork_to_fd returning
+ * `pass`. Returns the pid of the restored process or TELEFORK_ERROR.
+ */
+int telepad_from_fd(int fd, int pass);
+
+/*
+ * Wait for a restored process to exit. Returns its exit status or
+ * TELEFORK_ERROR if it didn't exit normally.
+ */
+int telefork_wait_for_exit(int pid);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TELEFORK_H */
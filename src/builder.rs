@@ -0,0 +1,811 @@
+//! Builders for when the plain `telefork(out)` and `telepad(inp, pass)`
+//! aren't enough. They stack compression and encryption around the stream,
+//! report progress, and on the receiving side let you decide what happens to
+//! each file descriptor and mapping.
+//!
+//! ```no_run
+//! use telefork::{Compression, TeleforkBuilder, TeleforkLocation};
+//!
+//! let mut out = std::fs::File::create("dump.telefork.bin").unwrap();
+//! let loc = TeleforkBuilder::new()
+//!     .compression(Compression::Zstd(3))
+//!     .on_progress(|bytes| eprintln!("{} bytes so far", bytes))
+//!     .telefork(&mut out)
+//!     .unwrap();
+//! if let TeleforkLocation::Child(_) = loc {
+//!     println!("woke up!");
+//! }
+//! ```
+//!
+//! Both ends need to agree on the compression and key, nothing about them
+//! is recorded in the stream.
+//!
+//! For anything else there's `StreamMiddleware`, layers you stack on the
+//! stream yourself. Their names do get written at the start of the stream
+//! so the receiver can check it has the same ones and undo them in the
+//! right order.
+
+use crate::crypt::{DecryptReader, EncryptWriter};
+use crate::{
+    bad_stream, let_go, teledump_with_hooks, telefork_with_hooks, telepad_with_hooks, Config,
+    DumpHooks, FdAction, FdInfo, MapAction, MapInfo, RestoreHooks, RestoredProcess, Result,
+    ResumeBarrier, SamePid, Streamed, TeleforkLocation, TeleforkStats, UnsupportedFdPolicy,
+};
+
+use nix::unistd::Pid;
+
+use tracing::warn;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// How to compress the stream. Memory images are mostly zeroes and repeated
+/// code so even fast settings shrink them a lot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd at the given level, 1 to 22, where 3 is zstd's usual default
+    Zstd(i32),
+}
+
+/// Don't bother the progress callback more often than this
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+/// Output layers that need to be told when the stream is over, like the
+/// compressor flushing its last block or the encryption sealing its final
+/// chunk. Each finishes then finishes the layer under it, so if you write
+/// one for a `StreamMiddleware` make sure to pass it on.
+pub trait Sink: Write {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl Sink for &mut dyn Write {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl<'a> Sink for EncryptWriter<Box<dyn Sink + 'a>> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        EncryptWriter::finish(*self)?.finish()
+    }
+}
+
+impl<'a> Sink for zstd::stream::write::Encoder<'static, Box<dyn Sink + 'a>> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        zstd::stream::write::Encoder::finish(*self)?.finish()
+    }
+}
+
+/// A layer on the stream, like compression, encryption or just watching
+/// the bytes go by. `TeleforkBuilder::middleware` stacks them with the first
+/// one added seeing the process state first, and `TelepadBuilder::middleware`
+/// needs the same ones registered to read it back.
+///
+/// ```no_run
+/// use std::io::{self, Read, Write};
+/// use telefork::builder::{Sink, StreamMiddleware};
+///
+/// struct Xor<T>(T);
+///
+/// impl<T: Write> Write for Xor<T> {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+///         let flipped: Vec<u8> = buf.iter().map(|b| b ^ 0x55).collect();
+///         self.0.write(&flipped)
+///     }
+///     fn flush(&mut self) -> io::Result<()> {
+///         self.0.flush()
+///     }
+/// }
+///
+/// impl<'a> Sink for Xor<Box<dyn Sink + 'a>> {
+///     fn finish(self: Box<Self>) -> io::Result<()> {
+///         self.0.finish()
+///     }
+/// }
+///
+/// impl<T: Read> Read for Xor<T> {
+///     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+///         let len = self.0.read(buf)?;
+///         buf[..len].iter_mut().for_each(|b| *b ^= 0x55);
+///         Ok(len)
+///     }
+/// }
+///
+/// struct XorMiddleware;
+///
+/// impl StreamMiddleware for XorMiddleware {
+///     fn name(&self) -> &str {
+///         "xor"
+///     }
+///     fn wrap_write<'s>(&self, inner: Box<dyn Sink + 's>) -> io::Result<Box<dyn Sink + 's>> {
+///         Ok(Box::new(Xor(inner)))
+///     }
+///     fn wrap_read<'s>(&self, inner: Box<dyn Read + 's>) -> io::Result<Box<dyn Read + 's>> {
+///         Ok(Box::new(Xor(inner)))
+///     }
+/// }
+/// ```
+pub trait StreamMiddleware {
+    /// Written into the stream header to match it up on the other end
+    fn name(&self) -> &str;
+    fn wrap_write<'s>(&self, inner: Box<dyn Sink + 's>) -> io::Result<Box<dyn Sink + 's>>;
+    fn wrap_read<'s>(&self, inner: Box<dyn Read + 's>) -> io::Result<Box<dyn Read + 's>>;
+    /// Whether the receiver has to undo this, if not it isn't recorded in
+    /// the header and the receiver doesn't need to have it
+    fn needs_inverse(&self) -> bool {
+        true
+    }
+}
+
+/// Starts the middleware header, so a receiver expecting middleware can
+/// tell it's getting a plain stream instead
+pub const MIDDLEWARE_MAGIC: [u8; 4] = *b"TFMW";
+
+/// The stream was sent with a middleware the `TelepadBuilder` doesn't have
+#[derive(Debug)]
+pub struct MissingMiddleware(pub String);
+
+impl std::fmt::Display for MissingMiddleware {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "stream was sent with middleware {} which we don't have",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for MissingMiddleware {}
+
+/// The names of the middleware a stream was sent with, in the order they
+/// were added, or `None` if it doesn't start with a middleware header. Reads
+/// just the header, or the first four bytes if there isn't one.
+pub fn read_middleware_names(inp: &mut dyn Read) -> Result<Option<Vec<String>>> {
+    let mut magic = [0u8; 4];
+    inp.read_exact(&mut magic)?;
+    if magic != MIDDLEWARE_MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(bincode::deserialize_from(inp)?))
+}
+
+/// zstd compression at the given level as a `StreamMiddleware`
+pub struct ZstdMiddleware(pub i32);
+
+impl StreamMiddleware for ZstdMiddleware {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn wrap_write<'s>(&self, inner: Box<dyn Sink + 's>) -> io::Result<Box<dyn Sink + 's>> {
+        Ok(Box::new(zstd::stream::write::Encoder::new(inner, self.0)?))
+    }
+
+    fn wrap_read<'s>(&self, inner: Box<dyn Read + 's>) -> io::Result<Box<dyn Read + 's>> {
+        Ok(Box::new(zstd::stream::read::Decoder::new(inner)?))
+    }
+}
+
+/// XChaCha20-Poly1305 encryption with a 32 byte key as a `StreamMiddleware`,
+/// the same as `TeleforkBuilder::encrypt`
+pub struct ChaChaMiddleware(pub [u8; 32]);
+
+impl StreamMiddleware for ChaChaMiddleware {
+    fn name(&self) -> &str {
+        "chacha20poly1305"
+    }
+
+    fn wrap_write<'s>(&self, inner: Box<dyn Sink + 's>) -> io::Result<Box<dyn Sink + 's>> {
+        Ok(Box::new(EncryptWriter::new(inner, &self.0)?))
+    }
+
+    fn wrap_read<'s>(&self, inner: Box<dyn Read + 's>) -> io::Result<Box<dyn Read + 's>> {
+        Ok(Box::new(DecryptReader::new(inner, &self.0)?))
+    }
+}
+
+/// Copies the stream as it goes by at its place in the stack into a file,
+/// for looking at what actually got sent. It doesn't change the stream so
+/// the receiver doesn't need one.
+pub struct TeeMiddleware(pub std::path::PathBuf);
+
+struct Tee<T> {
+    inner: T,
+    copy: File,
+}
+
+impl<T: Write> Write for Tee<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.copy.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.copy.flush()?;
+        self.inner.flush()
+    }
+}
+
+impl<'a> Sink for Tee<Box<dyn Sink + 'a>> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.copy.flush()?;
+        self.inner.finish()
+    }
+}
+
+impl<T: Read> Read for Tee<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
+
+impl StreamMiddleware for TeeMiddleware {
+    fn name(&self) -> &str {
+        "tee"
+    }
+
+    fn wrap_write<'s>(&self, inner: Box<dyn Sink + 's>) -> io::Result<Box<dyn Sink + 's>> {
+        let copy = File::create(&self.0)?;
+        Ok(Box::new(Tee { inner, copy }))
+    }
+
+    fn wrap_read<'s>(&self, inner: Box<dyn Read + 's>) -> io::Result<Box<dyn Read + 's>> {
+        let copy = File::create(&self.0)?;
+        Ok(Box::new(Tee { inner, copy }))
+    }
+
+    fn needs_inverse(&self) -> bool {
+        false
+    }
+}
+
+/// Under everything else, copies exactly what goes on the wire into a file,
+/// see `TeleforkBuilder::also_write_to`
+struct Archive<'a> {
+    inner: Box<dyn Sink + 'a>,
+    /// `None` once writing to it failed and we carried on without it
+    copy: Option<File>,
+    path: PathBuf,
+    required: bool,
+}
+
+impl Archive<'_> {
+    fn copy(&mut self, buf: &[u8]) -> io::Result<()> {
+        let copy = match &mut self.copy {
+            Some(copy) => copy,
+            None => return Ok(()),
+        };
+        if let Err(e) = copy.write_all(buf) {
+            if self.required {
+                return Err(e);
+            }
+            // Half an archive would look like a dump that's just corrupt
+            warn!(
+                "couldn't write to archive {:?}, carrying on without it: {}",
+                self.path, e
+            );
+            self.copy = None;
+            let _ = std::fs::remove_file(&self.path);
+        }
+        Ok(())
+    }
+}
+
+impl Write for Archive<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.copy(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for Archive<'_> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let Archive {
+            inner,
+            copy,
+            path,
+            required,
+        } = *self;
+        inner.finish()?;
+        if let Some(Err(e)) = copy.map(|copy| copy.sync_all()) {
+            if required {
+                return Err(e);
+            }
+            warn!("couldn't write out archive {:?}: {}", path, e);
+            let _ = std::fs::remove_file(&path);
+        }
+        Ok(())
+    }
+}
+
+/// Counts the bytes of process state going through it
+struct Progress<'a, 'b, T> {
+    inner: T,
+    total: u64,
+    callback: Option<&'b mut (dyn FnMut(u64) + 'a)>,
+}
+
+impl<T> Progress<'_, '_, T> {
+    fn advance(&mut self, len: usize) {
+        let before = self.total / PROGRESS_STEP;
+        self.total += len as u64;
+        if self.total / PROGRESS_STEP != before {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        if let Some(callback) = &mut self.callback {
+            callback(self.total);
+        }
+    }
+}
+
+impl<T: Write> Write for Progress<'_, '_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.advance(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Read> Read for Progress<'_, '_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.advance(len);
+        Ok(len)
+    }
+}
+
+/// The sending side, a configurable `telefork`
+#[derive(Default)]
+pub struct TeleforkBuilder<'a> {
+    config: Config,
+    compression: Compression,
+    key: Option<[u8; 32]>,
+    middleware: Vec<Box<dyn StreamMiddleware + 'a>>,
+    on_progress: Option<Box<dyn FnMut(u64) + 'a>>,
+    archive: Option<PathBuf>,
+    archive_required: bool,
+    hooks: DumpHooks<'a>,
+}
+
+impl<'a> TeleforkBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypt and authenticate the stream with a 32 byte key, the receiver
+    /// needs `TelepadBuilder::decrypt` with the same key.
+    pub fn encrypt(mut self, key: [u8; 32]) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Add a layer to the stream, see `StreamMiddleware`. They go on top of
+    /// `compression` and `encrypt` if you use those too.
+    pub fn middleware(mut self, middleware: impl StreamMiddleware + 'a) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Also write the stream to a file as it goes out, exactly as sent, so
+    /// migrating a process leaves a backup that restores with the same
+    /// `TelepadBuilder` settings without dumping it twice. If writing the
+    /// file fails it's deleted and the live stream carries on, unless
+    /// `archive_required` says otherwise.
+    pub fn also_write_to(mut self, path: impl AsRef<Path>) -> Self {
+        self.archive = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Fail the whole telefork if the `also_write_to` file can't be written
+    pub fn archive_required(mut self, required: bool) -> Self {
+        self.archive_required = required;
+        self
+    }
+
+    /// See `Config::skip_device_maps`
+    pub fn skip_device_maps(mut self, skip: bool) -> Self {
+        self.config.skip_device_maps = skip;
+        self
+    }
+
+    /// See `Config::index`
+    pub fn index(mut self, index: bool) -> Self {
+        self.config.index = index;
+        self
+    }
+
+    /// See `Config::bundle_files`
+    pub fn bundle_files(mut self, bundle: bool) -> Self {
+        self.config.bundle_files = bundle;
+        self
+    }
+
+    /// See `Config::small_file_limit`
+    pub fn small_file_limit(mut self, limit: usize) -> Self {
+        self.config.small_file_limit = limit;
+        self
+    }
+
+    /// See `Config::precopy_passes`, only `teledump` uses it
+    pub fn precopy_passes(mut self, passes: usize) -> Self {
+        self.config.precopy_passes = passes;
+        self
+    }
+
+    /// See `Config::janky_vdso`
+    pub fn janky_vdso(mut self, janky: bool) -> Self {
+        self.config.janky_vdso = janky;
+        self
+    }
+
+    /// See `Config::frame_checksums`
+    pub fn frame_checksums(mut self, checksums: bool) -> Self {
+        self.config.frame_checksums = checksums;
+        self
+    }
+
+    /// See `Config::contents_lengths`
+    pub fn contents_lengths(mut self, lengths: bool) -> Self {
+        self.config.contents_lengths = lengths;
+        self
+    }
+
+    /// See `Config::settle_timeout`
+    pub fn settle_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.config.settle_timeout = timeout;
+        self
+    }
+
+    /// Called with the total bytes of process state sent so far, every MB or
+    /// so and once at the end. Those are bytes before compression, so it's a
+    /// measure of how far through the process we are rather than of traffic.
+    pub fn on_progress(mut self, callback: impl FnMut(u64) + 'a) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Called with the address and contents of each page of memory before
+    /// it's written, to change what goes in the dump: zero out secrets, or
+    /// pin down timestamps and pointers that differ from run to run so
+    /// `diff_dumps` only shows what matters. Huge pages come a chunk at a
+    /// time rather than a page. Nothing undoes it, the restored process gets
+    /// the transformed pages as its memory.
+    pub fn transform_pages(mut self, transform: impl FnMut(usize, &mut [u8]) + 'a) -> Self {
+        self.hooks.page_transform = Some(Box::new(transform));
+        self
+    }
+
+    /// Stack up the compression, encryption and middleware around `out`
+    fn sink<'o>(&self, out: &'o mut dyn Write) -> Result<Box<dyn Sink + 'o>> {
+        let mut sink: Box<dyn Sink + 'o> = Box::new(out);
+        if let Some(path) = &self.archive {
+            let copy = match File::create(path) {
+                Ok(copy) => Some(copy),
+                Err(e) if !self.archive_required => {
+                    warn!("couldn't create archive {:?}, not writing one: {}", path, e);
+                    None
+                }
+                Err(e) => return Err(e.into()),
+            };
+            sink = Box::new(Archive {
+                inner: sink,
+                copy,
+                path: path.clone(),
+                required: self.archive_required,
+            });
+        }
+        if let Some(key) = &self.key {
+            sink = Box::new(EncryptWriter::new(sink, key)?);
+        }
+        if let Compression::Zstd(level) = self.compression {
+            sink = Box::new(zstd::stream::write::Encoder::new(sink, level)?);
+        }
+        if !self.middleware.is_empty() {
+            let names: Vec<&str> = self
+                .middleware
+                .iter()
+                .filter(|m| m.needs_inverse())
+                .map(|m| m.name())
+                .collect();
+            sink.write_all(&MIDDLEWARE_MAGIC)?;
+            bincode::serialize_into(&mut sink, &names)?;
+            // The last one added is closest to the wire
+            for middleware in self.middleware.iter().rev() {
+                sink = middleware.wrap_write(sink)?;
+            }
+        }
+        Ok(sink)
+    }
+
+    /// Like `teledump` but through the same layers as `telefork`
+    pub fn teledump(
+        &mut self,
+        pid: i32,
+        out: &mut dyn Write,
+        leave_running: bool,
+    ) -> Result<TeleforkStats> {
+        let sink = self.sink(out)?;
+        let mut progress = Progress {
+            inner: sink,
+            total: 0,
+            callback: self.on_progress.as_deref_mut(),
+        };
+        let stats = teledump_with_hooks(
+            pid,
+            &mut progress,
+            leave_running,
+            &self.config,
+            &mut self.hooks,
+        )?;
+        let Progress {
+            inner,
+            total,
+            callback,
+        } = progress;
+        inner.finish()?;
+        if let Some(callback) = callback {
+            callback(total);
+        }
+        Ok(stats)
+    }
+
+    /// Like `telefork`, returns twice: once here as the `Parent` and once in
+    /// the restored process as the `Child`.
+    pub fn telefork(&mut self, out: &mut dyn Write) -> Result<TeleforkLocation> {
+        let sink = self.sink(out)?;
+        let mut progress = Progress {
+            inner: sink,
+            total: 0,
+            callback: self.on_progress.as_deref_mut(),
+        };
+        match telefork_with_hooks(&mut progress, &self.config, &mut self.hooks)? {
+            TeleforkLocation::Parent => {
+                let Progress {
+                    inner,
+                    total,
+                    callback,
+                } = progress;
+                inner.finish()?;
+                if let Some(callback) = callback {
+                    callback(total);
+                }
+                Ok(TeleforkLocation::Parent)
+            }
+            child => {
+                // The layers in here are copies from before anything was
+                // written, make sure they never write anything on drop
+                std::mem::forget(progress);
+                Ok(child)
+            }
+        }
+    }
+}
+
+/// The receiving side, a configurable `telepad`
+#[derive(Default)]
+pub struct TelepadBuilder<'a> {
+    config: Config,
+    compression: Compression,
+    key: Option<[u8; 32]>,
+    middleware: Vec<Box<dyn StreamMiddleware + 'a>>,
+    on_progress: Option<Box<dyn FnMut(u64) + 'a>>,
+    hooks: RestoreHooks<'a>,
+}
+
+impl<'a> TelepadBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Has to match what the sender used, only whether it's zstd matters,
+    /// not the level.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn decrypt(mut self, key: [u8; 32]) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Undo a `StreamMiddleware` the sender used. Add the same ones in the
+    /// same order as the sender did, they're checked against the names
+    /// recorded in the stream. Ones that don't need undoing, like
+    /// `TeeMiddleware`, don't have to match up and just go where they're
+    /// added.
+    pub fn middleware(mut self, middleware: impl StreamMiddleware + 'a) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// See `Config::scratch_addr`
+    pub fn scratch_addr(mut self, addr: usize) -> Self {
+        self.config.scratch_addr = Some(addr);
+        self
+    }
+
+    /// See `Config::verify_restore`
+    pub fn verify_restore(mut self, verify: bool) -> Self {
+        self.config.verify_restore = verify;
+        self
+    }
+
+    /// See `Config::reattach_shm`
+    pub fn reattach_shm(mut self, reattach: bool) -> Self {
+        self.config.reattach_shm = reattach;
+        self
+    }
+
+    /// See `Config::debug_first_fault`
+    pub fn debug_first_fault(mut self, debug: bool) -> Self {
+        self.config.debug_first_fault = debug;
+        self
+    }
+
+    /// See `Config::mount_namespace`
+    pub fn mount_namespace(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.mount_namespace = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// See `Config::keep_going`, what was skipped is in
+    /// `RestoredProcess::skipped` from `telepad_attached`
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.config.keep_going = keep_going;
+        self
+    }
+
+    /// See `Config::unsupported_fds`
+    pub fn unsupported_fds(mut self, policy: UnsupportedFdPolicy) -> Self {
+        self.config.unsupported_fds = policy;
+        self
+    }
+
+    /// See `Config::same_pid`
+    pub fn same_pid(mut self, policy: SamePid) -> Self {
+        self.config.same_pid = policy;
+        self
+    }
+
+    /// See `Config::create_cgroups`
+    pub fn create_cgroups(mut self, create: bool) -> Self {
+        self.config.create_cgroups = create;
+        self
+    }
+
+    /// Point the restored process's fd 0, 1 or 2 at `ours`, one of our own
+    /// fds, like a file to capture its output in. See `Config::stdio`, you
+    /// need to keep `ours` open until the restore is done.
+    pub fn redirect_stdio(mut self, fd: usize, ours: RawFd) -> Self {
+        self.config.stdio[fd] = Some(ours);
+        self
+    }
+
+    /// Called with the total bytes of process state received so far
+    pub fn on_progress(mut self, callback: impl FnMut(u64) + 'a) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// See `Config::resume_barrier`
+    pub fn resume_barrier(mut self, barrier: Arc<ResumeBarrier>) -> Self {
+        self.config.resume_barrier = Some(barrier);
+        self
+    }
+
+    /// Decide what to do with each file descriptor, for example swapping
+    /// sockets that can't be restored for `/dev/null`.
+    pub fn fd_policy(mut self, policy: impl FnMut(&FdInfo) -> FdAction + 'a) -> Self {
+        self.hooks.fd_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Called with the original process's environment variables. Restoring
+    /// what `/proc/pid/environ` shows needs `CAP_SYS_RESOURCE`, so without it
+    /// this is the way to find out what the environment was.
+    pub fn on_environment(mut self, callback: impl FnMut(&[String]) + 'a) -> Self {
+        self.hooks.environment = Some(Box::new(callback));
+        self
+    }
+
+    /// Decide which memory mappings to restore
+    pub fn map_policy(mut self, policy: impl FnMut(&MapInfo) -> MapAction + 'a) -> Self {
+        self.hooks.map_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Called once everything from the stream is restored, just before it's
+    /// let go, with a handle that can run syscalls inside the process to do
+    /// extra setup like opening another fd. An error fails the restore.
+    pub fn pre_resume(mut self, hook: impl FnMut(&RestoredProcess) -> Result<()> + 'a) -> Self {
+        self.hooks.pre_resume = Some(Box::new(hook));
+        self
+    }
+
+    /// Like `telepad`, returns the pid of the restored process
+    pub fn telepad(&mut self, inp: &mut dyn Read, pass_to_child: i32) -> Result<Pid> {
+        let restored = self.telepad_attached(inp, pass_to_child);
+        let_go(restored, &self.config)
+    }
+
+    /// Like `telepad_attached`, the restored process is left stopped for you
+    /// to resume
+    pub fn telepad_attached(
+        &mut self,
+        inp: &mut dyn Read,
+        pass_to_child: i32,
+    ) -> Result<RestoredProcess> {
+        let mut source: Box<dyn Read + '_> = Box::new(inp);
+        if let Some(key) = &self.key {
+            source = Box::new(DecryptReader::new(source, key)?);
+        }
+        if let Compression::Zstd(_) = self.compression {
+            source = Box::new(zstd::stream::read::Decoder::new(source)?);
+        }
+        if !self.middleware.is_empty() {
+            let names = match read_middleware_names(&mut source)? {
+                Some(names) => names,
+                None => return bad_stream("it wasn't sent with any middleware".to_string()),
+            };
+            let mut names = names.iter();
+            // Wire side first, the reverse of the order they were added
+            for middleware in self.middleware.iter().rev() {
+                if middleware.needs_inverse() {
+                    match names.next_back() {
+                        Some(name) if name == middleware.name() => {}
+                        name => {
+                            return bad_stream(format!(
+                                "expected it to use middleware {} but it says {:?}",
+                                middleware.name(),
+                                name
+                            ))
+                        }
+                    }
+                }
+                source = middleware.wrap_read(source)?;
+            }
+            if let Some(name) = names.next_back() {
+                return Err(Box::new(MissingMiddleware(name.clone())));
+            }
+        }
+        let mut progress = Progress {
+            inner: source,
+            total: 0,
+            callback: self.on_progress.as_deref_mut(),
+        };
+        let child = telepad_with_hooks(
+            &mut Streamed(&mut progress),
+            pass_to_child.into(),
+            &self.config,
+            &mut self.hooks,
+        )?;
+        progress.report();
+        Ok(child)
+    }
+}
@@ -1,36 +1,792 @@
-use crate::{teledump, telepad, wait_for_exit};
-use std::fs::File;
-use std::io::ErrorKind;
-use std::path::Path;
+use crate::builder::{read_middleware_names, ChaChaMiddleware, MissingMiddleware, ZstdMiddleware};
+use crate::dumpdir::{DumpDir, DumpDirReader};
+use crate::harness::round_trip;
+use crate::{
+    cuda, diff_dumps, estimate_dump_size, scan_file_descriptors, teledump_with_config,
+    telepad_dir_attached, telepad_many, wait_for_exit, BrkRestore, Config, RestoredProcess,
+    TeleforkBuilder, TeleforkStats, TelepadBuilder, FIRST_FAULT_WINDOW, PAGE_SIZE,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+
+use serde::Serialize;
 
 use tracing::info;
 
+/// Where to write the JSON summary of a dump, if anywhere
+pub enum Meta {
+    None,
+    /// Next to the dump as `<path>.meta.json`
+    Sibling,
+    Path(PathBuf),
+}
+
+/// How to lay out, compress and encrypt a dump
+#[derive(Default)]
+pub struct Transport {
+    /// zstd level
+    pub compress: Option<i32>,
+    pub key: Option<[u8; 32]>,
+    pub format: Format,
+}
+
+/// How a dump is laid out on disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Everything in one file
+    #[default]
+    File,
+    /// A directory with a file per mapping, see `dumpdir`
+    Dir,
+}
+
+/// Parse `--format`, `file` or `dir`
+pub fn parse_format(s: &str) -> Result<Format, String> {
+    match s {
+        "file" => Ok(Format::File),
+        "dir" => Ok(Format::Dir),
+        _ => Err(format!("unknown format {}, should be file or dir", s)),
+    }
+}
+
+/// Parse `--compress`, `zstd` or `zstd:LEVEL`
+pub fn parse_compression(s: &str) -> Result<i32, String> {
+    match s.split_once(':') {
+        None if s == "zstd" => Ok(3),
+        Some(("zstd", level)) => match level.parse() {
+            Ok(level) if (1..=22).contains(&level) => Ok(level),
+            _ => Err(format!("zstd level should be 1 to 22, not {}", level)),
+        },
+        _ => Err(format!("unknown compression {}, only zstd is supported", s)),
+    }
+}
+
+/// Read a key for `--key-file`, either 32 raw bytes or 64 hex digits
+pub fn read_key(path: impl AsRef<Path>) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(path)?;
+    let mut key = [0u8; 32];
+    if bytes.len() == 32 {
+        key.copy_from_slice(&bytes);
+        return Ok(key);
+    }
+    let hex = String::from_utf8_lossy(&bytes);
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("key file should have 32 raw bytes or 64 hex digits".into());
+    }
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(key)
+}
+
 pub fn dump(
     pid: i32,
     path: impl AsRef<Path>,
     leave_running: bool,
+    cuda: bool,
+    meta: Meta,
+    transport: Transport,
+    precopy_passes: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut output = File::create(&path).map_err(|e| {
-        Box::new(std::io::Error::new(
-            ErrorKind::Other,
-            format!("Failed to create file: {}", e),
-        ))
-    })?;
+    let mut dir = None;
+    let mut file = None;
+    let output: &mut dyn Write = match transport.format {
+        Format::File => file.insert(File::create(&path).map_err(|e| {
+            Box::new(std::io::Error::other(format!(
+                "Failed to create file: {}",
+                e
+            )))
+        })?),
+        // It has to be able to pick the commands out of the stream
+        Format::Dir if transport.compress.is_some() || transport.key.is_some() => {
+            return Err("directory dumps can't be compressed or encrypted".into());
+        }
+        Format::Dir => dir.insert(DumpDir::create(&path)?),
+    };
+    if cuda {
+        // Has to be all the way done before we start reading memory
+        cuda::checkpoint(pid)?;
+    }
+    let dumped = dump_checkpointed(pid, output, leave_running, transport, precopy_passes).and_then(
+        |stats| {
+            if let Some(dir) = &mut dir {
+                dir.finish()?;
+            }
+            Ok(stats)
+        },
+    );
+    let stats = match dumped {
+        Ok(stats) => stats,
+        Err(e) => {
+            // Otherwise a failed dump leaves it stuck with its GPU state
+            // copied out
+            if cuda {
+                if let Err(e) = cuda::restore(pid) {
+                    tracing::warn!(
+                        "couldn't put back the GPU state after the dump failed: {}",
+                        e
+                    );
+                }
+            }
+            return Err(e);
+        }
+    };
+    if cuda && leave_running {
+        cuda::restore(pid)?;
+    }
+    let meta_path = match meta {
+        Meta::None => return Ok(()),
+        Meta::Sibling => {
+            let mut p = path.as_ref().as_os_str().to_owned();
+            p.push(".meta.json");
+            PathBuf::from(p)
+        }
+        Meta::Path(p) => p,
+    };
+    drop(file);
+    write_meta(&meta_path, pid, path.as_ref(), &stats)?;
+    Ok(())
+}
+
+/// The part of `dump` between checkpointing the GPU state and putting it
+/// back, which mustn't return early without that happening
+fn dump_checkpointed(
+    pid: i32,
+    output: &mut dyn Write,
+    leave_running: bool,
+    transport: Transport,
+    precopy_passes: usize,
+) -> Result<TeleforkStats, Box<dyn std::error::Error>> {
     info!("dumping pid {:?}", pid);
-    teledump(pid, &mut output, leave_running)?;
+    match estimate_dump_size(pid) {
+        Ok(estimate) => info!(
+            "expecting {} bytes of memory in {} mappings, {} of them resident",
+            estimate.total_bytes, estimate.mappings, estimate.resident_bytes
+        ),
+        Err(e) => tracing::warn!("couldn't estimate the size of the dump: {}", e),
+    }
+    let stats = match transport {
+        // Plain dumps are left without a middleware header so older
+        // versions can still read them
+        Transport {
+            compress: None,
+            key: None,
+            ..
+        } => {
+            let config = Config {
+                precopy_passes,
+                ..Config::default()
+            };
+            teledump_with_config(pid, output, leave_running, &config)?
+        }
+        Transport { compress, key, .. } => {
+            let mut builder = TeleforkBuilder::new().precopy_passes(precopy_passes);
+            if let Some(level) = compress {
+                builder = builder.middleware(ZstdMiddleware(level));
+            }
+            if let Some(key) = key {
+                builder = builder.middleware(ChaChaMiddleware(key));
+            }
+            builder.teledump(pid, output, leave_running)?
+        }
+    };
+    Ok(stats)
+}
+
+/// What `write_meta` puts in the JSON summary of a dump
+#[derive(Serialize)]
+struct DumpMeta {
+    pid: i32,
+    /// Of the whole dump, compressed and encrypted if it was
+    bytes: u64,
+    memory_bytes: usize,
+    mappings: usize,
+    fds: usize,
+    frozen_us: u64,
+    cpu_user_us: u64,
+    cpu_system_us: u64,
+    kernel: String,
+    /// Of the whole dump, in hex
+    crc32: String,
+}
+
+/// Write a little JSON file about a dump for scripts to pick up
+fn write_meta(
+    meta_path: &Path,
+    pid: i32,
+    dump_path: &Path,
+    stats: &TeleforkStats,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Of the stream, which for a directory is what it puts back together
+    let mut dump: Box<dyn Read> = if dump_path.is_dir() {
+        Box::new(DumpDirReader::open(dump_path)?)
+    } else {
+        Box::new(File::open(dump_path)?)
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    let mut bytes = 0u64;
+    loop {
+        let len = dump.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        bytes += len as u64;
+    }
+    // Zeroes if we couldn't read them, which only monitoring looks at anyway
+    let cpu = stats.cpu.unwrap_or_default();
+    let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease")?;
+    let meta = DumpMeta {
+        pid,
+        bytes,
+        memory_bytes: stats.memory_bytes,
+        mappings: stats.mappings,
+        fds: stats.fds,
+        frozen_us: stats.frozen.as_micros() as u64,
+        cpu_user_us: cpu.user.as_micros() as u64,
+        cpu_system_us: cpu.system.as_micros() as u64,
+        kernel: kernel.trim().to_string(),
+        crc32: format!("{:08x}", hasher.finalize()),
+    };
+    let mut json = serde_json::to_vec_pretty(&meta)?;
+    json.push(b'\n');
+    std::fs::write(meta_path, json)?;
+    info!("wrote dump summary to {:?}", meta_path);
+    Ok(())
+}
+
+/// Files to give the restored process as its stdin, stdout and stderr
+/// instead of ours
+#[derive(Default)]
+pub struct Stdio {
+    pub stdin: Option<PathBuf>,
+    pub stdout: Option<PathBuf>,
+    pub stderr: Option<PathBuf>,
+}
+
+pub fn restore(
+    path: impl AsRef<Path>,
+    cuda: bool,
+    stdio: Stdio,
+    key: Option<[u8; 32]>,
+    mut config: Config,
+    count: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // These only need to stay open until the child has its own copies
+    let mut redirects = Vec::new();
+    if let Some(p) = &stdio.stdin {
+        redirects.push((0, File::open(p)?));
+    }
+    if let Some(p) = &stdio.stdout {
+        redirects.push((1, File::create(p)?));
+    }
+    if let Some(p) = &stdio.stderr {
+        redirects.push((2, File::create(p)?));
+    }
+    for (fd, file) in &redirects {
+        config.stdio[*fd] = Some(file.as_raw_fd());
+    }
+    info!("restoring {} from {:?}", count, path.as_ref());
+    // Copy `i` is passed `i + 1`, so a single restore is passed 1
+    let restored = if path.as_ref().is_dir() {
+        if key.is_some() {
+            tracing::warn!("directory dumps aren't encrypted, ignoring the key");
+        }
+        (1..=count as i32)
+            .map(|pass| telepad_dir_attached(&path, pass, &config))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        let mut input = File::open(&path)
+            .map_err(|e| Box::new(std::io::Error::other(format!("Failed to open file: {}", e))))?;
+        let names = match read_middleware_names(&mut input) {
+            Ok(names) => names,
+            // Too short to have a header, the restore will say what's wrong
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof) =>
+            {
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(names) = names {
+            let mut builder = TelepadBuilder::new()
+                .reattach_shm(config.reattach_shm)
+                .keep_going(config.keep_going);
+            // The same ones in the same order as it was dumped with
+            for name in &names {
+                builder = match (name.as_str(), key) {
+                    ("zstd", _) => builder.middleware(ZstdMiddleware(0)),
+                    ("chacha20poly1305", Some(key)) => builder.middleware(ChaChaMiddleware(key)),
+                    ("chacha20poly1305", None) => {
+                        return Err("the dump is encrypted, restore it with --key-file".into())
+                    }
+                    (name, _) => return Err(Box::new(MissingMiddleware(name.to_string()))),
+                };
+            }
+            if key.is_some() && !names.iter().any(|n| n == "chacha20poly1305") {
+                tracing::warn!("the dump isn't encrypted, ignoring the key");
+            }
+            if let Some(ns) = &config.mount_namespace {
+                builder = builder.mount_namespace(ns);
+            }
+            for (fd, file) in &redirects {
+                builder = builder.redirect_stdio(*fd, file.as_raw_fd());
+            }
+            let mut restored = Vec::with_capacity(count);
+            for pass in 1..=count as i32 {
+                input.rewind()?;
+                restored.push(builder.telepad_attached(&mut input, pass)?);
+            }
+            restored
+        } else {
+            if key.is_some() {
+                tracing::warn!("the dump isn't encrypted, ignoring the key");
+            }
+            // Safety: the same as `telepad_file`, a dump changing under us
+            // is just a corrupt one
+            let map = unsafe { memmap2::Mmap::map(&input)? };
+            telepad_many(&map, count, 1, &config)?
+        }
+    };
+    drop(redirects);
+    finish_restore(restored, cuda, config.debug_first_fault)
+}
+
+/// Log what each restore did and say what `--keep-going` left out, then let
+/// the restored processes go and wait them out, once they have their GPU
+/// state back if they need it. With `--debug-faults` one that faults straight
+/// away gets reported and killed.
+fn finish_restore(
+    restored: Vec<RestoredProcess>,
+    cuda: bool,
+    debug_faults: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut children = Vec::with_capacity(restored.len());
+    for mut restored in restored {
+        info!("restored {}:\n{}", restored.pid(), restored.report());
+        for skipped in restored.skipped() {
+            println!(
+                "{}: skipped {}: {}",
+                restored.pid(),
+                skipped.what,
+                skipped.error
+            );
+        }
+        if debug_faults {
+            if let Err(e) = restored.watch_first_fault(FIRST_FAULT_WINDOW) {
+                println!("{}", e);
+                let pid = restored.pid();
+                drop(restored.detach_stopped());
+                kill(pid, Signal::SIGKILL)?;
+                waitpid(pid, None)?;
+                return Err(e);
+            }
+        } else {
+            restored.resume()?;
+        }
+        let child = restored.detach()?;
+        if cuda {
+            // Its memory is all back by now, and any CUDA calls it makes in
+            // the meantime just wait for this
+            cuda::restore(child.as_raw())?;
+        }
+        children.push(child);
+    }
+    for child in children {
+        let status = wait_for_exit(child).unwrap();
+        info!("child {} exited with status = {}", child, status);
+    }
     Ok(())
 }
 
-pub fn restore(path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
-    let mut input = File::open(&path).map_err(|e| {
-        Box::new(std::io::Error::new(
-            ErrorKind::Other,
-            format!("Failed to open file: {}", e),
-        ))
-    })?;
-    info!("restoring from {:?}", path.as_ref());
-    let child = telepad(&mut input, 1)?;
-    let status = wait_for_exit(child).unwrap();
-    info!("child exited with status = {}", status);
+/// Print each of a process's file descriptors and whether a telefork of it
+/// would bring that fd along or drop it.
+pub fn fds(pid: i32) -> Result<(), Box<dyn std::error::Error>> {
+    let cm = scan_file_descriptors(pid)?;
+    let mut fds: Vec<_> = cm.into_iter().collect();
+    fds.sort_by_key(|(fd, _)| *fd);
+    for (fd, conn) in fds {
+        let status = if conn.restorable() { "restore" } else { "drop" };
+        match conn.path() {
+            Some(_) => println!("{:>4}  {:<7}  {}", fd, status, conn),
+            // The link target says what unsupported things actually are, like `pipe:[1234]`
+            None => {
+                let target = std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd))
+                    .map(|t| t.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                println!("{:>4}  {:<7}  {}  ({})", fd, status, conn, target);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print what changed between the dumps at `a` and `b`, see `diff_dumps`
+pub fn diff(a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let diff = diff_dumps(a.as_ref(), b.as_ref())?;
+    if diff.is_empty() {
+        println!("no differences");
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
+}
+
+/// What `selftest` checks, each with what it probably means if it fails. The
+/// canary sets bit `i` of the failures it writes out if check `i` failed,
+/// there are more checks than fit in an exit status.
+const SELFTEST_CHECKS: &[(&str, &str)] = &[
+    (
+        "memory",
+        "heap or global contents changed, mappings aren't restored properly",
+    ),
+    ("brk", "the program break moved, malloc will misbehave"),
+    ("fds", "an open file didn't come back at the right offset"),
+    (
+        "vdso",
+        "the clock went backwards, the vDSO probably doesn't match this kernel",
+    ),
+    ("registers", "a value on the stack was wrong after resuming"),
+    (
+        "signals",
+        "an ignored SIGPIPE wasn't ignored anymore, signal dispositions weren't restored",
+    ),
+    (
+        "shm",
+        "a SysV shared memory segment lost its contents or came back as private memory",
+    ),
+    (
+        "heap",
+        "small allocations got corrupted after more mallocs, the heap and brk disagree",
+    ),
+    (
+        "append",
+        "a write to an O_APPEND file didn't land at the end",
+    ),
+    (
+        "dirfd",
+        "openat relative to a directory fd didn't find the file",
+    ),
+    (
+        "mm",
+        "the code, data, stack, argument or environment boundaries in /proc/self/stat moved, or PR_SET_MM_MAP couldn't set them all at once",
+    ),
+    (
+        "relro",
+        "the GOT came back changed or writable, mapping protections weren't restored",
+    ),
+    (
+        "pidfd",
+        "a pidfd for the selftest's parent couldn't signal it anymore",
+    ),
+    (
+        "sigmask",
+        "a blocked SIGUSR2 wasn't blocked anymore, the signal mask wasn't restored",
+    ),
+    (
+        "dup",
+        "a dup of an open file stopped sharing its offset, it was opened again separately",
+    ),
+];
+
+static SELFTEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // glibc answers this from the vDSO without a real syscall
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// The memory layout fields of `/proc/self/stat`: code, stack start, then
+/// data, heap start, arguments and environment
+fn stat_layout() -> Vec<String> {
+    let stat = std::fs::read_to_string("/proc/self/stat").unwrap_or_default();
+    let fields: Vec<&str> = match stat.rfind(')') {
+        Some(at) => stat[at + 1..].split_whitespace().collect(),
+        None => Vec::new(),
+    };
+    // Numbered from 3, the first after the command name
+    [26, 27, 28, 45, 46, 47, 48, 49, 50, 51]
+        .iter()
+        .filter_map(|n| fields.get(n - 3).map(|f| f.to_string()))
+        .collect()
+}
+
+/// Where our own executable's `PT_GNU_RELRO` region is, rounded to pages the
+/// way the loader protects it
+fn own_relro() -> Option<(usize, usize)> {
+    let phdr = unsafe { libc::getauxval(libc::AT_PHDR) } as usize;
+    let phnum = unsafe { libc::getauxval(libc::AT_PHNUM) } as usize;
+    if phdr == 0 {
+        return None;
+    }
+    let headers = unsafe { std::slice::from_raw_parts(phdr as *const libc::Elf64_Phdr, phnum) };
+    let bias = headers
+        .iter()
+        .find(|h| h.p_type == libc::PT_PHDR)
+        .map(|h| phdr - h.p_vaddr as usize)?;
+    let relro = headers.iter().find(|h| h.p_type == libc::PT_GNU_RELRO)?;
+    let page = PAGE_SIZE;
+    let start = (bias + relro.p_vaddr as usize) & !(page - 1);
+    let end = (bias + (relro.p_vaddr + relro.p_memsz) as usize) & !(page - 1);
+    Some((start, end.checked_sub(start).filter(|len| *len > 0)?))
+}
+
+/// The permissions column of our own mapping containing `addr`, like `r--p`
+fn own_perms(addr: usize) -> Option<String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (start, end) = fields.next()?.split_once('-')?;
+        let range = usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?;
+        range
+            .contains(&addr)
+            .then(|| fields.next().map(String::from))?
+    })
+}
+
+/// Round trip ourselves through memory with some state we know the value of
+/// and check it all survived, as a quick way to find out if telefork works
+/// on this machine.
+pub fn selftest() -> Result<(), Box<dyn std::error::Error>> {
+    let pattern: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    SELFTEST_COUNTER.store(41, Ordering::SeqCst);
+    // Small enough that malloc puts them on the brk heap rather than mmaping
+    let small: Vec<Vec<u8>> = (0..256).map(|i| vec![i as u8; 200]).collect();
+    let brk = unsafe { libc::sbrk(0) as usize };
+    let path = std::env::temp_dir().join(format!("telefork-selftest-{}", std::process::id()));
+    let mut file = File::create(&path)?;
+    file.write_all(b"telefork selftest\n")?;
+    drop(file);
+    let mut file = File::open(&path)?;
+    file.seek(SeekFrom::Start(9))?;
+    // Shares the offset with `file`, so reading that moves this one too
+    let mut dup = file.try_clone()?;
+    // Its offset is at the start but writes still have to go on the end
+    let log_path = path.with_extension("log");
+    std::fs::write(&log_path, "before\n")?;
+    let mut log = OpenOptions::new().append(true).open(&log_path)?;
+    let result_path = path.with_extension("result");
+    let dir = File::open(std::env::temp_dir())?;
+    let name = std::ffi::CString::new(path.file_name().unwrap().as_bytes())?;
+    let layout = stat_layout();
+    let relro = own_relro().map(|(addr, len)| {
+        let bytes = unsafe { std::slice::from_raw_parts(addr as *const u8, len) };
+        (addr, bytes.to_vec())
+    });
+    let marker: u64 = 0x7e1e_f04c;
+    // Rust already ignores SIGPIPE but that's exactly the sort of thing that
+    // needs to survive, so be explicit about it
+    unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) };
+    // Removed right away so it can't outlive us, it stays until detached
+    let shm = unsafe {
+        let id = libc::shmget(libc::IPC_PRIVATE, 16 * 4096, libc::IPC_CREAT | 0o600);
+        let addr = libc::shmat(id, std::ptr::null(), 0);
+        libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut());
+        if id < 0 || addr as isize == -1 {
+            None
+        } else {
+            let bytes = std::slice::from_raw_parts_mut(addr as *mut u8, 16 * 4096);
+            bytes
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b = (i % 253) as u8);
+            Some(addr as usize)
+        }
+    };
+    // Of ourselves, which is still around for the restored copy to signal.
+    // Kernels without pidfds don't get this checked.
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, std::process::id(), 0) };
+    // The copy we dump is forked from this thread so it gets this mask
+    let mut usr2 = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    let mut old_mask = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    unsafe {
+        libc::sigemptyset(&mut usr2);
+        libc::sigaddset(&mut usr2, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &usr2, &mut old_mask);
+    }
+    let before = monotonic_ns();
+
+    let trip = round_trip(move || {
+        let mut failed = 0;
+        let counter = SELFTEST_COUNTER.fetch_add(1, Ordering::SeqCst) + 1;
+        if counter != 42
+            || !pattern
+                .iter()
+                .enumerate()
+                .all(|(i, b)| *b == (i % 251) as u8)
+        {
+            failed |= 1 << 0;
+        }
+        if unsafe { libc::sbrk(0) as usize } != brk {
+            failed |= 1 << 1;
+        }
+        let mut rest = String::new();
+        if file.read_to_string(&mut rest).is_err() || rest != "selftest\n" {
+            failed |= 1 << 2;
+        }
+        if dup.stream_position().ok() != Some(18) {
+            failed |= 1 << 14;
+        }
+        if monotonic_ns() < before {
+            failed |= 1 << 3;
+        }
+        if std::hint::black_box(marker) != 0x7e1e_f04c {
+            failed |= 1 << 4;
+        }
+        // If SIGPIPE went back to the default this write kills us instead
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe(pipe.as_mut_ptr()) } == 0 {
+            unsafe { libc::close(pipe[0]) };
+            let res = unsafe { libc::write(pipe[1], b"x".as_ptr() as *const libc::c_void, 1) };
+            if res != -1 || std::io::Error::last_os_error().raw_os_error() != Some(libc::EPIPE) {
+                failed |= 1 << 5;
+            }
+            unsafe { libc::close(pipe[1]) };
+        } else {
+            failed |= 1 << 5;
+        }
+        let shared = shm.is_some_and(|addr| {
+            let bytes = unsafe { std::slice::from_raw_parts(addr as *const u8, 16 * 4096) };
+            let maps = std::fs::read_to_string("/proc/self/maps").unwrap_or_default();
+            bytes.iter().enumerate().all(|(i, b)| *b == (i % 253) as u8)
+                && maps
+                    .lines()
+                    .any(|l| l.starts_with(&format!("{:x}-", addr)) && l.contains("/SYSV"))
+        });
+        if !shared {
+            failed |= 1 << 6;
+        }
+        // Enough churn to grow the heap past where it was and shrink it back
+        for round in 0..16 {
+            let more: Vec<Vec<u8>> = (0..4096).map(|i| vec![(i + round) as u8; 300]).collect();
+            if more
+                .iter()
+                .enumerate()
+                .any(|(i, v)| v[299] != (i + round) as u8)
+            {
+                failed |= 1 << 7;
+            }
+        }
+        if small
+            .iter()
+            .enumerate()
+            .any(|(i, v)| v.iter().any(|b| *b != i as u8))
+        {
+            failed |= 1 << 7;
+        }
+        let appended = log.write_all(b"after\n").is_ok()
+            && std::fs::read_to_string(&log_path).is_ok_and(|l| l == "before\nafter\n");
+        if !appended {
+            failed |= 1 << 8;
+        }
+        let relative = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), libc::O_RDONLY) };
+        let mut contents = String::new();
+        if relative < 0
+            || unsafe { File::from_raw_fd(relative) }
+                .read_to_string(&mut contents)
+                .is_err()
+            || contents != "telefork selftest\n"
+        {
+            failed |= 1 << 9;
+        }
+        if stat_layout() != layout {
+            failed |= 1 << 10;
+        }
+        // Not built with RELRO means nothing to check
+        let protected = relro.as_ref().is_none_or(|(addr, bytes)| {
+            let now = unsafe { std::slice::from_raw_parts(*addr as *const u8, bytes.len()) };
+            now == &bytes[..] && own_perms(*addr).as_deref() == Some("r--p")
+        });
+        if !protected {
+            failed |= 1 << 11;
+        }
+        // Signal 0 only checks it could be sent
+        let signalled = pidfd < 0
+            || unsafe {
+                libc::syscall(
+                    libc::SYS_pidfd_send_signal,
+                    pidfd,
+                    0,
+                    std::ptr::null::<libc::siginfo_t>(),
+                    0,
+                )
+            } == 0;
+        if !signalled {
+            failed |= 1 << 12;
+        }
+        let blocked = unsafe {
+            let mut mask = std::mem::zeroed::<libc::sigset_t>();
+            libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask) == 0
+                && libc::sigismember(&mask, libc::SIGUSR2) == 1
+        };
+        if !blocked {
+            failed |= 1 << 13;
+        }
+        let _ = std::fs::write(&result_path, failed.to_string());
+        (failed != 0) as i32
+    });
+    // Everything failed if the canary didn't get as far as saying
+    let failed: u32 = std::fs::read_to_string(path.with_extension("result"))
+        .ok()
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(u32::MAX);
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(path.with_extension("log"));
+    let _ = std::fs::remove_file(path.with_extension("result"));
+    if pidfd >= 0 {
+        unsafe { libc::close(pidfd as i32) };
+    }
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut()) };
+
+    let trip = match trip {
+        Ok(trip) => trip,
+        Err(e) => {
+            println!("round trip failed: {}", e);
+            println!(
+                "if the restored process crashed, the vDSO or registers are the likely culprits"
+            );
+            return Err(e);
+        }
+    };
+    // Setting the fields one at a time puts the same numbers back, which
+    // would hide `PR_SET_MM_MAP` not working at all
+    let failed = if trip.report.brk == BrkRestore::MmMap {
+        failed
+    } else {
+        failed | 1 << 10
+    };
+    for (i, (check, hint)) in SELFTEST_CHECKS.iter().enumerate() {
+        if failed & (1 << i) == 0 {
+            println!("{:<10} ok", check);
+        } else {
+            println!("{:<10} FAILED: {}", check, hint);
+        }
+    }
+    if trip.before.fds.len() != trip.after.fds.len() {
+        println!(
+            "note: had {} fds before and {} after",
+            trip.before.fds.len(),
+            trip.after.fds.len()
+        );
+    }
+    if trip.status != 0 || failed != 0 {
+        return Err(std::io::Error::other("selftest failed").into());
+    }
+    println!("telefork works here");
     Ok(())
 }
@@ -0,0 +1,182 @@
+//! Authenticated encryption for the telefork stream, so a process can be
+//! sent over a network you don't trust without anyone reading its memory or
+//! tampering with it on the way.
+//!
+//! The stream is cut into chunks that are each sealed with
+//! XChaCha20-Poly1305. It starts with a random 16 byte nonce prefix, and
+//! each chunk's nonce is that prefix plus the chunk number, so chunks can't
+//! be reordered or replayed. Every chunk is written as a `u32` length whose
+//! top bit marks the final chunk, followed by the ciphertext. The final flag
+//! is also authenticated, so cutting the stream short is noticed instead of
+//! looking like a clean end.
+
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{AeadCore, Key, XChaCha20Poly1305, XNonce};
+
+use std::io::{self, Read, Write};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const PREFIX_LEN: usize = 16;
+const FINAL_FLAG: u32 = 1 << 31;
+/// Poly1305 tag on the end of every chunk
+const TAG_LEN: usize = 16;
+
+fn nonce(prefix: &[u8; PREFIX_LEN], counter: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Encrypts everything written to it into `inner`. Call `finish` at the end,
+/// dropping it without doing so leaves a stream the other end will reject as
+/// truncated.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_LEN],
+    counter: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, key: &[u8; 32]) -> io::Result<Self> {
+        // A fresh random prefix per stream means reusing a key is fine
+        let random = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut prefix = [0u8; PREFIX_LEN];
+        prefix.copy_from_slice(&random[..PREFIX_LEN]);
+        inner.write_all(&prefix)?;
+        Ok(EncryptWriter {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+            prefix,
+            counter: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let aad = [last as u8];
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce(&self.prefix, self.counter),
+                Payload {
+                    msg: &self.buf,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| invalid("failed to encrypt telefork chunk"))?;
+        self.counter += 1;
+        self.buf.clear();
+        let mut len = sealed.len() as u32;
+        if last {
+            len |= FINAL_FLAG;
+        }
+        self.inner.write_all(&len.to_le_bytes())?;
+        self.inner.write_all(&sealed)
+    }
+
+    /// Seal the last chunk and hand back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.seal(false)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Sealing partial chunks on every flush would just waste tags
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream made by `EncryptWriter`, failing with `InvalidData` if
+/// the key is wrong or anything was modified.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_LEN],
+    counter: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &[u8; 32]) -> io::Result<Self> {
+        let mut prefix = [0u8; PREFIX_LEN];
+        inner.read_exact(&mut prefix)?;
+        Ok(DecryptReader {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+            prefix,
+            counter: 0,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn open_next(&mut self) -> io::Result<()> {
+        let mut len = [0u8; 4];
+        self.inner
+            .read_exact(&mut len)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid("encrypted telefork stream was cut short"),
+                _ => e,
+            })?;
+        let len = u32::from_le_bytes(len);
+        let last = len & FINAL_FLAG != 0;
+        let len = (len & !FINAL_FLAG) as usize;
+        if len > CHUNK_SIZE + TAG_LEN {
+            return Err(invalid("oversized encrypted telefork chunk"));
+        }
+        let mut sealed = vec![0u8; len];
+        self.inner.read_exact(&mut sealed)?;
+        let aad = [last as u8];
+        self.buf = self
+            .cipher
+            .decrypt(
+                &nonce(&self.prefix, self.counter),
+                Payload {
+                    msg: &sealed,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| invalid("couldn't decrypt telefork stream, wrong key?"))?;
+        self.counter += 1;
+        self.pos = 0;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_next()?;
+        }
+        let len = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
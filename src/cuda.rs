@@ -0,0 +1,93 @@
+//! Getting CUDA processes in and out of a state we can dump, using NVIDIA's
+//! `cuda-checkpoint` tool. It copies everything the process has on the GPU
+//! into its regular memory and releases the GPU, so from there it's just a
+//! normal process to us. Restoring is the same in reverse once the memory is
+//! back.
+//!
+//! The catch is that `cuda-checkpoint --toggle` returns before the process
+//! has actually got there, so we poll `--get-state` until it has. Dumping
+//! while the GPU memory is still on its way over gets you a corrupt dump.
+
+use crate::{error, Result, Unsupported};
+
+use tracing::info;
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// The tool to run, override with `CUDA_CHECKPOINT` if it's not on the `PATH`
+fn tool() -> String {
+    std::env::var("CUDA_CHECKPOINT").unwrap_or_else(|_| "cuda-checkpoint".to_string())
+}
+
+/// How long to give the GPU memory to get copied in or out
+const TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn run(pid: i32, args: &[&str]) -> Result<String> {
+    let output = Command::new(tool())
+        .args(args)
+        .arg("--pid")
+        .arg(pid.to_string())
+        .output();
+    // It doesn't come with the driver, so not having it is the usual way
+    // for this to go wrong
+    let output = match output {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Box::new(Unsupported(format!(
+                "{} isn't installed, put NVIDIA's cuda-checkpoint on the PATH or point CUDA_CHECKPOINT at it",
+                tool()
+            ))))
+        }
+        output => output?,
+    };
+    if !output.status.success() {
+        tracing::error!(
+            "cuda-checkpoint {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return error("cuda-checkpoint failed");
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The process's CUDA state, one of `running`, `locked`, `checkpointed` or `failed`
+pub fn state(pid: i32) -> Result<String> {
+    run(pid, &["--get-state"])
+}
+
+fn toggle_and_wait(pid: i32, from: &str, to: &str) -> Result<()> {
+    let current = state(pid)?;
+    if current != from {
+        tracing::error!("CUDA state of {} is {}, expected {}", pid, current, from);
+        return error("process isn't in the CUDA state we expected");
+    }
+    run(pid, &["--toggle"])?;
+    let start = Instant::now();
+    loop {
+        match state(pid)?.as_str() {
+            s if s == to => break,
+            "failed" => return error("cuda-checkpoint says the toggle failed"),
+            // Still locked or not switched over yet
+            _ if start.elapsed() > TIMEOUT => {
+                return error("timed out waiting for cuda-checkpoint")
+            }
+            _ => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+    info!("CUDA state of {} is now {}", pid, to);
+    Ok(())
+}
+
+/// Move the process's GPU state into its memory, returning once it's all
+/// there and safe to dump
+pub fn checkpoint(pid: i32) -> Result<()> {
+    toggle_and_wait(pid, "running", "checkpointed")
+}
+
+/// Put a checkpointed process's state back on the GPU. Only do this once
+/// its memory has been completely restored.
+pub fn restore(pid: i32) -> Result<()> {
+    toggle_and_wait(pid, "checkpointed", "running")
+}
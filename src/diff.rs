@@ -0,0 +1,386 @@
+//! Comparing two dumps, for working out what a process changed between two
+//! snapshots or why two dumps of what should be the same thing aren't. Each
+//! dump is read into what it would restore, which for a pre-copy dump means
+//! later passes replacing what earlier ones sent, then mappings are lined up
+//! by address and compared a page at a time by checksum, so neither dump has
+//! to fit in memory.
+//!
+//! Only plain dumps and directory dumps can be compared, compressed or
+//! encrypted ones need restoring or re-dumping without that first.
+
+use crate::builder::MIDDLEWARE_MAGIC;
+use crate::dumpdir::DumpDirReader;
+use crate::{
+    read_command, read_header, Command, ConnectionMap, DumpReader, Mapping, Result, Unsupported,
+    PAGE_SIZE,
+};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+/// A mapping as one side of a `DumpDiff` has it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingSummary {
+    pub addr: usize,
+    pub size: usize,
+    pub name: Option<String>,
+    /// Where the contents come from when they aren't in the dump, like
+    /// `/data/db at offset 0` for a shared file mapping
+    pub source: Option<String>,
+}
+
+impl fmt::Display for MappingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:x}-{:x} {}",
+            self.addr,
+            self.addr + self.size,
+            self.name.as_deref().unwrap_or("anonymous mapping")
+        )?;
+        if let Some(source) = &self.source {
+            write!(f, " ({})", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// A mapping at the same address in both dumps that isn't the same
+#[derive(Debug, Clone)]
+pub struct ChangedMapping {
+    pub before: MappingSummary,
+    pub after: MappingSummary,
+    /// The addresses of pages whose contents differ, including any only one
+    /// of them has when they're different sizes
+    pub pages: Vec<usize>,
+}
+
+/// A register with a different value in the second dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub name: &'static str,
+    pub before: u64,
+    pub after: u64,
+}
+
+/// A file descriptor that's only in one dump or points at something else
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdChange {
+    Added { fd: u32, to: String },
+    Removed { fd: u32, was: String },
+    Changed { fd: u32, was: String, to: String },
+}
+
+impl fmt::Display for FdChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdChange::Added { fd, to } => write!(f, "+ fd {}: {}", fd, to),
+            FdChange::Removed { fd, was } => write!(f, "- fd {}: {}", fd, was),
+            FdChange::Changed { fd, was, to } => write!(f, "~ fd {}: {} -> {}", fd, was, to),
+        }
+    }
+}
+
+/// What `diff_dumps` found going from the first dump to the second
+#[derive(Debug, Clone, Default)]
+pub struct DumpDiff {
+    pub added: Vec<MappingSummary>,
+    pub removed: Vec<MappingSummary>,
+    pub changed: Vec<ChangedMapping>,
+    pub registers: Vec<RegisterChange>,
+    pub fds: Vec<FdChange>,
+}
+
+impl DumpDiff {
+    /// Whether the two dumps would restore the same process
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.registers.is_empty()
+            && self.fds.is_empty()
+    }
+}
+
+impl fmt::Display for DumpDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for map in &self.removed {
+            writeln!(f, "- {}", map)?;
+        }
+        for map in &self.added {
+            writeln!(f, "+ {}", map)?;
+        }
+        for change in &self.changed {
+            if change.before == change.after {
+                writeln!(f, "~ {}", change.after)?;
+            } else {
+                writeln!(f, "~ {} -> {}", change.before, change.after)?;
+            }
+            if !change.pages.is_empty() {
+                writeln!(f, "    {} pages differ", change.pages.len())?;
+            }
+        }
+        for reg in &self.registers {
+            writeln!(f, "~ {}: {:#x} -> {:#x}", reg.name, reg.before, reg.after)?;
+        }
+        for fd in &self.fds {
+            writeln!(f, "{}", fd)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare the dumps at `a` and `b`, either files or directories from
+/// `DumpDir`
+pub fn diff_dumps(a: &Path, b: &Path) -> Result<DumpDiff> {
+    let (a, b) = (summarize_path(a)?, summarize_path(b)?);
+    let mut diff = DumpDiff::default();
+
+    for (addr, before) in &a.maps {
+        match b.maps.get(addr) {
+            None => diff.removed.push(before.summary.clone()),
+            Some(after) => {
+                let pages = changed_pages(*addr, &before.pages, &after.pages);
+                if before.summary != after.summary || !pages.is_empty() {
+                    diff.changed.push(ChangedMapping {
+                        before: before.summary.clone(),
+                        after: after.summary.clone(),
+                        pages,
+                    });
+                }
+            }
+        }
+    }
+    for (addr, after) in &b.maps {
+        if !a.maps.contains_key(addr) {
+            diff.added.push(after.summary.clone());
+        }
+    }
+
+    if let (Some(before), Some(after)) = (&a.regs, &b.regs) {
+        for ((name, before), (_, after)) in named_registers(before)
+            .into_iter()
+            .zip(named_registers(after))
+        {
+            if before != after {
+                diff.registers.push(RegisterChange {
+                    name,
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+
+    let (before, after) = (describe_fds(&a.fds), describe_fds(&b.fds));
+    for (&fd, was) in &before {
+        match after.get(&fd) {
+            None => diff.fds.push(FdChange::Removed {
+                fd,
+                was: was.1.clone(),
+            }),
+            Some(to) if to.0 != was.0 => diff.fds.push(FdChange::Changed {
+                fd,
+                was: was.1.clone(),
+                to: to.1.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (&fd, to) in &after {
+        if !before.contains_key(&fd) {
+            diff.fds.push(FdChange::Added {
+                fd,
+                to: to.1.clone(),
+            });
+        }
+    }
+    Ok(diff)
+}
+
+/// What one dump would restore, as far as comparing goes
+#[derive(Default)]
+struct Summary {
+    maps: BTreeMap<usize, MapContents>,
+    regs: Option<libc::user_regs_struct>,
+    fds: ConnectionMap,
+}
+
+struct MapContents {
+    summary: MappingSummary,
+    /// CRC32 of each page, empty if the contents aren't in the dump
+    pages: Vec<u32>,
+}
+
+fn summarize_path(path: &Path) -> Result<Summary> {
+    if path.is_dir() {
+        return summarize(&mut DumpDirReader::open(path)?);
+    }
+    let file = File::open(path)?;
+    // Safety: like `telepad_file`, the dump changing under us just makes for
+    // a nonsense diff
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    if map.starts_with(&MIDDLEWARE_MAGIC) {
+        return Err(Box::new(Unsupported(format!(
+            "{:?} is compressed or encrypted, only plain dumps can be compared",
+            path
+        ))));
+    }
+    summarize(&mut &map[..])
+}
+
+fn summarize(inp: &mut dyn DumpReader) -> Result<Summary> {
+    let mut summary = Summary::default();
+    let (mut framing, mut first) = read_header(inp)?;
+    loop {
+        let comm = match first.take() {
+            Some(comm) => comm,
+            None => read_command(inp, &mut framing)?,
+        };
+        match comm {
+            Command::Mapping(m) | Command::HugetlbMapping { mapping: m, .. } => {
+                let pages = page_checksums(inp, m.size)?;
+                summary.add(&m, None, pages);
+            }
+            Command::PartialMapping { mapping: m, skip } => {
+                let zero = crc32fast::hash(&[0u8; PAGE_SIZE]);
+                let mut pages = vec![zero; skip / PAGE_SIZE];
+                pages.extend(page_checksums(inp, m.size - skip)?);
+                summary.add(&m, None, pages);
+            }
+            Command::SharedMemory(shm) => {
+                let pages = page_checksums(inp, shm.mapping.size)?;
+                summary.add(&shm.mapping, None, pages);
+            }
+            Command::FileMapping(fm) => {
+                let source = format!("{} at offset {}", fm.path, fm.offset);
+                summary.add(&fm.mapping, Some(source), Vec::new());
+            }
+            Command::PastEof(fm) => {
+                let source = format!("past the end of {}", fm.path);
+                summary.add(&fm.mapping, Some(source), Vec::new());
+            }
+            Command::Remap { name, addr, size } => {
+                let summary_of = MappingSummary {
+                    addr,
+                    size,
+                    name: Some(name),
+                    source: Some("this kernel's".to_string()),
+                };
+                summary.maps.insert(
+                    addr,
+                    MapContents {
+                        summary: summary_of,
+                        pages: Vec::new(),
+                    },
+                );
+            }
+            Command::Unmap { addr, .. } => {
+                summary.maps.remove(&addr);
+            }
+            Command::DirtyPages { map, pages } => {
+                let dirty = page_checksums(inp, pages.len() * PAGE_SIZE)?;
+                if let Some(contents) = summary.maps.get_mut(&map) {
+                    for (page, crc) in pages.iter().zip(dirty) {
+                        if let Some(slot) = contents.pages.get_mut((page - map) / PAGE_SIZE) {
+                            *slot = crc;
+                        }
+                    }
+                }
+            }
+            Command::FileDescriptors(cm) => summary.fds = cm,
+            Command::ResumeWithRegisters { len } => {
+                let mut bytes = vec![0u8; len];
+                inp.read_exact(&mut bytes)?;
+                if len >= std::mem::size_of::<libc::user_regs_struct>() {
+                    // Safety: it's plain integers, and long enough
+                    summary.regs = Some(unsafe {
+                        std::ptr::read_unaligned(bytes.as_ptr() as *const libc::user_regs_struct)
+                    });
+                }
+                return Ok(summary);
+            }
+            // Everything else either has nothing after it or isn't
+            // something we compare
+            _ => {}
+        }
+    }
+}
+
+impl Summary {
+    fn add(&mut self, m: &Mapping, source: Option<String>, pages: Vec<u32>) {
+        let summary = MappingSummary {
+            addr: m.addr,
+            size: m.size,
+            name: m.name.clone(),
+            source,
+        };
+        // A later pre-copy pass sending it again replaces it
+        self.maps.insert(m.addr, MapContents { summary, pages });
+    }
+}
+
+/// Read `len` bytes of mapping contents, checksumming each page
+fn page_checksums(inp: &mut dyn DumpReader, len: usize) -> Result<Vec<u32>> {
+    if let Some(bytes) = inp.borrow_bytes(len) {
+        return Ok(bytes.chunks(PAGE_SIZE).map(crc32fast::hash).collect());
+    }
+    let mut pages = Vec::with_capacity(len / PAGE_SIZE);
+    let mut buf = vec![0u8; PAGE_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let batch = std::cmp::min(PAGE_SIZE, remaining);
+        inp.read_exact(&mut buf[..batch])?;
+        pages.push(crc32fast::hash(&buf[..batch]));
+        remaining -= batch;
+    }
+    Ok(pages)
+}
+
+fn changed_pages(addr: usize, before: &[u32], after: &[u32]) -> Vec<usize> {
+    (0..std::cmp::max(before.len(), after.len()))
+        .filter(|&i| before.get(i) != after.get(i))
+        .map(|i| addr + i * PAGE_SIZE)
+        .collect()
+}
+
+/// Each fd's `Debug` to compare by and `Display` to show
+fn describe_fds(cm: &ConnectionMap) -> BTreeMap<u32, (String, String)> {
+    cm.iter()
+        .map(|(&fd, conn)| (fd, (format!("{:?}", conn), conn.to_string())))
+        .collect()
+}
+
+fn named_registers(regs: &libc::user_regs_struct) -> Vec<(&'static str, u64)> {
+    vec![
+        ("rip", regs.rip),
+        ("rsp", regs.rsp),
+        ("rbp", regs.rbp),
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("orig_rax", regs.orig_rax),
+        ("eflags", regs.eflags),
+        ("fs_base", regs.fs_base),
+        ("gs_base", regs.gs_base),
+        ("cs", regs.cs),
+        ("ss", regs.ss),
+        ("ds", regs.ds),
+        ("es", regs.es),
+        ("fs", regs.fs),
+        ("gs", regs.gs),
+    ]
+}
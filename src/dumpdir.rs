@@ -0,0 +1,465 @@
+//! Dumps as a directory instead of one file, for processes big enough that a
+//! monolithic dump is a pain to look at or move around. Each mapping's
+//! contents go in their own file named by the address range they cover, like
+//! `7f3a1c000000-7f3a1c021000.bin`, and all the commands in between go in
+//! `commands.bin`. `manifest.json` lists which bytes of which files make up
+//! the stream in order, so putting it back together is just reading them in
+//! that order.
+//!
+//! Dumping again into the same directory only rewrites files whose contents
+//! changed in ways `rsync` can tell, and unchanged mappings keep their names,
+//! so only what changed has to transfer.
+//!
+//! `DumpDir` is a `Write` that splits the stream as it goes, which it can do
+//! because everything after the header it starts with says how long it is. Restoring goes through `DumpDirReader`, which maps the files and
+//! hands `telepad` mapping contents straight out of them.
+
+use crate::{
+    frame_len_with, let_go, telepad_with_hooks, Command, Config, DumpReader, Frame, RestoreHooks,
+    RestoredProcess, Result, Unsupported, CONTENTS_LENGTHS, DUMP_MAGIC, DUMP_VERSION,
+    FRAME_CHECKSUMS, PAGE_SIZE,
+};
+
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.json";
+const COMMANDS: &str = "commands.bin";
+const MANIFEST_VERSION: u32 = 1;
+
+/// What `manifest.json` holds
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    parts: Vec<Part>,
+}
+
+/// Some bytes of one file in the directory, the stream is all of these in
+/// order
+#[derive(Serialize, Deserialize)]
+struct Part {
+    file: String,
+    offset: u64,
+    len: u64,
+    /// Like `[heap] at 55d0...`, just for people reading the manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    what: Option<String>,
+}
+
+/// Where `DumpDir` is in the stream
+enum State {
+    /// Waiting on the `DUMP_MAGIC` and version the stream starts with
+    Start,
+    /// And the header flags after it from version 2
+    Flags,
+    Frame,
+    Command {
+        len: usize,
+        optional: bool,
+        /// From the frame, with `CONTENTS_LENGTHS`
+        contents: Option<usize>,
+    },
+    /// Bytes that follow a command outside its frame, which go in a file of
+    /// their own if they're a mapping's contents or the commands if not
+    Contents {
+        file: Option<BufWriter<File>>,
+        remaining: usize,
+    },
+}
+
+/// Splits a dump written to it into a directory, see the module docs. It
+/// only needs `finish` once the dump is done, and won't restore before then.
+pub struct DumpDir {
+    dir: PathBuf,
+    commands: BufWriter<File>,
+    commands_len: u64,
+    parts: Vec<Part>,
+    state: State,
+    /// The start of whatever `state` is waiting for
+    pending: Vec<u8>,
+    /// Files written so far, to keep names unique
+    names: HashSet<String>,
+    /// Files the last dump in this directory had
+    previous: Vec<String>,
+    /// The header flags, for what follows each frame
+    flags: u32,
+}
+
+impl DumpDir {
+    /// Start a dump in `dir`, creating it if needed. Whatever dump was there
+    /// stops being restorable until this one finishes.
+    pub fn create(dir: impl AsRef<Path>) -> Result<DumpDir> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let previous = match std::fs::read(dir.join(MANIFEST)) {
+            Ok(manifest) => {
+                let manifest: Manifest = serde_json::from_slice(&manifest)?;
+                std::fs::remove_file(dir.join(MANIFEST))?;
+                manifest.parts.into_iter().map(|p| p.file).collect()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let commands = BufWriter::new(File::create(dir.join(COMMANDS))?);
+        Ok(DumpDir {
+            dir,
+            commands,
+            commands_len: 0,
+            parts: Vec::new(),
+            state: State::Start,
+            pending: Vec::new(),
+            names: std::iter::once(COMMANDS.to_string()).collect(),
+            previous,
+            flags: 0,
+        })
+    }
+
+    /// Write out the manifest once the whole dump has gone in, and clean up
+    /// any files from an earlier dump this one didn't need
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.pending.is_empty() || !matches!(self.state, State::Frame) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the dump ended partway through a command",
+            )));
+        }
+        self.commands.flush()?;
+        for stale in self.previous.drain(..) {
+            if !self.names.contains(&stale) {
+                let _ = std::fs::remove_file(self.dir.join(stale));
+            }
+        }
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            parts: std::mem::take(&mut self.parts),
+        };
+        // Only there once everything it points at is
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&manifest)?)?;
+        std::fs::rename(&tmp, self.dir.join(MANIFEST))?;
+        info!(
+            "wrote {} parts of dump to {:?}",
+            manifest.parts.len(),
+            self.dir
+        );
+        Ok(())
+    }
+
+    fn append_commands(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.commands.write_all(bytes)?;
+        match self.parts.last_mut() {
+            Some(last) if last.file == COMMANDS && last.offset + last.len == self.commands_len => {
+                last.len += bytes.len() as u64;
+            }
+            _ => self.parts.push(Part {
+                file: COMMANDS.to_string(),
+                offset: self.commands_len,
+                len: bytes.len() as u64,
+                what: None,
+            }),
+        }
+        self.commands_len += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// What `state` was waiting for has all arrived in `pending`
+    fn parsed(&mut self) -> io::Result<()> {
+        let bytes = std::mem::take(&mut self.pending);
+        self.append_commands(&bytes)?;
+        self.state = match self.state {
+            State::Start => {
+                let version = u32::from_le_bytes(bytes[4..].try_into().unwrap());
+                if bytes[..4] != DUMP_MAGIC || !(1..=DUMP_VERSION).contains(&version) {
+                    return Err(invalid(
+                        "directory dumps need a stream of framed commands from this version",
+                    ));
+                }
+                if version >= 2 {
+                    State::Flags
+                } else {
+                    State::Frame
+                }
+            }
+            State::Flags => {
+                let flags = u32::from_le_bytes(bytes[..].try_into().unwrap());
+                if flags & !(FRAME_CHECKSUMS | CONTENTS_LENGTHS) != 0 {
+                    return Err(invalid(
+                        "the dump is framed in a way this version doesn't know",
+                    ));
+                }
+                self.flags = flags;
+                State::Frame
+            }
+            State::Frame => {
+                let frame: Frame = bincode::deserialize(&bytes).map_err(io::Error::other)?;
+                // After the checksum, if there is one
+                let contents = (self.flags & CONTENTS_LENGTHS != 0).then(|| {
+                    let at = frame_len_with(self.flags) - 8;
+                    u64::from_le_bytes(bytes[at..].try_into().unwrap()) as usize
+                });
+                State::Command {
+                    len: frame.len as usize,
+                    optional: frame.optional,
+                    contents,
+                }
+            }
+            State::Command {
+                optional, contents, ..
+            } => match bincode::deserialize::<Command>(&bytes) {
+                Ok(comm) => self.contents_of(&comm)?,
+                // Newer than us, so whatever's after it stays with the
+                // commands, which without the length it can't have
+                Err(_) if optional => match contents {
+                    Some(remaining) if remaining > 0 => State::Contents {
+                        file: None,
+                        remaining,
+                    },
+                    _ => State::Frame,
+                },
+                Err(e) => {
+                    return Err(invalid(&format!(
+                        "can't split a dump with a command this version doesn't know: {}",
+                        e
+                    )))
+                }
+            },
+            State::Contents { .. } => unreachable!("contents aren't buffered"),
+        };
+        Ok(())
+    }
+
+    /// What comes after `comm` outside its frame, if anything
+    fn contents_of(&mut self, comm: &Command) -> io::Result<State> {
+        let (stem, len, what) = match comm {
+            Command::Mapping(m) => (range_name(m.addr, m.size), m.size, m.describe()),
+            Command::PartialMapping { mapping: m, skip } => (
+                range_name(m.addr + skip, m.size - skip),
+                m.size - skip,
+                m.describe(),
+            ),
+            Command::HugetlbMapping { mapping: m, .. } => {
+                (range_name(m.addr, m.size), m.size, m.describe())
+            }
+            Command::SharedMemory(shm) => {
+                let m = &shm.mapping;
+                (range_name(m.addr, m.size), m.size, m.describe())
+            }
+            Command::DirtyPages { map, pages } => (
+                format!("{:012x}-dirty", map),
+                pages.len() * PAGE_SIZE,
+                format!("dirty pages of mapping at {:x}", map),
+            ),
+            // Registers are small and change every time, they can stay put
+            Command::ResumeWithRegisters { len } => {
+                return Ok(State::Contents {
+                    file: None,
+                    remaining: *len,
+                })
+            }
+            _ => return Ok(State::Frame),
+        };
+        if len == 0 {
+            return Ok(State::Frame);
+        }
+        // Precopy can send a mapping more than once
+        let mut name = format!("{}.bin", stem);
+        let mut n = 1;
+        while self.names.contains(&name) {
+            name = format!("{}.{}.bin", stem, n);
+            n += 1;
+        }
+        let file = BufWriter::new(File::create(self.dir.join(&name))?);
+        self.names.insert(name.clone());
+        self.parts.push(Part {
+            file: name,
+            offset: 0,
+            len: len as u64,
+            what: Some(what),
+        });
+        Ok(State::Contents {
+            file: Some(file),
+            remaining: len,
+        })
+    }
+}
+
+impl Write for DumpDir {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            if let State::Contents { file, remaining } = &mut self.state {
+                let len = std::cmp::min(*remaining, rest.len());
+                let (bytes, tail) = rest.split_at(len);
+                rest = tail;
+                *remaining -= len;
+                let done = *remaining == 0;
+                let into_commands = match file {
+                    Some(file) => {
+                        file.write_all(bytes)?;
+                        if done {
+                            file.flush()?;
+                        }
+                        false
+                    }
+                    None => true,
+                };
+                if done {
+                    self.state = State::Frame;
+                }
+                if into_commands {
+                    self.append_commands(bytes)?;
+                }
+                continue;
+            }
+            let need = match self.state {
+                State::Start => DUMP_MAGIC.len() + 4,
+                State::Flags => 4,
+                // The checksum and length are copied along with the frame,
+                // `telepad` checks them when it reads the directory back
+                State::Frame => frame_len_with(self.flags),
+                State::Command { len, .. } => len,
+                State::Contents { .. } => unreachable!(),
+            };
+            let len = std::cmp::min(need - self.pending.len(), rest.len());
+            self.pending.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            if self.pending.len() == need {
+                self.parsed()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let State::Contents {
+            file: Some(file), ..
+        } = &mut self.state
+        {
+            file.flush()?;
+        }
+        self.commands.flush()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn range_name(addr: usize, size: usize) -> String {
+    format!("{:012x}-{:012x}", addr, addr + size)
+}
+
+/// Reads the stream a `DumpDir` split back out of the directory
+pub struct DumpDirReader {
+    files: Vec<memmap2::Mmap>,
+    /// Index into `files` and the range of it still to read, for each part
+    parts: VecDeque<(usize, usize, usize)>,
+}
+
+impl DumpDirReader {
+    pub fn open(dir: impl AsRef<Path>) -> Result<DumpDirReader> {
+        let dir = dir.as_ref();
+        let manifest = match std::fs::read(dir.join(MANIFEST)) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Box::new(invalid(
+                    "no manifest.json, the dump in this directory never finished",
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let manifest: Manifest = serde_json::from_slice(&manifest)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(Box::new(Unsupported(format!(
+                "the directory dump is version {} and this telefork only reads version {}",
+                manifest.version, MANIFEST_VERSION
+            ))));
+        }
+        let mut files = Vec::new();
+        let mut indexes = HashMap::new();
+        let mut parts = VecDeque::new();
+        for part in manifest.parts.iter().filter(|p| p.len > 0) {
+            let index = match indexes.get(&part.file) {
+                Some(&index) => index,
+                None => {
+                    let file = File::open(dir.join(&part.file))?;
+                    // Safety: like `telepad_file`, the files changing under
+                    // us just makes for a corrupt dump
+                    files.push(unsafe { memmap2::Mmap::map(&file)? });
+                    indexes.insert(part.file.clone(), files.len() - 1);
+                    files.len() - 1
+                }
+            };
+            let (start, end) = (part.offset as usize, (part.offset + part.len) as usize);
+            if end > files[index].len() {
+                return Err(Box::new(invalid(&format!(
+                    "manifest.json wants bytes {} to {} of {} but it's only {} long",
+                    start,
+                    end,
+                    part.file,
+                    files[index].len()
+                ))));
+            }
+            parts.push_back((index, start, end));
+        }
+        Ok(DumpDirReader { files, parts })
+    }
+}
+
+impl Read for DumpDirReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (index, start, end) = match self.parts.front_mut() {
+            Some(part) => part,
+            None => return Ok(0),
+        };
+        let len = std::cmp::min(buf.len(), *end - *start);
+        buf[..len].copy_from_slice(&self.files[*index][*start..*start + len]);
+        *start += len;
+        if start == end {
+            self.parts.pop_front();
+        }
+        Ok(len)
+    }
+}
+
+impl DumpReader for DumpDirReader {
+    fn borrow_bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let (index, start, end) = self.parts.front_mut()?;
+        if *end - *start < len {
+            return None;
+        }
+        let bytes = &self.files[*index][*start..*start + len];
+        *start += len;
+        if start == end {
+            self.parts.pop_front();
+        }
+        Some(bytes)
+    }
+}
+
+/// `telepad` from a directory `DumpDir` wrote
+pub fn telepad_dir(dir: impl AsRef<Path>, pass_to_child: i32, config: &Config) -> Result<Pid> {
+    let_go(telepad_dir_attached(dir, pass_to_child, config), config)
+}
+
+/// `telepad_dir` but leaving it stopped like `telepad_attached`
+pub fn telepad_dir_attached(
+    dir: impl AsRef<Path>,
+    pass_to_child: i32,
+    config: &Config,
+) -> Result<RestoredProcess> {
+    let mut inp = DumpDirReader::open(dir)?;
+    telepad_with_hooks(
+        &mut inp,
+        pass_to_child.into(),
+        config,
+        &mut RestoreHooks::default(),
+    )
+}
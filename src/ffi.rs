@@ -0,0 +1,73 @@
+//! A tiny C-compatible interface so programs that aren't written in Rust can
+//! `telefork` themselves by linking against the `cdylib`. The matching header
+//! with the return value conventions is `include/telefork.h`.
+//!
+//! Everything here works on raw file descriptors that the caller owns, so we
+//! wrap them in a `File` for the duration of the call and then forget it
+//! rather than closing the caller's fd out from under them.
+
+use crate::{telefork, telepad, wait_for_exit, TeleforkLocation};
+
+use libc::c_int;
+use nix::unistd::Pid;
+
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::FromRawFd;
+
+/// Returned by `telefork_to_fd` in the original process after a successful telefork.
+pub const TELEFORK_PARENT: c_int = 0;
+/// Returned by all the entry points when something went wrong.
+pub const TELEFORK_ERROR: c_int = -1;
+
+/// Borrow a caller-owned fd as a `File` without taking ownership of it
+fn borrow_fd(fd: c_int) -> ManuallyDrop<File> {
+    ManuallyDrop::new(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Stream the current process out over `fd`.
+///
+/// Returns `TELEFORK_PARENT` in the original process, and in the process
+/// woken up on the other end returns whatever was passed to
+/// `telepad_from_fd`, which is why callers should pass a positive value
+/// there. Returns `TELEFORK_ERROR` on failure.
+#[no_mangle]
+pub extern "C" fn telefork_to_fd(fd: c_int) -> c_int {
+    let mut out = borrow_fd(fd);
+    match telefork(&mut *out) {
+        Ok(TeleforkLocation::Parent) => TELEFORK_PARENT,
+        Ok(TeleforkLocation::Child(pass)) => pass,
+        Err(e) => {
+            tracing::error!("telefork_to_fd failed: {}", e);
+            TELEFORK_ERROR
+        }
+    }
+}
+
+/// Receive a process streamed by `telefork_to_fd` from `fd` and wake it up
+/// passing it `pass`. Returns the pid of the restored process or
+/// `TELEFORK_ERROR`.
+#[no_mangle]
+pub extern "C" fn telepad_from_fd(fd: c_int, pass: c_int) -> c_int {
+    let mut inp = borrow_fd(fd);
+    match telepad(&mut *inp, pass) {
+        Ok(child) => child.as_raw(),
+        Err(e) => {
+            tracing::error!("telepad_from_fd failed: {}", e);
+            TELEFORK_ERROR
+        }
+    }
+}
+
+/// Wait for a process restored by `telepad_from_fd` to exit and return its
+/// exit status, or `TELEFORK_ERROR` if it didn't exit normally.
+#[no_mangle]
+pub extern "C" fn telefork_wait_for_exit(pid: c_int) -> c_int {
+    match wait_for_exit(Pid::from_raw(pid)) {
+        Ok(code) => code,
+        Err(e) => {
+            tracing::error!("telefork_wait_for_exit failed: {}", e);
+            TELEFORK_ERROR
+        }
+    }
+}
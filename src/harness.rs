@@ -0,0 +1,242 @@
+//! Helpers for checking that a process survives a round trip, without real
+//! networking or files in the way. The channel is just a buffer in memory,
+//! and `round_trip` teleforks into it and telepads back out of it in the
+//! same process, so it behaves the same every time.
+//!
+//! ```no_run
+//! use std::sync::atomic::{AtomicI32, Ordering};
+//!
+//! static COUNTER: AtomicI32 = AtomicI32::new(1);
+//!
+//! COUNTER.store(42, Ordering::SeqCst);
+//! let trip = telefork::harness::round_trip(|| COUNTER.load(Ordering::SeqCst)).unwrap();
+//! assert_eq!(trip.status, 42);
+//! assert_eq!(trip.before.fds.len(), trip.after.fds.len());
+//! ```
+//!
+//! `round_trip_dir` does the same through a directory dump on disk instead.
+//!
+//! `Synthetic` is the other direction, a separate process with as much memory
+//! as you like for `teledump` to chew on, which is what the benchmarks use.
+
+use crate::{
+    error, telefork, telepad_dir_attached, telepad_with_report, wait_for_exit, Config, DumpDir,
+    RestoreReport, Result, TeleforkLocation, PAGE_SIZE,
+};
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, ForkResult, Pid};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Bytes written to it come back out when read, in order. Reading when it's
+/// empty gives end of file rather than blocking.
+#[derive(Debug, Default)]
+pub struct MemoryChannel {
+    buf: VecDeque<u8>,
+}
+
+impl MemoryChannel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many bytes are waiting to be read
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+impl Write for MemoryChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MemoryChannel {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.buf.read(out)
+    }
+}
+
+/// The parts of a process we can look at from outside to compare it before
+/// and after a round trip
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The lines of `/proc/pid/maps`
+    pub maps: Vec<String>,
+    /// Each open fd and what its `/proc/pid/fd` link points at
+    pub fds: BTreeMap<u32, String>,
+}
+
+impl Snapshot {
+    pub fn of(pid: i32) -> Result<Snapshot> {
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid))?
+            .lines()
+            .map(str::to_string)
+            .collect();
+        let mut fds = BTreeMap::new();
+        for entry in std::fs::read_dir(format!("/proc/{}/fd", pid))? {
+            let entry = entry?;
+            let fd = match entry.file_name().to_string_lossy().parse() {
+                Ok(fd) => fd,
+                Err(_) => continue,
+            };
+            // It can close between listing and reading, like the one
+            // `read_dir` itself has open when looking at ourselves
+            if let Ok(target) = std::fs::read_link(entry.path()) {
+                fds.insert(fd, target.to_string_lossy().to_string());
+            }
+        }
+        Ok(Snapshot { maps, fds })
+    }
+}
+
+/// What `round_trip` saw
+#[derive(Debug, Clone)]
+pub struct RoundTrip {
+    /// This process just before teleforking
+    pub before: Snapshot,
+    /// The restored process, just after it woke up
+    pub after: Snapshot,
+    /// What the closure returned in the restored process
+    pub status: i32,
+    /// What the restore said it did
+    pub report: RestoreReport,
+}
+
+/// Telefork the current process into memory and restore it as a child. The
+/// restored copy stops itself right after waking up so we can snapshot it,
+/// then runs `f` and exits with whatever it returns, so `f` is where to
+/// check that state made it across.
+pub fn round_trip<F: FnOnce() -> i32>(f: F) -> Result<RoundTrip> {
+    let mut channel = MemoryChannel::new();
+    round_trip_through(&mut channel, f, |channel| {
+        telepad_with_report(channel, 0, &Config::default())
+    })
+}
+
+/// `round_trip` but through a directory dump in `dir`, see `DumpDir`
+pub fn round_trip_dir<F: FnOnce() -> i32>(dir: &Path, f: F) -> Result<RoundTrip> {
+    let mut out = DumpDir::create(dir)?;
+    round_trip_through(&mut out, f, |out| {
+        out.finish()?;
+        let config = Config::default();
+        let restored = telepad_dir_attached(dir, 0, &config)?;
+        let report = restored.report().clone();
+        Ok((restored.let_go(&config)?, report))
+    })
+}
+
+fn round_trip_through<W: Write, F: FnOnce() -> i32>(
+    out: &mut W,
+    f: F,
+    restore: impl FnOnce(&mut W) -> Result<(Pid, RestoreReport)>,
+) -> Result<RoundTrip> {
+    let before = Snapshot::of(std::process::id() as i32)?;
+    if let TeleforkLocation::Child(_) = telefork(out)? {
+        // Hold still until the harness has had a look at us
+        unsafe { libc::raise(libc::SIGSTOP) };
+        std::process::exit(f());
+    }
+
+    let (child, report) = restore(out)?;
+    match waitpid(child, Some(WaitPidFlag::WUNTRACED))? {
+        WaitStatus::Stopped(_, Signal::SIGSTOP) => {}
+        status => {
+            tracing::error!("restored process didn't stop: {:?}", status);
+            return error("restored process didn't stop itself");
+        }
+    }
+    let after = Snapshot::of(child.as_raw());
+    kill(child, Signal::SIGCONT)?;
+    let status = wait_for_exit(child)?;
+    Ok(RoundTrip {
+        before,
+        after: after?,
+        status,
+        report,
+    })
+}
+
+/// A forked child that maps `memory_bytes` of anonymous memory, fills every
+/// page so none of it is left unbacked, then sits in `pause` until dropped.
+/// It's a copy of us apart from that, so it has the same fds and mappings a
+/// small Rust program would.
+pub struct Synthetic {
+    pub pid: Pid,
+    pub memory_bytes: usize,
+}
+
+impl Synthetic {
+    pub fn spawn(memory_bytes: usize) -> Result<Synthetic> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let (ready_read, ready_write) = (fds[0], fds[1]);
+        match fork()? {
+            ForkResult::Child => unsafe {
+                // Only plain libc calls from here, whoever forked us could
+                // have had other threads holding the allocator's locks
+                libc::close(ready_read);
+                let mem = libc::mmap(
+                    std::ptr::null_mut(),
+                    memory_bytes,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                if mem == libc::MAP_FAILED {
+                    libc::_exit(1);
+                }
+                // Different bytes in each page so nothing along the way can
+                // get away with noticing they're all the same
+                let mem = mem as *mut u8;
+                for (i, offset) in (0..memory_bytes).step_by(PAGE_SIZE).enumerate() {
+                    std::ptr::write_bytes(mem.add(offset), i as u8, PAGE_SIZE);
+                }
+                libc::write(ready_write, b"!".as_ptr() as *const libc::c_void, 1);
+                libc::close(ready_write);
+                loop {
+                    libc::pause();
+                }
+            },
+            ForkResult::Parent { child } => {
+                unsafe { libc::close(ready_write) };
+                let mut ready = [0u8; 1];
+                let read =
+                    unsafe { libc::read(ready_read, ready.as_mut_ptr() as *mut libc::c_void, 1) };
+                unsafe { libc::close(ready_read) };
+                let synthetic = Synthetic {
+                    pid: child,
+                    memory_bytes,
+                };
+                if read != 1 {
+                    return error("synthetic process couldn't map its memory");
+                }
+                Ok(synthetic)
+            }
+        }
+    }
+}
+
+impl Drop for Synthetic {
+    fn drop(&mut self) {
+        let _ = kill(self.pid, Signal::SIGKILL);
+        let _ = waitpid(self.pid, None);
+    }
+}
@@ -0,0 +1,17 @@
+//! Reading dumps from before they started with `DUMP_MAGIC`, which are
+//! version 0 of the format: bare bincode commands one after the other, with
+//! no frames around them. Everything here can go once nobody has any of
+//! those left.
+
+use crate::{Command, Result};
+
+use std::io::Read;
+
+/// Carry on reading a legacy dump whose first four bytes were `tag`, which
+/// are the start of its first command, handing that command back
+pub(crate) fn read_start(tag: [u8; 4], inp: &mut dyn Read) -> Result<Command> {
+    tracing::debug!("reading a legacy unframed dump");
+    let mut rest = (&tag[..]).chain(inp);
+    let first = bincode::deserialize_from::<&mut dyn Read, Command>(&mut rest)?;
+    Ok(first)
+}
@@ -9,48 +9,212 @@
 //! all in one module, because I can't make a good reading order across modules.
 
 // The nix crate is a handy Rust-ified wrapper over libc stuff
-use nix;
 use nix::errno::Errno;
 use nix::sys::ptrace;
 use nix::sys::signal::{kill, Signal};
+use nix::sys::statfs::{statfs, TMPFS_MAGIC};
 use nix::sys::uio;
 use nix::sys::wait::{waitpid, WaitStatus};
 use nix::unistd::{ForkResult, Pid};
 
 // But not everything we want to use has a nix wrapper
-use libc;
 use libc::{PROT_EXEC, PROT_READ, PROT_WRITE};
 
-// Handy crate to inspect process memory maps
-use proc_maps;
-
-// We use these to serialize our state over the wire
-use bincode;
+// We use these to serialize our state over the wire, along with the
+// `proc_maps` crate to inspect process memory maps
 use serde::{Deserialize, Serialize};
-use tracing::{info, warn};
+use tracing::{info, trace, warn};
 
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
 // Error handling
 use std::error::Error;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 // Used for the `yoyo` helper at the bottom
 use std::net::{TcpStream, ToSocketAddrs};
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{FromRawFd, RawFd};
 
+pub mod builder;
 pub mod cmd;
+mod crypt;
+pub mod cuda;
+pub mod diff;
+pub mod dumpdir;
+pub mod ffi;
+pub mod harness;
+mod legacy;
+mod precopy;
+pub mod resumable;
+pub mod snapshot;
+mod sock_diag;
+pub mod vdso;
+
+pub use builder::{Compression, StreamMiddleware, TeleforkBuilder, TelepadBuilder};
+pub use diff::{diff_dumps, DumpDiff};
+pub use dumpdir::{telepad_dir, telepad_dir_attached, DumpDir};
+pub use snapshot::{telepad_snapshot, Snapshot};
+pub use vdso::VdsoCompat;
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 const PAGE_SIZE: usize = 4096;
 
-// In order to do the path tracing demo to a remote server with a different
-// kernel I really just wanted to get it to work even though it used the vDSO.
-// I did this by just overriding it to teleport the vDSO contents anyways
-// instead of remapping. The issue is I have no idea how the vDSO actually
-// interacts with the kernel so this might totally not work. Also I don't
-// properly handle the case where mappings collide and the existing and new
-// map vDSO might overlap. This setting enables this janky vDSO support.
-const JANKY_VDSO_TELEPORT: bool = false;
+/// Knobs for tweaking how `telefork` and `telepad` do their thing. The
+/// defaults are what the plain functions use.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Where to put the scratch region `telepad` uses for passing syscall
+    /// arguments and as a stack while injecting syscalls. By default it's
+    /// placed just above the highest mapping in the dump so it can't collide
+    /// with anything being restored.
+    pub scratch_addr: Option<usize>,
+    /// Send the stream in acknowledged chunks so a dropped connection can be
+    /// resumed where it left off, see `telefork_connect` and `telepad_accept`.
+    pub resumable: bool,
+    /// In order to do the path tracing demo to a remote server with a
+    /// different kernel I really just wanted to get it to work even though it
+    /// used the vDSO. I did this by just overriding it to teleport the vDSO
+    /// contents anyways instead of remapping. The issue is I have no idea how
+    /// the vDSO actually interacts with the kernel so this might totally not
+    /// work. Also I don't properly handle the case where mappings collide and
+    /// the existing and new map vDSO might overlap. This setting enables this
+    /// janky vDSO support when dumping.
+    pub janky_vdso: bool,
+    /// Send the contents of regular files the process has open along with
+    /// their paths, so they can still be restored on a machine where the
+    /// path doesn't exist. Files bigger than `BUNDLE_FILE_LIMIT` are still
+    /// just sent as a path.
+    pub bundle_files: bool,
+    /// Files at most this big get their contents sent along even without
+    /// `bundle_files`, since things like config files are cheap to send and
+    /// often aren't on the machine we restore to. Defaults to
+    /// `SMALL_FILE_LIMIT`, 0 turns it off.
+    pub small_file_limit: usize,
+    /// Read each mapping back out of the restored process and check it
+    /// against the checksum the dump carries, failing the restore if they
+    /// differ. It doubles the memory copying so it's off by default.
+    pub verify_restore: bool,
+    /// Point the restored process's stdin, stdout and stderr at these fds of
+    /// ours instead of whatever they were, whatever the source had there.
+    /// The restored process starts out as a fork of the one calling
+    /// `telepad`, so it already has them open under the same numbers.
+    pub stdio: [Option<RawFd>; 3],
+    /// Attach restored shared memory segments to the ones already on this
+    /// machine with the same SysV key or `/dev/shm` name, instead of making
+    /// fresh segments holding the dumped contents. Whatever else has the
+    /// segment attached keeps writing to it while we dump and restore, so
+    /// neither way is a consistent snapshot of it together with them, this
+    /// just picks whether the restored process sees their changes or its
+    /// own copy. Shared mappings of files elsewhere on a tmpfs are always
+    /// attached again when the file is still there.
+    pub reattach_shm: bool,
+    /// Have `teledump` copy memory in up to this many passes while the
+    /// process keeps running, and only stop it at the end to send what
+    /// changed since along with everything else, see `precopy`. 0 stops it
+    /// for the whole dump.
+    pub precopy_passes: usize,
+    /// Carry on restoring when something the process might manage without
+    /// fails, like an fd for a file that's not there anymore or signal
+    /// dispositions we couldn't set, and list it in
+    /// `RestoredProcess::skipped` instead. Memory, registers and file locks
+    /// someone else holds still fail the restore.
+    pub keep_going: bool,
+    /// Stay attached to the restored process for `FIRST_FAULT_WINDOW` after
+    /// it resumes and catch the first `SIGSEGV` or the like, failing with a
+    /// `FaultReport` instead of letting it quietly die. A botched restore
+    /// nearly always crashes right away, so this tells which mapping it was.
+    pub debug_first_fault: bool,
+    /// Hold the restored process stopped until every restore sharing the
+    /// barrier is ready to let go of its own, so a group of processes that
+    /// talk to each other all start together. Only what resumes the process
+    /// for you waits on it, `telepad_attached` leaves that to you.
+    pub resume_barrier: Option<std::sync::Arc<ResumeBarrier>>,
+    /// Follow every command in the dump with its CRC32, so a corrupted one
+    /// fails the restore with a `CorruptFrame` saying which instead of
+    /// whatever misreading it leads to. Mapping contents aren't in frames,
+    /// they have `Command::MappingChecksum` for `verify_restore`. Readers
+    /// from before this was added can't read dumps with it on.
+    pub frame_checksums: bool,
+    /// Say in each frame how many bytes follow its command outside it, like
+    /// a mapping's contents, so a reader can skip any command without
+    /// knowing what it is, see `FrameReader`. Readers from before this was
+    /// added can't read dumps with it on.
+    pub contents_lengths: bool,
+    /// How long `teledump` waits for a process caught partway through a
+    /// `vfork` or an `exec` to get through it before giving up with a
+    /// `TransientState`. Either side of a `vfork` is running on memory the
+    /// other one is about to carry on with, so a dump of it isn't of any
+    /// one process.
+    pub settle_timeout: std::time::Duration,
+    /// Move the restored process into the mount namespace at this path, like
+    /// `/proc/<pid>/ns/mnt` of something in the same container, before
+    /// anything is restored by path. The paths of a process dumped from
+    /// inside a container are from its view of the filesystem and might not
+    /// exist outside it, or be something else entirely. It needs
+    /// `CAP_SYS_ADMIN`.
+    pub mount_namespace: Option<std::path::PathBuf>,
+    /// What `telepad` does with fds it doesn't know how to bring back, like
+    /// TCP sockets and pipes. Whichever it is, the ones left closed are in
+    /// `RestoreReport::dropped_fds`.
+    pub unsupported_fds: UnsupportedFdPolicy,
+    /// Make the cgroup the process was in when it isn't here, instead of
+    /// leaving the restored process in `telepad`'s. Only the cgroup comes
+    /// back, none of the original's limits are set on it.
+    pub create_cgroups: bool,
+    /// Dump mappings of devices like `/dev/dri/card0` as zeroed memory of the
+    /// same size instead of refusing to dump the process. What's behind them
+    /// is the device's, a GPU's buffers or a card's registers, and none of it
+    /// comes along, so a process that touches them again after a restore
+    /// sees zeroes where the device used to be. Only for processes that are
+    /// done with the device or never look at it again.
+    pub skip_device_maps: bool,
+    /// Whether the restored process has to have the pid it was dumped with,
+    /// see `SamePid`. Asking for a pid needs Linux 5.5 and
+    /// `CAP_CHECKPOINT_RESTORE`, and nothing here can have it already, so
+    /// only one restore of a dump at a time can get it.
+    pub same_pid: SamePid,
+    /// Write a `DumpIndex` of where each command is after the dump, so it
+    /// can be found from the end of a file of several dumps one after
+    /// another, see `telepad_seek`. Readers stop at the end of the dump and
+    /// never see it, but it's only any use in a file, not compressed or
+    /// encrypted and not in a `DumpDir`.
+    pub index: bool,
+}
+
+/// How long `Config::debug_first_fault` watches for
+pub const FIRST_FAULT_WINDOW: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// The default `Config::settle_timeout`, long enough for any `vfork` child
+/// that's only going to `exec`
+pub const SETTLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            scratch_addr: None,
+            resumable: false,
+            janky_vdso: false,
+            bundle_files: false,
+            small_file_limit: SMALL_FILE_LIMIT,
+            verify_restore: false,
+            stdio: [None; 3],
+            reattach_shm: false,
+            precopy_passes: 0,
+            keep_going: false,
+            debug_first_fault: false,
+            resume_barrier: None,
+            frame_checksums: false,
+            contents_lengths: false,
+            settle_timeout: SETTLE_TIMEOUT,
+            mount_namespace: None,
+            unsupported_fds: UnsupportedFdPolicy::Warn,
+            create_cgroups: false,
+            skip_device_maps: false,
+            same_pid: SamePid::Any,
+            index: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum TeleforkLocation {
@@ -62,6 +226,24 @@ pub enum TeleforkLocation {
 
 /// The `telefork` function streams the current process's state over a writeable channel
 pub fn telefork(out: &mut dyn Write) -> Result<TeleforkLocation> {
+    telefork_with_config(out, &Config::default())
+}
+
+/// `telefork` with the knobs in `Config`
+pub fn telefork_with_config(out: &mut dyn Write, config: &Config) -> Result<TeleforkLocation> {
+    telefork_with_hooks(out, config, &mut DumpHooks::default())
+}
+
+pub(crate) fn telefork_with_hooks(
+    out: &mut dyn Write,
+    config: &Config,
+    hooks: &mut DumpHooks,
+) -> Result<TeleforkLocation> {
+    if shares_memory_with_parent() {
+        return error(
+            "telefork called from a process sharing memory with its parent, like a vfork child",
+        );
+    }
     // == 1. Record anything we can easily record within our own process
     let proc_state = ProcessState {
         // sbrk(0) returns current brk address and it won't change for child since we don't malloc before forking
@@ -71,7 +253,7 @@ pub fn telefork(out: &mut dyn Write) -> Result<TeleforkLocation> {
     // without it changing. If we try to inspect ourselves we'll run into
     // problems where our registers and stack are changing as we're
     // serializing.
-    let child: Pid = match fork_frozen_traced()? {
+    let child: Pid = match fork_frozen_traced(None)? {
         // On the other end the process will be restarted from its frozen
         // state and return thinking its a forked child to this point, so
         // return from telefork notifying we're on the other end.
@@ -79,21 +261,53 @@ pub fn telefork(out: &mut dyn Write) -> Result<TeleforkLocation> {
         NormalForkLocation::Parent(p) => p,
     };
     // == 3. Inspect all the pieces of state and stream them out
-    write_state(out, child, proc_state)?;
+    let out = &mut FrameWriter::new(out, config.index);
+    write_state(
+        out,
+        child,
+        nix::unistd::getpid(),
+        Sent::Nothing(proc_state),
+        config,
+        hooks,
+    )?;
+    out.finish()?;
     // == 4. Now that we're done reading it we no longer need the forked child and we can return
     kill(child, Signal::SIGKILL)?;
     // == 5. We're the parent, return normally saying so
     Ok(TeleforkLocation::Parent)
 }
 
+/// Whether we're a `vfork` child or otherwise made with `CLONE_VM`. Forking
+/// from there is only a copy of memory our parent is still using, and our
+/// parent is either suspended waiting for us or carrying on changing it
+/// under us, so either way what we'd send is nonsense. `kcmp` can tell if
+/// two processes share an address space, if it's not available we just
+/// assume it's fine.
+fn shares_memory_with_parent() -> bool {
+    let me = nix::unistd::getpid().as_raw();
+    let parent = nix::unistd::getppid().as_raw();
+    let res = unsafe { libc::syscall(libc::SYS_kcmp, me, parent, KCMP_VM, 0, 0) };
+    if res < 0 {
+        tracing::debug!("couldn't kcmp with our parent: {}", Errno::last());
+    }
+    res == 0
+}
+
+const KCMP_VM: i32 = 1;
+
 // === 2. Fork our process into a frozen child
 enum NormalForkLocation {
     Parent(Pid),
     Woke(i32),
 }
 
-fn fork_frozen_traced() -> Result<NormalForkLocation> {
-    match nix::unistd::fork()? {
+/// Fork a child that stops itself for us to trace, as `pid` if there's one
+/// it has to be
+fn fork_frozen_traced(pid: Option<i32>) -> Result<NormalForkLocation> {
+    // Yama lets a child `traceme` its parent at scope 1 without the
+    // `PR_SET_PTRACER` dance, it's only 2 and up that get in the way
+    check_ptrace_scope(ptrace_scope(), has_cap_sys_ptrace())?;
+    match fork_as(pid)? {
         ForkResult::Parent { child, .. } => match waitpid(child, None)? {
             WaitStatus::Stopped(_, Signal::SIGSTOP) => Ok(NormalForkLocation::Parent(child)),
             _ => error("couldn't trace child"),
@@ -114,6 +328,59 @@ fn fork_frozen_traced() -> Result<NormalForkLocation> {
     }
 }
 
+/// `clone3`'s arguments as far as `set_tid`, which is all we need
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+}
+
+/// `fork`, or a `clone3` that's the same as one but asks for `pid`
+fn fork_as(pid: Option<i32>) -> Result<ForkResult> {
+    let pid = match pid {
+        Some(pid) => pid,
+        None => return Ok(nix::unistd::fork()?),
+    };
+    let set_tid = [pid as libc::pid_t];
+    let args = CloneArgs {
+        exit_signal: libc::SIGCHLD as u64,
+        set_tid: set_tid.as_ptr() as u64,
+        set_tid_size: 1,
+        ..CloneArgs::default()
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_clone3,
+            &args as *const CloneArgs,
+            std::mem::size_of::<CloneArgs>(),
+        )
+    };
+    match res {
+        0 => Ok(ForkResult::Child),
+        child if child > 0 => Ok(ForkResult::Parent {
+            child: Pid::from_raw(child as i32),
+        }),
+        _ => {
+            let reason = match Errno::last() {
+                Errno::EEXIST => "something here has it already".to_string(),
+                Errno::EPERM => "asking for a pid needs CAP_CHECKPOINT_RESTORE".to_string(),
+                Errno::ENOSYS | Errno::E2BIG => "asking for a pid needs Linux 5.5".to_string(),
+                errno => format!("clone3 failed with {}", errno),
+            };
+            Err(Box::new(PidConflict { pid, reason }))
+        }
+    }
+}
+
 fn kill_me_if_parent_dies() -> nix::Result<()> {
     let res = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
     Errno::result(res).map(|_| ())
@@ -121,9 +388,28 @@ fn kill_me_if_parent_dies() -> nix::Result<()> {
 
 // ==== 3. Inspect all pieces of the state and stream them out
 
+/// Every dump starts with this and then `DUMP_VERSION`, as a little endian
+/// `u32`. Dumps from before there was a header are read by `legacy`.
+pub const DUMP_MAGIC: [u8; 4] = *b"TFDP";
+/// Bumped when a dump made now couldn't be read by the rules for the one
+/// before, not for new optional commands which older versions skip anyway.
+/// The legacy unversioned format is version 0.
+///
+/// Version 2 has a little endian `u32` of header flags after the version.
+/// Dumps that don't set any are still written as version 1 so older
+/// versions can read them.
+pub const DUMP_VERSION: u32 = 2;
+/// Header flag for each `Frame` being followed by the CRC32 of its command,
+/// see `Config::frame_checksums`
+pub const FRAME_CHECKSUMS: u32 = 1;
+/// Header flag for each `Frame` being followed by a little endian `u64` of
+/// how many bytes come after its command, see `Config::contents_lengths`
+pub const CONTENTS_LENGTHS: u32 = 2;
+
 /// We want to stream the state as opposed to doing it all at once so we do it
 /// as a series of commands to restore specific pieces, rather than one big
-/// data structure.
+/// data structure. Each is sent tagged with its index in here, so new ones
+/// go on the end.
 #[derive(Serialize, Deserialize)]
 enum Command {
     ProcessState(ProcessState),
@@ -137,934 +423,9659 @@ enum Command {
     ResumeWithRegisters {
         len: usize,
     },
+    FsContext(FsContext),
+    /// Sent before `ResumeWithRegisters` when the process was interrupted in
+    /// the middle of a syscall and the registers have been rewound so that it
+    /// gets issued again on resume, see `rewind_interrupted_syscall`.
+    RestartSyscall {
+        nr: u64,
+    },
+    /// The highest address any streamed mapping reaches, sent up front so
+    /// `telepad` can keep its own scratch memory out of the way.
+    AddressSpace {
+        highest: usize,
+    },
+    /// Like `Mapping` but only the part from `addr + skip` onwards is
+    /// streamed, the first `skip` bytes are restored as zeroes.
+    PartialMapping {
+        mapping: Mapping,
+        skip: usize,
+    },
+    PrctlState(PrctlState),
+    Environment(Environment),
+    /// A mapping of a file that's recreated by mapping the same file again
+    /// rather than by streaming its contents.
+    FileMapping(FileMapping),
+    /// Sent up front to say every mapping's contents are followed by a
+    /// `MappingEnd`, older dumps don't have them.
+    CheckedMappings,
+    /// How many content bytes were actually written for the mapping before
+    /// it, so `telepad` can tell if it read the right amount.
+    MappingEnd {
+        written: usize,
+    },
+    /// Sent after a mapping that had a NUMA policy set on it with `mbind`.
+    /// It's separate from `Mapping` so older dumps still read fine.
+    MemoryPolicy(MemoryPolicy),
+    /// Locks the process held on its open files, sent after the
+    /// `FileDescriptors` they're taken through.
+    FileLocks(Vec<FileLock>),
+    /// CRC32 of the contents just sent for the mapping before it, for
+    /// `Config::verify_restore` to check what actually got written.
+    MappingChecksum {
+        crc32: u32,
+    },
+    /// Sent after a mapping that was backed by transparent huge pages, so
+    /// `telepad` can ask for them again.
+    HugePages {
+        addr: usize,
+        size: usize,
+    },
+    /// The flags each file fd was opened with, sent before the
+    /// `FileDescriptors` they're for. Older dumps don't have it and their
+    /// files get opened read-only.
+    OpenFlags(HashMap<u32, i32>),
+    /// Which signals the process ignored and which it had handlers for, as
+    /// bitmasks with bit `n - 1` for signal `n`
+    SignalDispositions {
+        ignored: u64,
+        caught: u64,
+    },
+    /// A shared memory segment mapping, followed by its contents like a
+    /// `Mapping` so it can be recreated somewhere the segment doesn't exist.
+    SharedMemory(SharedMemory),
+    /// A mapping sent by an earlier pass of a pre-copy dump that's since
+    /// gone or changed shape
+    Unmap {
+        addr: usize,
+        size: usize,
+    },
+    /// Pages of the mapping at `map` written to since an earlier pass of a
+    /// pre-copy dump sent them, followed by each page's new contents and a
+    /// `MappingEnd`
+    DirtyPages {
+        map: usize,
+        pages: Vec<usize>,
+    },
+    /// Sent after the mappings so `telepad` can line the kernel's idea of
+    /// the heap up with the `[heap]` mapping it restored
+    Heap(HeapLayout),
+    /// Who the process runs as, applied last since dropping privileges any
+    /// earlier would get in the way of restoring everything else
+    Credentials(Credentials),
+    /// Every field of the kernel's idea of the memory layout, sent just
+    /// before `Heap` so it can all be set in one go where that works
+    MmLayout(MmLayout),
+    /// Which signals each thread had blocked and what was pending, sent
+    /// after `SignalDispositions`
+    SignalMasks(SignalMasks),
+    /// The dumping kernel's vDSO image, sent before the `Remap` of the
+    /// process's vDSO so `telepad` can check its own can take its place
+    Vdso(Vec<u8>),
+    /// The `MCL_*` flags the process called `mlockall` with. `MCL_FUTURE`
+    /// is kept by the kernel rather than on any mapping, so it has to be
+    /// asked for again or what the process maps after resuming isn't locked.
+    MlockAll {
+        flags: i32,
+    },
+    /// The CPU time the process had used and the monotonic clock when it
+    /// was dumped, neither of which can be set on the restored one
+    Clocks(Clocks),
+    /// A mapping made of hugetlbfs pages of `page_size` bytes, like one from
+    /// `mmap` with `MAP_HUGETLB`, followed by its contents like a `Mapping`.
+    /// It gets huge pages again since the process can rely on their
+    /// alignment when it unmaps parts of it, as well as on them being fast.
+    HugetlbMapping {
+        mapping: Mapping,
+        page_size: usize,
+        shared: bool,
+    },
+    /// How the kernel schedules the process, sent with the other things
+    /// `telepad` puts back just before resuming
+    Scheduling(Scheduling),
+    /// The ids file access is checked as, sent after `Credentials` when the
+    /// process had moved them off its effective ones with `setfsuid` or
+    /// `setfsgid`
+    FsIds {
+        uid: u32,
+        gid: u32,
+    },
+    /// The part of a private file mapping past the end of its file, which
+    /// has no contents since touching it is a SIGBUS. It follows the
+    /// `Mapping` of the part the file does reach, if there is one.
+    PastEof(FileMapping),
+    /// The `ITIMER_REAL` timer `alarm` and `setitimer` set, as how long it
+    /// had left and how often it repeats after that. It counts down again
+    /// from when it's restored, so it goes off that much later than it would
+    /// have.
+    RealTimer {
+        remaining: std::time::Duration,
+        interval: std::time::Duration,
+    },
+    /// The cgroup v2 the process was in, as its path in the hierarchy like
+    /// `/system.slice/worker.service`. It comes before any of the memory so
+    /// that gets charged to the cgroup it's moved into.
+    Cgroup(String),
+    /// Every signal queued on the process with its `siginfo`, sent after
+    /// `SignalMasks` when there are any. A real-time signal can be queued
+    /// several times, each with its own value from `sigqueue`, which the
+    /// pending bitmasks can only say one of.
+    QueuedSignals(QueuedSignals),
+    /// The pid the process had, as it saw it in its own pid namespace. It
+    /// comes first thing after the header so `telepad` can read it before
+    /// forking, for `Config::same_pid`.
+    Pid(i32),
+    /// The process's working directory, as seen from outside it like the fd
+    /// paths so it goes through `FsContext::root` the same way
+    Cwd(String),
+    Umask(u32),
+    /// Every signal's full `sigaction` as the process had it, sent after
+    /// `SignalDispositions`. Those can only say that a signal was caught,
+    /// this has where the handler was and its flags and mask too.
+    SignalActions(Vec<SignalAction>),
 }
 
-/// Most of the state is composed of memory mappings
-#[derive(Serialize, Deserialize, Debug)]
-struct Mapping {
-    name: Option<String>,
-    readable: bool,
-    writeable: bool,
-    executable: bool,
-    addr: usize,
-    size: usize,
+/// The order a restore goes in, each stage relying on what the ones before
+/// it put in place. Commands are applied as they're read, so the ones that
+/// belong to a stage have to come in this order, see `Command::stage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+    /// How much address space there is, the brk, and where the kernel's own
+    /// mappings go before anything is mapped on top of them
+    Layout,
+    /// The mappings and their contents, then the mm fields pointing into them
+    Memory,
+    /// The root, working directory and umask, which the paths and modes of
+    /// the fds opened next go through
+    Filesystem,
+    /// The fds and the locks held through them
+    Fds,
+    /// Signal handlers, masks and queued signals, and the prctl state with
+    /// its seccomp mode. Nothing after the fds should run with these in
+    /// place, so they come once the fds are open.
+    Signals,
+    /// The registers, and with them everything held onto until the end, like
+    /// credentials and timers
+    Resume,
 }
 
-impl Mapping {
-    fn _prot(&self) -> i32 {
-        let mut prot = 0;
-        if self.readable {
-            prot |= PROT_READ;
+impl Command {
+    /// Whether restoring without this still gets a process that works, if
+    /// maybe not quite like the original. New commands need to say here so
+    /// older versions reading them know whether they can skip them.
+    fn optional(&self) -> bool {
+        match self {
+            Command::PrctlState(_)
+            | Command::Environment(_)
+            | Command::MappingChecksum { .. }
+            | Command::MemoryPolicy(_)
+            | Command::FileLocks(_)
+            | Command::HugePages { .. }
+            | Command::OpenFlags(_)
+            | Command::SignalDispositions { .. }
+            | Command::Heap(_)
+            | Command::MmLayout(_)
+            | Command::SignalMasks(_)
+            | Command::Vdso(_)
+            | Command::MlockAll { .. }
+            | Command::Clocks(_)
+            | Command::Scheduling(_)
+            | Command::FsIds { .. }
+            | Command::RealTimer { .. }
+            | Command::Cgroup(_)
+            | Command::QueuedSignals(_)
+            | Command::Pid(_)
+            | Command::Cwd(_)
+            | Command::Umask(_)
+            | Command::SignalActions(_)
+            | Command::Credentials(_) => true,
+            // Either something the process can't run without or followed by
+            // data that's not in the frame, which we can't skip
+            Command::ProcessState(_)
+            | Command::Mapping(_)
+            | Command::Remap { .. }
+            | Command::FileDescriptors(_)
+            | Command::ResumeWithRegisters { .. }
+            | Command::FsContext(_)
+            | Command::AddressSpace { .. }
+            | Command::RestartSyscall { .. }
+            | Command::PartialMapping { .. }
+            | Command::FileMapping(_)
+            | Command::CheckedMappings
+            | Command::MappingEnd { .. }
+            | Command::SharedMemory(_)
+            | Command::Unmap { .. }
+            | Command::DirtyPages { .. }
+            | Command::HugetlbMapping { .. }
+            | Command::PastEof(_) => false,
         }
-        if self.writeable {
-            prot |= PROT_WRITE;
+    }
+
+    /// Whether this goes into the process's memory or registers, which is
+    /// all `apply_memory_image` restores
+    fn memory_image(&self) -> bool {
+        match self {
+            Command::AddressSpace { .. }
+            | Command::ProcessState(_)
+            | Command::Mapping(_)
+            | Command::PartialMapping { .. }
+            | Command::FileMapping(_)
+            | Command::PastEof(_)
+            | Command::HugetlbMapping { .. }
+            | Command::SharedMemory(_)
+            | Command::CheckedMappings
+            | Command::MappingEnd { .. }
+            | Command::MappingChecksum { .. }
+            | Command::MemoryPolicy(_)
+            | Command::HugePages { .. }
+            | Command::Unmap { .. }
+            | Command::DirtyPages { .. }
+            | Command::Vdso(_)
+            | Command::Remap { .. }
+            | Command::Heap(_)
+            | Command::MmLayout(_)
+            | Command::Environment(_)
+            | Command::RestartSyscall { .. }
+            | Command::ResumeWithRegisters { .. } => true,
+            Command::FileDescriptors(_)
+            | Command::FsContext(_)
+            | Command::PrctlState(_)
+            | Command::FileLocks(_)
+            | Command::OpenFlags(_)
+            | Command::SignalDispositions { .. }
+            | Command::SignalMasks(_)
+            | Command::Credentials(_)
+            | Command::MlockAll { .. }
+            | Command::Clocks(_)
+            | Command::Scheduling(_)
+            | Command::FsIds { .. }
+            | Command::RealTimer { .. }
+            | Command::Cgroup(_)
+            | Command::QueuedSignals(_)
+            | Command::Pid(_)
+            | Command::Cwd(_)
+            | Command::Umask(_)
+            | Command::SignalActions(_) => false,
         }
-        if self.executable {
-            prot |= PROT_EXEC;
+    }
+
+    /// Which `Stage` this is applied in. The ones that are only held onto
+    /// until the end like signal masks and credentials, or that don't rely
+    /// on anything else, can come anywhere and don't have one. A new command
+    /// that relies on what another puts in place goes in that one's stage or
+    /// a later one.
+    fn stage(&self) -> Option<Stage> {
+        match self {
+            Command::Pid(_)
+            | Command::AddressSpace { .. }
+            | Command::CheckedMappings
+            | Command::ProcessState(_)
+            | Command::Cgroup(_)
+            | Command::Vdso(_)
+            | Command::Remap { .. } => Some(Stage::Layout),
+            Command::Mapping(_)
+            | Command::PartialMapping { .. }
+            | Command::FileMapping(_)
+            | Command::PastEof(_)
+            | Command::HugetlbMapping { .. }
+            | Command::SharedMemory(_)
+            | Command::MappingEnd { .. }
+            | Command::MappingChecksum { .. }
+            | Command::MemoryPolicy(_)
+            | Command::HugePages { .. }
+            | Command::Unmap { .. }
+            | Command::DirtyPages { .. }
+            | Command::Heap(_)
+            | Command::MmLayout(_) => Some(Stage::Memory),
+            Command::FsContext(_) | Command::Cwd(_) | Command::Umask(_) => Some(Stage::Filesystem),
+            Command::OpenFlags(_) | Command::FileDescriptors(_) | Command::FileLocks(_) => {
+                Some(Stage::Fds)
+            }
+            Command::PrctlState(_)
+            | Command::SignalDispositions { .. }
+            | Command::SignalActions(_)
+            | Command::SignalMasks(_)
+            | Command::QueuedSignals(_) => Some(Stage::Signals),
+            Command::ResumeWithRegisters { .. } => Some(Stage::Resume),
+            Command::Environment(_)
+            | Command::Credentials(_)
+            | Command::FsIds { .. }
+            | Command::MlockAll { .. }
+            | Command::Clocks(_)
+            | Command::Scheduling(_)
+            | Command::RealTimer { .. }
+            | Command::RestartSyscall { .. } => None,
+        }
+    }
+
+    /// What it's called in a `DumpIndex`
+    fn name(&self) -> &'static str {
+        match self {
+            Command::ProcessState(_) => "ProcessState",
+            Command::Mapping(_) => "Mapping",
+            Command::Remap { .. } => "Remap",
+            Command::FileDescriptors(_) => "FileDescriptors",
+            Command::ResumeWithRegisters { .. } => "ResumeWithRegisters",
+            Command::FsContext(_) => "FsContext",
+            Command::RestartSyscall { .. } => "RestartSyscall",
+            Command::AddressSpace { .. } => "AddressSpace",
+            Command::PartialMapping { .. } => "PartialMapping",
+            Command::PrctlState(_) => "PrctlState",
+            Command::Environment(_) => "Environment",
+            Command::FileMapping(_) => "FileMapping",
+            Command::CheckedMappings => "CheckedMappings",
+            Command::MappingEnd { .. } => "MappingEnd",
+            Command::MemoryPolicy(_) => "MemoryPolicy",
+            Command::FileLocks(_) => "FileLocks",
+            Command::MappingChecksum { .. } => "MappingChecksum",
+            Command::HugePages { .. } => "HugePages",
+            Command::OpenFlags(_) => "OpenFlags",
+            Command::SignalDispositions { .. } => "SignalDispositions",
+            Command::SharedMemory(_) => "SharedMemory",
+            Command::Unmap { .. } => "Unmap",
+            Command::DirtyPages { .. } => "DirtyPages",
+            Command::Heap(_) => "Heap",
+            Command::Credentials(_) => "Credentials",
+            Command::MmLayout(_) => "MmLayout",
+            Command::SignalMasks(_) => "SignalMasks",
+            Command::Vdso(_) => "Vdso",
+            Command::MlockAll { .. } => "MlockAll",
+            Command::Clocks(_) => "Clocks",
+            Command::HugetlbMapping { .. } => "HugetlbMapping",
+            Command::Scheduling(_) => "Scheduling",
+            Command::FsIds { .. } => "FsIds",
+            Command::PastEof(_) => "PastEof",
+            Command::RealTimer { .. } => "RealTimer",
+            Command::Cgroup(_) => "Cgroup",
+            Command::QueuedSignals(_) => "QueuedSignals",
+            Command::Pid(_) => "Pid",
+            Command::Cwd(_) => "Cwd",
+            Command::Umask(_) => "Umask",
+            Command::SignalActions(_) => "SignalActions",
+        }
+    }
+
+    /// How many bytes come after this outside its frame, like a mapping's
+    /// contents
+    fn contents_len(&self) -> usize {
+        match self {
+            Command::Mapping(m) | Command::HugetlbMapping { mapping: m, .. } => m.size,
+            Command::PartialMapping { mapping, skip } => mapping.size - skip,
+            Command::SharedMemory(shm) => shm.mapping.size,
+            Command::DirtyPages { pages, .. } => pages.len() * PAGE_SIZE,
+            Command::ResumeWithRegisters { len } => *len,
+            _ => 0,
         }
-        prot
     }
 }
 
-/// Some state that we can safely and more easily read before forking
+/// Goes in front of each command after the header, so a `telepad` that
+/// doesn't know some newer command can tell how much to skip
 #[derive(Serialize, Deserialize)]
-struct ProcessState {
-    brk_addr: usize,
+struct Frame {
+    /// Bytes of the command after this
+    len: u32,
+    /// See `Command::optional`
+    optional: bool,
 }
 
-/// Some maps are not safe/a good idea to serialize and teleport to the remote process, we try to remap them instead
-fn is_special_kernel_map(map: &proc_maps::MapRange) -> bool {
-    match map.filename() {
-        Some(n) if (n == "[vdso]" || n == "[vsyscall]" || n == "[vvar]") => true,
-        _ => false,
-    }
+/// The biggest command a frame can hold. The biggest we send is the fds with
+/// their bundled files, which would take a lot of open files as big as
+/// `BUNDLE_FILE_LIMIT` to get anywhere near this, so a frame claiming more is
+/// a corrupted dump.
+pub const MAX_FRAME_LEN: u32 = 1 << 30;
+
+/// Send a command in its `Frame`
+fn write_command(out: &mut dyn Write, comm: &Command) -> Result<()> {
+    let bytes = bincode::serialize(comm)?;
+    let len = match u32::try_from(bytes.len()) {
+        Ok(len) if len <= MAX_FRAME_LEN => len,
+        _ => {
+            tracing::error!("a command serialized to {} bytes", bytes.len());
+            return error("a command is too big to fit in a frame");
+        }
+    };
+    let frame = Frame {
+        len,
+        optional: comm.optional(),
+    };
+    bincode::serialize_into::<&mut dyn Write, Frame>(out, &frame)?;
+    out.write_all(&bytes)?;
+    Ok(())
 }
 
-/// It turns out that even remapping them doesn't work across different kernel
-/// builds so given a flag we can try teleporting them anyways. This worked in
-/// my experience in a case where the kernel version was the same but the
-/// builds/distros were different so remapping segfaulted.
-fn should_teleport_kernel_map_anyways(map: &proc_maps::MapRange) -> bool {
-    if !JANKY_VDSO_TELEPORT {
-        return false;
+/// The header flags `config` asks for
+fn header_flags(config: &Config) -> u32 {
+    let mut flags = 0;
+    if config.frame_checksums {
+        flags |= FRAME_CHECKSUMS;
     }
-    match map.filename() {
-        Some(n) if n == "[vdso]" => true,
-        _ => false,
+    if config.contents_lengths {
+        flags |= CONTENTS_LENGTHS;
     }
+    flags
 }
 
-fn should_skip_map(map: &proc_maps::MapRange) -> bool {
-    // TODO handle non-library read-only things by remapping as readable
-    // TODO or maybe preserve them without contents and map zero pages on rehydrate
-    if !map.is_read() || map.size() == 0 {
-        return true;
-    }
-    false
+/// Adds what the header flags ask for after each frame as the stream goes
+/// past, the CRC32 of its command for `Config::frame_checksums` and then the
+/// length of what follows it outside the frame for
+/// `Config::contents_lengths`. It follows the frames the same way `DumpDir`
+/// does rather than every `write_command` needing to know. A dump without
+/// flags goes straight through.
+pub(crate) struct FrameWriter<'a> {
+    out: &'a mut dyn Write,
+    flags: u32,
+    state: WriterState,
+    /// The header, or the frame and command, being put together
+    pending: Vec<u8>,
+    /// How much has gone out so far
+    written: u64,
+    /// Where each command went, for `Config::index`
+    index: Option<DumpIndex>,
 }
 
-/// Handy crappy utility to make it easier to raise custom errors. If this was for real I'd use the `anyhow` crate.
-fn error<T>(s: &'static str) -> Result<T> {
-    Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, s)))
+enum WriterState {
+    /// The magic and version, then the flags after them from version 2
+    Header,
+    Flags,
+    /// Contents outside a frame, which go straight through
+    Passing(usize),
+    /// Everything left, in a dump without flags
+    Through,
+    Frame,
+    Command(usize),
 }
 
-/// We still need to record the expected location of special maps
-fn write_special_kernel_map(out: &mut dyn Write, map: &proc_maps::MapRange) -> Result<()> {
-    let comm = Command::Remap {
-        name: map
-            .filename()
-            .clone()
-            .expect("can't be a kernel map without a name"),
-        addr: map.start(),
-        size: map.size(),
-    };
-    bincode::serialize_into::<&mut dyn Write, Command>(out, &comm)?;
-    return Ok(());
+/// How long a serialized `Frame` is
+pub(crate) fn frame_len() -> usize {
+    bincode::serialized_size(&Frame {
+        len: 0,
+        optional: false,
+    })
+    .unwrap() as usize
 }
 
-/// Record a normal memory map's info and then stream its contents over the output channel
-fn write_regular_map(out: &mut dyn Write, child: Pid, map: &proc_maps::MapRange) -> Result<()> {
-    let mapping = Mapping {
-        name: map.filename().clone(),
-        readable: map.is_read(),
-        writeable: map.is_write(),
-        executable: map.is_exec(),
-        addr: map.start(),
-        size: map.size(),
-    };
-    bincode::serialize_into::<&mut dyn Write, Command>(out, &Command::Mapping(mapping))?;
+/// How long a frame is along with what the header `flags` say comes after it
+pub(crate) fn frame_len_with(flags: u32) -> usize {
+    let mut len = frame_len();
+    if flags & FRAME_CHECKSUMS != 0 {
+        len += 4;
+    }
+    if flags & CONTENTS_LENGTHS != 0 {
+        len += 8;
+    }
+    len
+}
 
-    // === write contents to output channel a page at a time
-    let mut remaining_size = map.size();
-    let mut buf = vec![0u8; PAGE_SIZE];
-    while remaining_size > 0 {
-        let read_size = std::cmp::min(buf.len(), remaining_size);
-        let offset = map.start() + (map.size() - remaining_size);
+impl<'a> FrameWriter<'a> {
+    pub(crate) fn new(out: &'a mut dyn Write, index: bool) -> Self {
+        FrameWriter {
+            out,
+            flags: 0,
+            state: WriterState::Header,
+            pending: Vec::new(),
+            written: 0,
+            index: index.then(DumpIndex::default),
+        }
+    }
 
-        // This is a rare special syscall to copy memory from another process
-        let wrote = uio::process_vm_readv(
-            child,
-            &[uio::IoVec::from_mut_slice(&mut buf[..read_size])],
-            &[uio::RemoteIoVec {
-                base: offset,
-                len: read_size,
-            }],
-        )?;
-        if wrote == 0 {
-            return error("failed to read from other process");
+    fn emit(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Once the whole dump has gone through, write the `DumpIndex` after it
+    /// if there's one
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        if let Some(mut index) = self.index.take() {
+            index.len = self.written;
+            let bytes = bincode::serialize(&index)?;
+            self.out.write_all(&bytes)?;
+            self.out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+            self.out.write_all(&INDEX_MAGIC)?;
         }
-        out.write(&buf[..])?;
-        remaining_size -= read_size;
+        Ok(())
     }
 
-    Ok(())
+    /// What `state` was waiting for has all arrived in `pending`
+    fn parsed(&mut self) -> std::io::Result<()> {
+        let bytes = std::mem::take(&mut self.pending);
+        self.state = match self.state {
+            WriterState::Header => {
+                self.emit(&bytes)?;
+                let version = u32::from_le_bytes(bytes[4..].try_into().unwrap());
+                if bytes[..4] != DUMP_MAGIC {
+                    return Err(std::io::Error::other("a dump has to start with its header"));
+                }
+                match version {
+                    2.. => WriterState::Flags,
+                    // An index needs to know where the frames are even
+                    // when nothing has to be added to them
+                    _ if self.index.is_some() => WriterState::Frame,
+                    _ => WriterState::Through,
+                }
+            }
+            WriterState::Flags => {
+                self.emit(&bytes)?;
+                self.flags = u32::from_le_bytes(bytes[..].try_into().unwrap());
+                match self.flags {
+                    0 if self.index.is_none() => WriterState::Through,
+                    _ => WriterState::Frame,
+                }
+            }
+            WriterState::Frame => {
+                let frame: Frame = bincode::deserialize(&bytes).map_err(std::io::Error::other)?;
+                self.pending = bytes;
+                WriterState::Command(frame.len as usize)
+            }
+            WriterState::Command(_) => {
+                let (frame, bytes) = bytes.split_at(frame_len());
+                let comm: Command = bincode::deserialize(bytes).map_err(std::io::Error::other)?;
+                if let Some(index) = &mut self.index {
+                    let offsets = index.commands.entry(comm.name().to_string());
+                    offsets.or_default().push(self.written);
+                }
+                self.emit(frame)?;
+                if self.flags & FRAME_CHECKSUMS != 0 {
+                    self.emit(&crc32fast::hash(bytes).to_le_bytes())?;
+                }
+                if self.flags & CONTENTS_LENGTHS != 0 {
+                    self.emit(&(comm.contents_len() as u64).to_le_bytes())?;
+                }
+                self.emit(bytes)?;
+                match comm.contents_len() {
+                    0 => WriterState::Frame,
+                    len => WriterState::Passing(len),
+                }
+            }
+            WriterState::Passing(_) | WriterState::Through => {
+                unreachable!("passed through without buffering")
+            }
+        };
+        Ok(())
+    }
 }
 
-/// Serialized registers
-///
-/// NOTE I think this might break if you use a different build of telefork on
-/// the destination that was compiled with a sufficiently different libc
-#[repr(C)]
-struct RegInfo {
-    pub regs: libc::user_regs_struct,
-}
+impl Write for FrameWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let need = match self.state {
+                WriterState::Through => {
+                    self.emit(rest)?;
+                    break;
+                }
+                WriterState::Passing(remaining) => {
+                    let len = std::cmp::min(remaining, rest.len());
+                    self.emit(&rest[..len])?;
+                    rest = &rest[len..];
+                    self.state = match remaining - len {
+                        0 => WriterState::Frame,
+                        remaining => WriterState::Passing(remaining),
+                    };
+                    continue;
+                }
+                WriterState::Header => DUMP_MAGIC.len() + 4,
+                WriterState::Flags => 4,
+                WriterState::Frame => frame_len(),
+                // Still holding the frame in front of it
+                WriterState::Command(len) => frame_len() + len,
+            };
+            let len = std::cmp::min(need - self.pending.len(), rest.len());
+            self.pending.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            if self.pending.len() == need {
+                self.parsed()?;
+            }
+        }
+        Ok(buf.len())
+    }
 
-/// Be incredibly lazy with implementing proper serialization routines and
-/// just splat the raw bytes to and from the stream in a very unsafe
-/// non-Rust-y way because this is a tech demo I did for fun on a weekend.
-impl RegInfo {
-    fn to_bytes(&self) -> &[u8] {
-        let pointer = self as *const Self as *const u8;
-        unsafe { std::slice::from_raw_parts(pointer, std::mem::size_of::<Self>()) }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
     }
+}
 
-    fn from_bytes(bytes: &[u8]) -> Option<&Self> {
-        if bytes.len() < std::mem::size_of::<Self>() {
-            return None;
-        }
-        if bytes.as_ptr().align_offset(std::mem::align_of::<Self>()) != 0 {
-            return None;
-        }
-        Some(unsafe { std::mem::transmute::<*const u8, &Self>(bytes.as_ptr()) })
+fn write_header(out: &mut dyn Write, flags: u32) -> Result<()> {
+    out.write_all(&DUMP_MAGIC)?;
+    if flags == 0 {
+        out.write_all(&1u32.to_le_bytes())?;
+        return Ok(());
     }
+    out.write_all(&DUMP_VERSION.to_le_bytes())?;
+    out.write_all(&flags.to_le_bytes())?;
+    Ok(())
 }
 
-/// Write out each piece of state in the ideal order using the above functions
-fn write_state(out: &mut dyn Write, child: Pid, proc_state: ProcessState) -> Result<()> {
-    bincode::serialize_into::<&mut dyn Write, Command>(out, &Command::ProcessState(proc_state))?;
+/// How the commands after the header come, from `read_header`
+pub(crate) struct Framing {
+    /// In a `Frame` each, which is everything but legacy dumps
+    framed: bool,
+    /// With the CRC32 of the command after each frame
+    checksums: bool,
+    /// With how many bytes follow each command outside its frame, after the
+    /// CRC32 if there is one
+    contents_lengths: bool,
+    /// How many frames have been read, to say which one was corrupted
+    frames: u64,
+}
 
-    let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
-    // _print_maps_info(&maps);
+/// Read the header a dump starts with, saying how its commands are framed.
+/// Dumps from before the header are handed to `legacy`, which hands back
+/// their first command since it was read in place of the magic.
+fn read_header(inp: &mut dyn Read) -> Result<(Framing, Option<Command>)> {
+    let mut magic = [0u8; 4];
+    inp.read_exact(&mut magic)?;
+    if magic != DUMP_MAGIC {
+        let first = legacy::read_start(magic, inp)?;
+        let framing = Framing {
+            framed: false,
+            checksums: false,
+            contents_lengths: false,
+            frames: 0,
+        };
+        return Ok((framing, Some(first)));
+    }
+    let mut version = [0u8; 4];
+    inp.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version > DUMP_VERSION {
+        tracing::error!("dump is version {}", version);
+        return Err(Box::new(Unsupported(format!(
+            "the dump is format version {} and this telefork only reads up to version {}",
+            version, DUMP_VERSION
+        ))));
+    }
+    let flags = if version >= 2 {
+        let mut flags = [0u8; 4];
+        inp.read_exact(&mut flags)?;
+        u32::from_le_bytes(flags)
+    } else {
+        0
+    };
+    if flags & !(FRAME_CHECKSUMS | CONTENTS_LENGTHS) != 0 {
+        tracing::error!("dump has header flags {:#x}", flags);
+        return Err(Box::new(Unsupported(
+            "the dump is framed in a way this version of telefork doesn't know".to_string(),
+        )));
+    }
+    let framing = Framing {
+        framed: true,
+        checksums: flags & FRAME_CHECKSUMS != 0,
+        contents_lengths: flags & CONTENTS_LENGTHS != 0,
+        frames: 0,
+    };
+    Ok((framing, None))
+}
 
-    // we write out special kernel maps like the vdso first so that we can remap them
-    // to their correct position before some other regular map perhaps stomps on their
-    // original position.
-    let (special_maps, regular_maps) = maps
-        .into_iter()
-        .filter(|m| !should_skip_map(&m))
-        .partition::<Vec<proc_maps::MapRange>, _>(|m| {
-            is_special_kernel_map(&m) && !should_teleport_kernel_map_anyways(&m)
-        });
+/// A command whose bytes don't match the checksum after its frame, from a
+/// dump made with `Config::frame_checksums`
+#[derive(Debug)]
+pub struct CorruptFrame {
+    /// Counting from 0 for the first frame after the header
+    pub frame: u64,
+    /// Bytes of the command in it
+    pub len: u32,
+    pub expected: u32,
+    pub actual: u32,
+}
 
-    for map in &special_maps {
-        write_special_kernel_map(out, map)?;
-    }
-    for map in &regular_maps {
-        write_regular_map(out, child, map)?;
+impl std::fmt::Display for CorruptFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "frame {} of the dump (a {} byte command) is corrupted, its checksum is {:08x} instead of {:08x}",
+            self.frame, self.len, self.actual, self.expected
+        )
     }
+}
 
-    // === Write file descriptors
-    let cm = scan_file_descriptors(child.as_raw())?;
-    bincode::serialize_into::<&mut dyn Write, Command>(out, &Command::FileDescriptors(cm))?;
+impl Error for CorruptFrame {}
 
-    // === Write registers
-    let regs = RegInfo {
-        regs: ptrace::getregs(child)?,
-    };
-    let reg_bytes = regs.to_bytes();
-    bincode::serialize_into::<&mut dyn Write, Command>(
-        out,
-        &Command::ResumeWithRegisters {
-            len: reg_bytes.len(),
-        },
-    )?;
-    out.write(reg_bytes)?;
+/// A frame saying its command is longer than `MAX_FRAME_LEN`, which we'd
+/// never have written. It's caught before trying to make room for it.
+#[derive(Debug)]
+pub struct OversizedFrame {
+    /// Counting from 0 for the first frame after the header
+    pub frame: u64,
+    pub len: u32,
+}
 
-    Ok(())
+impl std::fmt::Display for OversizedFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "frame {} of the dump says its command is {} bytes, more than the {} a frame can hold",
+            self.frame, self.len, MAX_FRAME_LEN
+        )
+    }
 }
 
-// === Child process manipulation utilities
-//
-// In order to restore the serialized process we need various tools to mold an
-// existing process into a rehydrated clone of the serialized process.
+impl Error for OversizedFrame {}
 
-/// Print a representation of the mappings of a process
-///
-/// Prefixed with an underscore because I normally don't use this, but I often
-/// uncomment a line that does use it for debugging.
-fn _print_maps_info(maps: &[proc_maps::MapRange]) {
-    for map in maps {
-        println!(
-            "{:>7} {:>16x} {} {:?}",
-            map.size(),
-            map.start(),
-            map.flags,
-            map.filename()
-        );
-        // println!("{:?}", map);
-    }
-    let total_size: usize = maps.iter().map(|m| m.size()).sum();
-    println!("{:>7} total", total_size);
+/// A frame with its command still serialized, see `FrameReader`
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    /// Counting from 0 for the first frame after the header
+    pub index: u64,
+    /// Whether a restore can do without it
+    pub optional: bool,
+    /// The bincode of the command
+    pub command: Vec<u8>,
+    /// How many bytes follow it outside the frame, in dumps made with
+    /// `Config::contents_lengths`
+    pub contents_len: Option<u64>,
 }
 
-/// Advance the child process by one instruction. This is used to execute
-/// syscall instructions in the child process.
-fn single_step(child: Pid) -> Result<()> {
-    ptrace::step(child, None)?;
-    match waitpid(child, None)? {
-        WaitStatus::Stopped(_, Signal::SIGTRAP) => Ok(()),
-        err => {
-            tracing::error!("waitpid error = {:?}", err);
-            error("couldn't single step child")
+/// Fill as much of `buf` as the stream has, for telling a dump that ends
+/// between two frames from one cut off partway through one
+fn read_up_to(inp: &mut dyn Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match inp.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
     }
+    Ok(filled)
 }
 
-/// Wrapper to signify that we've verified a given memory offset in the child
-/// as containing a syscall instruction we can use by manipulating the
-/// instruction pointer to point to it, putting the arguments in registers,
+/// Read the next frame and the command in it, checking the command against
+/// its checksum if it has one. `None` if the dump ends right before it.
+fn read_frame(inp: &mut dyn Read, framing: &mut Framing) -> Result<Option<RawFrame>> {
+    let mut frame = vec![0u8; frame_len()];
+    match read_up_to(inp, &mut frame)? {
+        0 => return Ok(None),
+        n if n < frame.len() => {
+            return Err(Box::new(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )))
+        }
+        _ => {}
+    }
+    let frame: Frame = bincode::deserialize(&frame)?;
+    let index = framing.frames;
+    framing.frames += 1;
+    if frame.len > MAX_FRAME_LEN {
+        let err = OversizedFrame {
+            frame: index,
+            len: frame.len,
+        };
+        tracing::error!("{}", err);
+        return Err(Box::new(err));
+    }
+    let expected = if framing.checksums {
+        let mut crc = [0u8; 4];
+        inp.read_exact(&mut crc)?;
+        Some(u32::from_le_bytes(crc))
+    } else {
+        None
+    };
+    let contents_len = if framing.contents_lengths {
+        let mut len = [0u8; 8];
+        inp.read_exact(&mut len)?;
+        Some(u64::from_le_bytes(len))
+    } else {
+        None
+    };
+    let mut command = vec![0u8; frame.len as usize];
+    inp.read_exact(&mut command)?;
+    if let Some(expected) = expected {
+        let actual = crc32fast::hash(&command);
+        if actual != expected {
+            let err = CorruptFrame {
+                frame: index,
+                len: frame.len,
+                expected,
+                actual,
+            };
+            tracing::error!("{}", err);
+            return Err(Box::new(err));
+        }
+    }
+    Ok(Some(RawFrame {
+        index,
+        optional: frame.optional,
+        command,
+        contents_len,
+    }))
+}
+
+/// Read the next command, in a `Frame` if the dump said they would be. An
+/// optional command we don't understand is from a newer version and gets
+/// skipped, one that isn't means we can't restore this dump.
+fn read_command(inp: &mut dyn Read, framing: &mut Framing) -> Result<Command> {
+    if !framing.framed {
+        return Ok(bincode::deserialize_from::<&mut dyn Read, Command>(inp)?);
+    }
+    loop {
+        let frame = match read_frame(inp, framing)? {
+            Some(frame) => frame,
+            None => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "the dump ended before the process was resumed",
+                )))
+            }
+        };
+        match bincode::deserialize::<Command>(&frame.command) {
+            Ok(comm) => match frame.contents_len {
+                Some(len) if len != comm.contents_len() as u64 => {
+                    return bad_stream(format!(
+                        "frame {} says {} bytes follow it but its command has {}",
+                        frame.index,
+                        len,
+                        comm.contents_len()
+                    ))
+                }
+                _ => return Ok(comm),
+            },
+            Err(e) if frame.optional => {
+                warn!(
+                    "skipping a {} byte command this version doesn't understand: {}",
+                    frame.command.len(),
+                    e
+                );
+                // Only dumps that say how much follows can have anything
+                // after an optional command
+                if let Some(len) = frame.contents_len {
+                    std::io::copy(&mut (&mut *inp).take(len), &mut std::io::sink())?;
+                }
+            }
+            Err(e) => {
+                tracing::error!(
+                    "couldn't read a {} byte command: {}",
+                    frame.command.len(),
+                    e
+                );
+                return Err(Box::new(Unsupported(
+                    "the dump needs something this version of telefork doesn't know how to restore"
+                        .to_string(),
+                )));
+            }
+        }
+    }
+}
+
+/// The last bytes of a dump written with `Config::index`, after the
+/// `DumpIndex` and its length as a little endian `u64`
+pub const INDEX_MAGIC: [u8; 4] = *b"TFIX";
+
+/// Where the commands in a dump are, written after it with `Config::index`.
+/// Dumps with one can be put one after another in a file and each found from
+/// the end with `read_indexes` without reading the ones after it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DumpIndex {
+    /// How long the dump is up to the index, so where it starts is this far
+    /// before the index
+    pub len: u64,
+    /// Where each command's frame starts from the start of the dump, by the
+    /// name of the command like `Mapping` or `FileDescriptors`
+    pub commands: std::collections::BTreeMap<String, Vec<u64>>,
+}
+
+/// The indexes of the dumps in `inp`, first to last, with where each one
+/// starts. Only the indexes are read, working back from the end, so every
+/// dump in it has to have been written with `Config::index`.
+pub fn read_indexes<R: Read + Seek>(inp: &mut R) -> Result<Vec<(u64, DumpIndex)>> {
+    use bincode::Options;
+
+    let footer = (8 + INDEX_MAGIC.len()) as u64;
+    let mut end = inp.seek(SeekFrom::End(0))?;
+    let mut indexes = Vec::new();
+    while end > 0 {
+        if end < footer {
+            return bad_stream("there are stray bytes before the first dump".to_string());
+        }
+        inp.seek(SeekFrom::Start(end - footer))?;
+        let mut tail = [0u8; 12];
+        inp.read_exact(&mut tail)?;
+        if tail[8..] != INDEX_MAGIC {
+            return bad_stream(format!(
+                "the dump ending at {} doesn't have an index after it",
+                end
+            ));
+        }
+        let len = u64::from_le_bytes(tail[..8].try_into().unwrap());
+        let at = match (end - footer).checked_sub(len) {
+            Some(at) => at,
+            None => return bad_stream("an index is longer than everything before it".to_string()),
+        };
+        inp.seek(SeekFrom::Start(at))?;
+        // Exactly `len` bytes, so a wrong length can't have it read into the
+        // footer or leave some of the index unread. The limit is so reading
+        // from the wrong place can't have it allocate whatever size it
+        // finds there, the same encoding as `bincode::deserialize` otherwise.
+        let mut bytes = (&mut *inp).take(len);
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(len);
+        let index: DumpIndex = match options.deserialize_from(&mut bytes) {
+            Ok(index) => index,
+            Err(e) => {
+                return bad_stream(format!(
+                    "couldn't read the {} byte index before {}: {}",
+                    len, end, e
+                ))
+            }
+        };
+        if bytes.limit() != 0 {
+            return bad_stream(format!(
+                "the index before {} is {} bytes but says it's {}",
+                end,
+                len - bytes.limit(),
+                len
+            ));
+        }
+        let start = match at.checked_sub(index.len) {
+            Some(start) => start,
+            None => {
+                return bad_stream(
+                    "an index's dump is longer than everything before it".to_string(),
+                )
+            }
+        };
+        indexes.push((start, index));
+        end = start;
+    }
+    indexes.reverse();
+    Ok(indexes)
+}
+
+/// Goes through a dump a frame at a time without making anything of the
+/// commands, for tools that only need to find their way around one. Reading
+/// from it reads the contents after the current frame, and whatever of them
+/// isn't read gets skipped by `next_frame`.
+///
+/// With `Config::contents_lengths` each frame says how much follows it, so
+/// any frame can be skipped even from a newer version. Without, how much
+/// follows is worked out from the command, which has to be one this version
+/// knows unless it's optional, which never has anything after it.
+pub struct FrameReader<R> {
+    inp: R,
+    framing: Framing,
+    /// What's left of the current frame's contents
+    contents: u64,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Read the header, failing for legacy dumps whose commands aren't
+    /// in frames
+    pub fn new(mut inp: R) -> Result<Self> {
+        let (framing, _) = read_header(&mut inp)?;
+        if !framing.framed {
+            return Err(Box::new(Unsupported(
+                "the dump is from before commands were framed".to_string(),
+            )));
+        }
+        Ok(FrameReader {
+            inp,
+            framing,
+            contents: 0,
+        })
+    }
+
+    /// The next frame, or `None` at the end of the dump
+    pub fn next_frame(&mut self) -> Result<Option<RawFrame>> {
+        let skipped = std::io::copy(
+            &mut (&mut self.inp).take(self.contents),
+            &mut std::io::sink(),
+        )?;
+        if skipped < self.contents {
+            return Err(Box::new(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        self.contents = 0;
+        let frame = match read_frame(&mut self.inp, &mut self.framing)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        self.contents = match frame.contents_len {
+            Some(len) => len,
+            None => match bincode::deserialize::<Command>(&frame.command) {
+                Ok(comm) => comm.contents_len() as u64,
+                Err(_) if frame.optional => 0,
+                Err(e) => {
+                    return Err(Box::new(Unsupported(format!(
+                        "can't tell how much follows frame {}, it has a command this version doesn't know: {}",
+                        frame.index, e
+                    ))))
+                }
+            },
+        };
+        Ok(Some(frame))
+    }
+
+    /// How much of the current frame's contents hasn't been read yet
+    pub fn remaining(&self) -> u64 {
+        self.contents
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inp
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.contents) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = self.inp.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        self.contents -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Most of the state is composed of memory mappings. They always go back at
+/// the same address, which is what keeps the absolute pointers in them
+/// right, like the ones the dynamic loader put in each object's GOT. Nothing
+/// relocates them, so restoring anywhere else would need that first.
+#[derive(Serialize, Deserialize, Debug)]
+struct Mapping {
+    name: Option<String>,
+    readable: bool,
+    writeable: bool,
+    executable: bool,
+    addr: usize,
+    size: usize,
+}
+
+impl Mapping {
+    /// Like `libc.so.6 at 7f12...` for messages
+    fn describe(&self) -> String {
+        format!(
+            "{} at {:x}",
+            self.name.as_deref().unwrap_or("anonymous mapping"),
+            self.addr
+        )
+    }
+
+    fn prot(&self) -> i32 {
+        let mut prot = 0;
+        if self.readable {
+            prot |= PROT_READ;
+        }
+        if self.writeable {
+            prot |= PROT_WRITE;
+        }
+        if self.executable {
+            prot |= PROT_EXEC;
+        }
+        prot
+    }
+}
+
+/// A file mapped into memory, for now only `MAP_SHARED` ones since writes to
+/// those have to keep going to the file, like a memory-mapped database, and
+/// the end of private ones that's past the end of the file.
+#[derive(Serialize, Deserialize, Debug)]
+struct FileMapping {
+    mapping: Mapping,
+    path: String,
+    /// Offset into the file the mapping starts at
+    offset: usize,
+    shared: bool,
+}
+
+/// A mapping of a System V (`shmat`) or POSIX (`shm_open`) shared memory
+/// segment. Copying these into private memory like any other mapping would
+/// quietly stop the restored process sharing them, so they get made into
+/// segments again, see `Config::reattach_shm`.
+#[derive(Serialize, Deserialize, Debug)]
+struct SharedMemory {
+    mapping: Mapping,
+    segment: ShmSegment,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum ShmSegment {
+    /// Shows up in the maps as `/SYSV<key in hex>`, with the shmid as inode
+    SysV {
+        key: i32,
+        shmid: i32,
+        mode: u32,
+        /// Marked for removal with `IPC_RMID`, so it goes away once the
+        /// last process detaches and nobody can attach it by key anymore
+        removed: bool,
+    },
+    /// A file in `/dev/shm`
+    Posix {
+        path: String,
+        offset: usize,
+        mode: u32,
+        /// `shm_unlink`ed while still mapped
+        unlinked: bool,
+    },
+    /// A file anywhere else on a tmpfs, like `/tmp` often is. It's memory
+    /// with a name as much as `/dev/shm` is and won't be on another machine,
+    /// but unlike those it's still used from where it is when it's here.
+    Tmpfs {
+        path: String,
+        offset: usize,
+        mode: u32,
+    },
+}
+
+/// Some state that we can safely and more easily read before forking
+#[derive(Serialize, Deserialize)]
+struct ProcessState {
+    brk_addr: usize,
+}
+
+/// Where the data segment and heap are, as the kernel keeps track of them
+/// for `brk`. `ProcessState` only has the break, and `teledump` filled that
+/// in with ours rather than the process's.
+#[derive(Serialize, Deserialize, Debug)]
+struct HeapLayout {
+    start_data: usize,
+    end_data: usize,
+    start_brk: usize,
+    /// The exact break, which can be partway through the last heap page
+    brk: usize,
+}
+
+/// Where the kernel thinks the code, data, heap, stack, arguments and
+/// environment are, which is what `/proc/pid/stat` shows and things like
+/// `brk` and core dumps go by. The restored process starts out with ours,
+/// from a different program entirely.
+#[derive(Serialize, Deserialize, Debug)]
+struct MmLayout {
+    start_code: usize,
+    end_code: usize,
+    start_data: usize,
+    end_data: usize,
+    start_brk: usize,
+    brk: usize,
+    start_stack: usize,
+    arg_start: usize,
+    arg_end: usize,
+    env_start: usize,
+    env_end: usize,
+    /// The auxiliary vector from `/proc/pid/auxv`, as pairs of words
+    auxv: Vec<u64>,
+}
+
+/// The process's user and group ids, each as real, effective and saved like
+/// `setresuid` takes them, from the `Uid`/`Gid` lines of `/proc/pid/status`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Credentials {
+    uid: [u32; 3],
+    gid: [u32; 3],
+    /// Supplementary groups
+    groups: Vec<u32>,
+}
+
+/// The blocked and pending signals of one thread, as bitmasks like
+/// `SignalDispositions`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ThreadSignals {
+    tid: i32,
+    blocked: u64,
+    pending: u64,
+}
+
+/// A signal's action as the kernel's `struct sigaction` has it, which is
+/// what `rt_sigaction` reads and writes rather than libc's version
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SignalAction {
+    sig: u32,
+    handler: u64,
+    flags: u64,
+    restorer: u64,
+    mask: u64,
+}
+
+impl SignalAction {
+    fn from_bytes(sig: u32, bytes: &[u8]) -> SignalAction {
+        let word = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        SignalAction {
+            sig,
+            handler: word(0),
+            flags: word(1),
+            restorer: word(2),
+            mask: word(3),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        let words = [self.handler, self.flags, self.restorer, self.mask];
+        for (chunk, word) in bytes.chunks_mut(8).zip(&words) {
+            chunk.copy_from_slice(&word.to_ne_bytes());
+        }
+        bytes
+    }
+}
+
+/// The signal mask belongs to each thread rather than the process, and so do
+/// some pending signals. Ones sent with `tgkill` wait for that thread, ones
+/// sent with `kill` wait for whichever thread doesn't block them first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SignalMasks {
+    /// The thread group leader first
+    threads: Vec<ThreadSignals>,
+    /// Pending for the whole process, `ShdPnd`
+    shared_pending: u64,
+}
+
+/// Each signal waiting to be delivered as a raw 128 byte `siginfo_t`, in the
+/// order they'd be delivered within each signal number. Only the leader's
+/// own queue is kept since it's the only thread restored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct QueuedSignals {
+    /// For the leader alone, like from `tgkill`
+    thread: Vec<Vec<u8>>,
+    /// For whichever thread takes it, like from `kill` or `sigqueue`
+    shared: Vec<Vec<u8>>,
+}
+
+/// How much CPU time a process had used, from `/proc/pid/stat`. The kernel
+/// has no way to set these, so a restored process starts again from nothing
+/// and `CLOCK_PROCESS_CPUTIME_ID` and `times` only count from the restore.
+/// Adding these on gets the total.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTimes {
+    pub user: std::time::Duration,
+    pub system: std::time::Duration,
+    /// Of the children it waited for
+    pub children_user: std::time::Duration,
+    pub children_system: std::time::Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Clocks {
+    cpu: CpuTimes,
+    /// `CLOCK_MONOTONIC` when it was dumped, which on another machine or
+    /// after a reboot could be ahead of the one it's restored on
+    monotonic: std::time::Duration,
+}
+
+/// The process's `sched_attr` from `sched_getattr`, which a restored process
+/// would otherwise swap for whatever `telepad` runs with. Most processes
+/// just have a nice value, real-time ones a policy and priority, and
+/// `SCHED_DEADLINE` ones the runtime they get every period and how soon
+/// after each period starts it has to have run by.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct Scheduling {
+    /// `SCHED_OTHER`, `SCHED_FIFO`, `SCHED_DEADLINE` and so on
+    policy: u32,
+    /// The `SCHED_FLAG_*` that are about the process rather than how the
+    /// call is made, like `SCHED_FLAG_RESET_ON_FORK`
+    flags: u64,
+    nice: i32,
+    /// For `SCHED_FIFO` and `SCHED_RR`
+    priority: u32,
+    /// For `SCHED_DEADLINE`, all in nanoseconds
+    runtime: u64,
+    deadline: u64,
+    period: u64,
+}
+
+// From linux/sched.h and linux/sched/types.h
+const SCHED_DEADLINE: u32 = 6;
+/// `SCHED_FLAG_RESET_ON_FORK`, `SCHED_FLAG_RECLAIM` and
+/// `SCHED_FLAG_DL_OVERRUN`. The rest only say which fields to use.
+const SCHED_FLAGS_KEPT: u64 = 0x7;
+/// `SCHED_ATTR_SIZE_VER0`, without the utilization clamps from later
+/// kernels
+const SCHED_ATTR_SIZE: usize = 48;
+
+impl Scheduling {
+    fn from_attr(attr: &[u8; SCHED_ATTR_SIZE]) -> Scheduling {
+        let u32_at = |i: usize| u32::from_ne_bytes(attr[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_ne_bytes(attr[i..i + 8].try_into().unwrap());
+        Scheduling {
+            policy: u32_at(4),
+            flags: u64_at(8) & SCHED_FLAGS_KEPT,
+            nice: u32_at(16) as i32,
+            priority: u32_at(20),
+            runtime: u64_at(24),
+            deadline: u64_at(32),
+            period: u64_at(40),
+        }
+    }
+
+    fn to_attr(self) -> [u8; SCHED_ATTR_SIZE] {
+        let mut attr = Vec::with_capacity(SCHED_ATTR_SIZE);
+        attr.extend_from_slice(&(SCHED_ATTR_SIZE as u32).to_ne_bytes());
+        attr.extend_from_slice(&self.policy.to_ne_bytes());
+        attr.extend_from_slice(&self.flags.to_ne_bytes());
+        attr.extend_from_slice(&self.nice.to_ne_bytes());
+        attr.extend_from_slice(&self.priority.to_ne_bytes());
+        attr.extend_from_slice(&self.runtime.to_ne_bytes());
+        attr.extend_from_slice(&self.deadline.to_ne_bytes());
+        attr.extend_from_slice(&self.period.to_ne_bytes());
+        attr.try_into().unwrap()
+    }
+
+    fn policy_name(&self) -> String {
+        match self.policy {
+            0 => "SCHED_OTHER".to_string(),
+            1 => "SCHED_FIFO".to_string(),
+            2 => "SCHED_RR".to_string(),
+            3 => "SCHED_BATCH".to_string(),
+            5 => "SCHED_IDLE".to_string(),
+            SCHED_DEADLINE => "SCHED_DEADLINE".to_string(),
+            other => format!("scheduling policy {}", other),
+        }
+    }
+}
+
+/// Where the process sees the root of the filesystem and which namespaces it
+/// lives in. The fd paths we record are resolved through this, so a process
+/// in a `chroot` has paths that only make sense relative to its root.
+#[derive(Serialize, Deserialize, Debug)]
+struct FsContext {
+    /// The process root as seen from outside, `/` unless it's chrooted
+    root: String,
+    /// Namespace name (`mnt`, `pid`, ...) to its `/proc/pid/ns` link like `mnt:[4026531840]`
+    namespaces: HashMap<String, String>,
+}
+
+/// Process attributes that live in the kernel and are set with `prctl`, so
+/// copying memory and registers doesn't bring them along.
+#[derive(Serialize, Deserialize, Debug)]
+struct PrctlState {
+    /// The thread name from `PR_SET_NAME`, also what `ps` shows
+    name: String,
+    timerslack_ns: u64,
+    child_subreaper: bool,
+    dumpable: bool,
+    no_new_privs: bool,
+    /// 0 for none, 1 for strict and 2 for filter mode
+    seccomp: u8,
+}
+
+/// The environment variables and where the block holding them is. The block
+/// itself is at the top of the stack so it comes along with the memory, but
+/// the kernel keeps its own pointers to it for `/proc/pid/environ` which
+/// would otherwise still point wherever the telepad process had its own.
+#[derive(Serialize, Deserialize, Debug)]
+struct Environment {
+    start: usize,
+    end: usize,
+    vars: Vec<String>,
+}
+
+/// A NUMA memory policy from `/proc/pid/numa_maps`, like the `bind:0-1` a
+/// compute process might put on its big arrays to keep them near its cores.
+#[derive(Serialize, Deserialize, Debug)]
+struct MemoryPolicy {
+    addr: usize,
+    size: usize,
+    /// One of the `MPOL_*` modes, as passed to `mbind`
+    mode: i32,
+    nodes: Vec<u32>,
+}
+
+/// The kinds of file lock in `/proc/locks`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum LockKind {
+    /// `fcntl(F_SETLK)`, owned by the process
+    Posix,
+    /// `flock`, owned by the open file
+    Flock,
+    /// `fcntl(F_OFD_SETLK)`, also owned by the open file
+    OpenFile,
+}
+
+/// A lock held on one of the process's files, like a daemon's pidfile lock
+/// that keeps a second copy from starting.
+#[derive(Serialize, Deserialize, Debug)]
+struct FileLock {
+    /// An fd the lock can be taken through again
+    fd: u32,
+    kind: LockKind,
+    write: bool,
+    /// The locked byte range, `end` is inclusive and `None` means to the end
+    /// of the file however long it gets. `flock` always locks everything.
+    start: u64,
+    end: Option<u64>,
+}
+
+/// Some maps are not safe/a good idea to serialize and teleport to the remote process, we try to remap them instead.
+/// Newer kernels put the vDSO's clock pages in a separate `[vvar_vclock]`
+/// mapping next to `[vvar]`.
+fn is_special_kernel_map(map: &proc_maps::MapRange) -> bool {
+    matches!(map.filename(), Some(n) if (n == "[vdso]" || n == "[vsyscall]" || n.starts_with("[vvar")))
+}
+
+/// It turns out that even remapping them doesn't work across different kernel
+/// builds so given a flag we can try teleporting them anyways. This worked in
+/// my experience in a case where the kernel version was the same but the
+/// builds/distros were different so remapping segfaulted.
+fn should_teleport_kernel_map_anyways(map: &proc_maps::MapRange, config: &Config) -> bool {
+    if !config.janky_vdso {
+        return false;
+    }
+    matches!(map.filename(), Some(n) if n == "[vdso]")
+}
+
+/// Shared mappings of real files, which we map again instead of copying.
+/// Shared anonymous memory shows up as a deleted `/dev/zero` and memfds as
+/// deleted `/memfd:` files, neither of which we could open again.
+fn is_shared_file_map(map: &proc_maps::MapRange) -> bool {
+    if map.flags.get(3..4) != Some("s") {
+        return false;
+    }
+    matches!(map.filename(), Some(n) if n.starts_with('/') && !n.ends_with(" (deleted)"))
+}
+
+/// How much of a private file mapping its file reaches, when that isn't all
+/// of it. Whole pages past the end of the file aren't memory at all,
+/// touching them is a SIGBUS and `process_vm_readv` can't read them.
+fn file_backed_len(pid: i32, map: &proc_maps::MapRange) -> Option<usize> {
+    if map.flags.get(3..4) == Some("s") {
+        return None;
+    }
+    let path = map.filename().as_deref().filter(|n| n.starts_with('/'))?;
+    // The mapping's own link is the file even if it's since been renamed
+    // or deleted, but it needs privileges the path doesn't
+    let own = format!(
+        "/proc/{}/map_files/{:x}-{:x}",
+        pid,
+        map.start(),
+        map.start() + map.size()
+    );
+    let len = std::fs::metadata(own)
+        .or_else(|_| std::fs::metadata(path))
+        .ok()?
+        .len() as usize;
+    let backed = len.saturating_sub(map.offset).next_multiple_of(PAGE_SIZE);
+    (backed < map.size()).then_some(backed)
+}
+
+/// Devices whose mappings are just memory, which we copy like any other
+const MEMORY_DEVICES: [&str; 1] = ["/dev/zero"];
+
+/// The device a mapping is of, when it's of a character device. GPU buffers
+/// mapped through `/dev/dri` or `/dev/nvidia*` and the like are the device's
+/// memory rather than the process's, there's nothing we could copy that would
+/// mean anything once the device isn't behind it.
+fn mapped_device(pid: i32, map: &proc_maps::MapRange) -> Option<String> {
+    let path = map
+        .filename()
+        .as_deref()
+        .filter(|n| n.starts_with('/') && !n.ends_with(" (deleted)"))?;
+    if MEMORY_DEVICES.contains(&path) {
+        return None;
+    }
+    let own = format!(
+        "/proc/{}/map_files/{:x}-{:x}",
+        pid,
+        map.start(),
+        map.start() + map.size()
+    );
+    let meta = std::fs::metadata(own)
+        .or_else(|_| std::fs::metadata(format!("/proc/{}/root{}", pid, path)))
+        .ok()?;
+    meta.file_type().is_char_device().then(|| path.to_string())
+}
+
+/// The starts of the mappings of devices, which go as zeroed memory with
+/// `Config::skip_device_maps` and otherwise fail the dump
+fn check_device_maps(
+    pid: i32,
+    maps: &[proc_maps::MapRange],
+    config: &Config,
+) -> Result<Vec<usize>> {
+    let mut devices = Vec::new();
+    for map in maps {
+        let device = match mapped_device(pid, map) {
+            Some(device) => device,
+            None => continue,
+        };
+        if !config.skip_device_maps {
+            let hint = if device.starts_with("/dev/nvidia") {
+                ", checkpoint its CUDA state first with --cuda so it lets go of the GPU"
+            } else {
+                ""
+            };
+            return Err(Box::new(Unsupported(format!(
+                "the mapping at {:x} is of the device {}, which can't be brought along{}. \
+                 Config::skip_device_maps dumps it as zeroes instead",
+                map.start(),
+                device,
+                hint
+            ))));
+        }
+        warn!(
+            "the {} bytes at {:x} are a mapping of {}, they're restored as zeroes \
+             the device knows nothing about and the process will break if it uses them",
+            map.size(),
+            map.start(),
+            device
+        );
+        devices.push(map.start());
+    }
+    Ok(devices)
+}
+
+/// Whether `path` as the process sees it is a regular file on a tmpfs. `/dev`
+/// is usually a tmpfs too, but its devices are anything but memory.
+fn is_tmpfs_file(pid: i32, path: &str) -> bool {
+    if path.ends_with(" (deleted)") {
+        return false;
+    }
+    let path = format!("/proc/{}/root{}", pid, path);
+    std::fs::metadata(&path).is_ok_and(|meta| meta.is_file())
+        && matches!(statfs(path.as_str()), Ok(fs) if fs.filesystem_type() == TMPFS_MAGIC)
+}
+
+/// Set in the mode `/proc/sysvipc/shm` shows once a segment has been removed
+const SHM_DEST: u32 = 0o1000;
+
+/// Whether `map` is a shared memory segment and which one. SysV ones are
+/// always shown as deleted since they live on an internal filesystem, POSIX
+/// ones only once they've been `shm_unlink`ed. Shared mappings of other
+/// files on a tmpfs count too.
+fn scan_shm_segment(pid: i32, map: &proc_maps::MapRange) -> Option<ShmSegment> {
+    if map.flags.get(3..4) != Some("s") {
+        return None;
+    }
+    let name = map.filename().as_deref()?;
+    if let Some(key) = name.strip_prefix("/SYSV") {
+        let key = u32::from_str_radix(key.get(..8)?, 16).ok()? as i32;
+        let shmid = map.inode as i32;
+        // Columns are key, shmid, perms in octal, then sizes and owners
+        let perms = std::fs::read_to_string("/proc/sysvipc/shm")
+            .unwrap_or_default()
+            .lines()
+            .skip(1)
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .find(|fields| fields.get(1).and_then(|id| id.parse().ok()) == Some(shmid))
+            .and_then(|fields| u32::from_str_radix(fields.get(2)?, 8).ok());
+        return Some(ShmSegment::SysV {
+            key,
+            shmid,
+            mode: perms.map_or(0o600, |p| p & 0o777),
+            // Not listed at all means it's gone too
+            removed: perms.is_none_or(|p| p & SHM_DEST != 0),
+        });
+    }
+    if !name.starts_with("/dev/shm/") && is_tmpfs_file(pid, name) {
+        let mode = std::fs::metadata(format!("/proc/{}/root{}", pid, name)).map_or(0o600, |meta| {
+            std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o777
+        });
+        return Some(ShmSegment::Tmpfs {
+            path: name.to_string(),
+            offset: map.offset,
+            mode,
+        });
+    }
+    if name.starts_with("/dev/shm/") {
+        let (path, unlinked) = match name.strip_suffix(" (deleted)") {
+            Some(path) => (path, true),
+            None => (name, false),
+        };
+        let mode = std::fs::metadata(format!("/proc/{}/root{}", pid, path)).map_or(0o600, |meta| {
+            std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o777
+        });
+        return Some(ShmSegment::Posix {
+            path: path.to_string(),
+            offset: map.offset,
+            mode,
+            unlinked,
+        });
+    }
+    None
+}
+
+fn should_skip_map(map: &proc_maps::MapRange) -> bool {
+    // TODO handle non-library read-only things by remapping as readable
+    // TODO or maybe preserve them without contents and map zero pages on rehydrate
+    if !map.is_read() || map.size() == 0 {
+        return true;
+    }
+    false
+}
+
+/// `/proc/pid/maps` isn't read atomically, so if the process changes its
+/// mappings while we're reading it can show the same region twice or two
+/// regions on top of each other. Restoring those with `MAP_FIXED` would
+/// have the later one silently clobber the earlier, so sort them out here
+/// where it's cheap. Exact duplicates are just dropped, anything else
+/// overlapping is an error since we can't tell which one is right.
+fn check_overlapping_maps(maps: &mut Vec<proc_maps::MapRange>) -> Result<()> {
+    maps.sort_by_key(|m| m.start());
+    maps.dedup();
+    for pair in maps.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        if a.start() + a.size() > b.start() {
+            tracing::error!(
+                "mappings overlap: {:x}-{:x} {:?} and {:x}-{:x} {:?}",
+                a.start(),
+                a.start() + a.size(),
+                a.filename(),
+                b.start(),
+                b.start() + b.size(),
+                b.filename()
+            );
+            return error("overlapping memory mappings, the process changed them while we looked?");
+        }
+    }
+    Ok(())
+}
+
+/// The stream doesn't make sense, as opposed to something going wrong while
+/// restoring it. Usually means the two ends disagree about the format or
+/// something wrote the wrong amount somewhere.
+#[derive(Debug)]
+pub struct BadStream(pub String);
+
+impl std::fmt::Display for BadStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "bad telefork stream: {}", self.0)
+    }
+}
+
+impl Error for BadStream {}
+
+fn bad_stream<T>(reason: String) -> Result<T> {
+    Err(Box::new(BadStream(reason)))
+}
+
+/// The dump is fine but this machine can't restore it, so there's no point
+/// trying again here.
+#[derive(Debug)]
+pub struct Unsupported(pub String);
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "can't restore here: {}", self.0)
+    }
+}
+
+impl Error for Unsupported {}
+
+/// The Yama security module's `kernel.yama.ptrace_scope` won't let us trace
+/// the process, which nothing short of changing the setting gets around.
+#[derive(Debug)]
+pub struct PtraceRestricted {
+    pub scope: u32,
+}
+
+impl std::fmt::Display for PtraceRestricted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "ptrace_scope={} prevents reading process memory; ",
+            self.scope
+        )?;
+        match self.scope {
+            1 => write!(
+                f,
+                "run as root, lower the setting or have the process allow us with prctl(PR_SET_PTRACER)"
+            ),
+            2 => write!(f, "run as root or lower the setting"),
+            _ => write!(f, "it can't be lowered again without a reboot"),
+        }
+    }
+}
+
+impl Error for PtraceRestricted {}
+
+/// The process already has a tracer, like gdb or strace, and a process can
+/// only have one so we can't attach until it lets go.
+#[derive(Debug)]
+pub struct AlreadyTraced {
+    pub pid: i32,
+    pub tracer: i32,
+    /// Its `comm`, if it's still there to ask
+    pub tracer_name: Option<String>,
+}
+
+impl std::fmt::Display for AlreadyTraced {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "process {} is already being traced by {}",
+            self.pid, self.tracer
+        )?;
+        if let Some(name) = &self.tracer_name {
+            write!(f, " ({})", name)?;
+        }
+        write!(
+            f,
+            ", detach that first since a process can only have one tracer"
+        )
+    }
+}
+
+impl Error for AlreadyTraced {}
+
+/// The pid given is one of a process's threads other than its leader.
+/// Only the leader gets restored, as the one thread of a process with its
+/// own pid, so dumping from any other thread would bring back the wrong one.
+#[derive(Debug)]
+pub struct NotLeader {
+    pub tid: i32,
+    /// The process it's a thread of, which is what to dump instead
+    pub tgid: i32,
+}
+
+impl std::fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is a thread of process {} rather than the process itself, dump {} instead",
+            self.tid, self.tgid, self.tgid
+        )
+    }
+}
+
+impl Error for NotLeader {}
+
+/// The process is partway through something it can't be dumped in the
+/// middle of, and didn't get through it within `Config::settle_timeout`
+#[derive(Debug)]
+pub struct TransientState {
+    pub pid: i32,
+    /// What it's in the middle of
+    pub reason: String,
+}
+
+impl std::fmt::Display for TransientState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} can't be dumped right now, {}", self.pid, self.reason)
+    }
+}
+
+impl Error for TransientState {}
+
+/// The restored process couldn't have the pid it was dumped with and
+/// `Config::same_pid` said it had to
+#[derive(Debug)]
+pub struct PidConflict {
+    pub pid: i32,
+    /// Why not, like something else here having it
+    pub reason: String,
+}
+
+impl std::fmt::Display for PidConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "couldn't restore the process as pid {}, {}",
+            self.pid, self.reason
+        )
+    }
+}
+
+impl Error for PidConflict {}
+
+/// A syscall we injected came back with `ENOSYS`, so the kernel here is too
+/// old or was built without it. Whatever needed it either got by without or
+/// this is what restoring failed with.
+#[derive(Debug)]
+pub struct MissingSyscall {
+    /// Its name, or its number if it's not one we know
+    pub syscall: String,
+    /// Roughly what kernel it needs
+    pub kernel_hint: &'static str,
+}
+
+impl MissingSyscall {
+    fn new(nr: u64) -> MissingSyscall {
+        let (syscall, kernel_hint) = match nr {
+            237 => ("mbind", "it needs a kernel built with NUMA support"),
+            294 => ("inotify_init1", "it needs Linux 2.6.27 or later"),
+            302 => ("prlimit64", "it needs Linux 2.6.36 or later"),
+            319 => ("memfd_create", "it needs Linux 3.17 or later"),
+            329 => ("pkey_mprotect", "it needs Linux 4.9 or later"),
+            330 => ("pkey_alloc", "it needs Linux 4.9 or later"),
+            331 => ("pkey_free", "it needs Linux 4.9 or later"),
+            424 => ("pidfd_send_signal", "it needs Linux 5.1 or later"),
+            434 => ("pidfd_open", "it needs Linux 5.3 or later"),
+            438 => ("pidfd_getfd", "it needs Linux 5.6 or later"),
+            _ => ("", "the kernel is probably too old or built without it"),
+        };
+        MissingSyscall {
+            syscall: match syscall {
+                "" => format!("syscall {}", nr),
+                name => name.to_string(),
+            },
+            kernel_hint,
+        }
+    }
+}
+
+impl std::fmt::Display for MissingSyscall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "can't restore here: this kernel doesn't have {}, {}",
+            self.syscall, self.kernel_hint
+        )
+    }
+}
+
+impl Error for MissingSyscall {}
+
+/// Whether something we injected failed because the kernel doesn't have it
+fn is_missing_syscall(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<MissingSyscall>().is_some()
+}
+
+/// Handy crappy utility to make it easier to raise custom errors. If this was for real I'd use the `anyhow` crate.
+fn error<T>(s: &'static str) -> Result<T> {
+    Err(Box::new(std::io::Error::other(s)))
+}
+
+/// We still need to record the expected location of special maps
+fn write_special_kernel_map(out: &mut dyn Write, map: &proc_maps::MapRange) -> Result<()> {
+    let comm = Command::Remap {
+        name: map
+            .filename()
+            .clone()
+            .expect("can't be a kernel map without a name"),
+        addr: map.start(),
+        size: map.size(),
+    };
+    write_command(out, &comm)?;
+    Ok(())
+}
+
+/// How much of the stack below the stack pointer we still stream, to cover
+/// the 128 byte red zone plus any signal frame the kernel might be about to
+/// push, which can be a few KB with the extended register state.
+const STACK_SAFETY_MARGIN: usize = 8 * PAGE_SIZE;
+
+/// The `[stack]` mapping can be tens of MB after some deep recursion but
+/// everything far enough below the stack pointer is dead. Returns how many
+/// bytes at the bottom of `map` we don't need to stream, which is 0 for
+/// anything other than the stack.
+fn dead_stack_size(map: &proc_maps::MapRange, rsp: usize) -> usize {
+    if map.filename().as_deref() != Some("[stack]") {
+        return 0;
+    }
+    // On a custom stack, where whatever switched to it might come back to
+    // anything in this one
+    if !map_contains(map, rsp) {
+        return 0;
+    }
+    let live_start = rsp.saturating_sub(STACK_SAFETY_MARGIN) & !(PAGE_SIZE - 1);
+    live_start.saturating_sub(map.start())
+}
+
+fn map_contains(map: &proc_maps::MapRange, addr: usize) -> bool {
+    map.start() <= addr && addr < map.start() + map.size()
+}
+
+/// Called with the address and contents of each page of memory on its way
+/// into the dump, see `TeleforkBuilder::transform_pages`
+pub(crate) type PageTransform<'a> = Box<dyn FnMut(usize, &mut [u8]) + 'a>;
+
+/// Callbacks that change what `teledump` sends, out of `Config` for the
+/// same reason as `RestoreHooks`
+#[derive(Default)]
+pub(crate) struct DumpHooks<'a> {
+    pub page_transform: Option<PageTransform<'a>>,
+}
+
+impl DumpHooks<'_> {
+    /// Run the page transform over `buf`, just read from `addr`
+    fn transform(&mut self, addr: usize, buf: &mut [u8]) {
+        if let Some(transform) = &mut self.page_transform {
+            transform(addr, buf);
+        }
+    }
+}
+
+/// Record a normal memory map's info and then stream its contents over the
+/// output channel, except for the first `skip` bytes. The contents are
+/// followed by a `MappingEnd` with how many bytes we really wrote.
+fn write_regular_map(
+    out: &mut dyn Write,
+    child: Pid,
+    map: &proc_maps::MapRange,
+    skip: usize,
+    unfaulted: &[(usize, usize)],
+    hooks: &mut DumpHooks,
+) -> Result<usize> {
+    let comm = match skip {
+        0 => Command::Mapping(describe_map(map)),
+        skip => Command::PartialMapping {
+            mapping: describe_map(map),
+            skip,
+        },
+    };
+    write_command(out, &comm)?;
+    let (start, size) = (map.start() + skip, map.size() - skip);
+    write_map_contents_in(out, child, start, size, PAGE_SIZE, unfaulted, hooks)
+}
+
+fn describe_map(map: &proc_maps::MapRange) -> Mapping {
+    Mapping {
+        name: map.filename().clone(),
+        readable: map.is_read(),
+        writeable: map.is_write(),
+        executable: map.is_exec(),
+        addr: map.start(),
+        size: map.size(),
+    }
+}
+
+/// Record a private file mapping that runs past the end of its file. The
+/// part the file reaches is streamed like any mapping, the rest goes as a
+/// `PastEof` with nothing to stream. Returns the bytes of contents written.
+fn write_past_eof_map(
+    out: &mut dyn Write,
+    child: Pid,
+    map: &proc_maps::MapRange,
+    backed: usize,
+    hooks: &mut DumpHooks,
+) -> Result<usize> {
+    let path = map.filename().clone().expect("file maps have a name");
+    info!(
+        "mapping of {} at {:x} runs {} bytes past the end of the file",
+        path,
+        map.start(),
+        map.size() - backed
+    );
+    let mut written = 0;
+    if backed > 0 {
+        let head = Mapping {
+            size: backed,
+            ..describe_map(map)
+        };
+        write_command(out, &Command::Mapping(head))?;
+        written = write_map_contents(out, child, map.start(), backed, hooks)?;
+    }
+    let comm = Command::PastEof(FileMapping {
+        mapping: Mapping {
+            addr: map.start() + backed,
+            size: map.size() - backed,
+            ..describe_map(map)
+        },
+        path,
+        offset: map.offset + backed,
+        shared: false,
+    });
+    write_command(out, &comm)?;
+    Ok(written)
+}
+
+/// Record a shared memory segment and stream its contents, which are only
+/// used if the restore makes a fresh segment
+fn write_shm_map(
+    out: &mut dyn Write,
+    child: Pid,
+    map: &proc_maps::MapRange,
+    segment: ShmSegment,
+    hooks: &mut DumpHooks,
+) -> Result<usize> {
+    info!(
+        "mapping at {:x} is a shared memory segment {:?}",
+        map.start(),
+        segment
+    );
+    let comm = Command::SharedMemory(SharedMemory {
+        mapping: describe_map(map),
+        segment,
+    });
+    write_command(out, &comm)?;
+    write_map_contents(out, child, map.start(), map.size(), hooks)
+}
+
+/// Huge pages are read this much at a time, a buffer the size of a whole
+/// 1 GB one wouldn't be any faster
+const HUGETLB_CHUNK: usize = 2 * 1024 * 1024;
+
+/// Record a mapping of huge pages and stream its contents in chunks of them
+fn write_hugetlb_map(
+    out: &mut dyn Write,
+    child: Pid,
+    map: &proc_maps::MapRange,
+    page_size: usize,
+    hooks: &mut DumpHooks,
+) -> Result<usize> {
+    info!(
+        "mapping at {:x} is made of {} kB huge pages",
+        map.start(),
+        page_size / 1024
+    );
+    let comm = Command::HugetlbMapping {
+        mapping: describe_map(map),
+        page_size,
+        shared: map.flags.get(3..4) == Some("s"),
+    };
+    write_command(out, &comm)?;
+    let chunk = page_size.min(HUGETLB_CHUNK);
+    write_map_contents_in(out, child, map.start(), map.size(), chunk, &[], hooks)
+}
+
+/// Stream `size` bytes of the child's memory at `start` over the output
+/// channel, followed by the `MappingEnd` and `MappingChecksum` for them
+fn write_map_contents(
+    out: &mut dyn Write,
+    child: Pid,
+    start: usize,
+    size: usize,
+    hooks: &mut DumpHooks,
+) -> Result<usize> {
+    write_map_contents_in(out, child, start, size, PAGE_SIZE, &[], hooks)
+}
+
+/// `write_map_contents` reading `chunk` bytes at a time, and sending zeroes
+/// for the `unfaulted` ranges of a userfaultfd region instead of reading
+/// them. Reading those would wait on a fault nobody's going to handle.
+fn write_map_contents_in(
+    out: &mut dyn Write,
+    child: Pid,
+    start: usize,
+    size: usize,
+    chunk: usize,
+    unfaulted: &[(usize, usize)],
+    hooks: &mut DumpHooks,
+) -> Result<usize> {
+    // === write contents to output channel a chunk at a time
+    let mut remaining_size = size;
+    let mut written = 0;
+    let mut crc = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; chunk];
+    while remaining_size > 0 {
+        let read_size = std::cmp::min(buf.len(), remaining_size);
+        let offset = start + (size - remaining_size);
+
+        let hole = unfaulted
+            .iter()
+            .any(|&(addr, len)| addr <= offset && offset + read_size <= addr + len);
+        let wrote = if hole {
+            buf[..read_size].fill(0);
+            read_size
+        } else {
+            // This is a rare special syscall to copy memory from another process
+            uio::process_vm_readv(
+                child,
+                &[uio::IoVec::from_mut_slice(&mut buf[..read_size])],
+                &[uio::RemoteIoVec {
+                    base: offset,
+                    len: read_size,
+                }],
+            )?
+        };
+        if wrote == 0 {
+            return error("failed to read from other process");
+        }
+        if !hole {
+            hooks.transform(offset, &mut buf[..wrote]);
+        }
+        // One of these per page adds up fast, so only at the trace level
+        trace!("read {} bytes at {:x}", wrote, offset);
+        // Only what we just read, the rest of the buffer is whatever the
+        // last chunk left there, and the reader only expects `size` bytes
+        crc.update(&buf[..wrote]);
+        out.write_all(&buf[..wrote])?;
+        written += wrote;
+        remaining_size -= wrote;
+    }
+    write_command(out, &Command::MappingEnd { written })?;
+    let crc32 = crc.finalize();
+    write_command(out, &Command::MappingChecksum { crc32 })?;
+
+    Ok(written)
+}
+
+/// Record a file mapping, the contents stay in the file
+fn write_file_map(out: &mut dyn Write, map: &proc_maps::MapRange) -> Result<()> {
+    let path = map.filename().clone().expect("file maps have a name");
+    info!("mapping of {} will be remapped from the file", path);
+    let comm = Command::FileMapping(FileMapping {
+        mapping: Mapping {
+            name: Some(path.clone()),
+            readable: map.is_read(),
+            writeable: map.is_write(),
+            executable: map.is_exec(),
+            addr: map.start(),
+            size: map.size(),
+        },
+        path,
+        offset: map.offset,
+        shared: true,
+    });
+    write_command(out, &comm)?;
+    Ok(())
+}
+
+/// Serialized registers
+///
+/// NOTE I think this might break if you use a different build of telefork on
+/// the destination that was compiled with a sufficiently different libc
+#[repr(C)]
+struct RegInfo {
+    pub regs: libc::user_regs_struct,
+}
+
+/// Be incredibly lazy with implementing proper serialization routines and
+/// just splat the raw bytes to and from the stream in a very unsafe
+/// non-Rust-y way because this is a tech demo I did for fun on a weekend.
+impl RegInfo {
+    fn to_bytes(&self) -> &[u8] {
+        let pointer = self as *const Self as *const u8;
+        unsafe { std::slice::from_raw_parts(pointer, std::mem::size_of::<Self>()) }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<&Self> {
+        if bytes.len() < std::mem::size_of::<Self>() {
+            return None;
+        }
+        if bytes.as_ptr().align_offset(std::mem::align_of::<Self>()) != 0 {
+            return None;
+        }
+        Some(unsafe { &*(bytes.as_ptr() as *const Self) })
+    }
+}
+
+// Kernel-internal return values a syscall interrupted by a signal (or by us
+// attaching with ptrace) can be left with in rax. Normally the kernel turns
+// these into either a restart or EINTR on the way back to userspace, but the
+// restored process never gets that far in the kernel so we handle it ourselves.
+const ERESTARTSYS: i64 = 512;
+const ERESTARTNOINTR: i64 = 513;
+const ERESTARTNOHAND: i64 = 514;
+const ERESTART_RESTARTBLOCK: i64 = 516;
+
+/// The syscalls event loops spend nearly all their time parked in. These are
+/// by far the most likely place to catch a server when dumping it.
+const WAIT_SYSCALLS: &[(u64, &str)] = &[
+    (7, "poll"),
+    (23, "select"),
+    (232, "epoll_wait"),
+    (270, "pselect6"),
+    (271, "ppoll"),
+    (281, "epoll_pwait"),
+    (441, "epoll_pwait2"),
+];
+
+/// If the process was stopped inside a syscall, move the instruction pointer
+/// back onto the 2 byte `syscall` instruction and put the syscall number back
+/// in rax, so it'll just be issued again when the restored process resumes.
+/// Returns the syscall number if we rewound.
+///
+/// The other arguments are still sitting in their registers, and pointer
+/// arguments (like the `pollfd` array or `epoll_event` buffer) point into
+/// stack or heap memory that we restore at the same address, so re-issuing
+/// is enough. Timeouts restart from their full original value rather than
+/// what was left, which seems like the right tradeoff for an event loop.
+///
+/// For `ERESTART_RESTARTBLOCK` the kernel would normally use the special
+/// `restart_syscall` with state it kept internally, which doesn't exist in
+/// the restored process, so we re-issue the original syscall instead.
+fn rewind_interrupted_syscall(regs: &mut libc::user_regs_struct) -> Option<u64> {
+    let ret = regs.rax as i64;
+    let nr = regs.orig_rax as i64;
+    if nr < 0 {
+        return None;
+    }
+    match -ret {
+        ERESTARTSYS | ERESTARTNOINTR | ERESTARTNOHAND | ERESTART_RESTARTBLOCK => {}
+        _ => return None,
+    }
+    regs.rip -= 2;
+    regs.rax = nr as u64;
+    Some(nr as u64)
+}
+
+/// Undo `rewind_interrupted_syscall` and have the syscall return `EINTR`
+/// instead, as if a signal had interrupted it. For when re-issuing it can't
+/// work.
+fn interrupt_rewound_syscall(regs: &mut libc::user_regs_struct) {
+    regs.rip += 2;
+    regs.rax = -(libc::EINTR as i64) as u64;
+}
+
+fn wait_syscall_name(nr: u64) -> Option<&'static str> {
+    WAIT_SYSCALLS
+        .iter()
+        .find(|(n, _)| *n == nr)
+        .map(|(_, name)| *name)
+}
+
+/// Leave out mappings we can't send and sort the rest into the special
+/// kernel ones we only record the location of and the regular ones
+fn split_maps(
+    maps: Vec<proc_maps::MapRange>,
+    config: &Config,
+) -> Result<(Vec<proc_maps::MapRange>, Vec<proc_maps::MapRange>)> {
+    let mut maps: Vec<_> = maps.into_iter().filter(|m| !should_skip_map(m)).collect();
+    check_overlapping_maps(&mut maps)?;
+    Ok(maps
+        .into_iter()
+        .partition(|m| is_special_kernel_map(m) && !should_teleport_kernel_map_anyways(m, config)))
+}
+
+/// The top of the highest mapping, for `Command::AddressSpace`
+fn highest_address(maps: &[proc_maps::MapRange]) -> usize {
+    maps.iter()
+        .filter(|m| m.filename().as_deref() != Some("[vsyscall]"))
+        .map(|m| m.start() + m.size())
+        .max()
+        .unwrap_or(0)
+}
+
+/// The pid `pid` has as it sees it, the last of its `NSpid`, which isn't
+/// ours for it when it's in a pid namespace of its own
+fn namespace_pid(pid: i32) -> i32 {
+    read_status_field(pid, "NSpid")
+        .ok()
+        .and_then(|nspid| nspid.split_whitespace().last()?.parse().ok())
+        .unwrap_or(pid)
+}
+
+/// Everything that has to come before the regular mappings: the pid of the
+/// `original` process, how much address space they need, the brk, the
+/// cgroup to charge them to, and where the special kernel maps go
+fn write_layout(
+    out: &mut dyn Write,
+    original: Pid,
+    highest: usize,
+    proc_state: ProcessState,
+    special_maps: &[proc_maps::MapRange],
+    config: &Config,
+) -> Result<()> {
+    write_header(out, header_flags(config))?;
+    write_command(out, &Command::Pid(namespace_pid(original.as_raw())))?;
+    write_command(out, &Command::AddressSpace { highest })?;
+    write_command(out, &Command::CheckedMappings)?;
+    write_command(out, &Command::ProcessState(proc_state))?;
+    match scan_cgroup(original.as_raw()) {
+        Ok(Some(cgroup)) => write_command(out, &Command::Cgroup(cgroup))?,
+        Ok(None) => {}
+        Err(e) => warn!("couldn't read the process's cgroup: {}", e),
+    }
+
+    // Every process on this kernel has the same one as us
+    match vdso::ours() {
+        Ok(image) => write_command(out, &Command::Vdso(image))?,
+        Err(e) => warn!(
+            "couldn't read the vDSO, telepad won't know if its own fits: {}",
+            e
+        ),
+    }
+    // we write out special kernel maps like the vdso first so that we can remap them
+    // to their correct position before some other regular map perhaps stomps on their
+    // original position.
+    for map in special_maps {
+        write_special_kernel_map(out, map)?;
+    }
+    Ok(())
+}
+
+/// What's already been sent by the time `write_state` runs
+enum Sent {
+    /// Nothing, it's an ordinary dump
+    Nothing(ProcessState),
+    /// The layout and copies of the mappings taken while the process was
+    /// still running, see `precopy`
+    Precopy(precopy::Precopy),
+}
+
+/// Write out each piece of state in the ideal order using the above functions
+///
+/// `lock_owner` is the process whose POSIX file locks, CPU time and pid
+/// we're bringing along. When teleforking that's us rather than the frozen
+/// child, since those locks don't get inherited over `fork`, the child has
+/// only just started using CPU, and any pid in the memory is ours.
+fn write_state(
+    out: &mut dyn Write,
+    child: Pid,
+    lock_owner: Pid,
+    sent: Sent,
+    config: &Config,
+    hooks: &mut DumpHooks,
+) -> Result<TeleforkStats> {
+    let mut stats = TeleforkStats::default();
+    // Injecting the prctl queries can map a temporary page, so get that
+    // over with before we look at the maps
+    let prctl = match scan_prctl_state(child) {
+        Ok(prctl) => Some(prctl),
+        Err(e) => {
+            warn!(
+                "couldn't read prctl attributes, they won't be restored: {}",
+                e
+            );
+            None
+        }
+    };
+
+    // This one maps a temporary page too
+    let mlockall = match scan_mlockall(child) {
+        Ok(flags) => flags,
+        Err(e) => {
+            warn!("couldn't tell if the process used mlockall: {}", e);
+            0
+        }
+    };
+
+    // And this one
+    let signal_actions = match scan_signal_actions(child) {
+        Ok(actions) => Some(actions),
+        Err(e) => {
+            warn!(
+                "couldn't read the process's signal actions, its handlers won't be restored: {}",
+                e
+            );
+            None
+        }
+    };
+
+    // And this one too, for a teledump
+    let real_timer = match scan_real_timer(child, lock_owner) {
+        Ok(timer) => timer,
+        Err(e) => {
+            warn!(
+                "couldn't read the process's alarm, it won't be restored: {}",
+                e
+            );
+            None
+        }
+    };
+
+    // Forking doesn't carry registrations over, so they're read from the
+    // original like the locks
+    let userfaults = scan_userfault_regions(lock_owner.as_raw())?;
+    check_userfaultfds(child.as_raw(), &userfaults)?;
+
+    let phase = std::time::Instant::now();
+    let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
+    // _print_maps_info(&maps);
+
+    let highest = highest_address(&maps);
+    let (special_maps, regular_maps) = split_maps(maps, config)?;
+    let devices = check_device_maps(child.as_raw(), &regular_maps, config)?;
+    stats.phases.map_enumeration = phase.elapsed();
+    let phase = std::time::Instant::now();
+    let precopy = match sent {
+        Sent::Nothing(proc_state) => {
+            write_layout(out, lock_owner, highest, proc_state, &special_maps, config)?;
+            None
+        }
+        Sent::Precopy(precopy) => {
+            // The layout went out with the first pass
+            precopy.write_unmapped(out, &regular_maps)?;
+            stats.memory_bytes += precopy.memory_bytes;
+            Some(precopy)
+        }
+    };
+    stats.mappings = special_maps.len() + regular_maps.len();
+    let rsp = ptrace::getregs(child)?.rsp as usize;
+    // Runtimes with green threads or their own `clone` stacks can be running
+    // somewhere other than `[stack]`. That's fine since every mapping comes
+    // back at the same address without any grow-down magic, as long as it's
+    // one we restore at all.
+    match regular_maps.iter().find(|m| map_contains(m, rsp)) {
+        Some(m) if m.filename().as_deref() != Some("[stack]") => info!(
+            "the stack pointer {:x} is on a custom stack in the mapping at {:x}",
+            rsp,
+            m.start()
+        ),
+        Some(_) => {}
+        None => {
+            tracing::error!(
+                "the stack pointer {:x} isn't in any mapping we restore",
+                rsp
+            );
+            return error("the process's stack isn't somewhere we can restore it");
+        }
+    }
+    let mut policies = scan_memory_policies(child.as_raw());
+    let huge = scan_huge_page_maps(child.as_raw());
+    let hugetlb = scan_hugetlb_maps(child.as_raw());
+    for (addr, size) in scan_relro_regions(child, &regular_maps) {
+        let writable = regular_maps
+            .iter()
+            .any(|m| m.is_write() && m.start() < addr + size && addr < m.start() + m.size());
+        if writable {
+            warn!(
+                "RELRO region at {:x} is still writable, the dynamic loader probably hadn't finished with it",
+                addr
+            );
+        } else {
+            tracing::debug!(
+                "RELRO region of {} bytes at {:x} goes back read-only",
+                size,
+                addr
+            );
+        }
+    }
+    for map in &regular_maps {
+        if let Some(segment) = scan_shm_segment(child.as_raw(), map) {
+            stats.memory_bytes += write_shm_map(out, child, map, segment, hooks)?;
+        } else if devices.contains(&map.start()) {
+            // All of it skipped, so just a reservation of zeroes
+            write_regular_map(out, child, map, map.size(), &[], hooks)?;
+        } else if is_shared_file_map(map) {
+            write_file_map(out, map)?;
+        } else if let Some(&page_size) = hugetlb.get(&map.start()) {
+            // Even if precopy sent it already, the dirty page tracking only
+            // knows about base pages
+            stats.memory_bytes += write_hugetlb_map(out, child, map, page_size, hooks)?;
+        } else if let Some(precopy) = precopy.as_ref().filter(|p| p.has_current(map)) {
+            stats.memory_bytes += precopy.write_dirty_pages(out, child, map, hooks)?;
+        } else if let Some(backed) = file_backed_len(child.as_raw(), map) {
+            stats.memory_bytes += write_past_eof_map(out, child, map, backed, hooks)?;
+        } else {
+            let skip = dead_stack_size(map, rsp);
+            if skip > 0 {
+                info!("skipping {} dead bytes below the stack pointer", skip);
+            }
+            let unfaulted = userfaults
+                .iter()
+                .find(|r| r.addr == map.start())
+                .map_or(&[][..], |r| &r.missing[..]);
+            stats.memory_bytes += write_regular_map(out, child, map, skip, unfaulted, hooks)?;
+        }
+        if let Some((mode, nodes)) = policies.remove(&map.start()) {
+            let policy = MemoryPolicy {
+                addr: map.start(),
+                size: map.size(),
+                mode,
+                nodes,
+            };
+            write_command(out, &Command::MemoryPolicy(policy))?;
+        }
+        if huge.contains(&map.start()) {
+            let (addr, size) = (map.start(), map.size());
+            write_command(out, &Command::HugePages { addr, size })?;
+        }
+    }
+
+    stats.phases.memory_stream = phase.elapsed();
+
+    match scan_heap_layout(child) {
+        Ok(heap) => {
+            match scan_mm_layout(child.as_raw(), &heap) {
+                Ok(mm) => write_command(out, &Command::MmLayout(mm))?,
+                Err(e) => warn!("couldn't read the memory layout: {}", e),
+            }
+            write_command(out, &Command::Heap(heap))?
+        }
+        Err(e) => warn!(
+            "couldn't read the heap layout, brk won't be restored: {}",
+            e
+        ),
+    }
+
+    // === Write file descriptors, along with the root they're relative to
+    let fs = scan_fs_context(child.as_raw())?;
+    write_command(out, &Command::FsContext(fs))?;
+    match scan_cwd(child.as_raw()) {
+        Ok(cwd) => write_command(out, &Command::Cwd(cwd))?,
+        Err(e) => warn!("couldn't read the process's working directory: {}", e),
+    }
+    match scan_umask(child.as_raw()) {
+        Ok(umask) => write_command(out, &Command::Umask(umask))?,
+        Err(e) => warn!(
+            "couldn't read the process's umask, it'll have telepad's: {}",
+            e
+        ),
+    }
+    if mlockall != 0 {
+        write_command(out, &Command::MlockAll { flags: mlockall })?;
+    }
+    // Like the CPU times it's the original's, a real-time one can have
+    // `SCHED_FLAG_RESET_ON_FORK` and a deadline one can't fork without it
+    match scan_scheduling(lock_owner.as_raw()) {
+        Ok(sched) => write_command(out, &Command::Scheduling(sched))?,
+        Err(e) => warn!(
+            "couldn't read the scheduling policy, it won't be restored: {}",
+            e
+        ),
+    }
+    match scan_credentials(child.as_raw()) {
+        Ok(creds) => write_command(out, &Command::Credentials(creds))?,
+        Err(e) => warn!("couldn't read uids and gids, they won't be restored: {}", e),
+    }
+    if let Some((remaining, interval)) = real_timer {
+        write_command(
+            out,
+            &Command::RealTimer {
+                remaining,
+                interval,
+            },
+        )?;
+    }
+    match scan_fs_ids(child.as_raw()) {
+        Ok(Some((uid, gid))) => write_command(out, &Command::FsIds { uid, gid })?,
+        Ok(None) => {}
+        Err(e) => warn!("couldn't read the fsuid and fsgid: {}", e),
+    }
+    match scan_environment(child.as_raw()) {
+        Ok(env) => write_command(out, &Command::Environment(env))?,
+        Err(e) => warn!("couldn't read the environment, it won't be restored: {}", e),
+    }
+    match scan_cpu_times(lock_owner.as_raw()) {
+        Ok(cpu) => {
+            stats.cpu = Some(cpu);
+            let clocks = Clocks {
+                cpu,
+                monotonic: monotonic_now(),
+            };
+            write_command(out, &Command::Clocks(clocks))?;
+        }
+        Err(e) => warn!("couldn't read CPU times, they won't be reported: {}", e),
+    }
+    let phase = std::time::Instant::now();
+    let mut cm = scan_file_descriptors(child.as_raw())?;
+    bundle_files(child.as_raw(), &mut cm, config);
+    if lock_owner != child {
+        attach_userfault_regions(&mut cm, userfaults);
+    }
+    stats.phases.fd_scan = phase.elapsed();
+
+    // === Write registers, first checking if we caught it in the middle of a syscall
+    let phase = std::time::Instant::now();
+    let mut regs = RegInfo {
+        regs: ptrace::getregs(child)?,
+    };
+    let mut rewound = rewind_interrupted_syscall(&mut regs.regs);
+    stats.phases.register_capture = phase.elapsed();
+    if let Some(nr) = rewound {
+        // The first argument of the epoll calls is the epoll fd, and a
+        // re-issued wait would just fail with EBADF if we can't bring it back
+        let waited_fd = regs.regs.rdi as u32;
+        let name = wait_syscall_name(nr);
+        if name.is_some_and(|name| name.starts_with("epoll"))
+            && !cm.get(&waited_fd).is_some_and(|c| c.restorable())
+        {
+            warn!(
+                "process is blocked in {} on fd {} which can't be restored, the wait will return EINTR instead of being re-issued",
+                name.unwrap_or_default(),
+                waited_fd
+            );
+            interrupt_rewound_syscall(&mut regs.regs);
+            rewound = None;
+        }
+    }
+    if let Some(nr) = rewound {
+        if let Some(name) = wait_syscall_name(nr) {
+            info!(
+                "process is blocked in {}, it'll be re-issued on restore",
+                name
+            );
+        } else {
+            info!(
+                "process was interrupted in syscall {}, it'll be re-issued on restore",
+                nr
+            );
+        }
+        write_command(out, &Command::RestartSyscall { nr })?;
+    }
+    let phase = std::time::Instant::now();
+    let locks = scan_file_locks(child.as_raw(), lock_owner.as_raw(), &cm)?;
+    let open_flags = scan_open_flags(child.as_raw(), &cm);
+    stats.phases.fd_scan += phase.elapsed();
+    if !open_flags.is_empty() {
+        write_command(out, &Command::OpenFlags(open_flags))?;
+    }
+    stats.fds = cm.len();
+    write_command(out, &Command::FileDescriptors(cm))?;
+    if !locks.is_empty() {
+        write_command(out, &Command::FileLocks(locks))?;
+    }
+
+    // === Write signals and the prctl state, which go in once the fds are open
+    if let Some(prctl) = prctl {
+        write_command(out, &Command::PrctlState(prctl))?;
+    }
+    let (ignored, caught) = scan_signal_dispositions(child.as_raw())?;
+    write_command(out, &Command::SignalDispositions { ignored, caught })?;
+    if let Some(actions) = signal_actions {
+        write_command(out, &Command::SignalActions(actions))?;
+    }
+    match scan_signal_masks(child.as_raw()) {
+        Ok(masks) => write_command(out, &Command::SignalMasks(masks))?,
+        Err(e) => warn!("couldn't read signal masks, they won't be restored: {}", e),
+    }
+    match scan_queued_signals(child) {
+        Ok(queued) if queued.thread.is_empty() && queued.shared.is_empty() => {}
+        Ok(queued) => write_command(out, &Command::QueuedSignals(queued))?,
+        Err(e) => warn!(
+            "couldn't read queued signals, only one of each will be restored: {}",
+            e
+        ),
+    }
+
+    let reg_bytes = regs.to_bytes();
+    write_command(
+        out,
+        &Command::ResumeWithRegisters {
+            len: reg_bytes.len(),
+        },
+    )?;
+    out.write_all(reg_bytes)?;
+
+    Ok(stats)
+}
+
+/// Some numbers about a dump, for reporting
+#[derive(Debug, Clone, Default)]
+pub struct TeleforkStats {
+    /// How many memory mappings it has, including ones sent without contents
+    pub mappings: usize,
+    /// The bytes of memory contents in it
+    pub memory_bytes: usize,
+    pub fds: usize,
+    /// How long `teledump` had the process stopped for
+    pub frozen: std::time::Duration,
+    pub phases: PhaseTimes,
+    /// The CPU time it had used by the time it was dumped
+    pub cpu: Option<CpuTimes>,
+}
+
+/// Where the time went while writing out the stopped process, to see what
+/// an optimization actually bought. Anything not listed, like the smaller
+/// bits of state between phases, isn't counted in any of them.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseTimes {
+    /// Reading `/proc/pid/maps` and sorting out which mappings to send how
+    pub map_enumeration: std::time::Duration,
+    /// Sending the contents of the mappings, the bulk of it usually
+    pub memory_stream: std::time::Duration,
+    /// Looking at the open files, bundling the ones that get bundled and
+    /// finding their locks and flags
+    pub fd_scan: std::time::Duration,
+    /// Reading the registers and checking for an interrupted syscall
+    pub register_capture: std::time::Duration,
+}
+
+/// What a dump of a process would come to, from `estimate_dump_size`
+#[derive(Debug, Clone, Default)]
+pub struct DumpEstimate {
+    /// How many memory mappings it'd have, like `TeleforkStats::mappings`
+    pub mappings: usize,
+    /// The bytes of memory contents that would be streamed, which is what
+    /// `TeleforkStats::memory_bytes` comes to unless it changes first
+    pub total_bytes: usize,
+    /// How much of that is in memory or swapped out rather than never
+    /// touched, so what a dump could shrink to if it left out untouched pages
+    pub resident_bytes: usize,
+}
+
+/// Work out how big a dump of `pid` would be without stopping it, going
+/// over its mappings the way `write_state` does. Only the memory contents
+/// are counted, the commands and bundled files on top of them are usually
+/// small next to them.
+pub fn estimate_dump_size(pid: i32) -> Result<DumpEstimate> {
+    let maps = proc_maps::get_process_maps(pid as proc_maps::Pid)?;
+    let (special_maps, regular_maps) = split_maps(maps, &Config::default())?;
+    // It isn't stopped, so this is wherever it last was in a syscall, close
+    // enough for the dead part of the stack
+    let rsp = read_stack_pointer(pid);
+    let hugetlb = scan_hugetlb_maps(pid);
+    let mut estimate = DumpEstimate {
+        mappings: special_maps.len() + regular_maps.len(),
+        ..DumpEstimate::default()
+    };
+    for map in &regular_maps {
+        let (start, size) = if scan_shm_segment(pid, map).is_some() {
+            (map.start(), map.size())
+        } else if is_shared_file_map(map) || mapped_device(pid, map).is_some() {
+            continue;
+        } else if hugetlb.contains_key(&map.start()) {
+            (map.start(), map.size())
+        } else if let Some(backed) = file_backed_len(pid, map) {
+            (map.start(), backed)
+        } else {
+            let skip = rsp.map_or(0, |rsp| dead_stack_size(map, rsp));
+            (map.start() + skip, map.size() - skip)
+        };
+        estimate.total_bytes += size;
+        estimate.resident_bytes += match missing_pages(pid, start, size) {
+            Ok(missing) => size - missing.iter().map(|(_, len)| len).sum::<usize>(),
+            // Without pagemap all of it might be
+            Err(_) => size,
+        };
+    }
+    Ok(estimate)
+}
+
+/// The stack pointer in `/proc/pid/syscall`, which is there whenever the
+/// process is blocked, in a syscall or not, but not while it's running
+fn read_stack_pointer(pid: i32) -> Option<usize> {
+    let syscall = std::fs::read_to_string(format!("/proc/{}/syscall", pid)).ok()?;
+    let fields: Vec<&str> = syscall.split_whitespace().collect();
+    if fields.len() < 3 {
+        return None;
+    }
+    let sp = fields[fields.len() - 2];
+    usize::from_str_radix(sp.trim_start_matches("0x"), 16).ok()
+}
+
+// === Child process manipulation utilities
+//
+// In order to restore the serialized process we need various tools to mold an
+// existing process into a rehydrated clone of the serialized process.
+
+/// Print a representation of the mappings of a process
+///
+/// Prefixed with an underscore because I normally don't use this, but I often
+/// uncomment a line that does use it for debugging.
+fn _print_maps_info(maps: &[proc_maps::MapRange]) {
+    for map in maps {
+        println!(
+            "{:>7} {:>16x} {} {:?}",
+            map.size(),
+            map.start(),
+            map.flags,
+            map.filename()
+        );
+        // println!("{:?}", map);
+    }
+    let total_size: usize = maps.iter().map(|m| m.size()).sum();
+    println!("{:>7} total", total_size);
+}
+
+/// Advance the child process by one instruction. This is used to execute
+/// syscall instructions in the child process.
+fn single_step(child: Pid) -> Result<()> {
+    ptrace::step(child, None)?;
+    match waitpid(child, None)? {
+        WaitStatus::Stopped(_, Signal::SIGTRAP) => Ok(()),
+        err => {
+            tracing::error!("waitpid error = {:?}", err);
+            error("couldn't single step child")
+        }
+    }
+}
+
+/// Wrapper to signify that we've verified a given memory offset in the child
+/// as containing a syscall instruction we can use by manipulating the
+/// instruction pointer to point to it, putting the arguments in registers,
 /// and then single stepping.
 #[derive(Copy, Clone)]
-struct SyscallLoc(u64);
+struct SyscallLoc {
+    addr: u64,
+    /// Base of the `Scratch` region to use for arguments and as the stack
+    /// while we inject syscalls. `None` means we just leave `rsp` wherever it
+    /// was and map temporary pages for arguments.
+    scratch: Option<usize>,
+}
+
+impl SyscallLoc {
+    fn new(addr: u64) -> Self {
+        SyscallLoc {
+            addr,
+            scratch: None,
+        }
+    }
+
+    fn stack_top(&self) -> Option<u64> {
+        self.scratch.map(|base| (base + SCRATCH_SIZE - 128) as u64)
+    }
+}
+
+/// We find these syscalls by searching for an existing syscall instruction
+/// in the child process. Returns the address of the first one in `map`, if
+/// any. The vDSO always seems to have one somewhere, though not always in
+/// its first page.
+fn try_to_find_syscall(child: Pid, map: &proc_maps::MapRange) -> Result<Option<usize>> {
+    let mut buf = vec![0u8; map.size()];
+    let wrote = uio::process_vm_readv(
+        child,
+        &[uio::IoVec::from_mut_slice(&mut buf[..])],
+        &[uio::RemoteIoVec {
+            base: map.start(),
+            len: map.size(),
+        }],
+    )?;
+    if wrote == 0 {
+        return error("failed to read from other process");
+    }
+
+    let syscall = &[0x0f, 0x05];
+    Ok(buf[..wrote]
+        .windows(syscall.len())
+        .position(|w| w == syscall)
+        .map(|index| map.start() + index))
+}
+
+/// Find a syscall instruction anywhere we can jump to in a process we're
+/// not about to hollow out, the vDSO by preference. Otherwise any other
+/// executable mapping will do: the CPU decodes `0f 05` as `syscall` when we
+/// jump straight to it even if it's really the middle of some other
+/// instruction. The same bytes in memory that isn't executable would just
+/// fault, so we never look there.
+fn find_syscall(child: Pid, maps: &[proc_maps::MapRange]) -> Result<usize> {
+    let vdso = find_map_named(maps, "[vdso]");
+    let executable = maps.iter().filter(|m| {
+        m.is_exec()
+            && m.is_read()
+            && !is_special_kernel_map(m)
+            && m.start() != vdso.map_or(0, |v| v.start())
+    });
+    for map in vdso.into_iter().chain(executable) {
+        match try_to_find_syscall(child, map) {
+            Ok(Some(addr)) => {
+                if vdso.is_none_or(|v| v.start() != map.start()) {
+                    info!(
+                        "no syscall instruction in the vdso, using one in {:?} at {:x}",
+                        map.filename(),
+                        addr
+                    );
+                }
+                return Ok(addr);
+            }
+            Ok(None) => {}
+            // Something like a mapping going away while we look
+            Err(e) => tracing::debug!("couldn't search {:x} for a syscall: {}", map.start(), e),
+        }
+    }
+    error("couldn't find a syscall instruction to inject syscalls with")
+}
+
+/// For `telepad`, where everything but the vDSO gets unmapped, so a syscall
+/// instruction has to be in there. If it doesn't have one we write one into
+/// the zero padding at the end of its last page, which ptrace can do even
+/// though it's not writeable, the same way debuggers set breakpoints.
+/// Returns its offset into the vDSO.
+fn vdso_syscall_offset(child: Pid, vdso: &proc_maps::MapRange) -> Result<usize> {
+    if let Some(addr) = try_to_find_syscall(child, vdso)? {
+        return Ok(addr - vdso.start());
+    }
+    let word = vdso.start() + vdso.size() - std::mem::size_of::<libc::c_long>();
+    if ptrace::read(child, word as ptrace::AddressType)? != 0 {
+        return error("no syscall instruction in the vdso and nowhere to put one");
+    }
+    info!(
+        "no syscall instruction in the vdso, writing one at {:x}",
+        word
+    );
+    let syscall = libc::c_long::from_le_bytes([0x0f, 0x05, 0, 0, 0, 0, 0, 0]);
+    // The data is passed by value as the word to write, it isn't actually
+    // a pointer
+    ptrace::write(
+        child,
+        word as ptrace::AddressType,
+        syscall as *mut libc::c_void,
+    )?;
+    Ok(word - vdso.start())
+}
+
+/// Execute an arbitrary syscall in the child. Every `remote_*` helper below
+/// goes through this, it returns the raw `rax` the kernel handed back, which
+/// is negative errno on failure.
+///
+/// The child's registers are put back the way they were afterwards, so
+/// injecting syscalls into a process that will keep running (like a
+/// `teledump --leave-running` target) doesn't perturb it.
+fn remote_syscall(child: Pid, syscall: SyscallLoc, nr: u64, args: [u64; 6]) -> Result<i64> {
+    // == 1. Get the current register state so we can modify and later restore it
+    let regs = ptrace::getregs(child)?;
+    // == 2. Modify only the registers involved in the syscall. If we have a
+    // scratch stack we also point rsp at it, so that nothing about executing
+    // the syscall can touch the real stack or the red zone below it.
+    let syscall_regs = libc::user_regs_struct {
+        rip: syscall.addr, // syscall instr (rip is the instruction pointer)
+        rax: nr,           // rax holds the syscall number
+        rdi: args[0],      // arguments go in rdi, rsi, rdx, r10, r8, r9
+        rsi: args[1],
+        rdx: args[2],
+        r10: args[3],
+        r8: args[4],
+        r9: args[5],
+        rsp: syscall.stack_top().unwrap_or(regs.rsp),
+        ..regs
+    };
+    // == 3. Set the modified regs
+    ptrace::setregs(child, syscall_regs)?;
+    // == 4. Execute the syscall instruction (we set rip to point to it)
+    // == 5. Get the registers so we can extract the return value from rax
+    let stepped = single_step(child).and_then(|_| Ok(ptrace::getregs(child)?));
+    // == 6. Put everything back how we found it, even if stepping failed
+    ptrace::setregs(child, regs)?;
+    let res = stepped?.rax as i64;
+    // Not something the call was refused for like other errnos, so it gets
+    // its own error that says why
+    if res == -(libc::ENOSYS as i64) {
+        let err = MissingSyscall::new(nr);
+        tracing::debug!("{}", err);
+        return Err(Box::new(err));
+    }
+    Ok(res)
+}
+
+/// A syscall we injected into the child that the kernel refused, with the
+/// errno it gave back. Restores mostly fail with one of these, so it's worth
+/// saying exactly what we were trying to do.
+#[derive(Debug)]
+pub struct RemoteSyscallError {
+    /// What we were doing, like "mmap of 4096 bytes at 0x7f0000000000"
+    pub call: String,
+    pub errno: Errno,
+}
+
+impl std::fmt::Display for RemoteSyscallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "remote {} failed: {:?}", self.call, self.errno)
+    }
+}
+
+impl Error for RemoteSyscallError {}
+
+/// Turn the raw `rax` from `remote_syscall` into a `RemoteSyscallError` if
+/// it's a negative errno. Only the top 4095 values are errors, so things like
+/// high addresses that look negative as an i64 get through fine.
+fn remote_result(res: i64, call: impl FnOnce() -> String) -> Result<i64> {
+    if (-4095..0).contains(&res) {
+        let err = RemoteSyscallError {
+            call: call(),
+            errno: Errno::from_i32(-res as i32),
+        };
+        tracing::debug!("{}", err);
+        return Err(Box::new(err));
+    }
+    Ok(res)
+}
+
+// The simplest case of a remote syscall
+fn remote_brk(child: Pid, syscall: SyscallLoc, brk: usize) -> Result<usize> {
+    let res = remote_syscall(child, syscall, 12, [brk as u64, 0, 0, 0, 0, 0])?;
+    Ok(res as usize)
+}
+
+// The most complex case of a remote syscall, but basically the same
+#[allow(clippy::too_many_arguments)]
+fn remote_mmap(
+    child: Pid,
+    syscall: SyscallLoc,
+    addr: usize,
+    length: usize,
+    prot: i32,
+    flags: i32,
+    fd: i32,
+    offset: usize,
+) -> Result<usize> {
+    if !length.is_multiple_of(PAGE_SIZE) {
+        error("mmap length must be multiple of page size")?;
+    }
+    let mmap_location = remote_syscall(
+        child,
+        syscall,
+        9, // mmap
+        [
+            addr as u64,   // addr
+            length as u64, // length
+            prot as u64,   // prot
+            flags as u64,  // flags
+            fd as u64,     // fd
+            offset as u64, // offset
+        ],
+    )?;
+    let mmap_location = remote_result(mmap_location, || {
+        format!("mmap of {} bytes at {:#x}", length, addr)
+    })?;
+    if addr != 0 && mmap_location as usize != addr {
+        error("failed to mmap at correct location")?;
+    }
+    Ok(mmap_location as usize)
+}
+
+fn remote_mmap_anon(
+    child: Pid,
+    syscall: SyscallLoc,
+    addr: Option<usize>,
+    length: usize,
+    prot: i32,
+) -> Result<usize> {
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    let (addr, flags) = match addr {
+        // Caller requested a specific address
+        Some(addr) => (addr, flags | libc::MAP_FIXED),
+        // No specific address requested, we just want to map anywhere available
+        None => (0, flags),
+    };
+    remote_mmap(child, syscall, addr, length, prot, flags, -1, 0)
+}
+
+/// Where `mmap` takes the log2 of the huge page size in its flags
+const MAP_HUGE_SHIFT: i32 = 26;
+
+/// Map huge pages of `page_size` where `m` was, first making sure there are
+/// enough of them free here to say so clearly if not. Without
+/// `MAP_NORESERVE` the kernel sets them all aside up front, so once the
+/// mapping's made the process can't run out.
+fn remote_mmap_hugetlb(
+    child: Pid,
+    syscall: SyscallLoc,
+    m: &Mapping,
+    page_size: usize,
+    shared: bool,
+) -> Result<usize> {
+    if !page_size.is_power_of_two() || !m.size.is_multiple_of(page_size) {
+        return bad_stream(format!(
+            "{} of {} bytes isn't a whole number of {} byte huge pages",
+            m.describe(),
+            m.size,
+            page_size
+        ));
+    }
+    let kb = page_size / 1024;
+    let pool = format!("/sys/kernel/mm/hugepages/hugepages-{}kB", kb);
+    let count = |file: &str| -> Option<usize> {
+        std::fs::read_to_string(format!("{}/{}", pool, file))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    // Reserved ones are promised to some mapping already even though
+    // they're counted as free until they're touched
+    let available = match (count("free_hugepages"), count("resv_hugepages")) {
+        (Some(free), resv) => free.saturating_sub(resv.unwrap_or(0)),
+        (None, _) => {
+            return Err(Box::new(Unsupported(format!(
+                "{} is made of {} kB huge pages and this machine doesn't have that size",
+                m.describe(),
+                kb
+            ))))
+        }
+    };
+    let needed = m.size / page_size;
+    if available < needed {
+        tracing::error!("{} huge pages available, {} needed", available, needed);
+        return Err(Box::new(Unsupported(format!(
+            "{} needs {} free {} kB huge pages and there are {}, reserve more in {}/nr_hugepages",
+            m.describe(),
+            needed,
+            kb,
+            available,
+            pool
+        ))));
+    }
+    let sharing = if shared {
+        libc::MAP_SHARED
+    } else {
+        libc::MAP_PRIVATE
+    };
+    let size_flag = (page_size.trailing_zeros() as i32) << MAP_HUGE_SHIFT;
+    let flags = sharing | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_FIXED | size_flag;
+    let prot = PROT_READ | PROT_WRITE | PROT_EXEC;
+    remote_mmap(child, syscall, m.addr, m.size, prot, flags, -1, 0)
+}
+
+fn remote_munmap(child: Pid, syscall: SyscallLoc, addr: usize, length: usize) -> Result<()> {
+    let res = remote_syscall(child, syscall, 11, [addr as u64, length as u64, 0, 0, 0, 0])?;
+    remote_result(res, || format!("munmap of {} bytes at {:#x}", length, addr))?;
+    Ok(())
+}
+
+fn remote_mremap(
+    child: Pid,
+    syscall: SyscallLoc,
+    addr: usize,
+    length: usize,
+    new_addr: usize,
+) -> Result<()> {
+    if addr == new_addr {
+        return Ok(());
+    }
+
+    let res = remote_syscall(
+        child,
+        syscall,
+        25, // mremap
+        [
+            addr as u64,                                        // addr
+            length as u64,                                      // old_length
+            length as u64,                                      // new_length
+            (libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED) as u64, // flags
+            new_addr as u64,                                    // new_addr
+            0,
+        ],
+    )?;
+    remote_result(res, || format!("mremap of {:#x} to {:#x}", addr, new_addr))?;
+    if res as usize != new_addr {
+        // println!("remapped to {:x} from {:x} instead of {:x}", res, addr, new_addr);
+        error("didn't mremap to correct location")?;
+    }
+    Ok(())
+}
+
+/// A small region we map in the restored child for injecting syscalls: the
+/// first page is where we put arguments like pathnames, and the rest is a
+/// stack to point rsp at, so the real stack the program expects to find is
+/// never touched.
+///
+/// It lives above the highest mapping in the dump when we know it (or
+/// wherever `Config::scratch_addr` says), since if we let the kernel pick it
+/// tends to land right where libraries are about to be restored with
+/// `MAP_FIXED`. For old dumps that don't tell us, we still check each mapping
+/// and move out of the way before it lands on top of us.
+struct Scratch {
+    addr: usize,
+}
+
+const SCRATCH_SIZE: usize = 5 * PAGE_SIZE;
+/// The highest address a user mapping can reach with 4-level paging
+const TASK_SIZE: usize = 0x7fff_ffff_f000;
+/// And with 5-level paging, if a process asks for addresses that high
+const TASK_SIZE_LA57: usize = 0x00ff_ffff_ffff_f000;
+
+/// How high mappings can go on this machine. The kernel only advertises the
+/// `la57` CPU flag when it's actually using 5-level paging.
+fn local_task_size() -> usize {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    let la57 = cpuinfo
+        .lines()
+        .filter(|line| line.starts_with("flags"))
+        .any(|line| line.split_whitespace().any(|flag| flag == "la57"));
+    if la57 {
+        TASK_SIZE_LA57
+    } else {
+        TASK_SIZE
+    }
+}
+
+/// Whether memory up to `highest` fits under `task_size`, failing with
+/// `Unsupported` if not
+fn check_address_space(highest: usize, task_size: usize) -> Result<()> {
+    if highest > task_size {
+        return Err(Box::new(Unsupported(format!(
+            "the process has memory up to {:#x} but this machine only has addresses up to {:#x}, it probably came from one with 5-level paging",
+            highest, task_size
+        ))));
+    }
+    Ok(())
+}
+
+impl Scratch {
+    fn map(child: Pid, syscall: SyscallLoc, at: Option<usize>) -> Result<Self> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let (addr, flags) = match at {
+            Some(addr) => (addr, flags | libc::MAP_FIXED_NOREPLACE),
+            None => (0, flags),
+        };
+        let prot = PROT_READ | PROT_WRITE;
+        let addr = remote_mmap(child, syscall, addr, SCRATCH_SIZE, prot, flags, -1, 0)?;
+        tracing::debug!("syscall scratch region at {:x}", addr);
+        Ok(Scratch { addr })
+    }
+
+    fn install(&self, syscall: &mut SyscallLoc) {
+        syscall.scratch = Some(self.addr);
+    }
+
+    fn overlaps(&self, addr: usize, size: usize) -> bool {
+        addr < self.addr + SCRATCH_SIZE && self.addr < addr + size
+    }
+
+    /// Move the scratch region to `at`, or wherever the kernel likes if
+    /// `None`. With `keep_old` false the old region is unmapped, otherwise
+    /// it's abandoned because something is about to be mapped over it.
+    fn move_to(
+        &mut self,
+        child: Pid,
+        syscall: &mut SyscallLoc,
+        at: Option<usize>,
+        keep_old: bool,
+    ) -> Result<()> {
+        let old = self.addr;
+        *self = Scratch::map(child, *syscall, at)?;
+        self.install(syscall);
+        if !keep_old {
+            remote_munmap(child, *syscall, old, SCRATCH_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Pick the scratch address given the highest address the dump maps
+    fn place_above(&mut self, child: Pid, syscall: &mut SyscallLoc, highest: usize) -> Result<()> {
+        // Leave a guard page between us and the highest mapping
+        let at = (highest + PAGE_SIZE + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if at + SCRATCH_SIZE > TASK_SIZE {
+            tracing::debug!("no room for syscall scratch region above {:x}", highest);
+            return Ok(());
+        }
+        if let Err(e) = self.move_to(child, syscall, Some(at), false) {
+            tracing::debug!("couldn't move syscall scratch region to {:x}: {}", at, e);
+        }
+        Ok(())
+    }
+
+    /// Make sure the scratch region isn't in the way of a mapping about to be
+    /// restored at `addr`.
+    fn avoid(
+        &mut self,
+        child: Pid,
+        syscall: &mut SyscallLoc,
+        addr: usize,
+        size: usize,
+    ) -> Result<()> {
+        if !self.overlaps(addr, size) {
+            return Ok(());
+        }
+        self.move_to(child, syscall, None, true)
+    }
+
+    fn unmap(self, child: Pid, syscall: &mut SyscallLoc) -> Result<()> {
+        syscall.scratch = None;
+        remote_munmap(child, *syscall, self.addr, SCRATCH_SIZE)
+    }
+}
+
+/// Where `telepad` reads a dump from. Any `Read` works through `Streamed`,
+/// but when the whole dump is already in memory, like a dump file we've
+/// mmaped, mapping contents can go straight from there into the child
+/// instead of being copied through a buffer first.
+pub(crate) trait DumpReader: Read {
+    /// The next `len` bytes without copying them, if we have them lying around
+    fn borrow_bytes(&mut self, _len: usize) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl DumpReader for &[u8] {
+    fn borrow_bytes(&mut self, len: usize) -> Option<&[u8]> {
+        if self.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.split_at(len);
+        *self = rest;
+        Some(bytes)
+    }
+}
+
+/// A dump coming from a plain `Read`, which we have to copy out of
+pub(crate) struct Streamed<'a>(pub(crate) &'a mut dyn Read);
+
+impl Read for Streamed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl DumpReader for Streamed<'_> {}
+
+/// Reads through to `inner`, keeping a copy of everything it read
+struct Recording<'a> {
+    inner: &'a mut dyn DumpReader,
+    seen: Vec<u8>,
+}
+
+impl Read for Recording<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.seen.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// What a `Recording` saw again, and then the rest of the dump after it
+struct Replay<'a> {
+    seen: std::io::Cursor<Vec<u8>>,
+    rest: &'a mut dyn DumpReader,
+}
+
+impl Read for Replay<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.seen.read(buf)? {
+            0 => self.rest.read(buf),
+            len => Ok(len),
+        }
+    }
+}
+
+impl DumpReader for Replay<'_> {
+    fn borrow_bytes(&mut self, len: usize) -> Option<&[u8]> {
+        if (self.seen.position() as usize) < self.seen.get_ref().len() {
+            return None;
+        }
+        self.rest.borrow_bytes(len)
+    }
+}
+
+/// The pid from the start of the dump, if it has one, along with the bytes
+/// read to find out for `Replay` to hand over again
+fn peek_pid(inp: &mut dyn DumpReader) -> Result<(Option<i32>, Vec<u8>)> {
+    let mut recording = Recording {
+        inner: inp,
+        seen: Vec::new(),
+    };
+    let (mut framing, first) = read_header(&mut recording)?;
+    let pid = match first {
+        // Only legacy dumps have a first command to hand back, and they're
+        // from before there was a `Pid`
+        Some(_) => None,
+        None => match read_command(&mut recording, &mut framing)? {
+            Command::Pid(pid) => Some(pid),
+            _ => None,
+        },
+    };
+    Ok((pid, recording.seen))
+}
+
+/// Copy bytes we have in memory into the child at `addr`
+fn write_memory(child: Pid, addr: usize, bytes: &[u8]) -> Result<()> {
+    let mut done = 0;
+    while done < bytes.len() {
+        // Big writes can come back partial so keep going from where it stopped
+        let wrote = uio::process_vm_writev(
+            child,
+            &[uio::IoVec::from_slice(&bytes[done..])],
+            &[uio::RemoteIoVec {
+                base: addr + done,
+                len: bytes.len() - done,
+            }],
+        )?;
+        if wrote == 0 {
+            return error("failed to write to process");
+        }
+        trace!("wrote {} bytes at {:x}", wrote, addr + done);
+        done += wrote;
+    }
+    Ok(())
+}
+
+/// The inverse of the streaming in `write_regular_map`. Streams memory from a
+/// `Read` channel into a child process at a certain address.
+fn stream_memory(child: Pid, inp: &mut dyn DumpReader, addr: usize, length: usize) -> Result<()> {
+    if let Some(bytes) = inp.borrow_bytes(length) {
+        return write_memory(child, addr, bytes);
+    }
+    let mut remaining_size = length;
+    let mut buf = vec![0u8; PAGE_SIZE];
+    while remaining_size > 0 {
+        let batch_size = std::cmp::min(buf.len(), remaining_size);
+        let offset = addr + (length - remaining_size);
+
+        inp.read_exact(&mut buf[..batch_size])?;
+
+        // The inverse of the earlier rare syscall, copies to a child's memory
+        let wrote = uio::process_vm_writev(
+            child,
+            &[uio::IoVec::from_slice(&buf[..batch_size])],
+            &[uio::RemoteIoVec {
+                base: offset,
+                len: batch_size,
+            }],
+        )?;
+        if wrote == 0 {
+            return error("failed to write to process");
+        }
+        trace!("wrote {} bytes at {:x}", batch_size, offset);
+        remaining_size -= batch_size;
+    }
+
+    Ok(())
+}
+
+/// Make sure the next thing after a mapping's contents is its `MappingEnd`
+/// saying the same number of bytes we read, so a framing mistake fails right
+/// here naming the mapping instead of as some confusing error later on.
+fn expect_mapping_end(
+    inp: &mut dyn Read,
+    framing: &mut Framing,
+    m: &Mapping,
+    read: usize,
+) -> Result<()> {
+    let name = m.name.as_deref().unwrap_or("anonymous");
+    match read_command(inp, framing) {
+        Ok(Command::MappingEnd { written }) if written == read => Ok(()),
+        Ok(Command::MappingEnd { written }) => bad_stream(format!(
+            "mapping at {:#x} ({}) declared {} bytes but {} were sent",
+            m.addr, name, read, written
+        )),
+        _ => bad_stream(format!(
+            "mapping at {:#x} ({}) wasn't followed by its end marker, more than its {} bytes were probably sent",
+            m.addr, name, read
+        )),
+    }
+}
+
+/// Helper to find a map with a specific name, used to match up special kernel maps
+fn find_map_named<'a>(
+    maps: &'a [proc_maps::MapRange],
+    name: &str,
+) -> Option<&'a proc_maps::MapRange> {
+    maps.iter()
+        .find(|map| matches!(map.filename(), Some(n) if n == name))
+}
+
+/// Refuse to put a mapping from the dump on top of our vDSO when it had to
+/// stay where it was, see `VdsoRestore::KeptInPlace`
+fn check_kept_vdso(kept: &[(usize, usize)], addr: usize, size: usize) -> Result<()> {
+    match kept.iter().find(|&&(a, s)| a < addr + size && addr < a + s) {
+        Some((kept_addr, _)) => Err(Box::new(Unsupported(format!(
+            "the mapping at {:x} goes where this kernel's vDSO is at {:x}, which couldn't be moved out of the way since it's a different size from the dumped one",
+            addr, kept_addr
+        )))),
+        None => Ok(()),
+    }
+}
+
+/// The brk pointer is an old school syscall that at least used to be used for
+/// expanding/contracting the `[heap]` memory mapping. It's one of the pieces
+/// of process state stored outside of memory and registers. I don't *think*
+/// it's used by modern heap allocation but I'm not sure.
+///
+/// It's hard to manipulate. This doesn't actually work a lot of the time. It
+/// probably doesn't really matter for many programs.
+fn restore_brk(child: Pid, syscall: SyscallLoc, brk_addr: usize) -> Result<()> {
+    // TODO according to DMTCP this is the procedure that should work, but in
+    // my testing it doesn't if the target brk is below the original heap,
+    // then brk just doesn't update the heap. The way to fix this that also
+    // restores a bunch of other things is to use PR_SET_MM_MAP but that's not
+    // always available, requires high permissions, and it's hard to source
+    // all the fields for that. In the case that it fails this implementation
+    // is basically the same as not restoring the brk at all.
+    //
+    // Newer dumps follow up with a `Heap` once the mappings are in, which
+    // sets the heap fields with PR_SET_MM when we have the permissions.
+
+    let orig_brk = remote_brk(child, syscall, 0)?;
+    // Is it possible that changing the brk could munmap the vdso? I think not with default layouts but maybe wrong.
+    let new_brk = remote_brk(child, syscall, brk_addr)?;
+
+    // println!("brk orig={:>16x} new={:>16x} target={:>16x}", orig_brk, new_brk, brk_addr);
+    if new_brk > orig_brk {
+        // we mapped a new region but we want everything cleared away still so munmap it
+        remote_munmap(child, syscall, orig_brk, new_brk - orig_brk)?;
+    }
+
+    Ok(())
+}
+
+#[allow(unused)]
+fn buggsy() {}
+
+/// Syscalls like `open` or `write` take a pointer to some bytes so we need to
+/// get them into the child's address space first. We copy them into the
+/// first page of the scratch region if we have one, otherwise into a
+/// temporary page, and call `f` with their address in the child.
+fn with_remote_bytes<T>(
+    child: Pid,
+    syscall: SyscallLoc,
+    bytes: &[u8],
+    f: impl FnOnce(usize) -> Result<T>,
+) -> Result<T> {
+    if bytes.len() > PAGE_SIZE {
+        return error("too much data to pass to a remote syscall");
+    }
+    // This virtual address is in the child's address space.
+    let (addr, temporary) = match syscall.scratch {
+        Some(addr) => (addr, false),
+        None => {
+            let prot = PROT_READ | PROT_WRITE;
+            (
+                remote_mmap_anon(child, syscall, None, PAGE_SIZE, prot)?,
+                true,
+            )
+        }
+    };
+    stream_memory(child, &mut &bytes[..], addr, bytes.len())?;
+    let res = f(addr);
+    if temporary {
+        remote_munmap(child, syscall, addr, PAGE_SIZE)?;
+    }
+    res
+}
+
+/// Pathnames are the most common case, they just need a NUL on the end
+fn with_remote_path<T>(
+    child: Pid,
+    syscall: SyscallLoc,
+    path: &str,
+    f: impl FnOnce(usize) -> Result<T>,
+) -> Result<T> {
+    if path.len() >= PAGE_SIZE {
+        return error("long pathname not supported");
+    }
+    let mut bytes = path.as_bytes().to_vec();
+    bytes.push(0);
+    with_remote_bytes(child, syscall, &bytes, f)
+}
+
+/// Copy memory out of the child, the inverse of `stream_memory` for small
+/// things like syscall out-parameters.
+fn read_memory(child: Pid, addr: usize, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    let read = uio::process_vm_readv(
+        child,
+        &[uio::IoVec::from_mut_slice(&mut buf[..])],
+        &[uio::RemoteIoVec { base: addr, len }],
+    )?;
+    if read != len {
+        return error("failed to read from other process");
+    }
+    Ok(buf)
+}
+
+/// CRC32 of a region of the child's memory, read a chunk at a time so huge
+/// mappings don't need a copy of the whole thing
+fn checksum_memory(child: Pid, addr: usize, len: usize) -> Result<u32> {
+    const CHUNK: usize = 256 * PAGE_SIZE;
+    let mut crc = crc32fast::Hasher::new();
+    let mut done = 0;
+    while done < len {
+        let chunk = std::cmp::min(CHUNK, len - done);
+        crc.update(&read_memory(child, addr + done, chunk)?);
+        done += chunk;
+    }
+    Ok(crc.finalize())
+}
+
+fn remote_open(child: Pid, syscall: SyscallLoc, path: &str, flags: i32) -> Result<u32> {
+    remote_open_mode(child, syscall, path, flags, 0)
+}
+
+/// `remote_open` with the mode for when `flags` has `O_CREAT`
+fn remote_open_mode(
+    child: Pid,
+    syscall: SyscallLoc,
+    path: &str,
+    flags: i32,
+    mode: u32,
+) -> Result<u32> {
+    let res = with_remote_path(child, syscall, path, |path_addr| {
+        remote_syscall(
+            child,
+            syscall,
+            2, // open
+            [path_addr as u64, flags as u64, mode as u64, 0, 0, 0],
+        )
+    })?;
+    let fd = remote_result(res, || format!("open of {}", path))?;
+    Ok(fd as u32)
+}
+
+/// `execve` inside the child. The argument and environment lists go in a
+/// mapping of their own since they can be much bigger than a page, which
+/// the exec unmaps along with everything else. Unlike `remote_syscall` the
+/// registers are only put back if it fails, once it works they're the new
+/// program's.
+fn remote_execve(
+    child: Pid,
+    syscall: SyscallLoc,
+    path: &str,
+    argv: &[&str],
+    env: &[&str],
+) -> Result<()> {
+    // The two NULL terminated pointer arrays first, then the strings
+    let pointers = (argv.len() + env.len() + 2) * 8;
+    let strings: usize = std::iter::once(path)
+        .chain(argv.iter().copied())
+        .chain(env.iter().copied())
+        .map(|s| s.len() + 1)
+        .sum();
+    let len = (pointers + strings).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let base = remote_mmap_anon(child, syscall, None, len, PROT_READ | PROT_WRITE)?;
+    let mut bytes = vec![0u8; pointers];
+    let mut add = |s: &str| {
+        let addr = base + bytes.len();
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+        addr as u64
+    };
+    let path_addr = add(path);
+    let argv: Vec<u64> = argv.iter().map(|arg| add(arg)).collect();
+    let env: Vec<u64> = env.iter().map(|var| add(var)).collect();
+    let envp = base + (argv.len() + 1) * 8;
+    for (i, addr) in argv.iter().chain(&[0]).chain(&env).enumerate() {
+        bytes[i * 8..i * 8 + 8].copy_from_slice(&addr.to_le_bytes());
+    }
+    write_memory(child, base, &bytes)?;
+
+    let regs = ptrace::getregs(child)?;
+    ptrace::setregs(
+        child,
+        libc::user_regs_struct {
+            rip: syscall.addr,
+            rax: 59, // execve
+            rdi: path_addr,
+            rsi: base as u64,
+            rdx: envp as u64,
+            rsp: syscall.stack_top().unwrap_or(regs.rsp),
+            ..regs
+        },
+    )?;
+    // A successful exec stops it with a SIGTRAP before the new program
+    // runs anything, which is what single stepping waits for anyway
+    single_step(child)?;
+    let res = ptrace::getregs(child)?.rax as i64;
+    if let Err(e) = remote_result(res, || format!("execve of {}", path)) {
+        ptrace::setregs(child, regs)?;
+        remote_munmap(child, syscall, base, len)?;
+        return Err(e);
+    }
+    info!("restored process is running {} now", path);
+    Ok(())
+}
+
+fn remote_chroot(child: Pid, syscall: SyscallLoc, path: &str) -> Result<()> {
+    let res = with_remote_path(child, syscall, path, |path_addr| {
+        remote_syscall(child, syscall, 161, [path_addr as u64, 0, 0, 0, 0, 0])
+    })?;
+    remote_result(res, || format!("chroot to {}", path))?;
+    Ok(())
+}
+
+fn remote_dup2(child: Pid, syscall: SyscallLoc, oldfd: u32, newfd: u32) -> Result<u32> {
+    let res = remote_syscall(child, syscall, 33, [oldfd as u64, newfd as u64, 0, 0, 0, 0])?;
+    remote_result(res, || format!("dup2 of fd {} onto {}", oldfd, newfd))?;
+    Ok(0)
+}
+
+fn remote_close(child: Pid, syscall: SyscallLoc, fd: u32) -> Result<()> {
+    let res = remote_syscall(child, syscall, 3, [fd as u64, 0, 0, 0, 0, 0])?;
+    remote_result(res, || format!("close of fd {}", fd))?;
+    Ok(())
+}
+
+fn remote_fcntl(child: Pid, syscall: SyscallLoc, fd: u32, cmd: i32, arg: u64) -> Result<i64> {
+    let res = remote_syscall(child, syscall, 72, [fd as u64, cmd as u64, arg, 0, 0, 0])?;
+    remote_result(res, || format!("fcntl {} on fd {}", cmd, fd))
+}
+
+/// Write all of `data` to an fd in the child, a page at a time
+fn remote_write(child: Pid, syscall: SyscallLoc, fd: u32, data: &[u8]) -> Result<()> {
+    for chunk in data.chunks(PAGE_SIZE) {
+        let mut written = 0;
+        while written < chunk.len() {
+            let rest = &chunk[written..];
+            let res = with_remote_bytes(child, syscall, rest, |addr| {
+                remote_syscall(
+                    child,
+                    syscall,
+                    1, // write
+                    [fd as u64, addr as u64, rest.len() as u64, 0, 0, 0],
+                )
+            })?;
+            let res = remote_result(res, || format!("write to fd {}", fd))?;
+            if res == 0 {
+                return error("remote write made no progress");
+            }
+            written += res as usize;
+        }
+    }
+    Ok(())
+}
+
+fn remote_socketpair(child: Pid, syscall: SyscallLoc, sock_type: i32) -> Result<(u32, u32)> {
+    // The two fds are returned through an int[2] out-parameter
+    let sv = with_remote_bytes(child, syscall, &[0u8; 8], |addr| {
+        let res = remote_syscall(
+            child,
+            syscall,
+            53, // socketpair
+            [libc::AF_UNIX as u64, sock_type as u64, 0, addr as u64, 0, 0],
+        )?;
+        remote_result(res, || "socketpair".to_string())?;
+        read_memory(child, addr, 8)
+    })?;
+    let a = u32::from_ne_bytes([sv[0], sv[1], sv[2], sv[3]]);
+    let b = u32::from_ne_bytes([sv[4], sv[5], sv[6], sv[7]]);
+    Ok((a, b))
+}
+
+/// Returns where it ended up, which for some special files isn't where we
+/// asked
+fn remote_lseek(child: Pid, syscall: SyscallLoc, fd: u32, offset: u64) -> Result<u64> {
+    let res = remote_syscall(
+        child,
+        syscall,
+        8, // lseek
+        [fd as u64, offset, libc::SEEK_SET as u64, 0, 0, 0],
+    )?;
+    let res = remote_result(res, || format!("lseek of fd {} to {}", fd, offset))?;
+    Ok(res as u64)
+}
+
+fn remote_prctl(child: Pid, syscall: SyscallLoc, option: i32, arg2: u64) -> Result<i64> {
+    let res = remote_syscall(child, syscall, 157, [option as u64, arg2, 0, 0, 0, 0])?;
+    remote_result(res, || format!("prctl {}", option))
+}
+
+/// `PR_SET_MM` is the one `prctl` that needs a third argument
+fn remote_set_mm(child: Pid, syscall: SyscallLoc, field: i32, addr: usize) -> Result<()> {
+    let args = [libc::PR_SET_MM as u64, field as u64, addr as u64, 0, 0, 0];
+    let res = remote_syscall(child, syscall, 157, args)?;
+    remote_result(res, || format!("prctl PR_SET_MM {} to {:#x}", field, addr))?;
+    Ok(())
+}
+
+/// Set several `PR_SET_MM` fields. The kernel checks the layout still makes
+/// sense after each one, like a start staying below its end, so which can
+/// move first depends on where the old values were. Keep going round until
+/// they're all set or a round doesn't get any more of them to go.
+fn remote_set_mm_fields(child: Pid, syscall: SyscallLoc, fields: &[(i32, usize)]) -> Result<()> {
+    let mut left = fields.to_vec();
+    while !left.is_empty() {
+        let mut last_err = None;
+        let before = left.len();
+        left.retain(
+            |&(field, addr)| match remote_set_mm(child, syscall, field, addr) {
+                Ok(()) => false,
+                Err(e) => {
+                    last_err = Some(e);
+                    true
+                }
+            },
+        );
+        if left.len() == before {
+            if let Some(e) = last_err {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remote_memfd_create(child: Pid, syscall: SyscallLoc, name: &str) -> Result<u32> {
+    let res = with_remote_path(child, syscall, name, |name_addr| {
+        remote_syscall(child, syscall, 319, [name_addr as u64, 0, 0, 0, 0, 0])
+    })?;
+    let fd = remote_result(res, || format!("memfd_create of {}", name))?;
+    Ok(fd as u32)
+}
+
+fn remote_mprotect(
+    child: Pid,
+    syscall: SyscallLoc,
+    addr: usize,
+    length: usize,
+    prot: i32,
+) -> Result<()> {
+    let args = [addr as u64, length as u64, prot as u64, 0, 0, 0];
+    let res = remote_syscall(child, syscall, 10, args)?;
+    remote_result(res, || {
+        format!("mprotect of {} bytes at {:#x}", length, addr)
+    })?;
+    Ok(())
+}
+
+fn remote_ftruncate(child: Pid, syscall: SyscallLoc, fd: u32, length: usize) -> Result<()> {
+    let res = remote_syscall(child, syscall, 77, [fd as u64, length as u64, 0, 0, 0, 0])?;
+    remote_result(res, || format!("ftruncate of fd {} to {}", fd, length))?;
+    Ok(())
+}
+
+fn remote_unlink(child: Pid, syscall: SyscallLoc, path: &str) -> Result<()> {
+    let res = with_remote_path(child, syscall, path, |path_addr| {
+        remote_syscall(child, syscall, 87, [path_addr as u64, 0, 0, 0, 0, 0])
+    })?;
+    remote_result(res, || format!("unlink of {}", path))?;
+    Ok(())
+}
+
+fn remote_shmget(
+    child: Pid,
+    syscall: SyscallLoc,
+    key: i32,
+    size: usize,
+    flags: i32,
+) -> Result<i32> {
+    let args = [key as u64, size as u64, flags as u64, 0, 0, 0];
+    let res = remote_syscall(child, syscall, 29, args)?;
+    let shmid = remote_result(res, || format!("shmget of key {:#x}", key))?;
+    Ok(shmid as i32)
+}
+
+fn remote_shmat(
+    child: Pid,
+    syscall: SyscallLoc,
+    shmid: i32,
+    addr: usize,
+    flags: i32,
+) -> Result<()> {
+    let args = [shmid as u64, addr as u64, flags as u64, 0, 0, 0];
+    let res = remote_syscall(child, syscall, 30, args)?;
+    remote_result(res, || format!("shmat of segment {} at {:#x}", shmid, addr))?;
+    if res as usize != addr {
+        error("failed to shmat at correct location")?;
+    }
+    Ok(())
+}
+
+fn remote_shm_remove(child: Pid, syscall: SyscallLoc, shmid: i32) -> Result<()> {
+    let args = [shmid as u64, libc::IPC_RMID as u64, 0, 0, 0, 0];
+    let res = remote_syscall(child, syscall, 31, args)?;
+    remote_result(res, || format!("IPC_RMID of segment {}", shmid))?;
+    Ok(())
+}
+
+/// Map the file behind a `FileMapping` back in at the same place. If the
+/// file isn't here we warn and leave zeroed memory there instead, so the
+/// process at least doesn't crash just touching it.
+fn restore_file_mapping(
+    child: Pid,
+    syscall: SyscallLoc,
+    fm: &FileMapping,
+    root: &str,
+) -> Result<()> {
+    let m = &fm.mapping;
+    // Looked at through the child's root, so it's the file it'll open
+    let path = path_relative_to_root(&fm.path, root);
+    match std::fs::metadata(format!("/proc/{}/root{}", child, path)) {
+        Ok(meta) if (meta.len() as usize) < fm.offset + m.size => warn!(
+            "{} is smaller than its mapping, touching the end will SIGBUS",
+            path
+        ),
+        Ok(_) => {}
+        Err(e) => {
+            warn!(
+                "can't remap {} ({}), it'll be zeroed memory instead",
+                path, e
+            );
+            remote_mmap_anon(child, syscall, Some(m.addr), m.size, m.prot())?;
+            return Ok(());
+        }
+    }
+    let open_flags = if m.writeable && fm.shared {
+        libc::O_RDWR
+    } else {
+        libc::O_RDONLY
+    };
+    let fd = remote_open(child, syscall, &path, open_flags)?;
+    let share = if fm.shared {
+        libc::MAP_SHARED
+    } else {
+        libc::MAP_PRIVATE
+    };
+    remote_mmap(
+        child,
+        syscall,
+        m.addr,
+        m.size,
+        m.prot(),
+        share | libc::MAP_FIXED,
+        fd as i32,
+        fm.offset,
+    )?;
+    remote_close(child, syscall, fd)?;
+    Ok(())
+}
+
+/// Put back the part of a private file mapping that was past the end of its
+/// file by mapping the file there again, so touching it is still a SIGBUS.
+/// If the file isn't here, or has grown into that part since, mapping it
+/// would give the process contents it never had, so that part gets zeroed
+/// memory instead.
+fn restore_past_eof(child: Pid, syscall: SyscallLoc, fm: &FileMapping, root: &str) -> Result<()> {
+    let m = &fm.mapping;
+    let path = path_relative_to_root(&fm.path, root);
+    match std::fs::metadata(format!("/proc/{}/root{}", child, path)) {
+        Ok(meta) if meta.len() as usize <= fm.offset => {}
+        Ok(_) => {
+            warn!(
+                "{} has grown past where its mapping at {:x} ran off the end of it, that part will be zeroed memory",
+                path, m.addr
+            );
+            remote_mmap_anon(child, syscall, Some(m.addr), m.size, m.prot())?;
+            return Ok(());
+        }
+        Err(e) => {
+            warn!(
+                "can't remap the end of {} ({}), it'll be zeroed memory instead of a SIGBUS",
+                path, e
+            );
+            remote_mmap_anon(child, syscall, Some(m.addr), m.size, m.prot())?;
+            return Ok(());
+        }
+    }
+    let fd = remote_open(child, syscall, &path, libc::O_RDONLY)?;
+    remote_mmap(
+        child,
+        syscall,
+        m.addr,
+        m.size,
+        m.prot(),
+        libc::MAP_PRIVATE | libc::MAP_FIXED,
+        fd as i32,
+        fm.offset,
+    )?;
+    remote_close(child, syscall, fd)?;
+    Ok(())
+}
+
+/// The shared memory segments recreated so far. A process can attach the
+/// same segment more than once, and those all have to end up on the one
+/// new segment.
+#[derive(Default)]
+struct SharedSegments {
+    /// Original shmid to the one it was recreated as
+    sysv: HashMap<i32, i32>,
+    /// `/dev/shm` and other tmpfs files we created, and how big we've made
+    /// them
+    posix: HashMap<String, usize>,
+    /// Segments the original process had already removed, which we can
+    /// only remove once nothing else in the dump needs to attach them
+    remove: Vec<ShmSegment>,
+}
+
+impl SharedSegments {
+    /// Attach the segment behind `shm` at its address. Returns whether it's
+    /// a fresh one the dumped contents need writing into, in which case
+    /// it's left writeable until `protect` is called.
+    fn attach(
+        &mut self,
+        child: Pid,
+        syscall: SyscallLoc,
+        shm: &SharedMemory,
+        root: &str,
+        reattach: bool,
+    ) -> Result<bool> {
+        let m = &shm.mapping;
+        match &shm.segment {
+            ShmSegment::SysV {
+                key,
+                shmid,
+                mode,
+                removed,
+            } => {
+                let fresh = !reattach || *removed || *key == libc::IPC_PRIVATE;
+                let new_id = match self.sysv.get(shmid) {
+                    Some(id) => *id,
+                    None if fresh => {
+                        let key = if *removed { libc::IPC_PRIVATE } else { *key };
+                        let flags = libc::IPC_CREAT | libc::IPC_EXCL | *mode as i32;
+                        let id = match remote_shmget(child, syscall, key, m.size, flags) {
+                            Err(e) if is_errno(&*e, Errno::EEXIST) => {
+                                return Err(Box::new(Unsupported(format!(
+                                    "a SysV shared memory segment with key {:#x} already exists here, restore with reattach_shm to attach to it",
+                                    key
+                                ))));
+                            }
+                            res => res?,
+                        };
+                        if *removed {
+                            self.remove.push(ShmSegment::SysV {
+                                key,
+                                shmid: id,
+                                mode: *mode,
+                                removed: true,
+                            });
+                        }
+                        id
+                    }
+                    None => remote_shmget(child, syscall, *key, 0, 0)?,
+                };
+                self.sysv.insert(*shmid, new_id);
+                let mut flags = libc::SHM_REMAP;
+                if !fresh && !m.writeable {
+                    flags |= libc::SHM_RDONLY;
+                }
+                if m.executable {
+                    flags |= libc::SHM_EXEC;
+                }
+                remote_shmat(child, syscall, new_id, m.addr, flags)?;
+                Ok(fresh)
+            }
+            ShmSegment::Posix {
+                path,
+                offset,
+                mode,
+                unlinked,
+            } => {
+                let fresh = !reattach || *unlinked;
+                let remote_path = path_relative_to_root(path, root);
+                let fd = match self.posix.get(path) {
+                    Some(_) => remote_open(child, syscall, &remote_path, libc::O_RDWR)?,
+                    None if fresh => {
+                        let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+                        let fd = match remote_open_mode(child, syscall, &remote_path, flags, *mode)
+                        {
+                            Err(e) if is_errno(&*e, Errno::EEXIST) => {
+                                return Err(Box::new(Unsupported(format!(
+                                    "shared memory {} already exists here, restore with reattach_shm to attach to it",
+                                    path
+                                ))));
+                            }
+                            res => res?,
+                        };
+                        if *unlinked {
+                            self.remove.push(shm.segment.clone());
+                        }
+                        fd
+                    }
+                    None if m.writeable => remote_open(child, syscall, &remote_path, libc::O_RDWR)?,
+                    None => remote_open(child, syscall, &remote_path, libc::O_RDONLY)?,
+                };
+                self.map_file(child, syscall, m, path, *offset, fd, fresh)?;
+                Ok(fresh)
+            }
+            ShmSegment::Tmpfs { path, offset, mode } => {
+                let remote_path = path_relative_to_root(path, root);
+                let (fd, fresh) = if self.posix.contains_key(path) {
+                    (
+                        remote_open(child, syscall, &remote_path, libc::O_RDWR)?,
+                        true,
+                    )
+                } else {
+                    let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+                    match remote_open_mode(child, syscall, &remote_path, flags, *mode) {
+                        Ok(fd) => (fd, true),
+                        // The same machine or one sharing its files, where
+                        // writes need to keep going to it like any other
+                        // shared file mapping
+                        Err(e) if is_errno(&*e, Errno::EEXIST) => {
+                            info!("{} is still here, mapping it again", path);
+                            let flags = if m.writeable {
+                                libc::O_RDWR
+                            } else {
+                                libc::O_RDONLY
+                            };
+                            (remote_open(child, syscall, &remote_path, flags)?, false)
+                        }
+                        Err(e) => return Err(e),
+                    }
+                };
+                self.map_file(child, syscall, m, path, *offset, fd, fresh)?;
+                Ok(fresh)
+            }
+        }
+    }
+
+    /// Map `fd` at the mapping's address and close it, growing the file
+    /// first if it's one we made and the mapping goes past its end
+    #[allow(clippy::too_many_arguments)]
+    fn map_file(
+        &mut self,
+        child: Pid,
+        syscall: SyscallLoc,
+        m: &Mapping,
+        path: &str,
+        offset: usize,
+        fd: u32,
+        fresh: bool,
+    ) -> Result<()> {
+        if fresh {
+            let end = offset + m.size;
+            let size = self.posix.entry(path.to_string()).or_insert(0);
+            if *size < end {
+                remote_ftruncate(child, syscall, fd, end)?;
+                *size = end;
+            }
+        }
+        let prot = if fresh {
+            PROT_READ | PROT_WRITE
+        } else {
+            m.prot()
+        };
+        let flags = libc::MAP_SHARED | libc::MAP_FIXED;
+        remote_mmap(
+            child, syscall, m.addr, m.size, prot, flags, fd as i32, offset,
+        )?;
+        remote_close(child, syscall, fd)?;
+        Ok(())
+    }
+
+    /// Put a fresh segment's mapping back to the protection it had, once its
+    /// contents are in
+    fn protect(child: Pid, syscall: SyscallLoc, m: &Mapping) -> Result<()> {
+        if m.prot() == PROT_READ | PROT_WRITE {
+            return Ok(());
+        }
+        remote_mprotect(child, syscall, m.addr, m.size, m.prot())
+    }
+
+    /// Remove the segments that were already removed in the original, so
+    /// they go away with the restored process just like they would have
+    /// with the original
+    fn finish(&mut self, child: Pid, syscall: SyscallLoc, root: &str) -> Result<()> {
+        for segment in self.remove.drain(..) {
+            match segment {
+                ShmSegment::SysV { shmid, .. } => remote_shm_remove(child, syscall, shmid)?,
+                ShmSegment::Posix { path, .. } | ShmSegment::Tmpfs { path, .. } => {
+                    remote_unlink(child, syscall, &path_relative_to_root(&path, root))?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a `remote_*` helper failed with `errno`
+fn is_errno(e: &(dyn Error + 'static), errno: Errno) -> bool {
+    matches!(e.downcast_ref::<RemoteSyscallError>(), Some(r) if r.errno == errno)
+}
+
+/// Put the restored process in the same filesystem view as the original as far
+/// as we can. Returns the root that recorded fd paths should be resolved
+/// against from inside the restored process: if we managed to `chroot` it
+/// into the original root, paths need that prefix stripped, otherwise they're
+/// used as the host-absolute paths they were recorded as.
+fn restore_fs_context(child: Pid, syscall: SyscallLoc, fs: FsContext) -> Result<String> {
+    // Namespaces we can only really warn about, `setns` needs an fd to the
+    // namespace which would have to come from some process on this machine.
+    let ours = read_namespaces(child.as_raw())?;
+    for (name, link) in &fs.namespaces {
+        match ours.get(name) {
+            Some(our_link) if our_link == link => {}
+            Some(our_link) if name == "mnt" => warn!(
+                "restoring into a different mount namespace ({} instead of {}), fd paths are from inside the original one, see Config::mount_namespace",
+                our_link, link
+            ),
+            Some(our_link) => warn!(
+                "restoring into a different {} namespace ({} instead of {}), paths and ids may resolve differently",
+                name, our_link, link
+            ),
+            None => warn!("this kernel doesn't have the {} namespace", name),
+        }
+    }
+
+    if fs.root == "/" {
+        return Ok(fs.root);
+    }
+    match remote_chroot(child, syscall, &fs.root) {
+        Ok(()) => {
+            info!("restored process root to {}", fs.root);
+            Ok(fs.root)
+        }
+        Err(e) => {
+            warn!(
+                "couldn't chroot restored process to {} ({}), it will see the host root",
+                fs.root, e
+            );
+            Ok("/".to_string())
+        }
+    }
+}
+
+/// `chdir` the restored process to its working directory, through `root`
+/// like the fd paths
+fn restore_cwd(child: Pid, syscall: SyscallLoc, cwd: &str, root: &str) -> Result<()> {
+    if cwd.ends_with(" (deleted)") {
+        let gone = format!("its working directory {} was deleted", cwd);
+        return Err(Box::new(NotHere(gone)));
+    }
+    let path = path_relative_to_root(cwd, root);
+    let res = with_remote_path(child, syscall, &path, |path_addr| {
+        remote_syscall(child, syscall, 80, [path_addr as u64, 0, 0, 0, 0, 0])
+    })?;
+    remote_result(res, || format!("chdir to {}", path))?;
+    Ok(())
+}
+
+/// Move the restored process into the mount namespace at `path`, for
+/// `Config::mount_namespace`
+fn join_mount_namespace(child: Pid, syscall: SyscallLoc, path: &std::path::Path) -> Result<()> {
+    let path = path.to_string_lossy();
+    let fd = remote_open(child, syscall, &path, libc::O_RDONLY | libc::O_CLOEXEC)?;
+    let res = remote_syscall(
+        child,
+        syscall,
+        308, // setns
+        [fd as u64, libc::CLONE_NEWNS as u64, 0, 0, 0, 0],
+    );
+    remote_close(child, syscall, fd)?;
+    remote_result(res?, || format!("setns into the mount namespace {}", path))?;
+    info!("restoring in the mount namespace {}", path);
+    Ok(())
+}
+
+/// Put back the `prctl` attributes that don't restrict what the process can
+/// do. The ones that do are left for `restrict_process` right at the end,
+/// since after that some of the syscalls we inject might not be allowed, and
+/// so is the dumpable flag.
+fn restore_prctl_state(child: Pid, syscall: SyscallLoc, prctl: &PrctlState) -> Result<()> {
+    with_remote_path(child, syscall, &prctl.name, |name_addr| {
+        remote_prctl(child, syscall, libc::PR_SET_NAME, name_addr as u64)
+    })?;
+    remote_prctl(child, syscall, libc::PR_SET_TIMERSLACK, prctl.timerslack_ns)?;
+    remote_prctl(
+        child,
+        syscall,
+        libc::PR_SET_CHILD_SUBREAPER,
+        prctl.child_subreaper as u64,
+    )?;
+    Ok(())
+}
+
+/// The last syscalls we inject, putting back the dumpable flag and turning on
+/// no-new-privs and seccomp. The kernel resets the dumpable flag whenever the
+/// credentials or fs ids change, so it has to come after those are restored.
+fn restrict_process(child: Pid, syscall: SyscallLoc, prctl: &PrctlState) -> Result<()> {
+    remote_prctl(child, syscall, libc::PR_SET_DUMPABLE, prctl.dumpable as u64)?;
+    if prctl.no_new_privs {
+        remote_prctl(child, syscall, libc::PR_SET_NO_NEW_PRIVS, 1)?;
+    }
+    match prctl.seccomp {
+        0 => {}
+        1 => {
+            remote_prctl(
+                child,
+                syscall,
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_STRICT as u64,
+            )?;
+        }
+        // The filter programs can only be read back with CAP_SYS_ADMIN and
+        // then we'd have to load them without tripping over them ourselves
+        _ => warn!("process had seccomp filters, it will be restored without them"),
+    }
+    Ok(())
+}
+
+/// Point the kernel at the environment block we restored with the stack, so
+/// `/proc/pid/environ` shows the original environment. This needs
+/// `CAP_SYS_RESOURCE`, without it we just warn.
+fn restore_environment(child: Pid, syscall: SyscallLoc, env: &Environment) -> Result<()> {
+    let fields = [
+        (libc::PR_SET_MM_ENV_START, env.start),
+        (libc::PR_SET_MM_ENV_END, env.end),
+    ];
+    remote_set_mm_fields(child, syscall, &fields)
+}
+
+/// Point the kernel's idea of the data segment and heap at the restored
+/// ones, so `brk` carries on growing the `[heap]` mapping we restored at
+/// `heap_top` instead of the one the child was forked with. This needs
+/// `CAP_SYS_RESOURCE` like the environment, without it `brk` calls from the
+/// restored process fail and malloc falls back to `mmap`.
+/// `mm_set` is whether `restore_mm_layout` already set the fields, in which
+/// case it's just checking that `brk` agrees and mapping any gap.
+fn restore_heap_layout(
+    child: Pid,
+    syscall: SyscallLoc,
+    heap: &HeapLayout,
+    heap_top: Option<usize>,
+    mm_set: bool,
+) -> Result<BrkRestore> {
+    let fields = [
+        (libc::PR_SET_MM_START_DATA, heap.start_data),
+        (libc::PR_SET_MM_END_DATA, heap.end_data),
+        (libc::PR_SET_MM_START_BRK, heap.start_brk),
+        (libc::PR_SET_MM_BRK, heap.brk),
+    ];
+    let set = if mm_set {
+        Ok(())
+    } else {
+        remote_set_mm_fields(child, syscall, &fields)
+    };
+    if let Err(e) = set {
+        warn!(
+            "couldn't set the heap layout, brk won't work in the restored process: {}",
+            e
+        );
+        return Ok(BrkRestore::Syscall);
+    }
+    let brk = remote_brk(child, syscall, 0)?;
+    if brk != heap.brk {
+        warn!(
+            "program break ended up at {:x} instead of {:x}",
+            brk, heap.brk
+        );
+        return Ok(BrkRestore::Syscall);
+    }
+    // The kernel assumes everything up to the page the break is in is
+    // mapped, and only maps from there on when it grows
+    let page_up = |addr: usize| (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mapped_to = heap_top.unwrap_or_else(|| page_up(heap.start_brk));
+    let needed_to = page_up(heap.brk);
+    if mapped_to < needed_to {
+        info!(
+            "mapping {} bytes of heap between {:x} and the break",
+            needed_to - mapped_to,
+            mapped_to
+        );
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+        let prot = PROT_READ | PROT_WRITE;
+        remote_mmap(
+            child,
+            syscall,
+            mapped_to,
+            needed_to - mapped_to,
+            prot,
+            flags,
+            -1,
+            0,
+        )?;
+    } else if mapped_to > needed_to {
+        tracing::debug!(
+            "heap mapping goes up to {:x}, past the break at {:x}",
+            mapped_to,
+            heap.brk
+        );
+    }
+    Ok(if mm_set {
+        BrkRestore::MmMap
+    } else {
+        BrkRestore::MmFields
+    })
+}
+
+// The `MPOL_*` modes from linux/mempolicy.h
+const MPOL_PREFERRED: i32 = 1;
+const MPOL_BIND: i32 = 2;
+const MPOL_INTERLEAVE: i32 = 3;
+const MPOL_LOCAL: i32 = 4;
+const MPOL_PREFERRED_MANY: i32 = 5;
+const MPOL_WEIGHTED_INTERLEAVE: i32 = 6;
+/// Move pages we already wrote to the right node instead of only placing new ones
+const MPOL_MF_MOVE: u64 = 1 << 1;
+
+/// Put a mapping's NUMA policy back with `mbind`. This runs after its
+/// contents are restored, so we ask the kernel to move the pages that are
+/// already there too. If we've landed on a machine without some of the
+/// nodes, those get folded onto the nodes it does have.
+fn restore_memory_policy(child: Pid, syscall: SyscallLoc, policy: &MemoryPolicy) -> Result<()> {
+    let online = std::fs::read_to_string("/sys/devices/system/node/online")
+        .map(|list| parse_node_list(&list))
+        .unwrap_or_default();
+    if online.is_empty() {
+        return error("this machine doesn't have NUMA nodes");
+    }
+    let mut nodes: Vec<u32> = policy
+        .nodes
+        .iter()
+        .map(|&node| {
+            if online.contains(&node) {
+                node
+            } else {
+                let moved = online[node as usize % online.len()];
+                warn!(
+                    "NUMA node {} doesn't exist here, binding mapping at {:x} to node {} instead",
+                    node, policy.addr, moved
+                );
+                moved
+            }
+        })
+        .collect();
+    nodes.sort_unstable();
+    nodes.dedup();
+
+    let mut mask = vec![0u64; nodes.last().map_or(0, |&n| n as usize / 64 + 1)];
+    for node in &nodes {
+        mask[*node as usize / 64] |= 1 << (node % 64);
+    }
+    let mbind = |mask_addr: usize, maxnode: usize| {
+        let res = remote_syscall(
+            child,
+            syscall,
+            237, // mbind
+            [
+                policy.addr as u64,
+                policy.size as u64,
+                policy.mode as u64,
+                mask_addr as u64,
+                maxnode as u64,
+                MPOL_MF_MOVE,
+            ],
+        )?;
+        remote_result(res, || {
+            format!("mbind({:x}, {}, {:?})", policy.addr, policy.size, nodes)
+        })
+    };
+    if mask.is_empty() {
+        // `local` and an empty `prefer` don't take any nodes
+        mbind(0, 0)?;
+    } else {
+        let bytes: Vec<u8> = mask.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        // The kernel only looks at `maxnode - 1` bits
+        with_remote_bytes(child, syscall, &bytes, |addr| {
+            mbind(addr, mask.len() * 64 + 1)
+        })?;
+    }
+    Ok(())
+}
+
+const MADV_HUGEPAGE: u64 = 14;
+const MADV_COLLAPSE: u64 = 25;
+
+/// Ask for huge pages for a mapping we've already written the contents of.
+/// `MADV_HUGEPAGE` makes it eligible and `MADV_COLLAPSE` (Linux 6.1+) turns
+/// what's there into huge pages right away. Without it khugepaged gets
+/// around to it eventually, or maybe not at all if memory is fragmented, so
+/// it's a hint rather than a promise either way.
+fn restore_huge_pages(child: Pid, syscall: SyscallLoc, addr: usize, size: usize) {
+    let madvise = |advice| -> Result<i64> {
+        let res = remote_syscall(
+            child,
+            syscall,
+            28, // madvise
+            [addr as u64, size as u64, advice, 0, 0, 0],
+        )?;
+        remote_result(res, || format!("madvise({:x}, {}, {})", addr, size, advice))
+    };
+    if let Err(e) = madvise(MADV_HUGEPAGE) {
+        warn!("couldn't ask for huge pages at {:x}: {}", addr, e);
+        return;
+    }
+    if let Err(e) = madvise(MADV_COLLAPSE) {
+        tracing::debug!("huge pages at {:x} will be collapsed later: {}", addr, e);
+    }
+}
+
+/// `utime`, `stime`, `cutime` and `cstime` from `/proc/pid/stat`, which are
+/// in clock ticks
+fn scan_cpu_times(pid: i32) -> Result<CpuTimes> {
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if hz <= 0 {
+        return error("couldn't get the clock tick rate");
+    }
+    let time = |n| -> Result<std::time::Duration> {
+        let ticks = read_stat_field(pid, n)? as u64;
+        Ok(std::time::Duration::from_nanos(
+            ticks * 1_000_000_000 / hz as u64,
+        ))
+    };
+    Ok(CpuTimes {
+        user: time(14)?,
+        system: time(15)?,
+        children_user: time(16)?,
+        children_system: time(17)?,
+    })
+}
+
+fn monotonic_now() -> std::time::Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    std::time::Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// The `SigIgn` and `SigCgt` masks from `/proc/pid/status`
+fn scan_credentials(pid: i32) -> Result<Credentials> {
+    // Real, effective, saved and filesystem, the last normally follows the
+    // effective one by itself, see `scan_fs_ids` for when it doesn't
+    let ids = |field| -> Result<[u32; 3]> {
+        let ids = read_status_field(pid, field)?
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<std::result::Result<Vec<u32>, _>>()?;
+        match ids[..] {
+            [real, effective, saved, ..] => Ok([real, effective, saved]),
+            _ => error("too few ids in /proc/pid/status"),
+        }
+    };
+    let groups = read_status_field(pid, "Groups")?
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(Credentials {
+        uid: ids("Uid")?,
+        gid: ids("Gid")?,
+        groups,
+    })
+}
+
+/// Switch the restored process to the original's ids, groups before the
+/// user since once the user isn't root it can't change its groups anymore.
+/// Without the privilege to do it the process just keeps ours.
+fn restore_credentials(child: Pid, syscall: SyscallLoc, creds: &Credentials) -> Result<()> {
+    let ours = scan_credentials(child.as_raw())?;
+    if &ours == creds {
+        return Ok(());
+    }
+    if ours.groups != creds.groups {
+        let groups: Vec<u8> = creds.groups.iter().flat_map(|g| g.to_ne_bytes()).collect();
+        let res = with_remote_bytes(child, syscall, &groups, |addr| {
+            remote_syscall(
+                child,
+                syscall,
+                116, // setgroups
+                [creds.groups.len() as u64, addr as u64, 0, 0, 0, 0],
+            )
+        });
+        if let Err(e) = res.and_then(|res| remote_result(res, || "setgroups".to_string())) {
+            warn!(
+                "couldn't restore supplementary groups {:?}, keeping ours: {}",
+                creds.groups, e
+            );
+        }
+    }
+    let [rgid, egid, sgid] = creds.gid;
+    let res = remote_syscall(
+        child,
+        syscall,
+        119, // setresgid
+        [rgid as u64, egid as u64, sgid as u64, 0, 0, 0],
+    )?;
+    if let Err(e) = remote_result(res, || "setresgid".to_string()) {
+        warn!(
+            "couldn't switch to gids {:?}, it'll run with ours: {}",
+            creds.gid, e
+        );
+    }
+    let [ruid, euid, suid] = creds.uid;
+    let res = remote_syscall(
+        child,
+        syscall,
+        117, // setresuid
+        [ruid as u64, euid as u64, suid as u64, 0, 0, 0],
+    )?;
+    if let Err(e) = remote_result(res, || "setresuid".to_string()) {
+        warn!(
+            "couldn't switch to uids {:?}, it'll run as us: {}",
+            creds.uid, e
+        );
+    }
+    Ok(())
+}
+
+/// The fsuid and fsgid, the last of the `Uid`/`Gid` ids in
+/// `/proc/pid/status`, if either isn't just the effective one
+fn scan_fs_ids(pid: i32) -> Result<Option<(u32, u32)>> {
+    let ids = |field| -> Result<(u32, u32)> {
+        let ids = read_status_field(pid, field)?
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<std::result::Result<Vec<u32>, _>>()?;
+        match ids[..] {
+            [_, effective, _, fs] => Ok((effective, fs)),
+            _ => error("expected four ids in /proc/pid/status"),
+        }
+    };
+    let (euid, fsuid) = ids("Uid")?;
+    let (egid, fsgid) = ids("Gid")?;
+    if (euid, egid) == (fsuid, fsgid) {
+        return Ok(None);
+    }
+    info!("process has fsuid {} and fsgid {}", fsuid, fsgid);
+    Ok(Some((fsuid, fsgid)))
+}
+
+/// Set the fs ids after `restore_credentials`, since changing the effective
+/// ids resets them. `setfsuid` and `setfsgid` never say if they failed, only
+/// what the id was before, so we ask again with an invalid one to see if it
+/// took. The gid goes first for the same reason as in `restore_credentials`.
+fn restore_fs_ids(child: Pid, syscall: SyscallLoc, uid: u32, gid: u32) -> Result<()> {
+    // setfsgid, setfsuid
+    for (nr, name, id) in [(123, "fsgid", gid), (122, "fsuid", uid)] {
+        remote_syscall(child, syscall, nr, [id as u64, 0, 0, 0, 0, 0])?;
+        let now = remote_syscall(child, syscall, nr, [u32::MAX as u64, 0, 0, 0, 0, 0])? as u32;
+        if now != id {
+            warn!(
+                "couldn't set the {} to {}, file access is checked as {}",
+                name, id, now
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A `struct itimerval`, the interval then the value, each as seconds and
+/// microseconds
+const ITIMERVAL_SIZE: usize = 32;
+
+/// What's left of the process's `ITIMER_REAL` and how often it repeats, if
+/// it has one going. Timers aren't inherited over `fork`, so teleforking
+/// reads `owner`'s, which is us, rather than the frozen child's.
+fn scan_real_timer(
+    child: Pid,
+    owner: Pid,
+) -> Result<Option<(std::time::Duration, std::time::Duration)>> {
+    let mut timer = [0u8; ITIMERVAL_SIZE];
+    if owner != child {
+        let res =
+            unsafe { libc::syscall(libc::SYS_getitimer, libc::ITIMER_REAL, timer.as_mut_ptr()) };
+        Errno::result(res)?;
+    } else {
+        // A filter could kill it for a syscall it never makes itself
+        if read_status_field(child.as_raw(), "Seccomp")? != "0" {
+            return error("can't inject getitimer into a process with a seccomp filter");
+        }
+        let read = with_dump_syscall(child, |syscall| {
+            with_remote_bytes(child, syscall, &timer, |addr| {
+                let res = remote_syscall(child, syscall, 36, [0, addr as u64, 0, 0, 0, 0])?; // getitimer
+                remote_result(res, || "getitimer(ITIMER_REAL)".to_string())?;
+                read_memory(child, addr, ITIMERVAL_SIZE)
+            })
+        })?;
+        timer.copy_from_slice(&read);
+    }
+    let word = |i: usize| i64::from_ne_bytes(timer[i * 8..i * 8 + 8].try_into().unwrap()) as u64;
+    let interval =
+        std::time::Duration::from_secs(word(0)) + std::time::Duration::from_micros(word(1));
+    let remaining =
+        std::time::Duration::from_secs(word(2)) + std::time::Duration::from_micros(word(3));
+    if remaining.is_zero() {
+        return Ok(None);
+    }
+    info!(
+        "process has an alarm going off in {:?}, repeating every {:?}",
+        remaining, interval
+    );
+    Ok(Some((remaining, interval)))
+}
+
+/// Set the restored process's `ITIMER_REAL` going again with the time it
+/// had left. Not worth failing the restore over, it just never gets its
+/// `SIGALRM`.
+fn restore_real_timer(
+    child: Pid,
+    syscall: SyscallLoc,
+    remaining: std::time::Duration,
+    interval: std::time::Duration,
+) {
+    let mut timer = Vec::with_capacity(ITIMERVAL_SIZE);
+    for time in [interval, remaining] {
+        timer.extend_from_slice(&(time.as_secs() as i64).to_ne_bytes());
+        timer.extend_from_slice(&(time.subsec_micros() as i64).to_ne_bytes());
+    }
+    let res = with_remote_bytes(child, syscall, &timer, |addr| {
+        let res = remote_syscall(child, syscall, 38, [0, addr as u64, 0, 0, 0, 0])?; // setitimer
+        remote_result(res, || format!("setitimer(ITIMER_REAL, {:?})", remaining))
+    });
+    if let Err(e) = res {
+        warn!(
+            "couldn't set the process's alarm going again, it won't get its SIGALRM: {}",
+            e
+        );
+    }
+}
+
+fn scan_signal_dispositions(pid: i32) -> Result<(u64, u64)> {
+    let ignored = u64::from_str_radix(&read_status_field(pid, "SigIgn")?, 16)?;
+    let caught = u64::from_str_radix(&read_status_field(pid, "SigCgt")?, 16)?;
+    Ok((ignored, caught))
+}
+
+/// Ask the stopped process for the action of every signal by injecting
+/// `rt_sigaction` into it, since `/proc` only says which signals are caught
+/// and not where their handlers are or what flags and mask they have.
+fn scan_signal_actions(child: Pid) -> Result<Vec<SignalAction>> {
+    if read_status_field(child.as_raw(), "Seccomp")? != "0" {
+        // Under seccomp our injected rt_sigaction could get the process killed
+        return error("process is using seccomp");
+    }
+    with_dump_syscall(child, |syscall| {
+        with_remote_bytes(child, syscall, &[0u8; 32], |addr| {
+            let mut actions = Vec::new();
+            for sig in 1..=64u32 {
+                // Those two always have the default action
+                if sig == libc::SIGKILL as u32 || sig == libc::SIGSTOP as u32 {
+                    continue;
+                }
+                let res = remote_syscall(
+                    child,
+                    syscall,
+                    13, // rt_sigaction
+                    [sig as u64, 0, addr as u64, 8, 0, 0],
+                )?;
+                remote_result(res, || format!("rt_sigaction of signal {}", sig))?;
+                actions.push(SignalAction::from_bytes(
+                    sig,
+                    &read_memory(child, addr, 32)?,
+                ));
+            }
+            Ok(actions)
+        })
+    })
+}
+
+fn scan_signal_masks(pid: i32) -> Result<SignalMasks> {
+    let mut tids = Vec::new();
+    for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse::<i32>() {
+            tids.push(tid);
+        }
+    }
+    // The leader first, the rest in the order they were made. It's the
+    // thread whose tid is the process's pid, the one signals to the pid and
+    // pidfds of it go to.
+    let leader: i32 = read_status_field(pid, "Tgid")?.parse()?;
+    tids.sort_by_key(|&tid| (tid != leader, tid));
+    let mut threads = Vec::new();
+    for tid in tids {
+        // `/proc/tid` is there for every thread even if it's not listed
+        threads.push(ThreadSignals {
+            tid,
+            blocked: u64::from_str_radix(&read_status_field(tid, "SigBlk")?, 16)?,
+            pending: u64::from_str_radix(&read_status_field(tid, "SigPnd")?, 16)?,
+        });
+    }
+    let shared_pending = u64::from_str_radix(&read_status_field(pid, "ShdPnd")?, 16)?;
+    Ok(SignalMasks {
+        threads,
+        shared_pending,
+    })
+}
+
+/// Size of a `siginfo_t`, however much of it a signal uses
+const SIGINFO_SIZE: usize = 128;
+
+/// `PTRACE_PEEKSIGINFO` reads the queue for the whole process rather than
+/// the thread with this
+const PTRACE_PEEKSIGINFO_SHARED: u32 = 1;
+
+/// Read the process's signal queues through ptrace, which unlike
+/// `/proc/pid/status` gives every queued signal and its `siginfo`
+fn scan_queued_signals(child: Pid) -> Result<QueuedSignals> {
+    let peek = |flags: u32| -> Result<Vec<Vec<u8>>> {
+        let mut queue = Vec::new();
+        loop {
+            // struct ptrace_peeksiginfo_args
+            let mut args = [0u8; 16];
+            args[..8].copy_from_slice(&(queue.len() as u64).to_ne_bytes());
+            args[8..12].copy_from_slice(&flags.to_ne_bytes());
+            args[12..].copy_from_slice(&32i32.to_ne_bytes());
+            let mut infos = vec![0u8; 32 * SIGINFO_SIZE];
+            let res = unsafe {
+                libc::ptrace(
+                    libc::PTRACE_PEEKSIGINFO,
+                    child.as_raw(),
+                    args.as_mut_ptr(),
+                    infos.as_mut_ptr(),
+                )
+            };
+            if res < 0 {
+                return Err(Box::new(std::io::Error::last_os_error()));
+            }
+            if res == 0 {
+                return Ok(queue);
+            }
+            queue.extend(
+                infos
+                    .chunks(SIGINFO_SIZE)
+                    .take(res as usize)
+                    .map(<[u8]>::to_vec),
+            );
+        }
+    };
+    Ok(QueuedSignals {
+        thread: peek(0)?,
+        shared: peek(PTRACE_PEEKSIGINFO_SHARED)?,
+    })
+}
+
+/// The signal number of a raw `siginfo_t`, its first field
+fn siginfo_signo(info: &[u8]) -> u64 {
+    i32::from_ne_bytes(info[..4].try_into().unwrap()) as u64
+}
+
+/// Block what the process's threads had blocked and queue up again whatever
+/// was pending on them. Only the leader is restored so it's the only one
+/// with a mask to set, and anything pending for the other threads alone
+/// goes with them.
+///
+/// Only pending signals the thread blocks can be sent again, the rest were
+/// on their way to being handled and would be handled now instead, by
+/// handlers that didn't come back with it.
+///
+/// With the `QueuedSignals` each one is queued again with its `siginfo`, as
+/// many times as it was. The process sends them to itself, which is the one
+/// case the kernel lets `rt_sigqueueinfo` claim to be from anyone, so they
+/// still look like they came from whoever sent them.
+fn restore_signal_masks(
+    child: Pid,
+    syscall: SyscallLoc,
+    masks: &SignalMasks,
+    queued: Option<&QueuedSignals>,
+) -> Result<()> {
+    let leader = match masks.threads.first() {
+        Some(leader) => leader,
+        None => return Ok(()),
+    };
+    for thread in &masks.threads[1..] {
+        warn!(
+            "thread {} isn't restored, its signal mask {:x} and pending signals {:x} are dropped",
+            thread.tid, thread.blocked, thread.pending
+        );
+    }
+    // SIGKILL and SIGSTOP can't be blocked anyway
+    with_remote_bytes(child, syscall, &leader.blocked.to_ne_bytes(), |addr| {
+        let res = remote_syscall(
+            child,
+            syscall,
+            14, // rt_sigprocmask
+            [libc::SIG_SETMASK as u64, addr as u64, 0, 8, 0, 0],
+        )?;
+        remote_result(res, || "rt_sigprocmask".to_string())
+    })?;
+    let pid = child.as_raw() as u64;
+    for sig in 1..=64u64 {
+        let bit = 1 << (sig - 1);
+        if leader.blocked & bit == 0 {
+            if (leader.pending | masks.shared_pending) & bit != 0 {
+                tracing::debug!("signal {} was about to be delivered, dropping it", sig);
+            }
+            continue;
+        }
+        if let Some(queued) = queued {
+            for info in queued.shared.iter().filter(|i| siginfo_signo(i) == sig) {
+                let res = with_remote_bytes(child, syscall, info, |addr| {
+                    // rt_sigqueueinfo
+                    remote_syscall(child, syscall, 129, [pid, sig, addr as u64, 0, 0, 0])
+                })?;
+                remote_result(res, || format!("rt_sigqueueinfo with signal {}", sig))?;
+            }
+            for info in queued.thread.iter().filter(|i| siginfo_signo(i) == sig) {
+                let res = with_remote_bytes(child, syscall, info, |addr| {
+                    // rt_tgsigqueueinfo
+                    remote_syscall(child, syscall, 297, [pid, pid, sig, addr as u64, 0, 0])
+                })?;
+                remote_result(res, || format!("rt_tgsigqueueinfo with signal {}", sig))?;
+            }
+            continue;
+        }
+        // Each queued once, if more of a realtime signal were queued up the
+        // rest are lost
+        if masks.shared_pending & bit != 0 {
+            let res = remote_syscall(child, syscall, 62, [pid, sig, 0, 0, 0, 0])?; // kill
+            remote_result(res, || format!("kill with signal {}", sig))?;
+        }
+        if leader.pending & bit != 0 {
+            let res = remote_syscall(child, syscall, 234, [pid, pid, sig, 0, 0, 0])?; // tgkill
+            remote_result(res, || format!("tgkill with signal {}", sig))?;
+        }
+    }
+    Ok(())
+}
+
+/// Put back which signals the process ignores. The child we restore into is
+/// a fork of us so it starts out with our dispositions, which is the wrong
+/// way round for things like a server that ignores SIGPIPE so a closed
+/// connection doesn't kill it. Telepad puts whatever it inherited as ignored
+/// back to the default first and does this last, right before resuming, so
+/// whatever the program ignores can't get in the way of the SIGSTOPs and
+/// SIGTRAPs the restore itself relies on.
+///
+/// Handlers are another matter, all `/proc` says is that there was one and
+/// not where it was, so those signals are left alone. Dumps with
+/// `SignalActions` go through `restore_signal_actions` instead.
+fn restore_signal_dispositions(
+    child: Pid,
+    syscall: SyscallLoc,
+    ignored: u64,
+    caught: u64,
+) -> Result<()> {
+    let (ours_ignored, ours_caught) = scan_signal_dispositions(child.as_raw())?;
+    for sig in 1..=64u64 {
+        let bit = 1 << (sig - 1);
+        // Those two can't be changed anyway
+        if sig == libc::SIGKILL as u64 || sig == libc::SIGSTOP as u64 || caught & bit != 0 {
+            continue;
+        }
+        let handler = if ignored & bit != 0 {
+            if ours_ignored & bit != 0 {
+                continue;
+            }
+            libc::SIG_IGN
+        } else {
+            if (ours_ignored | ours_caught) & bit == 0 {
+                continue;
+            }
+            libc::SIG_DFL
+        };
+        // The kernel's struct sigaction: handler, flags, restorer, mask
+        let mut act = [0u8; 32];
+        act[..8].copy_from_slice(&(handler as u64).to_ne_bytes());
+        with_remote_bytes(child, syscall, &act, |addr| {
+            let res = remote_syscall(
+                child,
+                syscall,
+                13, // rt_sigaction
+                [sig, addr as u64, 0, 8, 0, 0],
+            )?;
+            remote_result(res, || format!("rt_sigaction of signal {}", sig))
+        })?;
+    }
+    Ok(())
+}
+
+/// Put back every signal's action just as the dump read it, handlers
+/// included. Those point into the program's own code, which is back where it
+/// was by now. Like `restore_signal_dispositions` this goes last, and it
+/// also replaces any handlers the child inherited from us.
+fn restore_signal_actions(child: Pid, syscall: SyscallLoc, actions: &[SignalAction]) -> Result<()> {
+    for action in actions {
+        with_remote_bytes(child, syscall, &action.to_bytes(), |addr| {
+            let res = remote_syscall(
+                child,
+                syscall,
+                13, // rt_sigaction
+                [action.sig as u64, addr as u64, 0, 8, 0, 0],
+            )?;
+            remote_result(res, || format!("rt_sigaction of signal {}", action.sig))
+        })?;
+    }
+    Ok(())
+}
+
+/// Turn a recorded fd path (as seen from outside the original process) into
+/// one that resolves correctly from inside a process whose root is `root`.
+fn path_relative_to_root(path: &str, root: &str) -> String {
+    if root == "/" {
+        return path.to_string();
+    }
+    match path.strip_prefix(root.trim_end_matches('/')) {
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        Some("") => "/".to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// Recreate the `socketpair`s the process had. Each pair is a group of fds
+/// pointing at one end or the other, there can be more than one fd per end
+/// if they were `dup`ed. We make a fresh pair and `dup2` each end onto all
+/// the fds that pointed at it, so everything stays connected as before.
+fn restore_unix_pairs(
+    child: Pid,
+    syscall: SyscallLoc,
+    pairs: HashMap<u64, Vec<(u32, UnixPairConnection)>>,
+    min_fd: u32,
+) -> Result<()> {
+    for (pair, ends) in pairs {
+        let sock_type = ends[0].1.sock_type;
+        let (a, b) = remote_socketpair(child, syscall, sock_type)?;
+        // The fds the kernel hands us could be ones we're about to dup2
+        // over, so first move them out of the way above every restored fd
+        let a_high = remote_fcntl(child, syscall, a, libc::F_DUPFD, min_fd as u64)? as u32;
+        let b_high = remote_fcntl(child, syscall, b, libc::F_DUPFD, min_fd as u64)? as u32;
+        remote_close(child, syscall, a)?;
+        remote_close(child, syscall, b)?;
+
+        let mut refilled = Vec::new();
+        for (fd, conn) in &ends {
+            // The pair is keyed by the lower inode, that end gets `a`
+            let (ours, theirs) = if conn.ino == pair {
+                (a_high, b_high)
+            } else {
+                (b_high, a_high)
+            };
+            tracing::debug!("restoring socketpair end {} onto fd {}", conn.ino, fd);
+            remote_dup2(child, syscall, ours, *fd)?;
+            // Bytes that were waiting to be read on this end get written
+            // into the other end so they show up here again
+            if !conn.buffered.is_empty() && !refilled.contains(&conn.ino) {
+                remote_write(child, syscall, theirs, &conn.buffered)?;
+                refilled.push(conn.ino);
+            }
+        }
+        remote_close(child, syscall, a_high)?;
+        remote_close(child, syscall, b_high)?;
+    }
+    Ok(())
+}
+
+/// A file descriptor from the stream, as shown to a `TelepadBuilder::fd_policy`
+#[derive(Debug, Clone)]
+pub struct FdInfo {
+    pub fd: u32,
+    /// What sort of thing it is, like "file" or "socket"
+    pub kind: &'static str,
+    /// The path as recorded on the source machine, for files
+    pub path: Option<String>,
+    /// Whether `telepad` would be able to bring it back by itself
+    pub restorable: bool,
+}
+
+/// What to do with a file descriptor when restoring
+#[derive(Debug, Clone)]
+pub enum FdAction {
+    /// Whatever `telepad` would normally do
+    Restore,
+    /// Leave the fd closed in the restored process
+    Skip,
+    /// Open this path read-write instead, as seen from inside the restored
+    /// process. `/dev/null` is a handy one.
+    ReplaceWith(String),
+}
+
+/// See `Config::unsupported_fds`. An `FdAction` from a
+/// `TelepadBuilder::fd_policy` comes first, this is only for the fds it
+/// leaves to `telepad`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsupportedFdPolicy {
+    /// Leave them closed and warn, listing them in `RestoreReport::skipped`
+    Warn,
+    /// Fail the restore with an `Unsupported` error
+    Fail,
+    /// Leave them closed without making a fuss
+    Drop,
+}
+
+/// See `Config::same_pid`. A process that keeps its pid around, like in a
+/// pid file or a lock it checks is still its own, can get confused by a new
+/// one, but most never look at it again after starting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamePid {
+    /// Take whatever pid the fork gets
+    Any,
+    /// Try for the pid it had and carry on with another if it's taken or
+    /// we can't ask for it, listing it in `RestoreReport::skipped`
+    Prefer,
+    /// Fail the restore with a `PidConflict` unless it gets the pid it had
+    Require,
+}
+
+/// A mapping from the stream, as shown to a `TelepadBuilder::map_policy`
+#[derive(Debug, Clone)]
+pub struct MapInfo {
+    pub name: Option<String>,
+    pub addr: usize,
+    pub size: usize,
+    pub readable: bool,
+    pub writeable: bool,
+    pub executable: bool,
+}
+
+/// What to do with a memory mapping when restoring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapAction {
+    Restore,
+    /// Don't map it at all, anything touching it in the restored process
+    /// will segfault so this is mostly for things you know are unused.
+    Skip,
+}
+
+/// Callbacks that let the caller overrule how `telepad` restores things.
+/// These can't go in `Config` since closures aren't `Clone` or `Debug`.
+#[derive(Default)]
+pub(crate) struct RestoreHooks<'a> {
+    pub fd_policy: Option<FdPolicy<'a>>,
+    pub map_policy: Option<MapPolicy<'a>>,
+    pub environment: Option<EnvHook<'a>>,
+    pub pre_resume: Option<PreResumeHook<'a>>,
+}
+
+pub(crate) type FdPolicy<'a> = Box<dyn FnMut(&FdInfo) -> FdAction + 'a>;
+pub(crate) type MapPolicy<'a> = Box<dyn FnMut(&MapInfo) -> MapAction + 'a>;
+pub(crate) type EnvHook<'a> = Box<dyn FnMut(&[String]) + 'a>;
+pub(crate) type PreResumeHook<'a> = Box<dyn FnMut(&RestoredProcess) -> Result<()> + 'a>;
+
+impl RestoreHooks<'_> {
+    fn fd_action(&mut self, fd: u32, conn: &Connection) -> FdAction {
+        match &mut self.fd_policy {
+            Some(policy) => policy(&FdInfo {
+                fd,
+                kind: conn.kind(),
+                path: conn.path().map(str::to_string),
+                restorable: conn.restorable(),
+            }),
+            None => FdAction::Restore,
+        }
+    }
+
+    fn map_action(&mut self, m: &Mapping) -> MapAction {
+        match &mut self.map_policy {
+            Some(policy) => policy(&MapInfo {
+                name: m.name.clone(),
+                addr: m.addr,
+                size: m.size,
+                readable: m.readable,
+                writeable: m.writeable,
+                executable: m.executable,
+            }),
+            None => MapAction::Restore,
+        }
+    }
+}
+
+/// Open flags it makes no sense to replay on a file that already exists
+const UNREPLAYABLE_OPEN_FLAGS: i32 = libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC | libc::O_NOCTTY;
+
+fn restore_file(
+    child: Pid,
+    syscall: SyscallLoc,
+    fd: u32,
+    path: String,
+    offset: u64,
+    flags: i32,
+) -> Result<()> {
+    // Things like O_SYNC and the access mode can only be picked at open
+    // time, so it all goes through open rather than fixing it up with fcntl
+    let flags = flags & !UNREPLAYABLE_OPEN_FLAGS;
+    let open_fd = match remote_open(child, syscall, &path, flags) {
+        Ok(open_fd) => open_fd,
+        // O_DIRECT isn't supported everywhere and O_NOATIME needs us to own
+        // the file, neither is worth failing the restore over
+        Err(e) if flags & (libc::O_DIRECT | libc::O_NOATIME) != 0 => {
+            warn!(
+                "couldn't open {} with flags {:o}, trying without O_DIRECT and O_NOATIME: {}",
+                path, flags, e
+            );
+            let flags = flags & !(libc::O_DIRECT | libc::O_NOATIME);
+            remote_open(child, syscall, &path, flags)?
+        }
+        Err(e) => return Err(e),
+    };
+    tracing::debug!("opened file descriptor {} for {}", open_fd, path);
+    remote_dup2(child, syscall, open_fd, fd)?;
+    restore_offset(child, syscall, fd, offset, flags)
+}
+
+/// Put an fd's file offset back where it was, for the files that have one
+/// that means anything. Writes with `O_APPEND` go to the end wherever the
+/// offset is, so it only matters if the fd can read too.
+fn restore_offset(child: Pid, syscall: SyscallLoc, fd: u32, offset: u64, flags: i32) -> Result<()> {
+    if flags & libc::O_APPEND != 0 && flags & libc::O_ACCMODE == libc::O_WRONLY {
+        tracing::debug!("not seeking fd {}, it's append only", fd);
+        return Ok(());
+    }
+    // Following the link gets us what it's actually open on, without having
+    // to inject an fstat
+    let seekable = match std::fs::metadata(format!("/proc/{}/fd/{}", child, fd)) {
+        Ok(meta) => {
+            let kind = meta.file_type();
+            Some(kind.is_file() || kind.is_block_device())
+        }
+        Err(_) => None,
+    };
+    if seekable == Some(false) {
+        // Pipes, sockets and character devices either don't seek at all or
+        // don't have an offset worth restoring
+        tracing::debug!("not seeking fd {}, it's not a regular file", fd);
+        return Ok(());
+    }
+    match remote_lseek(child, syscall, fd, offset) {
+        Ok(at) if at == offset => Ok(()),
+        // Only a regular file is sure to land exactly where we asked
+        Ok(at) if seekable.is_none() => {
+            warn!("fd {} ended up at offset {} rather than {}", fd, at, offset);
+            Ok(())
+        }
+        Ok(_) => error("lseek ended up at the wrong offset"),
+        Err(e) if is_errno(&*e, Errno::ESPIPE) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Open the file at its path if it's there, otherwise recreate it from the
+/// bundled contents as an anonymous memfd so the process can still read it.
+fn restore_bundled_file(
+    child: Pid,
+    syscall: SyscallLoc,
+    fd: u32,
+    file: BundledFileConnection,
+    root: &str,
+    flags: i32,
+) -> Result<()> {
+    // Recorded paths are host paths whether or not the restored process
+    // ended up chrooted, and we share a filesystem view with it
+    if std::path::Path::new(&file.path).exists() {
+        let path = path_relative_to_root(&file.path, root);
+        return restore_file(child, syscall, fd, path, file.offset, flags);
+    }
+    info!(
+        "{} doesn't exist here, restoring fd {} from its bundled contents",
+        file.path, fd
+    );
+    let name = file.path.rsplit('/').next().unwrap_or("telefork");
+    let memfd = match remote_memfd_create(child, syscall, name) {
+        Err(e) if is_missing_syscall(&*e) => {
+            // An unlinked file somewhere temporary is just as anonymous
+            let path = format!("/tmp/.telefork-{}-{}", child, fd);
+            info!("{}, putting fd {} in {} instead", e, fd, path);
+            let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+            let tmp = remote_open_mode(child, syscall, &path, flags, 0o600)?;
+            remote_unlink(child, syscall, &path)?;
+            tmp
+        }
+        memfd => memfd?,
+    };
+    remote_write(child, syscall, memfd, &file.contents)?;
+    remote_dup2(child, syscall, memfd, fd)?;
+    remote_close(child, syscall, memfd)?;
+    if remote_lseek(child, syscall, fd, file.offset)? != file.offset {
+        return error("lseek ended up at the wrong offset");
+    }
+    Ok(())
+}
+
+/// Reopen a directory fd with the flags it had, so one opened `O_PATH` just
+/// to `openat` relative to stays that way and one being read with
+/// `getdents` can still be. If what we get isn't the same directory, the
+/// process's relative opens will find different files than they would have.
+fn restore_directory(
+    child: Pid,
+    syscall: SyscallLoc,
+    fd: u32,
+    dir: DirectoryConnection,
+    root: &str,
+    flags: i32,
+) -> Result<()> {
+    let path = path_relative_to_root(&dir.path, root);
+    let flags = (flags & !UNREPLAYABLE_OPEN_FLAGS) | libc::O_DIRECTORY;
+    let open_fd = remote_open(child, syscall, &path, flags)?;
+    if open_fd != fd {
+        remote_dup2(child, syscall, open_fd, fd)?;
+        remote_close(child, syscall, open_fd)?;
+    }
+    match std::fs::metadata(format!("/proc/{}/fd/{}", child, fd)) {
+        Ok(meta) if meta.ino() == dir.ino => {}
+        Ok(meta) => warn!(
+            "directory fd {} for {} is inode {} rather than {}, opens relative to it may find different files",
+            fd,
+            dir.path,
+            meta.ino(),
+            dir.ino
+        ),
+        Err(e) => warn!("couldn't check what directory fd {} is: {}", fd, e),
+    }
+    Ok(())
+}
+
+/// Make a new inotify instance and add the watches back. The process knows
+/// its watches by their descriptors, which count up from 1 as they're added,
+/// so we add them in order, and where the original had gaps from removed
+/// watches we burn through numbers by adding and removing until we get the
+/// same one.
+fn restore_inotify(
+    child: Pid,
+    syscall: SyscallLoc,
+    fd: u32,
+    mut inotify: InotifyConnection,
+    root: &str,
+) -> Result<()> {
+    // Non-blocking while we set it up so we can drain it after
+    let res = remote_syscall(
+        child,
+        syscall,
+        294,
+        [libc::IN_NONBLOCK as u64, 0, 0, 0, 0, 0],
+    )?;
+    let inotify_fd = remote_result(res, || "inotify_init1".to_string())? as u32;
+    inotify.watches.sort_by_key(|watch| watch.wd);
+    let mut burned = false;
+    for watch in &inotify.watches {
+        let path = path_relative_to_root(&watch.path, root);
+        loop {
+            let wd = with_remote_path(child, syscall, &path, |addr| {
+                let res = remote_syscall(
+                    child,
+                    syscall,
+                    254, // inotify_add_watch
+                    [inotify_fd as u64, addr as u64, watch.mask as u64, 0, 0, 0],
+                )?;
+                remote_result(res, || format!("inotify_add_watch on {}", path))
+            });
+            let wd = match wd {
+                Ok(wd) => wd as i32,
+                Err(e) => {
+                    warn!("couldn't watch {} again: {}", path, e);
+                    break;
+                }
+            };
+            if wd >= watch.wd {
+                if wd != watch.wd {
+                    warn!(
+                        "watch on {} came back as {} instead of {}",
+                        path, wd, watch.wd
+                    );
+                }
+                break;
+            }
+            let res = remote_syscall(
+                child,
+                syscall,
+                255, // inotify_rm_watch
+                [inotify_fd as u64, wd as u64, 0, 0, 0, 0],
+            )?;
+            remote_result(res, || "inotify_rm_watch".to_string())?;
+            burned = true;
+        }
+    }
+    if burned {
+        // Removing watches queues IN_IGNORED events the process would be
+        // confused by, read them all until there's nothing left
+        with_remote_bytes(child, syscall, &[0u8; PAGE_SIZE], |addr| {
+            loop {
+                let res = remote_syscall(
+                    child,
+                    syscall,
+                    0, // read
+                    [inotify_fd as u64, addr as u64, PAGE_SIZE as u64, 0, 0, 0],
+                )?;
+                if res <= 0 {
+                    return Ok(());
+                }
+            }
+        })?;
+    }
+    if !inotify.nonblocking {
+        remote_fcntl(child, syscall, inotify_fd, libc::F_SETFL, 0)?;
+    }
+    if inotify_fd != fd {
+        remote_dup2(child, syscall, inotify_fd, fd)?;
+        remote_close(child, syscall, inotify_fd)?;
+    }
+    Ok(())
+}
+
+/// Open a pidfd for the same process again, if it's still around. If it's
+/// gone that's a `NotHere` and the fd is left closed, the same as if we
+/// didn't know how to restore it.
+fn restore_pidfd(child: Pid, syscall: SyscallLoc, fd: u32, pidfd: &PidfdConnection) -> Result<()> {
+    match read_stat_field(pidfd.pid, 22) {
+        Ok(start_time) if start_time as u64 == pidfd.start_time => {}
+        Ok(_) => {
+            let gone = format!("pid {} is a different process now", pidfd.pid);
+            return Err(Box::new(NotHere(gone)));
+        }
+        Err(_) => {
+            let gone = format!("process {} doesn't exist here", pidfd.pid);
+            return Err(Box::new(NotHere(gone)));
+        }
+    }
+    // PIDFD_NONBLOCK is O_NONBLOCK
+    let flags = if pidfd.nonblocking {
+        libc::O_NONBLOCK
+    } else {
+        0
+    };
+    let res = remote_syscall(
+        child,
+        syscall,
+        434, // pidfd_open
+        [pidfd.pid as u64, flags as u64, 0, 0, 0, 0],
+    )?;
+    let new_fd = remote_result(res, || format!("pidfd_open of {}", pidfd.pid))? as u32;
+    if new_fd != fd {
+        remote_dup2(child, syscall, new_fd, fd)?;
+        remote_close(child, syscall, new_fd)?;
+    }
+    Ok(())
+}
+
+/// Open the queue again by name, and if it isn't here make it with the
+/// limits it had and put the saved messages in. One that's already here
+/// keeps whatever's in it now, likely the same messages if it's the one the
+/// original had open.
+fn restore_mqueue(child: Pid, syscall: SyscallLoc, fd: u32, mq: &MqueueConnection) -> Result<()> {
+    // The syscall wants the name without the slash libc takes off
+    let name = mq.name.trim_start_matches('/');
+    let open = |flags: i32| -> Result<u32> {
+        // The attributes and the name both go in the one scratch page
+        let mut bytes = Vec::with_capacity(MQ_ATTR_SIZE + name.len() + 1);
+        for field in [0, mq.max_messages, mq.message_size, 0] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.resize(MQ_ATTR_SIZE, 0);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        let res = with_remote_bytes(child, syscall, &bytes, |addr| {
+            let attr = if flags & libc::O_CREAT != 0 { addr } else { 0 };
+            remote_syscall(
+                child,
+                syscall,
+                240, // mq_open
+                [
+                    (addr + MQ_ATTR_SIZE) as u64,
+                    flags as u64,
+                    mq.mode as u64,
+                    attr as u64,
+                    0,
+                    0,
+                ],
+            )
+        })?;
+        Ok(remote_result(res, || format!("mq_open of {}", mq.name))? as u32)
+    };
+    let new_fd = match open(mq.flags) {
+        Ok(new_fd) => new_fd,
+        Err(e) if is_errno(&*e, Errno::ENOENT) => {
+            info!("message queue {} isn't here, making it", mq.name);
+            let made = open(libc::O_RDWR | libc::O_NONBLOCK | libc::O_CREAT | libc::O_EXCL)?;
+            let res = send_mqueue_messages(child, syscall, made, mq);
+            remote_close(child, syscall, made)?;
+            res?;
+            open(mq.flags)?
+        }
+        Err(e) => return Err(e),
+    };
+    if new_fd != fd {
+        remote_dup2(child, syscall, new_fd, fd)?;
+        remote_close(child, syscall, new_fd)?;
+        // mq_open always sets it, and dup2 doesn't carry it over
+        remote_fcntl(child, syscall, fd, libc::F_SETFD, libc::FD_CLOEXEC as u64)?;
+    }
+    Ok(())
+}
+
+/// Messages can be bigger than the scratch page so they go through a buffer
+/// of their own
+fn send_mqueue_messages(
+    child: Pid,
+    syscall: SyscallLoc,
+    queue: u32,
+    mq: &MqueueConnection,
+) -> Result<()> {
+    if mq.messages.is_empty() {
+        return Ok(());
+    }
+    let len = (mq.message_size as usize).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let buf = remote_mmap_anon(child, syscall, None, len, PROT_READ | PROT_WRITE)?;
+    let res = mq.messages.iter().try_for_each(|message| {
+        stream_memory(child, &mut &message.data[..], buf, message.data.len())?;
+        let res = remote_syscall(
+            child,
+            syscall,
+            242, // mq_timedsend
+            [
+                queue as u64,
+                buf as u64,
+                message.data.len() as u64,
+                message.priority as u64,
+                0,
+                0,
+            ],
+        )?;
+        remote_result(res, || format!("mq_timedsend to {}", mq.name)).map(drop)
+    });
+    remote_munmap(child, syscall, buf, len)?;
+    res
+}
+
+/// Make the userfaultfd again and register the same regions with it. The
+/// mappings are all back by now, but with every page filled in, so the ones
+/// that had never been touched get dropped again for the process's handler
+/// to be asked about like before.
+fn restore_userfaultfd(
+    child: Pid,
+    syscall: SyscallLoc,
+    fd: u32,
+    uffd: &UserfaultfdConnection,
+) -> Result<()> {
+    let call = |flags: i32| -> Result<i64> {
+        let res = remote_syscall(child, syscall, 323, [flags as u64, 0, 0, 0, 0, 0])?; // userfaultfd
+        remote_result(res, || "userfaultfd".to_string())
+    };
+    // Without privileges that's all unprivileged_userfaultfd=0 allows, and
+    // it's all a process handling its own memory needs
+    let new_fd = match call(uffd.flags) {
+        Err(e) if is_errno(&*e, Errno::EPERM) => call(uffd.flags | UFFD_USER_MODE_ONLY)?,
+        res => res?,
+    } as u32;
+    if let Some(features) = uffd.features {
+        let mut api = Vec::with_capacity(24);
+        for field in [UFFD_API, features, 0] {
+            api.extend_from_slice(&field.to_ne_bytes());
+        }
+        with_remote_bytes(child, syscall, &api, |addr| {
+            let res = remote_syscall(
+                child,
+                syscall,
+                16, // ioctl
+                [new_fd as u64, UFFDIO_API, addr as u64, 0, 0, 0],
+            )?;
+            remote_result(res, || format!("UFFDIO_API with features {:x}", features))
+        })?;
+    }
+    for region in &uffd.regions {
+        let mut register = Vec::with_capacity(32);
+        for field in [region.addr as u64, region.size as u64, region.mode, 0] {
+            register.extend_from_slice(&field.to_ne_bytes());
+        }
+        with_remote_bytes(child, syscall, &register, |addr| {
+            let res = remote_syscall(
+                child,
+                syscall,
+                16, // ioctl
+                [new_fd as u64, UFFDIO_REGISTER, addr as u64, 0, 0, 0],
+            )?;
+            remote_result(res, || {
+                format!(
+                    "UFFDIO_REGISTER of {} bytes at {:x}",
+                    region.size, region.addr
+                )
+            })
+        })?;
+        for &(addr, len) in &region.missing {
+            let res = remote_syscall(
+                child,
+                syscall,
+                28, // madvise
+                [addr as u64, len as u64, libc::MADV_DONTNEED as u64, 0, 0, 0],
+            )?;
+            remote_result(res, || format!("madvise of {} bytes at {:x}", len, addr))?;
+        }
+    }
+    if new_fd != fd {
+        remote_dup2(child, syscall, new_fd, fd)?;
+        remote_close(child, syscall, new_fd)?;
+    }
+    Ok(())
+}
+
+/// Bring back every fd from the dump that we can, returning the ones left
+/// closed for being something `Config::unsupported_fds` is about
+#[allow(clippy::too_many_arguments)]
+fn restore_file_descriptors(
+    child: Pid,
+    syscall: SyscallLoc,
+    mut cm: ConnectionMap,
+    root: &str,
+    open_flags: &HashMap<u32, i32>,
+    config: &Config,
+    hooks: &mut RestoreHooks,
+    skips: &mut Skips,
+) -> Result<Vec<u32>> {
+    let stdio = &config.stdio;
+    let mut dropped = Vec::new();
+    let min_fd = cm.keys().max().map_or(0, |fd| fd + 1);
+    // These go first, restoring other fds could land on top of the ones
+    // we inherited and want to copy from
+    for (fd, ours) in (0..).zip(stdio) {
+        if let Some(ours) = *ours {
+            let replaced = cm.remove(&fd);
+            info!(
+                "redirecting fd {} to our fd {} instead of {}",
+                fd,
+                ours,
+                replaced.as_ref().map_or("nothing", Connection::kind)
+            );
+            if ours as u32 != fd {
+                remote_dup2(child, syscall, ours as u32, fd)?;
+            }
+        }
+    }
+    // Don't leave our copies lying around in the restored process unless
+    // it had something there itself, which gets restored over it anyway
+    for ours in stdio.iter().flatten() {
+        let ours = *ours as u32;
+        if ours > 2 && !cm.contains_key(&ours) {
+            remote_close(child, syscall, ours)?;
+        }
+    }
+    let mut pairs: HashMap<u64, Vec<(u32, UnixPairConnection)>> = HashMap::new();
+    // Duplicates wait until what they're copies of is back, whichever order
+    // the map hands them to us in
+    let mut dups = Vec::new();
+    let mut restored: Vec<u32> = (0..)
+        .zip(stdio)
+        .filter(|(_, ours)| ours.is_some())
+        .map(|(fd, _)| fd)
+        .collect();
+    for (fd, conn) in cm {
+        match hooks.fd_action(fd, &conn) {
+            FdAction::Restore => {}
+            FdAction::Skip => {
+                info!("leaving {} fd {} closed by request", conn.kind(), fd);
+                continue;
+            }
+            FdAction::ReplaceWith(path) => {
+                info!("replacing {} fd {} with {}", conn.kind(), fd, path);
+                let open_fd = remote_open(child, syscall, &path, libc::O_RDWR)?;
+                if open_fd != fd {
+                    remote_dup2(child, syscall, open_fd, fd)?;
+                    remote_close(child, syscall, open_fd)?;
+                }
+                restored.push(fd);
+                continue;
+            }
+        }
+        let kind = conn.kind();
+        match conn {
+            Connection::UnixPair(pair) => {
+                pairs
+                    .entry(pair.ino.min(pair.peer))
+                    .or_default()
+                    .push((fd, pair));
+            }
+            Connection::Invalid | Connection::Tcp(_) => {
+                match config.unsupported_fds {
+                    UnsupportedFdPolicy::Warn => skips.skip(
+                        format!("{} fd {}", kind, fd),
+                        "telefork can't restore these",
+                    ),
+                    UnsupportedFdPolicy::Fail => {
+                        return Err(Box::new(Unsupported(format!(
+                            "{} fd {} can't be restored",
+                            kind, fd
+                        ))))
+                    }
+                    UnsupportedFdPolicy::Drop => {
+                        tracing::debug!("leaving {} fd {} closed", kind, fd)
+                    }
+                }
+                dropped.push(fd);
+            }
+            Connection::Stdio(_) => {
+                assert!(fd <= 2);
+            }
+            Connection::Dup(dup) => dups.push((fd, dup.of)),
+            conn => {
+                let res = restore_fd(child, syscall, fd, conn, root, open_flags);
+                if res.is_ok() {
+                    restored.push(fd);
+                }
+                skips.check(|| format!("{} fd {}", kind, fd), res)?;
+            }
+        }
+    }
+    for (fd, of) in dups {
+        tracing::debug!("restoring fd {} as a duplicate of fd {}", fd, of);
+        // Whatever kept that one out, by request or with `keep_going`, has
+        // already been said
+        if !restored.contains(&of) {
+            skips.skip(
+                format!("duplicate fd {}", fd),
+                format!("fd {} wasn't restored", of),
+            );
+            continue;
+        }
+        let res = remote_dup2(child, syscall, of, fd).map(drop);
+        skips.check(|| format!("duplicate fd {}", fd), res)?;
+    }
+    let res = restore_unix_pairs(child, syscall, pairs, min_fd);
+    skips.check(|| "unix socket pairs".to_string(), res)?;
+    dropped.sort_unstable();
+    Ok(dropped)
+}
+
+/// Bring back one fd of a kind we know how to restore on its own
+fn restore_fd(
+    child: Pid,
+    syscall: SyscallLoc,
+    fd: u32,
+    conn: Connection,
+    root: &str,
+    open_flags: &HashMap<u32, i32>,
+) -> Result<()> {
+    let flags = open_flags.get(&fd).copied().unwrap_or(libc::O_RDONLY);
+    match conn {
+        Connection::File(FileConnection { path, offset }) => {
+            tracing::debug!(
+                "restoring file descriptor {} for {} at offset {}",
+                fd,
+                path,
+                offset
+            );
+            let path = path_relative_to_root(&path, root);
+            restore_file(child, syscall, fd, path, offset, flags)
+        }
+        Connection::BundledFile(file) => {
+            restore_bundled_file(child, syscall, fd, file, root, flags)
+        }
+        Connection::Inotify(inotify) => restore_inotify(child, syscall, fd, inotify, root),
+        Connection::Pidfd(pidfd) => restore_pidfd(child, syscall, fd, &pidfd),
+        Connection::Userfaultfd(uffd) => restore_userfaultfd(child, syscall, fd, &uffd),
+        Connection::Mqueue(mq) => restore_mqueue(child, syscall, fd, &mq),
+        Connection::Directory(dir) => restore_directory(child, syscall, fd, dir, root, flags),
+        conn => unreachable!("{} fds aren't restored one at a time", conn.kind()),
+    }
+}
+
+/// Something a restore carried on without
+#[derive(Debug, Clone)]
+pub struct Skipped {
+    /// Like `file fd 4`
+    pub what: String,
+    /// Why it didn't come back
+    pub error: String,
+}
+
+/// What `telepad` ended up doing to bring a process back, for when it
+/// restored fine but behaves oddly. Get it from `RestoredProcess::report` or
+/// `telepad_with_report`.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreReport {
+    /// Mappings put back, by contents, by mapping the file again, or by
+    /// attaching shared memory
+    pub mappings: usize,
+    /// Mappings a `map_policy` said to leave out
+    pub mappings_skipped: usize,
+    /// Fds from the dump that the process has again
+    pub fds: usize,
+    /// Fds from the dump that it doesn't, whether by request, because we
+    /// don't support them or because they failed with `keep_going`
+    pub fds_skipped: usize,
+    /// Fds left closed since telefork can't restore what they were, see
+    /// `Config::unsupported_fds`
+    pub dropped_fds: Vec<u32>,
+    pub vdso: VdsoRestore,
+    /// How this kernel's vDSO compared to the dumped one, for dumps that
+    /// carry it
+    pub vdso_compat: Option<VdsoCompat>,
+    pub brk: BrkRestore,
+    /// Whether it has a thread pointer, `fs_base`, which is where glibc
+    /// keeps TLS
+    pub tls: bool,
+    /// What the process had used before it was dumped, which its own CPU
+    /// clocks don't count anymore
+    pub cpu: Option<CpuTimes>,
+    /// How far `CLOCK_MONOTONIC` here was behind where the process last saw
+    /// it, if it was
+    pub monotonic_behind: Option<std::time::Duration>,
+    /// The pid the process had when it was dumped, for dumps that say.
+    /// Whether it has it again is down to `Config::same_pid`.
+    pub original_pid: Option<i32>,
+    pub skipped: Vec<Skipped>,
+}
+
+/// How the restored process got a vDSO
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VdsoRestore {
+    /// The dump didn't say where it was, it has ours wherever that is
+    #[default]
+    Untouched,
+    /// Ours moved to where the old one was
+    Remapped,
+    /// Ours left where it was, since it's a different size from the old one
+    /// and wouldn't fit its place. The auxv points at it, but anything the
+    /// program looked up in the old one before it was dumped is gone.
+    KeptInPlace,
+    /// The old one's contents, with `Config::janky_vdso`
+    Teleported,
+}
+
+/// How the program break was put back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BrkRestore {
+    /// The dump didn't say where it was
+    #[default]
+    Untouched,
+    /// Only with `brk` itself, which can't move it below where the heap of
+    /// the process we restored into started, so it may not have worked
+    Syscall,
+    /// Field by field with `PR_SET_MM`
+    MmFields,
+    /// Along with the rest of the layout with `PR_SET_MM_MAP`
+    MmMap,
+}
+
+impl std::fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "mappings: {} restored, {} skipped",
+            self.mappings, self.mappings_skipped
+        )?;
+        writeln!(
+            f,
+            "fds: {} restored, {} skipped",
+            self.fds, self.fds_skipped
+        )?;
+        if !self.dropped_fds.is_empty() {
+            writeln!(f, "fds telefork can't restore: {:?}", self.dropped_fds)?;
+        }
+        match &self.vdso_compat {
+            Some(compat) => writeln!(f, "vdso: {:?} ({:?})", self.vdso, compat)?,
+            None => writeln!(f, "vdso: {:?}", self.vdso)?,
+        }
+        writeln!(f, "brk: {:?}", self.brk)?;
+        write!(f, "tls: {}", if self.tls { "set" } else { "none" })?;
+        if let Some(cpu) = &self.cpu {
+            write!(
+                f,
+                "\ncpu before the dump: {:?} user, {:?} system",
+                cpu.user, cpu.system
+            )?;
+        }
+        if let Some(behind) = self.monotonic_behind {
+            write!(f, "\nmonotonic clock: {:?} behind the dump", behind)?;
+        }
+        for skipped in &self.skipped {
+            write!(f, "\nskipped {}: {}", skipped.what, skipped.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whatever an fd or the working directory referred to isn't on this
+/// machine anymore, so there's nothing to restore it as
+#[derive(Debug)]
+struct NotHere(String);
+
+impl std::fmt::Display for NotHere {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for NotHere {}
+
+/// What `telepad` has carried on without so far
+struct Skips {
+    keep_going: bool,
+    skipped: Vec<Skipped>,
+}
+
+impl Skips {
+    fn skip(&mut self, what: String, error: impl ToString) {
+        let error = error.to_string();
+        warn!("carrying on without {}: {}", what, error);
+        self.skipped.push(Skipped { what, error });
+    }
+
+    /// Let an error from restoring `what` through, unless we're keeping
+    /// going, or it's something no amount of retrying here could fix
+    fn check(&mut self, what: impl FnOnce() -> String, res: Result<()>) -> Result<()> {
+        match res {
+            Err(e) if self.keep_going || is_missing_syscall(&*e) || e.is::<NotHere>() => {
+                self.skip(what(), e);
+                Ok(())
+            }
+            res => res,
+        }
+    }
+}
+
+/// The process's cgroup v2, from the `0::` line of `/proc/pid/cgroup`.
+/// There's nothing to say about one in the root cgroup, or on a machine with
+/// only cgroup v1.
+fn scan_cgroup(pid: i32) -> Result<Option<String>> {
+    let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+    let cgroup = match cgroups.lines().find_map(|l| l.strip_prefix("0::")) {
+        Some(cgroup) if cgroup != "/" => cgroup,
+        _ => return Ok(None),
+    };
+    if cgroup.ends_with(" (deleted)") {
+        warn!("the process's cgroup {} has been removed", cgroup);
+        return Ok(None);
+    }
+    Ok(Some(cgroup.to_string()))
+}
+
+/// Where the cgroup v2 hierarchy is mounted, `/sys/fs/cgroup` usually but
+/// `/sys/fs/cgroup/unified` next to v1 ones
+fn cgroup2_mount() -> Result<Option<std::path::PathBuf>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo.lines().find_map(|line| {
+        // The fields before ` - ` vary in number, the filesystem type is
+        // right after it
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split_whitespace().next() != Some("cgroup2") {
+            return None;
+        }
+        mount
+            .split_whitespace()
+            .nth(4)
+            .map(std::path::PathBuf::from)
+    }))
+}
+
+/// Move the restored process into the cgroup the original was in, which
+/// needs write access to its `cgroup.procs` and to the one it's in now's
+fn restore_cgroup(child: Pid, cgroup: &str, config: &Config) -> Result<()> {
+    let mount = match cgroup2_mount()? {
+        Some(mount) => mount,
+        None => {
+            return Err(Box::new(NotHere(
+                "cgroup v2 isn't mounted here".to_string(),
+            )))
+        }
+    };
+    if scan_cgroup(child.as_raw())?.as_deref() == Some(cgroup) {
+        return Ok(());
+    }
+    let dir = mount.join(cgroup.trim_start_matches('/'));
+    if !dir.is_dir() {
+        if !config.create_cgroups {
+            let missing = format!("there's no cgroup {} here", cgroup);
+            return Err(Box::new(NotHere(missing)));
+        }
+        info!("making cgroup {}", cgroup);
+        std::fs::create_dir_all(&dir)?;
+    }
+    std::fs::write(dir.join("cgroup.procs"), child.as_raw().to_string())?;
+    tracing::debug!("moved {} into cgroup {}", child, cgroup);
+    Ok(())
+}
+
+/// Take the process's file locks again through the restored fds. If someone
+/// else has one now we fail rather than let the process carry on thinking
+/// it has it, since that's how two copies of a daemon end up trampling on
+/// each other.
+fn restore_file_locks(child: Pid, syscall: SyscallLoc, locks: &[FileLock]) -> Result<()> {
+    for lock in locks {
+        let res = match lock.kind {
+            LockKind::Flock => {
+                let op = if lock.write {
+                    libc::LOCK_EX
+                } else {
+                    libc::LOCK_SH
+                };
+                let res = remote_syscall(
+                    child,
+                    syscall,
+                    73, // flock
+                    [lock.fd as u64, (op | libc::LOCK_NB) as u64, 0, 0, 0, 0],
+                )?;
+                remote_result(res, || format!("flock on fd {}", lock.fd))
+            }
+            LockKind::Posix | LockKind::OpenFile => {
+                let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+                flock.l_type = if lock.write {
+                    libc::F_WRLCK
+                } else {
+                    libc::F_RDLCK
+                } as i16;
+                flock.l_whence = libc::SEEK_SET as i16;
+                flock.l_start = lock.start as i64;
+                // A length of 0 means to the end of the file
+                flock.l_len = lock.end.map_or(0, |end| (end - lock.start + 1) as i64);
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(
+                        &flock as *const libc::flock as *const u8,
+                        std::mem::size_of::<libc::flock>(),
+                    )
+                };
+                let cmd = if lock.kind == LockKind::Posix {
+                    libc::F_SETLK
+                } else {
+                    libc::F_OFD_SETLK
+                };
+                with_remote_bytes(child, syscall, bytes, |addr| {
+                    remote_fcntl(child, syscall, lock.fd, cmd, addr as u64)
+                })
+            }
+        };
+        match res {
+            Ok(_) => {}
+            // The fd wasn't restored, like when an fd policy skipped it
+            Err(e) if matches!(e.downcast_ref::<RemoteSyscallError>(), Some(r) if r.errno == Errno::EBADF) =>
+            {
+                warn!(
+                    "fd {} isn't there to take {:?} lock through",
+                    lock.fd, lock.kind
+                )
+            }
+            Err(e) => {
+                tracing::error!(
+                    "couldn't take {:?} back, something else probably holds it now: {}",
+                    lock,
+                    e
+                );
+                return error("couldn't re-acquire a file lock the process held");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The other end of a `telefork`. Receive a program from a read channel and
+/// rehydrate it as a child process, passing it an i32 and return its pid.
+pub fn telepad(inp: &mut dyn Read, pass_to_child: i32) -> Result<Pid> {
+    telepad_with_config(inp, pass_to_child, &Config::default())
+}
+
+/// `telepad` with the knobs in `Config`
+pub fn telepad_with_config(inp: &mut dyn Read, pass_to_child: i32, config: &Config) -> Result<Pid> {
+    let_go(telepad_attached(inp, pass_to_child, config), config)
+}
+
+/// `RestoredProcess::let_go` a restore if it worked, and if it didn't tell
+/// the rest of its `Config::resume_barrier` group not to wait for it
+pub(crate) fn let_go(restored: Result<RestoredProcess>, config: &Config) -> Result<Pid> {
+    match restored {
+        Ok(restored) => restored.let_go(config),
+        Err(e) => {
+            if let Some(barrier) = &config.resume_barrier {
+                barrier.abort();
+            }
+            Err(e)
+        }
+    }
+}
+
+/// `telepad` that also says what it did, see `RestoreReport`
+pub fn telepad_with_report(
+    inp: &mut dyn Read,
+    pass_to_child: i32,
+    config: &Config,
+) -> Result<(Pid, RestoreReport)> {
+    let restored = telepad_attached(inp, pass_to_child, config);
+    let report = restored.as_ref().map(|r| r.report().clone()).ok();
+    let pid = let_go(restored, config)?;
+    Ok((pid, report.unwrap_or_default()))
+}
+
+/// `telepad` but handing the restored process something more than an i32,
+/// like several fds and some settings. The payload gets put in a fresh page
+/// of the restored process and it gets the address of that as the
+/// `TeleforkLocation::Child` value, which it turns back into a `P` with
+/// `read_payload`.
+pub fn telepad_with_payload<P: Serialize>(inp: &mut dyn Read, payload: &P) -> Result<Pid> {
+    let mut restored = telepad_with_hooks(
+        &mut Streamed(inp),
+        PassToChild::Payload(bincode::serialize(payload)?),
+        &Config::default(),
+        &mut RestoreHooks::default(),
+    )?;
+    restored.resume()?;
+    restored.detach()
+}
+
+/// Get the payload `telepad_with_payload` passed, from the value a
+/// `telefork` returned in the restored process. The page it was in is
+/// unmapped afterwards so this can only be done once.
+///
+/// # Safety
+///
+/// `arg` has to have come from a `telepad_with_payload`, anything else is
+/// read as an address and will likely segfault.
+pub unsafe fn read_payload<P: serde::de::DeserializeOwned>(arg: i32) -> Result<P> {
+    if arg <= 0 {
+        return error("no payload was passed to this process");
+    }
+    let addr = arg as usize;
+    let len = std::ptr::read(addr as *const u64) as usize;
+    let bytes = std::slice::from_raw_parts((addr + 8) as *const u8, len);
+    let payload = bincode::deserialize(bytes);
+    libc::munmap(
+        addr as *mut libc::c_void,
+        (8 + len).next_multiple_of(PAGE_SIZE),
+    );
+    Ok(payload?)
+}
+
+/// What to hand the restored process as the value `telefork` returns
+pub(crate) enum PassToChild {
+    Int(i32),
+    /// Serialized, see `telepad_with_payload`
+    Payload(Vec<u8>),
+    /// Nothing, `rax` stays as it was dumped, for `apply_memory_image`
+    Nothing,
+}
+
+impl From<i32> for PassToChild {
+    fn from(v: i32) -> Self {
+        PassToChild::Int(v)
+    }
+}
+
+/// Put a payload in a new page of the child for `read_payload` to find,
+/// prefixed with its length. `raise` only returns an int so it has to go in
+/// the bottom 2GB, which is what `MAP_32BIT` is for.
+fn map_payload(child: Pid, syscall: SyscallLoc, payload: &[u8]) -> Result<i32> {
+    let len = (8 + payload.len()).next_multiple_of(PAGE_SIZE);
+    let addr = remote_mmap(
+        child,
+        syscall,
+        0,
+        len,
+        PROT_READ | PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_32BIT,
+        -1,
+        0,
+    )?;
+    let mut bytes = (payload.len() as u64).to_le_bytes().to_vec();
+    bytes.extend_from_slice(payload);
+    write_memory(child, addr, &bytes)?;
+    Ok(addr as i32)
+}
+
+/// `telepad` from a dump file on disk. Rather than reading it we mmap the
+/// whole thing, so memory contents get written into the new process straight
+/// out of the page cache without an extra copy on the way, which adds up for
+/// big dumps.
+pub fn telepad_file(file: &std::fs::File, pass_to_child: i32, config: &Config) -> Result<Pid> {
+    let_go(telepad_file_attached(file, pass_to_child, config), config)
+}
+
+/// Restore `count` copies of the same dump, like a fork server handing out
+/// warm workers from one snapshot. Each is its own process with its own
+/// memory and its own fds opened again from scratch. Copy `i` is passed
+/// `pass_to_child + i` so it knows which one it is. If one fails the ones
+/// already restored are killed. They're left stopped for you to resume, like
+/// `telepad_attached`.
+pub fn telepad_many(
+    dump: &[u8],
+    count: usize,
+    pass_to_child: i32,
+    config: &Config,
+) -> Result<Vec<RestoredProcess>> {
+    let mut restored = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut bytes = dump;
+        let copy = telepad_with_hooks(
+            &mut bytes,
+            (pass_to_child + i as i32).into(),
+            config,
+            &mut RestoreHooks::default(),
+        )?;
+        info!("restored copy {} of {} as {}", i + 1, count, copy.pid());
+        restored.push(copy);
+    }
+    Ok(restored)
+}
+
+/// `telepad_file` but leaving it stopped like `telepad_attached`
+pub fn telepad_file_attached(
+    file: &std::fs::File,
+    pass_to_child: i32,
+    config: &Config,
+) -> Result<RestoredProcess> {
+    // Safety: the dump changing under us would just be a corrupt dump, which
+    // restoring can go wrong on in plenty of ways already
+    let map = unsafe { memmap2::Mmap::map(file)? };
+    let mut bytes: &[u8] = &map;
+    telepad_with_hooks(
+        &mut bytes,
+        pass_to_child.into(),
+        config,
+        &mut RestoreHooks::default(),
+    )
+}
+
+/// Restore one of several dumps written with `Config::index` one after
+/// another into `inp`, counting from 0 for the first. It's found from the
+/// indexes at the end, see `read_indexes`, so nothing before it is read and
+/// it doesn't matter how big the ones before it are.
+pub fn telepad_seek<R: Read + Seek>(
+    inp: &mut R,
+    snapshot: usize,
+    pass_to_child: i32,
+    config: &Config,
+) -> Result<Pid> {
+    let indexes = read_indexes(inp)?;
+    let (start, index) = match indexes.get(snapshot) {
+        Some(found) => found,
+        None => {
+            return bad_stream(format!(
+                "asked for snapshot {} but there are only {}",
+                snapshot,
+                indexes.len()
+            ))
+        }
+    };
+    inp.seek(SeekFrom::Start(*start))?;
+    let dump = &mut inp.take(index.len);
+    let restored = telepad_with_hooks(
+        &mut Streamed(dump),
+        pass_to_child.into(),
+        config,
+        &mut RestoreHooks::default(),
+    );
+    let_go(restored, config)
+}
+
+/// `telepad` but without letting the restored process run, see `RestoredProcess`
+pub fn telepad_attached(
+    inp: &mut dyn Read,
+    pass_to_child: i32,
+    config: &Config,
+) -> Result<RestoredProcess> {
+    telepad_with_hooks(
+        &mut Streamed(inp),
+        pass_to_child.into(),
+        config,
+        &mut RestoreHooks::default(),
+    )
+}
+
+/// Restore a process's fds and environment into a different program, for
+/// swapping out the binary a server runs without closing its listening
+/// sockets and whatever else it has open. The dump is restored as usual and
+/// then made to `execve` `path` with `argv`, see `RestoredProcess::exec` for
+/// what makes it through that. Its memory doesn't, so the new program has to
+/// get any state it needs from the fds it's handed.
+pub fn telepad_then_exec(
+    inp: &mut dyn Read,
+    path: &str,
+    argv: &[&str],
+    config: &Config,
+) -> Result<Pid> {
+    let mut env = Vec::new();
+    let mut hooks = RestoreHooks {
+        environment: Some(Box::new(|vars: &[String]| env = vars.to_vec())),
+        ..RestoreHooks::default()
+    };
+    let mut restored =
+        telepad_with_hooks(&mut Streamed(inp), PassToChild::Nothing, config, &mut hooks)?;
+    drop(hooks);
+    restored.exec(path, argv, &env)?;
+    restored.detach()
+}
+
+pub(crate) fn telepad_with_hooks(
+    inp: &mut dyn DumpReader,
+    pass_to_child: PassToChild,
+    config: &Config,
+    hooks: &mut RestoreHooks,
+) -> Result<RestoredProcess> {
+    // It has to be forked with the pid if it's going to have it, before
+    // anything else is read
+    let (wanted, seen) = match config.same_pid {
+        SamePid::Any => (None, Vec::new()),
+        _ => peek_pid(inp)?,
+    };
+    let inp = &mut Replay {
+        seen: std::io::Cursor::new(seen),
+        rest: inp,
+    };
+    let forked = match (wanted, config.same_pid) {
+        (None, SamePid::Require) => {
+            return error(
+                "the dump doesn't say what pid the process had, it's from an older telefork",
+            )
+        }
+        (Some(pid), SamePid::Prefer) => match fork_frozen_traced(Some(pid)) {
+            Err(e) if e.is::<PidConflict>() => {
+                info!("{}", e);
+                fork_frozen_traced(None)?
+            }
+            forked => forked?,
+        },
+        (wanted, _) => fork_frozen_traced(wanted)?,
+    };
+    // == 1. Create a frozen child to hollow out and replace with the process being streamed in
+    let child: Pid = match forked {
+        NormalForkLocation::Woke(_) => {
+            panic!("should've woken up with my brain replaced but didn't!")
+        }
+        NormalForkLocation::Parent(p) => p,
+    };
+    // If anything below fails this makes sure we don't leave a half
+    // restored process hanging around
+    let guard = KillOnDrop { child, armed: true };
+    let report = restore_into(child, inp, pass_to_child, config, hooks, false)?;
+
+    // Hand it back still stopped and attached, `telepad` resumes and
+    // detaches right away but callers can poke at it first
+    guard.disarm();
+    Ok(RestoredProcess {
+        pid: child,
+        attached: true,
+        syscall: None,
+        report,
+    })
+}
+
+/// Restore a memory image from a dump into a process you've already got
+/// stopped under ptrace, rather than into a fresh one like `telepad` does.
+/// Everything it has mapped apart from the vDSO and such is unmapped, then
+/// the dump's mappings and registers go in. Its fds, credentials, signal
+/// handlers and the rest of what the kernel keeps about it stay its own.
+///
+/// It's left stopped with the dumped registers for you to resume. If this
+/// fails partway it's left with some or none of its memory, so it's only
+/// good for killing.
+pub fn apply_memory_image(child: Pid, inp: &mut dyn Read) -> Result<RestoreReport> {
+    restore_into(
+        child,
+        &mut Streamed(inp),
+        PassToChild::Nothing,
+        &Config::default(),
+        &mut RestoreHooks::default(),
+        true,
+    )
+}
+
+/// Hollow out `child`, which is stopped with us tracing it, and replace it
+/// with the process from the stream. With `memory_only` that's only its
+/// memory and registers, see `apply_memory_image`.
+fn restore_into(
+    child: Pid,
+    inp: &mut dyn DumpReader,
+    pass_to_child: PassToChild,
+    config: &Config,
+    hooks: &mut RestoreHooks,
+    memory_only: bool,
+) -> Result<RestoreReport> {
+    // == 2. Inspect the state of the child so we can manipulate it to hollow it out
+    let orig_maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
+    // _print_maps_info(&orig_maps[..]);
+
+    // The vdso always seems to have a syscall in it we can use for remote syscalls
+    let vdso_map = find_map_named(&orig_maps, "[vdso]").unwrap();
+    let vdso_syscall_offset = vdso_syscall_offset(child, vdso_map)?;
+    let mut vdso_syscall = SyscallLoc::new((vdso_map.start() + vdso_syscall_offset) as u64);
+
+    // == 3. Remote munmap all original regions except special kernel stuff
+    for map in &orig_maps {
+        if is_special_kernel_map(map) || map.size() == 0 {
+            continue;
+        }
+        remote_munmap(child, vdso_syscall, map.start(), map.size())?;
+    }
+
+    let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
+    // println!("========== after delete:");
+    // _print_maps_info(&maps[..]);
+
+    // From here on inject syscalls using a scratch region rather than
+    // whatever rsp the hollowed out child happened to have. It gets moved
+    // above the highest restored mapping once the stream tells us where that is.
+    let mut scratch = Scratch::map(child, vdso_syscall, config.scratch_addr)?;
+    scratch.install(&mut vdso_syscall);
+    // It inherited our dispositions. Whatever we ignore goes back to the
+    // default until the program's own go back on right before it resumes.
+    if !memory_only {
+        let (_, inherited_caught) = scan_signal_dispositions(child.as_raw())?;
+        restore_signal_dispositions(child, vdso_syscall, 0, inherited_caught)?;
+    }
+    if let Some(ns) = &config.mount_namespace {
+        join_mount_namespace(child, vdso_syscall, ns)?;
+    }
+
+    // == 4. Now that it's hollowed out, start a loop to read restoration commands from the channel
+    let prot_all = PROT_READ | PROT_WRITE | PROT_EXEC;
+    // Dumps from before we recorded the root were all taken relative to `/`
+    let mut fs_root = "/".to_string();
+    let mut stage = Stage::Layout;
+    let mut restart_syscall = None;
+    let mut prctl_state = None;
+    let mut open_flags = HashMap::new();
+    let mut signal_dispositions = None;
+    let mut signal_actions = None;
+    let mut signal_masks = None;
+    let mut queued_signals = None;
+    let mut vdso_compat = None;
+    // Where our vDSO and its `[vvar]` are if they stayed put, which nothing
+    // from the dump can go on top of
+    let mut kept_vdso: Vec<(usize, usize)> = Vec::new();
+    // The `[vvar]` mappings we moved, as where they were, their size and
+    // where they went, to go back next to the vDSO if it turns out it can't
+    // be moved
+    let mut moved_vvar: Vec<(usize, usize, usize)> = Vec::new();
+    let mut mlockall = None;
+    let mut scheduling = None;
+    let mut credentials = None;
+    let mut fs_ids = None;
+    let mut real_timer = None;
+    // Mappings the hooks skipped, so later dirty pages for them can be too
+    let mut skipped_maps = std::collections::HashSet::new();
+    // The top of the restored `[heap]` mapping, for `Heap`
+    let mut heap_top = None;
+    let mut shm_segments = SharedSegments::default();
+    let mut checked_mappings = false;
+    // A legacy dump's first command was read in place of the header, that
+    // one gets handled first
+    let (mut framing, mut first) = read_header(inp)?;
+    // Whether `MmLayout` set everything, so `Heap` and `Environment` don't
+    // need to
+    let mut mm_set = false;
+    // Mappings that weren't `rwx` to begin with, by address. They're all
+    // mapped `rwx` so their contents and any dirty pages can go in, and only
+    // get their own protection back right before resuming.
+    let mut protections = std::collections::BTreeMap::new();
+    let mut skips = Skips {
+        keep_going: config.keep_going,
+        skipped: Vec::new(),
+    };
+    let mut report = RestoreReport::default();
+    // What the last mapping's contents went into, for `MappingChecksum`
+    let mut last_contents: Option<(String, usize, usize)> = None;
+    loop {
+        let comm = match first.take() {
+            Some(comm) => comm,
+            None => read_command(inp, &mut framing)?,
+        };
+        // The rest of the process stays the way it is
+        if memory_only && !comm.memory_image() {
+            continue;
+        }
+        if let Some(next) = comm.stage() {
+            if next < stage {
+                return bad_stream(format!(
+                    "a command from the {:?} stage came after the {:?} stage, which relies on it",
+                    next, stage
+                ));
+            }
+            stage = next;
+        }
+        match comm {
+            Command::AddressSpace { highest } => {
+                // Better to say so now than have a MAP_FIXED fail halfway through
+                check_address_space(highest, local_task_size())?;
+                if config.scratch_addr.is_none() {
+                    scratch.place_above(child, &mut vdso_syscall, highest)?;
+                }
+            }
+            Command::ProcessState(ProcessState { brk_addr }) => {
+                restore_brk(child, vdso_syscall, brk_addr)?;
+                report.brk = BrkRestore::Syscall;
+            }
+            Command::MlockAll { flags } => {
+                mlockall = Some(flags);
+            }
+            Command::Vdso(image) => match vdso::ours() {
+                Ok(ours) => vdso_compat = Some(vdso::compare(&image, &ours)),
+                Err(e) => warn!("couldn't read our vDSO to compare: {}", e),
+            },
+            Command::Remap { name, addr, size } => {
+                let matching_map = find_map_named(&maps, &name);
+                let matching_map = match matching_map {
+                    Some(m) => m,
+                    None => {
+                        eprintln!("no matching map for {} so can't remap", name);
+                        continue;
+                    }
+                };
+
+                // Moving ours over the old one's place when it's a different
+                // size would leave it overlapping whatever was next to the old
+                // one, or leave the program's pointers into the old one
+                // landing in different code. Better to leave it and the data
+                // it reads from `[vvar]` where they are and point the auxv at
+                // it.
+                if name == "[vdso]" && size != matching_map.size() {
+                    warn!(
+                        "the dumped vDSO was {} bytes and ours is {}, leaving ours at {:x} instead of moving it to {:x}, the process will crash if it calls into the old one, dump with janky_vdso to bring it along",
+                        size,
+                        matching_map.size(),
+                        matching_map.start(),
+                        addr
+                    );
+                    for (original, size, moved_to) in moved_vvar.drain(..) {
+                        remote_mremap(child, vdso_syscall, moved_to, size, original)?;
+                        kept_vdso.push((original, size));
+                    }
+                    kept_vdso.push((matching_map.start(), matching_map.size()));
+                    report.vdso = VdsoRestore::KeptInPlace;
+                    continue;
+                }
+
+                // Nothing better to do than remap ours, the old one would
+                // have had to come along in the dump
+                match (name.as_str(), &vdso_compat) {
+                    ("[vdso]", Some(VdsoCompat::Compatible)) => {
+                        tracing::debug!("the vDSO is the same as where it was dumped")
+                    }
+                    ("[vdso]", Some(VdsoCompat::RemappableSameSize)) => {
+                        info!("the vDSO is a different build with the same layout, remapping ours")
+                    }
+                    ("[vdso]", Some(VdsoCompat::Incompatible(reason))) => warn!(
+                        "this kernel's vDSO can't stand in for the dumped one, the process will crash if it calls into it, dump with janky_vdso to bring it along: {}",
+                        reason
+                    ),
+                    _ => {}
+                }
+                if size != matching_map.size() && vdso_compat.is_none() {
+                    // Some Linux distros/versions seem to have 1 page vDSOs
+                    // and some have 2 pages I made this a non-critical error
+                    // so that you can telefork anyway and it might work,
+                    // especially if the program doesn't use any vDSO
+                    // syscalls. Dumps that carry their vDSO get checked
+                    // properly instead, see `VdsoCompat`.
+
+                    // error("size mismatch in remap")?;
+                    eprintln!("size mismatch in remap for {}", name);
+                }
+
+                remote_mremap(
+                    child,
+                    vdso_syscall,
+                    matching_map.start(),
+                    matching_map.size(),
+                    addr,
+                )?;
+                if name.starts_with("[vvar") {
+                    moved_vvar.push((matching_map.start(), matching_map.size(), addr));
+                }
+
+                // When we remap the vDSO we have to change the address we're
+                // using for remote syscalls to the new location. It happens
+                // to still work to use a syscall in the vDSO to mremap the
+                // vDSO elsewhere even though it returns to unmapped space,
+                // because ptrace stops it before it executes anything from
+                // unmapped space.
+                if &name == "[vdso]" {
+                    vdso_syscall.addr = (addr + vdso_syscall_offset) as u64;
+                    report.vdso = VdsoRestore::Remapped;
+                }
+            }
+            Command::CheckedMappings => {
+                checked_mappings = true;
+            }
+            Command::MappingEnd { .. } => {
+                return bad_stream("mapping end marker without a mapping".to_string());
+            }
+            Command::Mapping(m) if hooks.map_action(&m) == MapAction::Skip => {
+                info!("skipping mapping at {:x} by request", m.addr);
+                skipped_maps.insert(m.addr);
+                report.mappings_skipped += 1;
+                std::io::copy(&mut (&mut *inp).take(m.size as u64), &mut std::io::sink())?;
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, &m, m.size)?;
+                }
+                last_contents = None;
+            }
+            Command::PartialMapping { mapping: m, skip }
+                if hooks.map_action(&m) == MapAction::Skip =>
+            {
+                info!("skipping mapping at {:x} by request", m.addr);
+                skipped_maps.insert(m.addr);
+                report.mappings_skipped += 1;
+                let len = (m.size - skip) as u64;
+                std::io::copy(&mut (&mut *inp).take(len), &mut std::io::sink())?;
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, &m, m.size - skip)?;
+                }
+                last_contents = None;
+            }
+            Command::HugetlbMapping { mapping: m, .. }
+                if hooks.map_action(&m) == MapAction::Skip =>
+            {
+                info!("skipping huge page mapping at {:x} by request", m.addr);
+                skipped_maps.insert(m.addr);
+                report.mappings_skipped += 1;
+                std::io::copy(&mut (&mut *inp).take(m.size as u64), &mut std::io::sink())?;
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, &m, m.size)?;
+                }
+                last_contents = None;
+            }
+            Command::HugetlbMapping {
+                mapping: m,
+                page_size,
+                shared,
+            } => {
+                check_kept_vdso(&kept_vdso, m.addr, m.size)?;
+                scratch.avoid(child, &mut vdso_syscall, m.addr, m.size)?;
+                let addr = remote_mmap_hugetlb(child, vdso_syscall, &m, page_size, shared)?;
+                stream_memory(child, inp, addr, m.size)?;
+                if m.prot() != prot_all {
+                    protections.insert(addr, (m.size, m.prot()));
+                }
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, &m, m.size)?;
+                }
+                report.mappings += 1;
+                last_contents = Some((m.describe(), addr, m.size));
+            }
+            Command::SharedMemory(shm) if hooks.map_action(&shm.mapping) == MapAction::Skip => {
+                let m = &shm.mapping;
+                info!("skipping shared memory at {:x} by request", m.addr);
+                report.mappings_skipped += 1;
+                std::io::copy(&mut (&mut *inp).take(m.size as u64), &mut std::io::sink())?;
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, m, m.size)?;
+                }
+                last_contents = None;
+            }
+            Command::SharedMemory(shm) => {
+                let m = &shm.mapping;
+                check_kept_vdso(&kept_vdso, m.addr, m.size)?;
+                scratch.avoid(child, &mut vdso_syscall, m.addr, m.size)?;
+                let fresh = shm_segments.attach(
+                    child,
+                    vdso_syscall,
+                    &shm,
+                    &fs_root,
+                    config.reattach_shm,
+                )?;
+                report.mappings += 1;
+                if fresh {
+                    stream_memory(child, inp, m.addr, m.size)?;
+                    SharedSegments::protect(child, vdso_syscall, m)?;
+                    last_contents = Some((m.describe(), m.addr, m.size));
+                } else {
+                    // What's in the segment here is what everything else
+                    // attached to it sees, so leave it alone
+                    std::io::copy(&mut (&mut *inp).take(m.size as u64), &mut std::io::sink())?;
+                    last_contents = None;
+                }
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, m, m.size)?;
+                }
+            }
+            Command::FileMapping(fm) if hooks.map_action(&fm.mapping) == MapAction::Skip => {
+                info!("skipping mapping of {} by request", fm.path);
+                report.mappings_skipped += 1;
+            }
+            Command::FileMapping(fm) => {
+                let m = &fm.mapping;
+                check_kept_vdso(&kept_vdso, m.addr, m.size)?;
+                scratch.avoid(child, &mut vdso_syscall, m.addr, m.size)?;
+                restore_file_mapping(child, vdso_syscall, &fm, &fs_root)?;
+                report.mappings += 1;
+            }
+            Command::PastEof(fm) if hooks.map_action(&fm.mapping) == MapAction::Skip => {
+                info!("skipping the end of the mapping of {} by request", fm.path);
+            }
+            Command::PastEof(fm) => {
+                let m = &fm.mapping;
+                check_kept_vdso(&kept_vdso, m.addr, m.size)?;
+                scratch.avoid(child, &mut vdso_syscall, m.addr, m.size)?;
+                restore_past_eof(child, vdso_syscall, &fm, &fs_root)?;
+                // Already counted if there was a `Mapping` of the start of it
+                if !matches!(&last_contents, Some((_, addr, size)) if addr + size == m.addr) {
+                    report.mappings += 1;
+                }
+            }
+            Command::Mapping(m) => {
+                check_kept_vdso(&kept_vdso, m.addr, m.size)?;
+                scratch.avoid(child, &mut vdso_syscall, m.addr, m.size)?;
+                let addr = remote_mmap_anon(child, vdso_syscall, Some(m.addr), m.size, prot_all)?;
+                match m.name.as_deref() {
+                    Some("[heap]") => heap_top = Some(m.addr + m.size),
+                    Some("[vdso]") => report.vdso = VdsoRestore::Teleported,
+                    _ => {}
+                }
+                report.mappings += 1;
+                // TODO set new area filenames
+                stream_memory(child, inp, addr, m.size)?;
+                if m.prot() != prot_all {
+                    protections.insert(addr, (m.size, m.prot()));
+                }
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, &m, m.size)?;
+                }
+                last_contents = Some((m.describe(), addr, m.size));
+            }
+            Command::PartialMapping { mapping: m, skip } => {
+                // Reserve the whole thing but only the end has contents
+                check_kept_vdso(&kept_vdso, m.addr, m.size)?;
+                scratch.avoid(child, &mut vdso_syscall, m.addr, m.size)?;
+                let addr = remote_mmap_anon(child, vdso_syscall, Some(m.addr), m.size, prot_all)?;
+                stream_memory(child, inp, addr + skip, m.size - skip)?;
+                report.mappings += 1;
+                if m.prot() != prot_all {
+                    protections.insert(addr, (m.size, m.prot()));
+                }
+                if checked_mappings {
+                    expect_mapping_end(inp, &mut framing, &m, m.size - skip)?;
+                }
+                last_contents = Some((m.describe(), addr + skip, m.size - skip));
+            }
+            Command::MmLayout(mut mm) => {
+                if report.vdso == VdsoRestore::KeptInPlace {
+                    set_auxv_entry(&mut mm.auxv, libc::AT_SYSINFO_EHDR, vdso_map.start() as u64);
+                }
+                match restore_mm_layout(child, vdso_syscall, &mm) {
+                    Ok(()) => mm_set = true,
+                    Err(e) => info!(
+                        "couldn't set the memory layout all at once, setting what we can one at a time: {}",
+                        e
+                    ),
+                }
+            }
+            Command::Heap(heap) => {
+                report.brk = restore_heap_layout(child, vdso_syscall, &heap, heap_top, mm_set)?;
+            }
+            Command::Unmap { addr, size } => {
+                remote_munmap(child, vdso_syscall, addr, size)?;
+                protections.remove(&addr);
+            }
+            Command::DirtyPages { map, pages } => {
+                if skipped_maps.contains(&map) {
+                    let len = (pages.len() * PAGE_SIZE) as u64;
+                    std::io::copy(&mut (&mut *inp).take(len), &mut std::io::sink())?;
+                } else {
+                    for page in &pages {
+                        stream_memory(child, inp, *page, PAGE_SIZE)?;
+                    }
+                }
+                let read = pages.len() * PAGE_SIZE;
+                match read_command(inp, &mut framing)? {
+                    Command::MappingEnd { written } if written == read => {}
+                    _ => {
+                        return bad_stream(format!(
+                        "{} dirty pages of mapping at {:#x} weren't followed by their end marker",
+                        pages.len(),
+                        map
+                    ))
+                    }
+                }
+                last_contents = None;
+            }
+            Command::MappingChecksum { crc32 } => {
+                // `take` so a checksum can't get checked against the wrong mapping
+                if let Some((name, addr, len)) = last_contents.take() {
+                    if config.verify_restore && checksum_memory(child, addr, len)? != crc32 {
+                        return bad_stream(format!(
+                            "restored contents of {} don't match the checksum in the dump",
+                            name
+                        ));
+                    }
+                }
+            }
+            Command::RestartSyscall { nr } => {
+                restart_syscall = Some(nr);
+            }
+            Command::FsContext(fs) => {
+                fs_root = restore_fs_context(child, vdso_syscall, fs)?;
+            }
+            Command::Cwd(cwd) => {
+                let res = restore_cwd(child, vdso_syscall, &cwd, &fs_root);
+                skips.check(|| format!("working directory {}", cwd), res)?;
+            }
+            Command::Umask(umask) => {
+                remote_syscall(
+                    child,
+                    vdso_syscall,
+                    95, // umask
+                    [umask as u64, 0, 0, 0, 0, 0],
+                )?;
+            }
+            Command::PrctlState(prctl) => {
+                restore_prctl_state(child, vdso_syscall, &prctl)?;
+                prctl_state = Some(prctl);
+            }
+            Command::Environment(env) => {
+                if let Some(hook) = &mut hooks.environment {
+                    hook(&env.vars);
+                }
+                if mm_set {
+                    // Already pointed at by the layout
+                } else if let Err(e) = restore_environment(child, vdso_syscall, &env) {
+                    warn!(
+                        "couldn't restore /proc/pid/environ, it'll show ours instead: {}",
+                        e
+                    );
+                }
+            }
+            Command::MemoryPolicy(policy) => {
+                // Only slower if it doesn't work, not worth failing over
+                if let Err(e) = restore_memory_policy(child, vdso_syscall, &policy) {
+                    warn!(
+                        "couldn't restore NUMA policy of mapping at {:x}: {}",
+                        policy.addr, e
+                    );
+                }
+            }
+            Command::HugePages { addr, size } => {
+                restore_huge_pages(child, vdso_syscall, addr, size);
+            }
+            Command::FileDescriptors(cm) => {
+                let dumped: Vec<u32> = cm.keys().copied().collect();
+                report.dropped_fds = restore_file_descriptors(
+                    child,
+                    vdso_syscall,
+                    cm,
+                    &fs_root,
+                    &open_flags,
+                    config,
+                    hooks,
+                    &mut skips,
+                )?;
+                let cm = scan_file_descriptors(child.as_raw())?;
+                report.fds = dumped.iter().filter(|fd| cm.contains_key(fd)).count();
+                report.fds_skipped = dumped.len() - report.fds;
+                tracing::debug!("restored file descriptors:");
+                for (fd, conn) in cm {
+                    tracing::debug!("fd = {}; {:?}", fd, conn);
+                }
+            }
+            Command::FileLocks(locks) => {
+                restore_file_locks(child, vdso_syscall, &locks)?;
+            }
+            Command::OpenFlags(flags) => {
+                open_flags = flags;
+            }
+            Command::SignalDispositions { ignored, caught } => {
+                signal_dispositions = Some((ignored, caught));
+            }
+            Command::SignalActions(actions) => {
+                signal_actions = Some(actions);
+            }
+            Command::SignalMasks(masks) => {
+                signal_masks = Some(masks);
+            }
+            Command::QueuedSignals(queued) => {
+                queued_signals = Some(queued);
+            }
+            Command::Credentials(creds) => {
+                credentials = Some(creds);
+            }
+            Command::Scheduling(sched) => {
+                scheduling = Some(sched);
+            }
+            Command::FsIds { uid, gid } => {
+                fs_ids = Some((uid, gid));
+            }
+            Command::RealTimer {
+                remaining,
+                interval,
+            } => {
+                real_timer = Some((remaining, interval));
+            }
+            Command::Pid(pid) => {
+                report.original_pid = Some(pid);
+                if pid != child.as_raw() {
+                    let reason = format!("it's {} here instead", child);
+                    match config.same_pid {
+                        SamePid::Any => {}
+                        SamePid::Prefer => skips.skip(format!("pid {}", pid), reason),
+                        SamePid::Require => return Err(Box::new(PidConflict { pid, reason })),
+                    }
+                }
+            }
+            Command::Cgroup(cgroup) => {
+                if let Err(e) = restore_cgroup(child, &cgroup, config) {
+                    skips.skip(format!("cgroup {}", cgroup), e);
+                }
+            }
+            Command::Clocks(clocks) => {
+                report.cpu = Some(clocks.cpu);
+                let ours = monotonic_now();
+                if ours < clocks.monotonic {
+                    let behind = clocks.monotonic - ours;
+                    warn!(
+                        "CLOCK_MONOTONIC here is {:?} behind where the process was dumped, it'll see it go backwards",
+                        behind
+                    );
+                    report.monotonic_behind = Some(behind);
+                }
+            }
+            Command::ResumeWithRegisters { len } => {
+                let pass_to_child = match &pass_to_child {
+                    PassToChild::Int(v) => Some(*v),
+                    PassToChild::Payload(payload) => {
+                        Some(map_payload(child, vdso_syscall, payload)?)
+                    }
+                    PassToChild::Nothing => None,
+                };
+                if let Some(actions) = &signal_actions {
+                    let res = restore_signal_actions(child, vdso_syscall, actions);
+                    skips.check(|| "signal actions".to_string(), res)?;
+                } else if let Some((ignored, caught)) = signal_dispositions {
+                    if caught != 0 {
+                        tracing::debug!(
+                            "process had handlers for signals {:x}, they can't be restored",
+                            caught
+                        );
+                    }
+                    let res = restore_signal_dispositions(child, vdso_syscall, ignored, caught);
+                    skips.check(|| "signal dispositions".to_string(), res)?;
+                }
+                if let Some(masks) = &signal_masks {
+                    let res =
+                        restore_signal_masks(child, vdso_syscall, masks, queued_signals.as_ref());
+                    skips.check(|| "signal masks".to_string(), res)?;
+                }
+                shm_segments.finish(child, vdso_syscall, &fs_root)?;
+                // Before the scratch region goes and any seccomp filter
+                // goes on, so the hook can inject whatever it likes
+                if let Some(hook) = &mut hooks.pre_resume {
+                    // We still own the attachment, dropping this mustn't detach
+                    hook(&RestoredProcess {
+                        pid: child,
+                        attached: false,
+                        syscall: Some(vdso_syscall),
+                        report: RestoreReport {
+                            skipped: skips.skipped.clone(),
+                            ..report.clone()
+                        },
+                    })?;
+                }
+                // After the hook too, it might want to write to them
+                for (&addr, &(size, prot)) in &protections {
+                    remote_mprotect(child, vdso_syscall, addr, size, prot)?;
+                }
+                // Before the credentials, dropping them can drop the
+                // CAP_IPC_LOCK that let it lock more than its limit
+                if let Some(flags) = mlockall {
+                    restore_mlockall(child, vdso_syscall, flags);
+                }
+                // For CAP_SYS_NICE too, but otherwise as late as possible
+                // since a deadline process only gets its runtime once a period
+                if let Some(sched) = &scheduling {
+                    restore_scheduling(child, vdso_syscall, sched, &mut skips);
+                }
+                if let Some(creds) = &credentials {
+                    restore_credentials(child, vdso_syscall, creds)?;
+                }
+                if let Some((uid, gid)) = fs_ids {
+                    restore_fs_ids(child, vdso_syscall, uid, gid)?;
+                }
+                // Last so it loses as little time as it can
+                if let Some((remaining, interval)) = real_timer {
+                    restore_real_timer(child, vdso_syscall, remaining, interval);
+                }
+                // We're done injecting syscalls so the scratch region can go.
+                // The few after this leave rsp wherever it was, which is fine
+                // since `syscall` itself never touches the stack and single
+                // stepping stops for a signal before it gets a frame pushed,
+                // so the restored stack and red zone stay byte for byte what
+                // was dumped.
+                scratch.unmap(child, &mut vdso_syscall)?;
+                if let Some(prctl) = &prctl_state {
+                    restrict_process(child, vdso_syscall, prctl)?;
+                }
+                let mut reg_bytes = vec![0u8; len];
+                inp.read_exact(&mut reg_bytes[..])?;
+                // FIXME remove unwrap and use a proper error for bad serialization
+                let reg_info = RegInfo::from_bytes(&reg_bytes[..]).unwrap();
+                let mut regs = reg_info.regs;
+                match (restart_syscall, pass_to_child) {
+                    // The registers were rewound to re-issue a syscall, rax
+                    // holds the syscall number so we can't pass anything along
+                    (Some(nr), _) => tracing::debug!("resuming by restarting syscall {}", nr),
+                    // We'll be resuming from the "raise" syscall which checks for an i32 result in rax and libc passes along
+                    (None, Some(v)) => regs.rax = v as u64,
+                    (None, None) => {}
+                }
+                // A hook skipping whatever mapping the stack was in would
+                // just have it crash on the first push
+                let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
+                if !maps.iter().any(|m| map_contains(m, regs.rsp as usize)) {
+                    tracing::error!("the stack pointer {:x} wasn't restored", regs.rsp);
+                    return error("the mapping with the stack in it wasn't restored");
+                }
+                ptrace::setregs(child, regs)?;
+                report.tls = regs.fs_base != 0;
+                break;
+            }
+        }
+    }
+
+    // TODO restore TLS: This seems to involve using the arch_prcntl syscall
+    // to save and restore the FS and GS registers ptrace does save/restore fs
+    // and gs though and TLS variables appear to work to me so maybe that
+    // isn't necessary? There's also something about how glibc caches the pid
+    // and tid which are wrong in the new process.
+
+    // TODO restore or forward some types of file descriptors? Maybe basic
+    // files that also exist on the new system?
+
+    // println!("========== recreated maps:");
+    // let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
+    // _print_maps_info(&maps[..]);
+
+    report.vdso_compat = vdso_compat;
+    report.skipped = skips.skipped;
+    Ok(report)
+}
+
+/// Kills and reaps the child being restored into when dropped, unless it
+/// made it all the way through. Being a guard means errors bubbling up with
+/// `?` from anywhere in `telepad` get cleaned up after too.
+struct KillOnDrop {
+    child: Pid,
+    armed: bool,
+}
+
+impl KillOnDrop {
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            tracing::debug!("restore failed, killing half restored child");
+            let _ = kill(self.child, Signal::SIGKILL);
+            let _ = waitpid(self.child, None);
+        }
+    }
+}
+
+/// A freshly restored process that's still stopped with us attached to it
+/// through ptrace, right where it's about to return from `telefork`. Get one
+/// from `telepad_attached` to look at it or step through it before letting
+/// it go, otherwise `telepad` does all that for you.
+///
+/// ```no_run
+/// let mut dump = std::fs::File::open("dump.telefork.bin").unwrap();
+/// let mut restored = telefork::telepad_attached(&mut dump, 0, &Default::default()).unwrap();
+/// println!("about to resume at {:x}", restored.registers().unwrap().rip);
+/// restored.resume().unwrap();
+/// let pid = restored.detach().unwrap();
+/// ```
+///
+/// If it's dropped while still attached it gets detached and left running.
+///
+/// The one `TelepadBuilder::pre_resume` hands its hook can also run syscalls
+/// inside the process, with `syscall` and the helpers after it.
+pub struct RestoredProcess {
+    pid: Pid,
+    attached: bool,
+    /// Only while a `pre_resume` hook runs, after that the scratch region
+    /// is gone and the process may be under seccomp
+    syscall: Option<SyscallLoc>,
+    report: RestoreReport,
+}
+
+impl std::fmt::Debug for RestoredProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RestoredProcess")
+            .field("pid", &self.pid)
+            .field("attached", &self.attached)
+            .field("report", &self.report)
+            .finish()
+    }
+}
+
+impl RestoredProcess {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// What didn't come back, which with `Config::keep_going` is anything
+    /// it could do without, and otherwise just what couldn't be restored at
+    /// all here like fds of kinds we don't support
+    pub fn skipped(&self) -> &[Skipped] {
+        &self.report.skipped
+    }
+
+    /// Everything `telepad` did to bring it back
+    pub fn report(&self) -> &RestoreReport {
+        &self.report
+    }
+
+    fn syscall_loc(&self) -> Result<SyscallLoc> {
+        match self.syscall {
+            Some(syscall) => Ok(syscall),
+            None => error("syscalls can only be injected from a pre_resume hook"),
+        }
+    }
+
+    /// Run syscall `nr` inside the process, returning what it returned or
+    /// its errno as a `RemoteSyscallError`, or a `MissingSyscall` if this
+    /// kernel doesn't have it. Pointer arguments have to point into its
+    /// memory, not ours.
+    pub fn syscall(&self, nr: i64, args: [u64; 6]) -> Result<i64> {
+        let res = remote_syscall(self.pid, self.syscall_loc()?, nr as u64, args)?;
+        remote_result(res, || format!("syscall {}", nr))
+    }
+
+    /// Open `path` as seen from inside the process, returning the new fd
+    pub fn open(&self, path: &str, flags: i32, mode: u32) -> Result<u32> {
+        remote_open_mode(self.pid, self.syscall_loc()?, path, flags, mode)
+    }
+
+    pub fn dup2(&self, oldfd: u32, newfd: u32) -> Result<u32> {
+        remote_dup2(self.pid, self.syscall_loc()?, oldfd, newfd)
+    }
+
+    pub fn close(&self, fd: u32) -> Result<()> {
+        remote_close(self.pid, self.syscall_loc()?, fd)
+    }
+
+    pub fn prctl(&self, option: i32, arg2: u64) -> Result<i64> {
+        remote_prctl(self.pid, self.syscall_loc()?, option, arg2)
+    }
+
+    /// Point one of the `PR_SET_MM_*` fields at `addr`, like
+    /// `PR_SET_MM_ENV_START` to change what `/proc/pid/environ` shows.
+    /// Needs `CAP_SYS_RESOURCE`.
+    pub fn set_mm(&self, field: i32, addr: usize) -> Result<()> {
+        remote_set_mm(self.pid, self.syscall_loc()?, field, addr)
+    }
+
+    /// Set one of its `RLIMIT_*` limits. Raising the hard limit needs
+    /// `CAP_SYS_RESOURCE` like anywhere else.
+    pub fn set_rlimit(&self, resource: u32, soft: u64, hard: u64) -> Result<()> {
+        let syscall = self.syscall_loc()?;
+        let mut limit = soft.to_ne_bytes().to_vec();
+        limit.extend_from_slice(&hard.to_ne_bytes());
+        let res = with_remote_bytes(self.pid, syscall, &limit, |addr| {
+            remote_syscall(
+                self.pid,
+                syscall,
+                302, // prlimit64
+                [0, resource as u64, addr as u64, 0, 0, 0],
+            )
+        })?;
+        remote_result(res, || format!("prlimit64 of resource {}", resource))?;
+        Ok(())
+    }
+
+    /// The registers it'll resume with
+    pub fn registers(&self) -> Result<libc::user_regs_struct> {
+        Ok(ptrace::getregs(self.pid)?)
+    }
+
+    pub fn set_registers(&mut self, regs: libc::user_regs_struct) -> Result<()> {
+        Ok(ptrace::setregs(self.pid, regs)?)
+    }
+
+    /// Have it `execve` `path` with `argv` and `env`, leaving it stopped at
+    /// the start of the new program. Exec throws away the restored memory and
+    /// registers, so all that carries over is what exec always keeps: its
+    /// fds, apart from ones with close-on-exec set, its ids, limits, signal
+    /// mask and ignored signals, and the like.
+    pub fn exec(&mut self, path: &str, argv: &[&str], env: &[String]) -> Result<()> {
+        let syscall = find_syscall_loc(self.pid)?;
+        let env: Vec<&str> = env.iter().map(String::as_str).collect();
+        remote_execve(self.pid, syscall, path, argv, &env)
+    }
+
+    /// Run exactly one instruction
+    pub fn single_step(&mut self) -> Result<()> {
+        single_step(self.pid)
+    }
+
+    /// Single step through the first instructions it runs while we're still
+    /// attached, which is what `telepad` has always done before letting go.
+    /// If something's badly wrong with the restore it tends to show up as a
+    /// fault in here, where we notice, rather than a silent crash later.
+    pub fn resume(&mut self) -> Result<()> {
+        tracing::debug!("regs = {:?}", self.registers()?);
+        for _ in 1..10000 {
+            ptrace::step(self.pid, None)?;
+            match waitpid(self.pid, None)? {
+                WaitStatus::Stopped(_, Signal::SIGTRAP) => {}
+                // Left stopped in the fault, detaching lets it die of it
+                WaitStatus::Stopped(_, sig) if is_fault(sig) => {
+                    let report = FaultReport::scan(self.pid, sig);
+                    tracing::error!("{}", report);
+                    return Err(Box::new(report));
+                }
+                err => {
+                    tracing::error!("waitpid error = {:?}", err);
+                    return error("couldn't single step child");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `resume`, then keep watching it run for up to `window` in case it
+    /// faults, which comes back as a `FaultReport` error with the process
+    /// left stopped in the fault for you to `detach` or kill. Otherwise it's
+    /// left for `detach` to let go, or if it's already exited, for
+    /// `wait_for_exit` to reap. Other signals it gets are passed on.
+    pub fn watch_first_fault(&mut self, window: std::time::Duration) -> Result<()> {
+        self.resume()?;
+        ptrace::cont(self.pid, None)?;
+        let deadline = std::time::Instant::now() + window;
+        let mut stopping = false;
+        loop {
+            if !stopping && std::time::Instant::now() >= deadline {
+                // It has to be stopped to detach from
+                kill(self.pid, Signal::SIGSTOP)?;
+                stopping = true;
+            }
+            // Only look, so if it's exited it's still there to be reaped
+            let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+            let mut options = libc::WEXITED | libc::WSTOPPED | libc::WNOWAIT;
+            if !stopping {
+                options |= libc::WNOHANG;
+            }
+            let res = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    self.pid.as_raw() as libc::id_t,
+                    &mut info,
+                    options,
+                )
+            };
+            if res < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            if unsafe { info.si_pid() } == 0 {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                continue;
+            }
+            if info.si_code != libc::CLD_TRAPPED {
+                info!("restored process exited before it could fault");
+                self.attached = false;
+                return Ok(());
+            }
+            match waitpid(self.pid, None)? {
+                // Detaching without passing it on drops our SIGSTOP
+                WaitStatus::Stopped(_, Signal::SIGSTOP) if stopping => return Ok(()),
+                WaitStatus::Stopped(_, sig) if is_fault(sig) => {
+                    let report = FaultReport::scan(self.pid, sig);
+                    tracing::error!("{}", report);
+                    return Err(Box::new(report));
+                }
+                WaitStatus::Stopped(_, sig) => ptrace::cont(self.pid, sig)?,
+                _ => ptrace::cont(self.pid, None)?,
+            }
+        }
+    }
+
+    /// `resume` and `detach`, watching for the first fault in between if
+    /// `Config::debug_first_fault` says to
+    pub(crate) fn let_go(mut self, config: &Config) -> Result<Pid> {
+        if let Some(barrier) = &config.resume_barrier {
+            if let Err(e) = barrier.wait() {
+                // It never ran, so there's nothing of it worth keeping
+                kill(self.pid, Signal::SIGKILL)?;
+                self.attached = false;
+                waitpid(self.pid, None)?;
+                return Err(e);
+            }
+        }
+        if config.debug_first_fault {
+            self.watch_first_fault(FIRST_FAULT_WINDOW)?;
+        } else {
+            self.resume()?;
+        }
+        self.detach()
+    }
+
+    /// Let it go and run on its own. This lets the other process be stopped
+    /// without triggering our waitpid, as well as to be debugged by a
+    /// different ptrace-er.
+    pub fn detach(mut self) -> Result<Pid> {
+        // Exited while `watch_first_fault` was watching
+        if !self.attached {
+            return Ok(self.pid);
+        }
+        tracing::debug!("detaching from child");
+        self.attached = false;
+        ptrace::detach(self.pid, None)?;
+        Ok(self.pid)
+    }
+
+    /// Let go of it but leave it stopped, for attaching a debugger to. It
+    /// carries on when it gets a `SIGCONT`.
+    pub fn detach_stopped(mut self) -> Result<Pid> {
+        self.attached = false;
+        ptrace::detach(self.pid, Signal::SIGSTOP)?;
+        Ok(self.pid)
+    }
+}
+
+impl Drop for RestoredProcess {
+    fn drop(&mut self) {
+        if self.attached {
+            let _ = ptrace::detach(self.pid, None);
+        }
+    }
+}
+
+/// Where a group of restores sharing `Config::resume_barrier` wait for each
+/// other. Each restore of the group has to get there or fail before any of
+/// them is resumed, and if one fails the rest are killed rather than left
+/// running without it.
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// let barrier = Arc::new(telefork::ResumeBarrier::new(2));
+/// let restores: Vec<_> = ["server.bin", "client.bin"]
+///     .into_iter()
+///     .map(|path| {
+///         let config = telefork::Config {
+///             resume_barrier: Some(barrier.clone()),
+///             ..Default::default()
+///         };
+///         // Each has to be restored on its own thread, which is the one
+///         // that can resume it
+///         std::thread::spawn(move || {
+///             let mut dump = std::fs::File::open(path).unwrap();
+///             telefork::telepad_with_config(&mut dump, 0, &config).map_err(|e| e.to_string())
+///         })
+///     })
+///     .collect();
+/// for restore in restores {
+///     println!("{:?}", restore.join().unwrap());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ResumeBarrier {
+    parties: usize,
+    state: std::sync::Mutex<BarrierState>,
+    ready: std::sync::Condvar,
+}
+
+#[derive(Debug, Default)]
+struct BarrierState {
+    arrived: usize,
+    failed: bool,
+}
+
+impl ResumeBarrier {
+    /// A barrier for a group of `parties` restores
+    pub fn new(parties: usize) -> Self {
+        ResumeBarrier {
+            parties,
+            state: Default::default(),
+            ready: std::sync::Condvar::new(),
+        }
+    }
+
+    /// Wait for the rest of the group to be ready too, failing with
+    /// `GroupFailed` if one of them doesn't make it
+    pub fn wait(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.arrived += 1;
+        self.ready.notify_all();
+        while !state.failed && state.arrived < self.parties {
+            state = self.ready.wait(state).unwrap();
+        }
+        if state.failed {
+            return Err(Box::new(GroupFailed));
+        }
+        Ok(())
+    }
+
+    /// Give up on the group, every restore waiting or still to get here
+    /// fails instead of resuming. A restore sharing the barrier that fails
+    /// does this itself.
+    pub fn abort(&self) {
+        self.state.lock().unwrap().failed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// Another restore sharing a `ResumeBarrier` failed, so this one was killed
+/// instead of being resumed without it
+#[derive(Debug)]
+pub struct GroupFailed;
+
+impl std::fmt::Display for GroupFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "another restore in the group failed, so this one wasn't resumed"
+        )
+    }
+}
+
+impl Error for GroupFailed {}
+
+/// Signals that mean the process did something it can't carry on from
+fn is_fault(sig: Signal) -> bool {
+    matches!(
+        sig,
+        Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGILL | Signal::SIGFPE
+    )
+}
+
+/// Where a restored process was and what it touched when it faulted, for
+/// telling whether it's the restore that went wrong and where
+#[derive(Debug, Clone)]
+pub struct FaultReport {
+    pub signal: Signal,
+    /// The address it touched, `si_addr`
+    pub addr: usize,
+    pub regs: libc::user_regs_struct,
+    /// Up to 16 bytes at `rip`, empty if that isn't readable either
+    pub instruction: Vec<u8>,
+    /// The `/proc/pid/maps` lines of the mappings holding `addr` and `rip`,
+    /// if any do
+    pub addr_mapping: Option<String>,
+    pub rip_mapping: Option<String>,
+}
+
+impl FaultReport {
+    /// Look at a process stopped in a fault, getting whatever we can
+    fn scan(pid: Pid, signal: Signal) -> FaultReport {
+        let addr = ptrace::getsiginfo(pid).map_or(0, |info| unsafe { info.si_addr() } as usize);
+        let regs = ptrace::getregs(pid).unwrap_or(unsafe { std::mem::zeroed() });
+        let rip = regs.rip as usize;
+        let instruction = (0..16)
+            .rev()
+            .find_map(|len| read_memory(pid, rip, len + 1).ok())
+            .unwrap_or_default();
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap_or_default();
+        let mapping = |addr: usize| {
+            maps.lines()
+                .find(|line| {
+                    let range = line
+                        .split_whitespace()
+                        .next()
+                        .and_then(|r| r.split_once('-'));
+                    matches!(range, Some((start, end))
+                        if usize::from_str_radix(start, 16).is_ok_and(|s| s <= addr)
+                            && usize::from_str_radix(end, 16).is_ok_and(|e| addr < e))
+                })
+                .map(String::from)
+        };
+        FaultReport {
+            signal,
+            addr,
+            regs,
+            instruction,
+            addr_mapping: mapping(addr),
+            rip_mapping: mapping(rip),
+        }
+    }
+}
+
+impl std::fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let none = "not in any mapping".to_string();
+        writeln!(
+            f,
+            "restored process got {:?} at {:x} touching {:x}",
+            self.signal, self.regs.rip, self.addr
+        )?;
+        writeln!(f, "  code: {}", self.rip_mapping.as_ref().unwrap_or(&none))?;
+        writeln!(
+            f,
+            "  touched: {}",
+            self.addr_mapping.as_ref().unwrap_or(&none)
+        )?;
+        let bytes: Vec<String> = self
+            .instruction
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        writeln!(f, "  instruction bytes: {}", bytes.join(" "))?;
+        write!(
+            f,
+            "  rax {:x} rbx {:x} rcx {:x} rdx {:x} rsi {:x} rdi {:x} rbp {:x} rsp {:x}",
+            self.regs.rax,
+            self.regs.rbx,
+            self.regs.rcx,
+            self.regs.rdx,
+            self.regs.rsi,
+            self.regs.rdi,
+            self.regs.rbp,
+            self.regs.rsp
+        )
+    }
+}
+
+impl Error for FaultReport {}
+
+/// Utility to wait for the child process to exit, which is often what you
+/// want to do after using `telepad`.
+pub fn wait_for_exit(child: Pid) -> Result<i32> {
+    match waitpid(child, None)? {
+        WaitStatus::Exited(_, code) => Ok(code),
+        status => {
+            eprintln!("wait got: {:?}", status);
+            error("somehow got other wait status instead of exit")
+        }
+    }
+}
+
+// Helper that magically executes a closure on a remote server, perhaps one
+// with way more processing power. See the `smallpt` example for a demo using
+// this to do ray tracing on a larger remote server. The closure can access
+// and modify any data in this process and after `yoyo` returns execution is
+// back on the original machine.
+//
+// To do this it teleforks to a server like the `teleserver` example, executes
+// closure `f`, then receives a telefork back. Only returns in the new process
+// that is teleforked back on the client, the original process waits for its
+// child to exit then exits with the same status.
+pub fn yoyo<A: ToSocketAddrs, F: FnOnce()>(dest: A, f: F) {
+    let mut stream = TcpStream::connect(dest).unwrap();
+    let loc = telefork(&mut stream).unwrap();
+    match loc {
+        TeleforkLocation::Child(fd) => {
+            let mut stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+            // Do some work on the remote server
+            f();
+
+            let loc = telefork(&mut stream).unwrap();
+            std::mem::forget(stream); // parent drops stream not us
+            match loc {
+                // return normally in the child we teleforked back
+                TeleforkLocation::Child(_) => return,
+                // exit succesfully in the now unnecessary server process
+                TeleforkLocation::Parent => std::process::exit(0),
+            };
+        }
+        // teleforked succesfully, return out of match statement and wait to receive telefork back
+        TeleforkLocation::Parent => (),
+    };
+
+    // receive the telefork back
+    let child = telepad(&mut stream, 0).unwrap();
+    // we don't return from this function in the original process, we let it
+    // return in the newly received process then just wait and exit with the
+    // same status
+    let status = wait_for_exit(child).unwrap();
+    std::process::exit(status);
+}
+
+/// `telefork` over connections made by `connect`. With `Config::resumable`
+/// it's called again to reconnect if the connection drops partway, and the
+/// transfer picks up where it left off. The other end should use
+/// `telepad_accept` with the same setting.
+pub fn telefork_connect<S, C>(mut connect: C, config: &Config) -> Result<TeleforkLocation>
+where
+    S: Read + Write,
+    C: FnMut() -> std::io::Result<S>,
+{
+    if !config.resumable {
+        return telefork_with_config(&mut connect()?, config);
+    }
+    let mut out = resumable::ResumableWriter::new(connect)?;
+    match telefork_with_config(&mut out, config)? {
+        TeleforkLocation::Parent => {
+            out.finish()?;
+            Ok(TeleforkLocation::Parent)
+        }
+        // Our copy of the writer is from before anything was sent, just
+        // drop it without touching the connection
+        child => Ok(child),
+    }
+}
+
+/// `telepad` from connections handed over by `accept`, the receiving side of
+/// `telefork_connect`.
+pub fn telepad_accept<S, A>(mut accept: A, pass_to_child: i32, config: &Config) -> Result<Pid>
+where
+    S: Read + Write,
+    A: FnMut() -> std::io::Result<S>,
+{
+    if !config.resumable {
+        return telepad_with_config(&mut accept()?, pass_to_child, config);
+    }
+    let mut inp = resumable::ResumableReader::new(accept)?;
+    let child = telepad_with_config(&mut inp, pass_to_child, config)?;
+    // Read up to the end marker so the sender gets its final ack
+    std::io::copy(&mut inp, &mut std::io::sink())?;
+    Ok(child)
+}
+
+/// The Yama `ptrace_scope`, or `None` if Yama isn't built into this kernel
+/// and ptrace works the classic way
+fn ptrace_scope() -> Option<u32> {
+    let scope = std::fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope").ok()?;
+    scope.trim().parse().ok()
+}
+
+/// From `linux/capability.h`, libc doesn't have the capability numbers
+const CAP_SYS_PTRACE: u32 = 19;
+
+/// Whether we have `CAP_SYS_PTRACE`, which is what Yama wants at scope 1 for
+/// processes that aren't ours and at scope 2 for anything
+fn has_cap_sys_ptrace() -> bool {
+    let caps = read_status_field(std::process::id() as i32, "CapEff")
+        .ok()
+        .and_then(|caps| u64::from_str_radix(&caps, 16).ok());
+    matches!(caps, Some(caps) if caps & (1 << CAP_SYS_PTRACE) != 0)
+}
+
+/// Fail up front with something actionable if `scope` rules out tracing
+/// anything at all. At scope 1 it depends on whether the process is a
+/// descendant of ours or allowed us with `PR_SET_PTRACER`, which only
+/// attaching will tell us.
+fn check_ptrace_scope(scope: Option<u32>, privileged: bool) -> Result<()> {
+    match scope {
+        Some(2) if privileged => Ok(()),
+        Some(scope) if scope >= 2 => {
+            tracing::error!("Yama ptrace_scope is {}, we can't trace anything", scope);
+            Err(Box::new(PtraceRestricted { scope }))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fail with `AlreadyTraced` if something else is tracing the process,
+/// which would otherwise just be an `EPERM` from attaching
+fn check_not_traced(pid: i32) -> Result<()> {
+    let tracer: i32 = read_status_field(pid, "TracerPid")?.parse()?;
+    if tracer == 0 {
+        return Ok(());
+    }
+    let tracer_name = std::fs::read_to_string(format!("/proc/{}/comm", tracer))
+        .ok()
+        .map(|comm| comm.trim_end().to_string());
+    tracing::error!("{} is already traced by {}", pid, tracer);
+    Err(Box::new(AlreadyTraced {
+        pid,
+        tracer,
+        tracer_name,
+    }))
+}
+
+/// Attach to a process that isn't our child and stop it so we can look at
+/// it. `PTRACE_ATTACH` works by sending a SIGSTOP, which gets muddled up with
+/// a process that's already stopped, so we use `PTRACE_SEIZE` and then
+/// `PTRACE_INTERRUPT` which stops it either way without a signal.
+pub(crate) fn seize_and_stop(child: Pid) -> Result<()> {
+    ptrace::seize(child, ptrace::Options::empty())?;
+    let res = unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, child.as_raw(), 0, 0) };
+    Errno::result(res)?;
+    match waitpid(child, None)? {
+        WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) | WaitStatus::Stopped(..) => Ok(()),
+        status => {
+            tracing::error!("waitpid after interrupting got {:?}", status);
+            error("process didn't stop after attaching")
+        }
+    }
+}
+
+/// The syscall `/proc/pid/syscall` says the process is blocked in and its
+/// arguments, or `None` if it's running or we can't tell
+fn read_blocked_syscall(pid: i32) -> Option<(i64, [u64; 6])> {
+    let syscall = std::fs::read_to_string(format!("/proc/{}/syscall", pid)).ok()?;
+    let mut fields = syscall.split_whitespace();
+    let nr = fields.next()?.parse().ok()?;
+    let mut args = [0u64; 6];
+    for arg in &mut args {
+        *arg = u64::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+    }
+    Some((nr, args))
+}
 
-/// We find these syscalls by searching for an existing syscall instruction
-/// inside a page in the child process. One can always be found (as far as I
-/// know) by passing the address of `[vdso]` as the `addr`.
-fn try_to_find_syscall(child: Pid, addr: usize) -> Result<usize> {
-    let mut buf = vec![0u8; PAGE_SIZE];
-    let wrote = uio::process_vm_readv(
-        child,
-        &[uio::IoVec::from_mut_slice(&mut buf[..])],
-        &[uio::RemoteIoVec {
-            base: addr,
-            len: PAGE_SIZE,
-        }],
-    )?;
-    if wrote == 0 {
-        return error("failed to read from other process");
+/// Whether the process is stuck in a `vfork`, or a `clone` or `clone3` with
+/// `CLONE_VFORK` like `posix_spawn` does, until its child execs or exits
+fn in_vfork(pid: i32) -> bool {
+    let vfork = libc::CLONE_VFORK as u64;
+    match read_blocked_syscall(pid) {
+        Some((58, _)) => true,
+        Some((56, args)) => args[0] & vfork != 0,
+        // The flags are the first field of the `struct clone_args` it points to
+        Some((435, args)) => {
+            use std::os::unix::fs::FileExt;
+            let mut flags = [0u8; 8];
+            std::fs::File::open(format!("/proc/{}/mem", pid))
+                .and_then(|mem| mem.read_exact_at(&mut flags, args[0]))
+                .is_ok()
+                && u64::from_ne_bytes(flags) & vfork != 0
+        }
+        _ => false,
     }
+}
 
-    let syscall = &[0x0f, 0x05];
-    match buf.windows(syscall.len()).position(|w| w == syscall) {
-        Some(index) => Ok(index),
-        None => error("couldn't find syscall"),
+/// What the process is partway through that it can't be dumped in the
+/// middle of, if anything: either side of a `vfork`, or an `exec`
+fn transient_state(pid: i32) -> Option<String> {
+    if in_vfork(pid) {
+        return Some("it's waiting for the child it vforked to exec or exit".to_string());
+    }
+    if let Some((59 | 322, _)) = read_blocked_syscall(pid) {
+        return Some("it's in the middle of an exec".to_string());
+    }
+    let ppid: i32 = read_status_field(pid, "PPid").ok()?.parse().ok()?;
+    if ppid <= 0 || !in_vfork(ppid) {
+        return None;
     }
+    // Its parent could be waiting on some other child it vforked, like in
+    // `shares_memory_with_parent`
+    let res = unsafe { libc::syscall(libc::SYS_kcmp, pid, ppid, KCMP_VM, 0, 0) };
+    match Errno::result(res) {
+        Ok(0) => {}
+        Ok(_) => return None,
+        Err(e) => tracing::debug!("couldn't compare {} to its parent with kcmp: {}", pid, e),
+    }
+    Some("it's a vfork child that hasn't exec'd yet, running on its parent's memory".to_string())
 }
 
-// The simplest case of a remote syscall
-fn remote_brk(child: Pid, syscall: SyscallLoc, brk: usize) -> Result<usize> {
-    let SyscallLoc(loc) = syscall;
-    // == 1. Get the current register state so we can modify
-    let regs = ptrace::getregs(child)?;
-    // == 2. Modify only the registers involved in the syscall
-    let syscall_regs = libc::user_regs_struct {
-        rip: loc as u64, // syscall instr (rip is the instruction pointer)
-        rax: 12,         // brk (rax holds the syscall number)
-        rdi: brk as u64, // addr (first argument to syscall goes in rdi)
-        ..regs
-    };
-    // == 2. Set the modified regs
-    ptrace::setregs(child, syscall_regs)?;
-    // == 3. Execute the syscall instruction (we set rip to point to it)
-    single_step(child)?;
-    // == 4. Get the instructions so we can extract the return value from rax
-    let new_regs = ptrace::getregs(child)?;
-    Ok(new_regs.rax as usize)
+/// Wait for the process to be out of any `transient_state`, polling for up
+/// to `timeout`
+fn wait_until_settled(pid: i32, timeout: std::time::Duration) -> Result<()> {
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        let reason = match transient_state(pid) {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+        if std::time::Instant::now() >= deadline {
+            let err = TransientState { pid, reason };
+            tracing::error!("{}", err);
+            return Err(Box::new(err));
+        }
+        tracing::debug!("waiting to dump {}, {}", pid, reason);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
 }
 
-// The most complex case of a remote syscall, but basically the same
-fn remote_mmap_anon(
-    child: Pid,
-    syscall: SyscallLoc,
-    addr: Option<usize>,
-    length: usize,
-    prot: i32,
-) -> Result<usize> {
-    if length % PAGE_SIZE != 0 {
-        error("mmap length must be multiple of page size")?;
+// Helper that attaches to a running process and dumps its state to a file
+// for later restore.
+pub fn teledump(pid: i32, out: &mut dyn Write, leave_running: bool) -> Result<TeleforkStats> {
+    teledump_with_config(pid, out, leave_running, &Config::default())
+}
+
+/// `teledump` with the knobs in `Config`
+pub fn teledump_with_config(
+    pid: i32,
+    out: &mut dyn Write,
+    leave_running: bool,
+    config: &Config,
+) -> Result<TeleforkStats> {
+    teledump_with_hooks(pid, out, leave_running, config, &mut DumpHooks::default())
+}
+
+pub(crate) fn teledump_with_hooks(
+    pid: i32,
+    out: &mut dyn Write,
+    leave_running: bool,
+    config: &Config,
+    hooks: &mut DumpHooks,
+) -> Result<TeleforkStats> {
+    let child = Pid::from_raw(pid);
+    // Check before precopy, which reads the running process mostly without
+    // attaching and would just see zeroes where it isn't allowed to
+    check_ptrace_scope(ptrace_scope(), has_cap_sys_ptrace())?;
+    check_not_traced(pid)?;
+    let tgid: i32 = read_status_field(pid, "Tgid")?.parse()?;
+    if tgid != pid {
+        tracing::error!("{} is a thread of {}", pid, tgid);
+        return Err(Box::new(NotLeader { tid: pid, tgid }));
     }
-    let SyscallLoc(loc) = syscall;
-    let regs = ptrace::getregs(child)?;
-    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
-    let (addr, flags) = match addr {
-        // Caller requested a specific address
-        Some(addr) => (addr, flags | libc::MAP_FIXED),
-        // No specific address requested, we just want to map anywhere available
-        None => (0, flags),
+    wait_until_settled(pid, config.settle_timeout)?;
+    if !config.skip_device_maps {
+        // Refuse before attaching so a process we won't dump is left alone,
+        // `write_state` checks again in case it's mapped one since
+        let maps = proc_maps::get_process_maps(pid as proc_maps::Pid)?;
+        check_device_maps(pid, &maps, config)?;
+    }
+    // TODO: This is wrong! Just a copy-paste from telefork, but here we need to read the remote brk state.
+    // == 1. Record anything we can easily record within our own process
+    let proc_state = ProcessState {
+        // sbrk(0) returns current brk address and it won't change for child since we don't malloc before forking
+        brk_addr: unsafe { libc::sbrk(0) as usize },
     };
-    let mmap_regs = libc::user_regs_struct {
-        rip: loc,
-        rax: 9,             // mmap
-        rdi: addr as u64,   // addr
-        rsi: length as u64, // length
-        rdx: prot as u64,   // prot
-        r10: flags as u64,  // flags
-        r8: (-1i64) as u64, // fd
-        r9: 0,              // offset
-        ..regs
+
+    let out = &mut FrameWriter::new(out, config.index);
+
+    // Someone might have stopped it with SIGSTOP already, like tooling
+    // that lines up a bunch of processes before dumping them
+    let was_stopped = read_status_field(pid, "State")?.starts_with('T');
+    let sent = if config.precopy_passes > 0 && !was_stopped {
+        Sent::Precopy(precopy::Precopy::run(
+            out, child, proc_state, config, hooks,
+        )?)
+    } else {
+        Sent::Nothing(proc_state)
     };
-    ptrace::setregs(child, mmap_regs)?;
-    single_step(child)?;
-    let regs = ptrace::getregs(child)?;
-    let mmap_location: i64 = regs.rax as i64;
-    // println!("mmap location = {:x}; pre sys = {:x}; pre = {:x}", mmap_location, mmap_regs.rax as i64, regs.rax as i64);
-    if mmap_location == -1 {
-        error("mmap syscall exited with -1")?;
+    let stopped_at = std::time::Instant::now();
+    if let Err(e) = seize_and_stop(child) {
+        tracing::error!("couldn't attach to {}: {}", pid, e);
+        // A debugger could have attached since we checked
+        match check_not_traced(pid) {
+            Err(e) if e.is::<AlreadyTraced>() => return Err(e),
+            _ => {}
+        }
+        if let (Some(scope @ 1..), Some(nix::Error::Sys(Errno::EPERM))) =
+            (ptrace_scope(), e.downcast_ref::<nix::Error>())
+        {
+            return Err(Box::new(PtraceRestricted { scope }));
+        }
+        return error("failed to attach to process");
+    };
+    // It could have been vforked since we checked. A vfork parent can't be
+    // stopped until its child is done with it, but the child can.
+    if let Some(reason) = transient_state(pid) {
+        let sig = was_stopped.then_some(Signal::SIGSTOP);
+        ptrace::detach(child, sig)?;
+        let err = TransientState { pid, reason };
+        tracing::error!("{}", err);
+        return Err(Box::new(err));
     }
-    if addr != 0 && mmap_location as usize != addr {
-        error("failed to mmap at correct location")?;
+    let mut stats = write_state(out, child, child, sent, config, hooks)?;
+    out.finish()?;
+
+    if leave_running {
+        // Detaching resumes it, so if it was stopped before stop it again
+        let sig = if was_stopped {
+            Some(Signal::SIGSTOP)
+        } else {
+            None
+        };
+        ptrace::detach(child, sig)?;
+    } else {
+        if ptrace::kill(child).is_err() {
+            return error("failed to kill the process");
+        }
     }
-    Ok(mmap_location as usize)
+    stats.frozen = stopped_at.elapsed();
+    info!("process was stopped for {:?}", stats.frozen);
+
+    Ok(stats)
 }
 
-fn remote_munmap(child: Pid, syscall: SyscallLoc, addr: usize, length: usize) -> Result<()> {
-    let SyscallLoc(loc) = syscall;
-    let regs = ptrace::getregs(child)?;
-    let syscall_regs = libc::user_regs_struct {
-        rip: loc as u64,    // syscall instr
-        rax: 11,            // munmap
-        rdi: addr as u64,   // addr
-        rsi: length as u64, // length
-        ..regs
-    };
-    ptrace::setregs(child, syscall_regs)?;
-    single_step(child)?;
-    let new_regs = ptrace::getregs(child)?;
-    if new_regs.rax != 0 {
-        // println!("rax = {:x}; rip = {:x}", new_regs.rax, new_regs.rip);
-        error("failed to munmap")?;
+/// What a file descriptor points at, as far as restoring it goes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Connection {
+    /// Something we don't know how to bring back, like a pipe
+    Invalid,
+    Tcp(TcpConnection),
+    File(FileConnection),
+    Stdio(StdioConnection),
+    UnixPair(UnixPairConnection),
+    BundledFile(BundledFileConnection),
+    Inotify(InotifyConnection),
+    Directory(DirectoryConnection),
+    Pidfd(PidfdConnection),
+    Dup(DupConnection),
+    Userfaultfd(UserfaultfdConnection),
+    Mqueue(MqueueConnection),
+}
+
+impl Connection {
+    /// Whether `telepad` knows how to bring this connection back
+    pub fn restorable(&self) -> bool {
+        matches!(
+            self,
+            Connection::File(_)
+                | Connection::Stdio(_)
+                | Connection::UnixPair(_)
+                | Connection::BundledFile(_)
+                | Connection::Inotify(_)
+                | Connection::Directory(_)
+                | Connection::Pidfd(_)
+                | Connection::Dup(_)
+                | Connection::Userfaultfd(_)
+                | Connection::Mqueue(_)
+        )
+    }
+
+    /// Short human readable name for the kind of connection
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Connection::Invalid => "unsupported",
+            Connection::Tcp(_) => "socket",
+            Connection::File(_) => "file",
+            Connection::Stdio(_) => "stdio",
+            Connection::UnixPair(_) => "unix socket pair",
+            Connection::BundledFile(_) => "bundled file",
+            Connection::Inotify(_) => "inotify",
+            Connection::Directory(_) => "directory",
+            Connection::Pidfd(_) => "pidfd",
+            Connection::Dup(_) => "duplicate",
+            Connection::Userfaultfd(_) => "userfaultfd",
+            Connection::Mqueue(_) => "message queue",
+        }
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Connection::File(f) => Some(&f.path),
+            Connection::BundledFile(f) => Some(&f.path),
+            Connection::Directory(d) => Some(&d.path),
+            _ => None,
+        }
     }
-    Ok(())
 }
 
-fn remote_mremap(
-    child: Pid,
-    syscall: SyscallLoc,
-    addr: usize,
-    length: usize,
-    new_addr: usize,
-) -> Result<()> {
-    if addr == new_addr {
-        return Ok(());
+impl std::fmt::Display for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Connection::Invalid => write!(f, "unsupported"),
+            Connection::Tcp(c) => write!(f, "socket {}", c.local_addr),
+            Connection::File(c) => write!(f, "file {} at offset {}", c.path, c.offset),
+            Connection::Stdio(_) => write!(f, "stdio"),
+            Connection::UnixPair(c) => write!(
+                f,
+                "unix socket pair end {} (peer {}), {} bytes buffered",
+                c.ino,
+                c.peer,
+                c.buffered.len()
+            ),
+            Connection::BundledFile(c) => write!(
+                f,
+                "bundled file {} at offset {}, {} bytes",
+                c.path,
+                c.offset,
+                c.contents.len()
+            ),
+            Connection::Inotify(c) => write!(f, "inotify watching {} paths", c.watches.len()),
+            Connection::Directory(c) => write!(f, "directory {} (inode {})", c.path, c.ino),
+            Connection::Pidfd(c) => write!(f, "pidfd of process {}", c.pid),
+            Connection::Dup(c) => write!(f, "duplicate of fd {}", c.of),
+            Connection::Userfaultfd(c) => write!(
+                f,
+                "userfaultfd handling faults in {} regions",
+                c.regions.len()
+            ),
+            Connection::Mqueue(c) => write!(
+                f,
+                "message queue {} with {} messages saved",
+                c.name,
+                c.messages.len()
+            ),
+        }
     }
+}
 
-    let SyscallLoc(loc) = syscall;
-    let regs = ptrace::getregs(child)?;
-    let syscall_regs = libc::user_regs_struct {
-        rip: loc as u64,                                         // syscall instr
-        rax: 25,                                                 // mremap
-        rdi: addr as u64,                                        // addr
-        rsi: length as u64,                                      // old_length
-        rdx: length as u64,                                      // new_length
-        r10: (libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED) as u64, // flags
-        r8: new_addr as u64,                                     // new_addr
-        ..regs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TcpConnection {
+    pub local_addr: String,
+    pub remote_addr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileConnection {
+    pub path: String,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StdioConnection {}
+
+/// One end of an anonymous unix socket pair, like `socketpair()` makes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnixPairConnection {
+    /// Inode of this end, only meaningful for matching up the ends
+    pub ino: u64,
+    /// Inode of the other end, which must also be in the process's fd table
+    pub peer: u64,
+    pub sock_type: i32,
+    /// Bytes waiting to be read on this end when we dumped it, only kept for
+    /// `SOCK_STREAM` pairs
+    pub buffered: Vec<u8>,
+}
+
+/// A directory held open, usually to `openat` relative to it or `fchdir`
+/// into. Those only keep working the same if it's the same directory, so the
+/// inode is kept to check what we reopen by path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryConnection {
+    pub path: String,
+    pub ino: u64,
+}
+
+/// A regular file sent along with its contents, see `Config::bundle_files`
+/// and `Config::small_file_limit`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BundledFileConnection {
+    pub path: String,
+    pub offset: u64,
+    pub contents: Vec<u8>,
+}
+
+// Don't dump whole files into the debug logs
+impl std::fmt::Debug for BundledFileConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BundledFileConnection")
+            .field("path", &self.path)
+            .field("offset", &self.offset)
+            .field("len", &self.contents.len())
+            .finish()
+    }
+}
+
+/// An inotify instance and what it was watching. Events that were queued up
+/// and not read yet don't come along.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InotifyConnection {
+    /// Whether it was non-blocking, the `O_NONBLOCK` bit of its file flags
+    pub nonblocking: bool,
+    pub watches: Vec<InotifyWatch>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InotifyWatch {
+    /// The watch descriptor the process knows it by, which we try to get the
+    /// same again
+    pub wd: i32,
+    pub path: String,
+    pub mask: u32,
+}
+
+/// An fd sharing its open file description with a lower one, from `dup` or
+/// inherited along with it, so the two move one offset and share flags.
+/// It's restored by `dup2`ing the one it's a copy of once that's back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DupConnection {
+    pub of: u32,
+}
+
+/// A pidfd from `pidfd_open`, for signalling or polling some process. It's
+/// opened again on whatever has that pid when we restore, as long as that's
+/// still the same process. It won't be our child anymore, so waiting on it
+/// through the pidfd stops working even if it was before.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PidfdConnection {
+    pub pid: i32,
+    /// Field 22 of its `/proc/pid/stat`, to tell it apart from something
+    /// else that got the same pid later
+    pub start_time: u64,
+    /// Whether it was opened with `PIDFD_NONBLOCK`
+    pub nonblocking: bool,
+}
+
+/// A userfaultfd, which the process handles page faults in some of its
+/// memory through. It's made again with the same features and the same
+/// regions registered, with the pages that were still missing dropped again
+/// so they still fault. Faults that were waiting to be read don't come along,
+/// and whatever thread was reading them only does if it's the main one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserfaultfdConnection {
+    /// The `O_NONBLOCK` and `O_CLOEXEC` it was made with
+    pub flags: i32,
+    /// What `UFFDIO_API` enabled, `None` if it was never called
+    pub features: Option<u64>,
+    pub regions: Vec<UserfaultRegion>,
+}
+
+/// Memory registered with `UFFDIO_REGISTER`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserfaultRegion {
+    pub addr: usize,
+    pub size: usize,
+    /// The `UFFDIO_REGISTER_MODE_*` bits
+    pub mode: u64,
+    /// Ranges of pages that hadn't been faulted in yet, which a missing mode
+    /// handler would still expect to be asked for
+    pub missing: Vec<(usize, usize)>,
+}
+
+/// A POSIX message queue from `mq_open`. It's opened again by name, and made
+/// with the same limits if it doesn't exist where we restore. The messages
+/// waiting in it are only saved if nothing else has it open, since saving
+/// them means taking them out and putting them back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqueueConnection {
+    /// Like `/jobs`, as the process passed it to `mq_open`
+    pub name: String,
+    /// The access mode and `O_NONBLOCK` it was opened with
+    pub flags: i32,
+    /// Permission bits, for if we have to make it
+    pub mode: u32,
+    pub max_messages: i64,
+    pub message_size: i64,
+    /// In the order they'd have been received
+    pub messages: Vec<MqueueMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqueueMessage {
+    pub priority: u32,
+    pub data: Vec<u8>,
+}
+
+/// Files bigger than this are sent as just a path even with `bundle_files`
+pub const BUNDLE_FILE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// The default `Config::small_file_limit`
+pub const SMALL_FILE_LIMIT: usize = 1024 * 1024;
+
+/// Most data sitting in a socket buffer is small messages, past this we
+/// just give up on preserving it.
+const SOCKET_BUFFER_LIMIT: usize = 64 * 1024;
+
+pub type ConnectionMap = HashMap<u32, Connection>;
+
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+/// The flags each regular file fd was opened with, from the octal `flags:`
+/// line in its `fdinfo`
+fn scan_open_flags(pid: i32, cm: &ConnectionMap) -> HashMap<u32, i32> {
+    let mut open_flags = HashMap::new();
+    for (&fd, conn) in cm {
+        if !matches!(
+            conn,
+            Connection::File(_) | Connection::BundledFile(_) | Connection::Directory(_)
+        ) {
+            continue;
+        }
+        let fdinfo = match std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd)) {
+            Ok(fdinfo) => fdinfo,
+            // Closed since we listed them
+            Err(_) => continue,
+        };
+        let flags = fdinfo
+            .lines()
+            .find_map(|l| l.strip_prefix("flags:"))
+            .and_then(|f| i32::from_str_radix(f.trim(), 8).ok());
+        if let Some(flags) = flags {
+            open_flags.insert(fd, flags);
+        }
+    }
+    open_flags
+}
+
+fn get_fd_offset(pid: i32, fd: u32) -> Result<Option<u64>> {
+    use std::io::BufRead;
+    let fdinfo_path = format!("/proc/{}/fdinfo/{}", pid, fd);
+    let file = match std::fs::File::open(&fdinfo_path) {
+        Ok(f) => f,
+        Err(_) => {
+            return error("failed to open fdinfo");
+        }
     };
-    ptrace::setregs(child, syscall_regs)?;
-    single_step(child)?;
-    let new_regs = ptrace::getregs(child)?;
-    if new_regs.rax as i64 == -1 {
-        error("failed to mremap")?;
+    let reader = std::io::BufReader::new(file);
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with("pos:") {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if let Some(offset_str) = parts.get(1) {
+                if let Ok(offset) = offset_str.parse::<u64>() {
+                    return Ok(Some(offset));
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Work out what each of a process's file descriptors points at. This is
+/// the same scan `telefork` does, handy on its own for checking ahead of
+/// time what would make it across.
+pub fn scan_file_descriptors(pid: i32) -> Result<ConnectionMap> {
+    let fd_dir: String = format!("/proc/{}/fd", pid);
+    let entries = std::fs::read_dir(fd_dir)?;
+
+    let mut cm: ConnectionMap = HashMap::new();
+    // Only asked for if the process has any sockets
+    let mut unix_sockets = None;
+    let mut peeked = Vec::new();
+    let root = std::fs::read_link(format!("/proc/{}/root", pid)).map_or_else(
+        |_| "/".to_string(),
+        |root| root.to_string_lossy().to_string(),
+    );
+
+    for entry in entries {
+        let entry = entry?;
+        let fd_path = entry.path();
+        let fd = fd_path.file_name().unwrap().to_string_lossy();
+        // Read the symbolic link to get the file descriptor target. It's
+        // not a real path for things like sockets and pipes so we stat the
+        // magic link itself which refers to the open file.
+        let looked = std::fs::read_link(&fd_path)
+            .and_then(|target| Ok((target, std::fs::metadata(&fd_path)?)));
+        let (target, metadata) = match looked {
+            Ok(looked) => looked,
+            Err(_) if fd_closed(&fd_path) => {
+                tracing::debug!(
+                    "fd {} was closed while we were scanning, leaving it out",
+                    fd
+                );
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let file_type = metadata.file_type();
+        info!("file descriptor {}: {:?}", fd, target);
+
+        let mqueue = file_type.is_file() && is_mqueue(&fd_path);
+        if (file_type.is_file() && !mqueue) || file_type.is_dir() {
+            check_fd_path(pid, &fd, &target, &root, &metadata);
+        }
+        if mqueue {
+            let fd = fd.parse::<u32>().unwrap();
+            let name = target.to_string_lossy().to_string();
+            let conn = match scan_mqueue(pid, fd, name, &metadata) {
+                Ok(mq) => Connection::Mqueue(mq),
+                Err(_) if fd_closed(&fd_path) => continue,
+                Err(e) => {
+                    warn!("message queue fd {} won't be restored: {}", fd, e);
+                    Connection::Invalid
+                }
+            };
+            cm.insert(fd, conn);
+        } else if file_type.is_file() {
+            let fd = fd.parse::<u32>().unwrap();
+            let offset = match get_fd_offset(pid, fd) {
+                Ok(offset) => offset.unwrap_or(0),
+                Err(_) if fd_closed(&fd_path) => {
+                    tracing::debug!(
+                        "fd {} was closed while we were scanning, leaving it out",
+                        fd
+                    );
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            cm.insert(
+                fd,
+                Connection::File(FileConnection {
+                    path: target.to_string_lossy().to_string(),
+                    offset,
+                }),
+            );
+        } else if file_type.is_dir() {
+            cm.insert(
+                fd.parse::<u32>().unwrap(),
+                Connection::Directory(DirectoryConnection {
+                    path: target.to_string_lossy().to_string(),
+                    ino: metadata.ino(),
+                }),
+            );
+        } else if file_type.is_socket() {
+            let fd = fd.parse::<u32>().unwrap();
+            if unix_sockets.is_none() {
+                unix_sockets = Some(sock_diag::unix_sockets().unwrap_or_else(|e| {
+                    warn!("couldn't list unix sockets: {}", e);
+                    HashMap::new()
+                }));
+            }
+            let ino = metadata.ino();
+            let pair = unix_sockets
+                .as_ref()
+                .and_then(|socks| socks.get(&ino))
+                .filter(|info| info.name.is_none());
+            match pair.and_then(|info| info.peer.map(|peer| (info, peer))) {
+                Some((info, peer)) => {
+                    let buffered = if peeked.contains(&ino) {
+                        Vec::new()
+                    } else {
+                        peeked.push(ino);
+                        sock_diag::peek_socket_buffer(pid, fd, info.sock_type, SOCKET_BUFFER_LIMIT)
+                    };
+                    if buffered.len() == SOCKET_BUFFER_LIMIT {
+                        warn!(
+                            "socket fd {} has more buffered data than we can preserve",
+                            fd
+                        );
+                    }
+                    cm.insert(
+                        fd,
+                        Connection::UnixPair(UnixPairConnection {
+                            ino,
+                            peer,
+                            sock_type: info.sock_type,
+                            buffered,
+                        }),
+                    );
+                }
+                None => {
+                    cm.insert(
+                        fd,
+                        Connection::Tcp(TcpConnection {
+                            local_addr: target.to_string_lossy().to_string(),
+                            remote_addr: target.to_string_lossy().to_string(),
+                        }),
+                    );
+                }
+            }
+        } else if file_type.is_char_device() {
+            let fd = fd.parse::<u32>().unwrap();
+            if matches!(fd, 0..=2) {
+                cm.insert(fd, Connection::Stdio(StdioConnection {}));
+            } else {
+                warn!("saving unsupported file descriptor");
+                cm.insert(fd, Connection::Invalid);
+            }
+        } else if target.to_str() == Some("anon_inode:inotify") {
+            let fd = fd.parse::<u32>().unwrap();
+            match scan_inotify(pid, fd) {
+                Ok(inotify) => cm.insert(fd, Connection::Inotify(inotify)),
+                Err(_) if fd_closed(&fd_path) => continue,
+                Err(e) => return Err(e),
+            };
+        } else if target.to_str() == Some("anon_inode:[pidfd]") {
+            let fd = fd.parse::<u32>().unwrap();
+            let conn = match scan_pidfd(pid, fd) {
+                Ok(pidfd) => Connection::Pidfd(pidfd),
+                Err(e) => {
+                    warn!("pidfd {} won't be restored: {}", fd, e);
+                    Connection::Invalid
+                }
+            };
+            cm.insert(fd, conn);
+        } else if target.to_str() == Some("anon_inode:[userfaultfd]") {
+            let fd = fd.parse::<u32>().unwrap();
+            match scan_userfaultfd(pid, fd) {
+                Ok(uffd) => cm.insert(fd, Connection::Userfaultfd(uffd)),
+                Err(_) if fd_closed(&fd_path) => continue,
+                Err(e) => return Err(e),
+            };
+        } else {
+            warn!("saving unsupported file descriptor");
+            cm.insert(fd.parse::<u32>().unwrap(), Connection::Invalid);
+        }
+    }
+
+    // We can only recreate a socket pair if we have both ends, if the other
+    // end lives in some other process there's nothing to connect to.
+    let ends: Vec<u64> = cm
+        .values()
+        .filter_map(|c| match c {
+            Connection::UnixPair(p) => Some(p.ino),
+            _ => None,
+        })
+        .collect();
+    for (fd, conn) in cm.iter_mut() {
+        if let Connection::UnixPair(p) = conn {
+            if !ends.contains(&p.peer) {
+                warn!("other end of unix socket fd {} is in another process", fd);
+                *conn = Connection::Invalid;
+            }
+        }
     }
-    if new_regs.rax as usize != new_addr {
-        // println!("remapped to {:x} from {:x} instead of {:x}", new_regs.rax, addr, new_addr);
-        error("didn't mremap to correct location")?;
+    find_duplicates(pid, &mut cm);
+    if cm.values().any(|c| matches!(c, Connection::Userfaultfd(_))) {
+        match scan_userfault_regions(pid) {
+            Ok(regions) => attach_userfault_regions(&mut cm, regions),
+            Err(e) => warn!("couldn't tell what's registered with userfaultfds: {}", e),
+        }
     }
-    Ok(())
+    Ok(cm)
 }
 
-/// The inverse of the streaming in `write_regular_map`. Streams memory from a
-/// `Read` channel into a child process at a certain address.
-fn stream_memory(child: Pid, inp: &mut dyn Read, addr: usize, length: usize) -> Result<()> {
-    let mut remaining_size = length;
-    let mut buf = vec![0u8; PAGE_SIZE];
-    while remaining_size > 0 {
-        let batch_size = std::cmp::min(buf.len(), remaining_size);
-        let offset = addr + (length - remaining_size);
-
-        inp.read_exact(&mut buf[..batch_size])?;
+/// Whether an fd we listed is gone now. A process still running, or one
+/// sharing its fd table with another that is, can close them while we go
+/// through them, which shouldn't fail the whole scan. Its link going away is
+/// what tells it apart from the fd being there but something about it
+/// not making sense.
+fn fd_closed(fd_path: &std::path::Path) -> bool {
+    matches!(std::fs::symlink_metadata(fd_path), Err(e) if e.kind() == std::io::ErrorKind::NotFound)
+}
 
-        // The inverse of the earlier rare syscall, copies to a child's memory
-        let wrote = uio::process_vm_writev(
-            child,
-            &[uio::IoVec::from_slice(&buf[..batch_size])],
-            &[uio::RemoteIoVec {
-                base: offset,
-                len: batch_size,
-            }],
-        )?;
-        if wrote == 0 {
-            return error("failed to write to process");
-        }
-        remaining_size -= batch_size;
+/// The path in an fd's link is from our view of the filesystem, which is
+/// the process's root with its path on the front if it's chrooted, or
+/// relative to the root of its own mount namespace if it's in another one,
+/// like a container. Either way, with the root taken off it should lead
+/// back to the same file through `/proc/pid/root`, which sees what the
+/// process sees. If it doesn't, restoring it by that path opens something
+/// else, so say so now.
+fn check_fd_path(
+    pid: i32,
+    fd: &str,
+    target: &std::path::Path,
+    root: &str,
+    metadata: &std::fs::Metadata,
+) {
+    let path = target.to_string_lossy();
+    if path.ends_with(" (deleted)") {
+        return;
+    }
+    let inside = path_relative_to_root(&path, root);
+    match std::fs::metadata(format!("/proc/{}/root{}", pid, inside)) {
+        Ok(m) if m.dev() == metadata.dev() && m.ino() == metadata.ino() => {}
+        _ => warn!(
+            "fd {} is open on {} but that path leads somewhere else from inside the process, it was probably renamed or mounted over",
+            fd, path
+        ),
     }
-
-    Ok(())
 }
 
-/// Helper to find a map with a specific name, used to match up special kernel maps
-fn find_map_named<'a>(
-    maps: &'a [proc_maps::MapRange],
-    name: &str,
-) -> Option<&'a proc_maps::MapRange> {
-    maps.iter().find(|map| match map.filename() {
-        Some(n) if n == name => true,
-        _ => false,
-    })
+/// Turn files and directories that share an open file description with a
+/// lower fd into `Connection::Dup`s of it. `kcmp` knows for sure, without it
+/// we go by the same path at the same offset with the same flags, which two
+/// separate opens could also happen to have.
+fn find_duplicates(pid: i32, cm: &mut ConnectionMap) {
+    let mut fds: Vec<u32> = cm
+        .iter()
+        .filter(|(_, conn)| matches!(conn, Connection::File(_) | Connection::Directory(_)))
+        .map(|(fd, _)| *fd)
+        .collect();
+    fds.sort_unstable();
+    let mut kcmp_works = true;
+    let mut originals: Vec<u32> = Vec::new();
+    for fd in fds {
+        let mut of = None;
+        for &original in &originals {
+            if cm[&original].path() != cm[&fd].path() {
+                continue;
+            }
+            if kcmp_works {
+                match same_open_file(pid, original, fd) {
+                    Ok(true) => of = Some(original),
+                    Ok(false) => continue,
+                    Err(e) => {
+                        tracing::debug!("couldn't kcmp fds of {}, going by fdinfo: {}", pid, e);
+                        kcmp_works = false;
+                    }
+                }
+            }
+            if !kcmp_works && fd_position(pid, original) == fd_position(pid, fd) {
+                of = Some(original);
+            }
+            if of.is_some() {
+                break;
+            }
+        }
+        match of {
+            Some(of) => {
+                info!("fd {} is a duplicate of fd {}", fd, of);
+                cm.insert(fd, Connection::Dup(DupConnection { of }));
+            }
+            None => originals.push(fd),
+        }
+    }
 }
 
-/// The brk pointer is an old school syscall that at least used to be used for
-/// expanding/contracting the `[heap]` memory mapping. It's one of the pieces
-/// of process state stored outside of memory and registers. I don't *think*
-/// it's used by modern heap allocation but I'm not sure.
-///
-/// It's hard to manipulate. This doesn't actually work a lot of the time. It
-/// probably doesn't really matter for many programs.
-fn restore_brk(child: Pid, syscall: SyscallLoc, brk_addr: usize) -> Result<()> {
-    // TODO according to DMTCP this is the procedure that should work, but in
-    // my testing it doesn't if the target brk is below the original heap,
-    // then brk just doesn't update the heap. The way to fix this that also
-    // restores a bunch of other things is to use PR_SET_MM_MAP but that's not
-    // always available, requires high permissions, and it's hard to source
-    // all the fields for that. In the case that it fails this implementation
-    // is basically the same as not restoring the brk at all.
-
-    let orig_brk = remote_brk(child, syscall, 0)?;
-    // Is it possible that changing the brk could munmap the vdso? I think not with default layouts but maybe wrong.
-    let new_brk = remote_brk(child, syscall, brk_addr)?;
+const KCMP_FILE: i32 = 0;
 
-    // println!("brk orig={:>16x} new={:>16x} target={:>16x}", orig_brk, new_brk, brk_addr);
-    if new_brk > orig_brk {
-        // we mapped a new region but we want everything cleared away still so munmap it
-        remote_munmap(child, syscall, orig_brk, new_brk - orig_brk)?;
+/// Whether two of a process's fds are the same open file description
+fn same_open_file(pid: i32, a: u32, b: u32) -> Result<bool> {
+    let res = unsafe { libc::syscall(libc::SYS_kcmp, pid, pid, KCMP_FILE, a, b) };
+    if res < 0 {
+        return Err(Errno::last().into());
     }
+    Ok(res == 0)
+}
 
-    Ok(())
+/// The offset and flags of an fd from its `fdinfo`, less `O_CLOEXEC` which
+/// belongs to the fd rather than what it's open on
+fn fd_position(pid: i32, fd: u32) -> Option<(u64, i32)> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd)).ok()?;
+    let field = |name: &str| {
+        fdinfo
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .map(str::trim)
+    };
+    let pos = field("pos:")?.parse().ok()?;
+    let flags = i32::from_str_radix(field("flags:")?, 8).ok()?;
+    Some((pos, flags & !libc::O_CLOEXEC))
 }
 
-#[allow(unused)]
-fn buggsy() {}
+/// Read an inotify fd's watches out of its fdinfo, which has a line per
+/// watch like `inotify wd:1 ino:1e5a sdev:fe00000 mask:fc6 ignored_mask:0
+/// fhandle-bytes:8 fhandle-type:1 f_handle:5a1e000000000000`.
+fn scan_inotify(pid: i32, fd: u32) -> Result<InotifyConnection> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))?;
+    let mut inotify = InotifyConnection {
+        nonblocking: false,
+        watches: Vec::new(),
+    };
+    for line in fdinfo.lines() {
+        if let Some(flags) = line.strip_prefix("flags:") {
+            let flags = i32::from_str_radix(flags.trim(), 8)?;
+            inotify.nonblocking = flags & libc::O_NONBLOCK != 0;
+        }
+        let watch = match line.strip_prefix("inotify ") {
+            Some(watch) => watch,
+            None => continue,
+        };
+        let fields: HashMap<&str, &str> = watch
+            .split_whitespace()
+            .filter_map(|field| field.split_once(':'))
+            .collect();
+        let field = |name| fields.get(name).copied().unwrap_or("");
+        let wd = field("wd").parse()?;
+        let mask = u32::from_str_radix(field("mask"), 16)?;
+        let path = u64::from_str_radix(field("sdev"), 16)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|sdev| {
+                let handle_type = field("fhandle-type").parse()?;
+                inode_path_from_handle(sdev, handle_type, field("f_handle"))
+            });
+        match path {
+            Ok(path) => {
+                info!("inotify fd {} watch {} is on {}", fd, wd, path);
+                inotify.watches.push(InotifyWatch { wd, path, mask });
+            }
+            Err(e) => warn!(
+                "couldn't work out what inotify fd {} watch {} (inode {}) is watching, dropping it: {}",
+                fd,
+                wd,
+                field("ino"),
+                e
+            ),
+        }
+    }
+    Ok(inotify)
+}
 
-fn remote_open(child: Pid, syscall: SyscallLoc, path: &str, flags: i32) -> Result<u32> {
-    let SyscallLoc(loc) = syscall;
-    let mode = 0; // TODO
+/// Not in `libc`, from `linux/userfaultfd.h`
+const UFFD_API: u64 = 0xaa;
+const UFFD_USER_MODE_ONLY: i32 = 1;
+/// Set by the kernel on top of the features asked for once `UFFDIO_API` is done
+const UFFD_FEATURE_INITIALIZED: u64 = 1 << 31;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFDIO_REGISTER_MODE_WP: u64 = 2;
+const UFFDIO_REGISTER_MODE_MINOR: u64 = 4;
+/// `_IOWR(UFFDIO, 0x3f, struct uffdio_api)`
+const UFFDIO_API: u64 = 0xc018_aa3f;
+/// `_IOWR(UFFDIO, 0x00, struct uffdio_register)`
+const UFFDIO_REGISTER: u64 = 0xc020_aa00;
 
-    // == 0. Allocate memory for the pathname
-    if path.len() > PAGE_SIZE {
-        return error("long pathname not supported");
+/// A userfaultfd's flags and features from its fdinfo, which has a line like
+/// `API:\taa:80000000:80000000000001ff` with the API version, features and
+/// ioctls. What's registered with it isn't in there, that's attached from
+/// `scan_userfault_regions` once we know which userfaultfd it must be.
+fn scan_userfaultfd(pid: i32, fd: u32) -> Result<UserfaultfdConnection> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))?;
+    let mut uffd = UserfaultfdConnection {
+        flags: 0,
+        features: None,
+        regions: Vec::new(),
+    };
+    for line in fdinfo.lines() {
+        if let Some(flags) = line.strip_prefix("flags:") {
+            uffd.flags =
+                i32::from_str_radix(flags.trim(), 8)? & (libc::O_NONBLOCK | libc::O_CLOEXEC);
+        }
+        if let Some(pending) = line.strip_prefix("pending:") {
+            if pending.trim() != "0" {
+                warn!(
+                    "userfaultfd {} has {} faults waiting to be handled, they won't be restored",
+                    fd,
+                    pending.trim()
+                );
+            }
+        }
+        if let Some(api) = line.strip_prefix("API:") {
+            let features = api.trim().split(':').nth(1).unwrap_or("0");
+            let features = u64::from_str_radix(features, 16)?;
+            // Kernels before the initialized bit only show features once
+            // they've been asked for, so none at all might still have been
+            if features != 0 {
+                uffd.features = Some(features & !UFFD_FEATURE_INITIALIZED);
+            }
+        }
     }
-    // This virtual address is in the child's address space.
-    let path_addr = remote_mmap_anon(
-        child,
-        syscall,
-        None,
-        PAGE_SIZE,
-        PROT_READ | PROT_WRITE | PROT_EXEC,
-    )?;
-    let bytes_reader: &mut dyn std::io::Read = &mut &path.as_bytes()[..];
-    stream_memory(child, bytes_reader, path_addr, path.as_bytes().len())?;
+    Ok(uffd)
+}
 
-    // == 1. Get the current register state so we can modify
-    let regs = ptrace::getregs(child)?;
-    // == 2. Modify only the registers involved in the syscall
-    let syscall_regs = libc::user_regs_struct {
-        rip: loc as u64,       // syscall instr (rip is the instruction pointer)
-        rax: 2,                // open (rax holds the syscall number)
-        rdi: path_addr as u64, // addr (first argument to syscall goes in rdi)
-        rsi: flags as u64,     // flags (second argument to syscall goes in rsi)
-        rdx: mode as u64,      // mode (third argument to syscall goes in rdx)
-        ..regs
-    };
-    // == 2. Set the modified regs
-    ptrace::setregs(child, syscall_regs)?;
-    // == 3. Execute the syscall instruction (we set rip to point to it)
-    single_step(child)?;
-    // == 4. Get the registers so we can extract the return value from rax
-    let new_regs = ptrace::getregs(child)?;
-    if (new_regs.rax as i64) < 0 {
-        tracing::error!("rax = {:x}; rip = {:x}", new_regs.rax, new_regs.rip);
-        error("failed to open")?;
+/// Which process a pidfd is for, from the `Pid:` line of its fdinfo. That's
+/// -1 once the process is gone, and 0 if it's not in our pid namespace.
+fn scan_pidfd(pid: i32, fd: u32) -> Result<PidfdConnection> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))?;
+    let mut target = None;
+    let mut nonblocking = false;
+    for line in fdinfo.lines() {
+        if let Some(flags) = line.strip_prefix("flags:") {
+            nonblocking = i32::from_str_radix(flags.trim(), 8)? & libc::O_NONBLOCK != 0;
+        }
+        if let Some(pid) = line.strip_prefix("Pid:") {
+            target = Some(pid.trim().parse::<i32>()?);
+        }
     }
+    let target = match target {
+        Some(target) if target > 0 => target,
+        Some(_) => return error("the process it's for has exited or isn't visible to us"),
+        None => return error("its fdinfo doesn't say which process it's for"),
+    };
+    Ok(PidfdConnection {
+        pid: target,
+        start_time: read_stat_field(target, 22)? as u64,
+        nonblocking,
+    })
+}
 
-    let fd = new_regs.rax as u32;
+/// `f_type` of the filesystem `mq_open`'s queues live on
+const MQUEUE_MAGIC: i64 = 0x1980_0202;
 
-    // == 5. Unmap the memory temporarily used to pass the pathname
-    remote_munmap(child, syscall, path_addr, path.len())?;
+/// Size of a `struct mq_attr`, four longs and four more reserved
+const MQ_ATTR_SIZE: usize = 64;
 
-    Ok(fd)
+/// Message queues look like regular files to `stat`, only the filesystem
+/// they're on gives them away
+fn is_mqueue(fd_path: &std::path::Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let path = match std::ffi::CString::new(fd_path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut fs: libc::statfs = unsafe { std::mem::zeroed() };
+    unsafe { libc::statfs(path.as_ptr(), &mut fs) == 0 && fs.f_type as i64 == MQUEUE_MAGIC }
 }
 
-fn remote_dup2(child: Pid, syscall: SyscallLoc, oldfd: u32, newfd: u32) -> Result<u32> {
-    let SyscallLoc(loc) = syscall;
-    // == 1. Get the current register state so we can modify
-    let regs = ptrace::getregs(child)?;
-    // == 2. Modify only the registers involved in the syscall
-    let syscall_regs = libc::user_regs_struct {
-        rip: loc as u64,   // syscall instr (rip is the instruction pointer)
-        rax: 33,           // dup2 (rax holds the syscall number)
-        rdi: oldfd as u64, // (first argument to syscall goes in rdi)
-        rsi: newfd as u64, // (second argument to syscall goes in rsi)
-        ..regs
+/// Reopening the queue through `/proc` gets us our own fd on it, in whatever
+/// ipc namespace the process is in, to ask its limits and read what's in it.
+fn scan_mqueue(
+    pid: i32,
+    fd: u32,
+    name: String,
+    metadata: &std::fs::Metadata,
+) -> Result<MqueueConnection> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))?;
+    let flags = match fdinfo.lines().find_map(|l| l.strip_prefix("flags:")) {
+        Some(flags) => i32::from_str_radix(flags.trim(), 8)?,
+        None => return error("its fdinfo doesn't have its flags"),
     };
-    // == 2. Set the modified regs
-    ptrace::setregs(child, syscall_regs)?;
-    // == 3. Execute the syscall instruction (we set rip to point to it)
-    single_step(child)?;
-    // == 4. Get the registers so we can extract the return value from rax
-    let new_regs = ptrace::getregs(child)?;
-    if new_regs.rax != newfd as u64 {
-        tracing::error!("rax = {:x}; rip = {:x}", new_regs.rax, new_regs.rip);
-        error("failed to dup2")?;
+    let shared = mqueue_open_elsewhere(pid, metadata);
+    let queue = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(format!("/proc/{}/fd/{}", pid, fd))?;
+    let mut attr: libc::mq_attr = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mq_getsetattr,
+            queue.as_raw_fd(),
+            std::ptr::null::<libc::mq_attr>(),
+            &mut attr as *mut libc::mq_attr,
+        )
+    };
+    if res < 0 {
+        return Err(Box::new(std::io::Error::last_os_error()));
     }
-    Ok(0)
+    let messages = if attr.mq_curmsgs == 0 {
+        Vec::new()
+    } else if shared {
+        warn!(
+            "message queue {} is open in another process too, the {} messages in it won't be saved",
+            name, attr.mq_curmsgs
+        );
+        Vec::new()
+    } else {
+        take_mqueue_messages(queue.as_raw_fd(), attr.mq_msgsize as usize)?
+    };
+    Ok(MqueueConnection {
+        name,
+        flags: flags & (libc::O_ACCMODE | libc::O_NONBLOCK),
+        mode: metadata.mode() & 0o7777,
+        max_messages: attr.mq_maxmsg as i64,
+        message_size: attr.mq_msgsize as i64,
+        messages,
+    })
 }
 
-fn remote_lseek(child: Pid, syscall: SyscallLoc, fd: u32, offset: u64) -> Result<()> {
-    let SyscallLoc(loc) = syscall;
-    let regs = ptrace::getregs(child)?;
-    let syscall_regs = libc::user_regs_struct {
-        rip: loc as u64,   // syscall instr (rip is the instruction pointer)
-        rax: 8,           // lseek (rax holds the syscall number)
-        rdi: fd as u64,    // (first argument to syscall goes in rdi)
-        rsi: offset as u64, // (second argument to syscall goes in rsi)
-        rdx: libc::SEEK_SET as u64,           // (third argument to syscall goes in rdx)
-        ..regs
+/// Whether any other process we can see has the same queue open, and so
+/// might be reading from or writing to it while we look
+fn mqueue_open_elsewhere(pid: i32, metadata: &std::fs::Metadata) -> bool {
+    let procs = match std::fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return true,
     };
-    // == 2. Set the modified regs
-    ptrace::setregs(child, syscall_regs)?;
-    // == 3. Execute the syscall instruction (we set rip to point to it)
-    single_step(child)?;
-    // == 4. Get the registers so we can extract the return value from rax
-    let new_regs = ptrace::getregs(child)?;
-    if new_regs.rax != offset as u64 {
-        tracing::error!("rax = {:x}; rip = {:x}", new_regs.rax, new_regs.rip);
-        error("failed to lseek")?;
-    }
+    procs
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|&other| other != pid && other != std::process::id() as i32)
+        .filter_map(|other| std::fs::read_dir(format!("/proc/{}/fd", other)).ok())
+        .flatten()
+        .flatten()
+        .any(|fd| {
+            matches!(std::fs::metadata(fd.path()),
+                Ok(m) if m.dev() == metadata.dev() && m.ino() == metadata.ino())
+        })
+}
 
-    Ok(())
+/// Receive everything in a queue and send it all straight back, which
+/// leaves it in the same order since they come out highest priority first
+/// and in the order they were sent within one priority
+fn take_mqueue_messages(queue: RawFd, message_size: usize) -> Result<Vec<MqueueMessage>> {
+    let mut messages = Vec::new();
+    let mut buf = vec![0u8; message_size];
+    loop {
+        let mut priority = 0u32;
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_mq_timedreceive,
+                queue,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut priority as *mut u32,
+                std::ptr::null::<libc::timespec>(),
+            )
+        };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EAGAIN) {
+                break;
+            }
+            return Err(Box::new(err));
+        }
+        messages.push(MqueueMessage {
+            priority,
+            data: buf[..len as usize].to_vec(),
+        });
+    }
+    for message in &messages {
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_mq_timedsend,
+                queue,
+                message.data.as_ptr(),
+                message.data.len(),
+                message.priority,
+                std::ptr::null::<libc::timespec>(),
+            )
+        };
+        if res < 0 {
+            warn!("lost messages putting them back in a message queue");
+            return Err(Box::new(std::io::Error::last_os_error()));
+        }
+    }
+    Ok(messages)
 }
 
-/// TODO
-fn restore_file_descriptors(child: Pid, syscall: SyscallLoc, cm: ConnectionMap) -> Result<()> {
-    fn restore_file(child: Pid, syscall: SyscallLoc, fd: u32, path: String, offset: u64) -> Result<()> {
-        let open_fd = remote_open(child, syscall, &path, libc::O_RDONLY)?;
-        tracing::debug!("opened file descriptor {} for {}", open_fd, path);
-        remote_dup2(child, syscall, open_fd, fd)?;
-        remote_lseek(child, syscall, fd, offset)?;
-        Ok(())
+/// inotify only remembers the inode it's watching, but fdinfo gives us a
+/// file handle for it, which `open_by_handle_at` can turn back into an open
+/// file and `/proc/self/fd` into a path. That needs `CAP_DAC_READ_SEARCH`
+/// and an fd on the same filesystem, which we find through the device number.
+fn inode_path_from_handle(sdev: u64, handle_type: i32, handle_hex: &str) -> Result<String> {
+    // The kernel's own device numbering, not the one userspace sees
+    let (major, minor) = (sdev >> 20, sdev & 0xfffff);
+    let dev = format!("{}:{}", major, minor);
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    let mount_point = mountinfo
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(2) == Some(&dev.as_str()))
+        .and_then(|fields| fields.get(4).map(|mount| mount.to_string()));
+    let mount_point = match mount_point {
+        Some(mount_point) => mount_point,
+        None => return error("watched filesystem isn't mounted here"),
+    };
+    let mount = std::fs::File::open(mount_point)?;
+
+    let bytes = (0..handle_hex.len() / 2)
+        .map(|i| u8::from_str_radix(&handle_hex[i * 2..i * 2 + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()?;
+    // A `struct file_handle`, the u32s keep it aligned
+    let mut handle = vec![0u32; 2 + bytes.len().div_ceil(4)];
+    handle[0] = bytes.len() as u32;
+    handle[1] = handle_type as u32;
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            bytes.as_ptr(),
+            handle[2..].as_mut_ptr() as *mut u8,
+            bytes.len(),
+        );
+    }
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_by_handle_at,
+            std::os::unix::io::AsRawFd::as_raw_fd(&mount),
+            handle.as_ptr(),
+            libc::O_PATH,
+        )
+    };
+    if fd < 0 {
+        return Err(Box::new(std::io::Error::last_os_error()));
     }
+    let file = unsafe { std::fs::File::from_raw_fd(fd as i32) };
+    let path = std::fs::read_link(format!(
+        "/proc/self/fd/{}",
+        std::os::unix::io::AsRawFd::as_raw_fd(&file)
+    ))?;
+    Ok(path.to_string_lossy().to_string())
+}
 
-    for (fd, conn) in cm {
-        match conn {
-            Connection::Invalid => {
-                warn!("invalid file descriptor {}", fd);
-            }
-            Connection::Tcp(_) => {
-                warn!("skipping tcp file descriptor {}", fd);
-            }
-            Connection::File(FileConnection { path, offset }) => {
-                tracing::debug!("restoring file descriptor {} for {} at offset {}", fd, path, offset);
-                restore_file(child, syscall, fd, path, offset)?;
+/// Swap regular files in `cm` for ones that carry their contents, small ones
+/// always and anything up to `BUNDLE_FILE_LIMIT` with `bundle_files`. We read
+/// through the `/proc` magic link so this works even if the file has since
+/// been deleted or is somewhere we couldn't otherwise see, like in a chroot.
+fn bundle_files(pid: i32, cm: &mut ConnectionMap, config: &Config) {
+    let limit = if config.bundle_files {
+        BUNDLE_FILE_LIMIT.max(config.small_file_limit)
+    } else {
+        config.small_file_limit
+    };
+    if limit == 0 {
+        return;
+    }
+    for (fd, conn) in cm.iter_mut() {
+        let file = match conn {
+            Connection::File(file) => file,
+            _ => continue,
+        };
+        let fd_path = format!("/proc/{}/fd/{}", pid, fd);
+        match std::fs::metadata(&fd_path) {
+            Ok(m) if m.is_file() && m.len() as usize <= limit => {}
+            // Big files going by path is only news if we were asked to bundle them
+            Ok(m) if m.is_file() && config.bundle_files => {
+                warn!(
+                    "{} is too big to bundle ({} bytes), only sending its path",
+                    file.path,
+                    m.len()
+                );
+                continue;
             }
-            Connection::Stdio(_) => {
-                assert!(fd <= 2);
+            _ => continue,
+        }
+        match std::fs::read(&fd_path) {
+            Ok(contents) => {
+                info!("bundling {} bytes of {}", contents.len(), file.path);
+                *conn = Connection::BundledFile(BundledFileConnection {
+                    path: std::mem::take(&mut file.path),
+                    offset: file.offset,
+                    contents,
+                });
             }
+            Err(e) => warn!("couldn't read {} to bundle it: {}", file.path, e),
         }
     }
-    Ok(())
 }
 
-/// The other end of a `telefork`. Receive a program from a read channel and
-/// rehydrate it as a child process, passing it an i32 and return its pid.
-pub fn telepad(inp: &mut dyn Read, pass_to_child: i32) -> Result<Pid> {
-    // == 1. Create a frozen child to hollow out and replace with the process being streamed in
-    let child: Pid = match fork_frozen_traced()? {
-        NormalForkLocation::Woke(_) => {
-            panic!("should've woken up with my brain replaced but didn't!")
+/// Find the file locks held through the process's fds. Each fd's `fdinfo`
+/// lists the locks taken through it in the same format as `/proc/locks`,
+/// which saves us guessing which of the locks on a shared file are ours.
+fn scan_file_locks(pid: i32, lock_owner: i32, cm: &ConnectionMap) -> Result<Vec<FileLock>> {
+    let mut locks = Vec::new();
+    for (&fd, conn) in cm {
+        if conn.path().is_none() {
+            continue;
         }
-        NormalForkLocation::Parent(p) => p,
-    };
-
-    // == 2. Inspect the state of the child so we can manipulate it to hollow it out
-    let orig_maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
-    // _print_maps_info(&orig_maps[..]);
-
-    // The vdso always seems to have a syscall in it we can use for remote syscalls
-    let vdso_map = find_map_named(&orig_maps, "[vdso]").unwrap();
-    let vdso_syscall_offset = try_to_find_syscall(child, vdso_map.start())?;
-    let mut vdso_syscall = SyscallLoc((vdso_map.start() + vdso_syscall_offset) as u64);
+        let ino = std::fs::metadata(format!("/proc/{}/fd/{}", pid, fd))?.ino();
+        locks.extend(read_fd_locks(
+            pid,
+            fd,
+            ino,
+            &[LockKind::Flock, LockKind::OpenFile],
+        ));
+        // POSIX ones belong to the process rather than the open file
+        locks.extend(read_fd_locks(lock_owner, fd, ino, &[LockKind::Posix]));
+    }
+    locks.sort_by_key(|lock| lock.fd);
+    for lock in &locks {
+        info!("file lock: {:?}", lock);
+    }
+    Ok(locks)
+}
 
-    // == 3. Remote munmap all original regions except special kernel stuff
-    for map in &orig_maps {
-        if is_special_kernel_map(map) || map.size() == 0 {
+/// The locks of the given kinds taken through `fd`, checking the fd is still
+/// on the file with inode `ino` since for POSIX locks it's someone else's fd
+/// table we're looking at.
+fn read_fd_locks(pid: i32, fd: u32, ino: u64, kinds: &[LockKind]) -> Vec<FileLock> {
+    let fdinfo = match std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd)) {
+        Ok(fdinfo) => fdinfo,
+        Err(_) => return Vec::new(),
+    };
+    let mut locks = Vec::new();
+    for line in fdinfo.lines() {
+        let lock = match line.strip_prefix("lock:") {
+            Some(lock) => lock,
+            None => continue,
+        };
+        // Like `1: POSIX  ADVISORY  WRITE 1234 08:01:5678 0 EOF`
+        let fields: Vec<&str> = lock.split_whitespace().collect();
+        if fields.len() < 8 {
             continue;
         }
-        remote_munmap(child, vdso_syscall, map.start(), map.size())?;
+        let kind = match fields[1] {
+            "POSIX" => LockKind::Posix,
+            "FLOCK" => LockKind::Flock,
+            "OFDLCK" => LockKind::OpenFile,
+            // Leases are for noticing other processes opening the file,
+            // we don't try to bring those back
+            _ => continue,
+        };
+        let lock_ino = fields[5].rsplit(':').next().and_then(|i| i.parse().ok());
+        if !kinds.contains(&kind) || lock_ino != Some(ino) {
+            continue;
+        }
+        locks.push(FileLock {
+            fd,
+            kind,
+            write: fields[3] == "WRITE",
+            start: fields[6].parse().unwrap_or(0),
+            end: fields[7].parse().ok(),
+        });
     }
+    locks
+}
 
-    let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
-    // println!("========== after delete:");
-    // _print_maps_info(&maps[..]);
-
-    // == 4. Now that it's hollowed out, start a loop to read restoration commands from the channel
-    let prot_all = PROT_READ | PROT_WRITE | PROT_EXEC;
-    loop {
-        match bincode::deserialize_from::<&mut dyn Read, Command>(inp)? {
-            Command::ProcessState(ProcessState { brk_addr }) => {
-                restore_brk(child, vdso_syscall, brk_addr)?;
-            }
-            Command::Remap { name, addr, size } => {
-                let matching_map = find_map_named(&maps, &name);
-                let matching_map = match matching_map {
-                    Some(m) => m,
-                    None => {
-                        eprintln!("no matching map for {} so can't remap", name);
-                        continue;
-                    }
-                };
+fn read_namespaces(pid: i32) -> Result<HashMap<String, String>> {
+    let mut namespaces = HashMap::new();
+    for entry in std::fs::read_dir(format!("/proc/{}/ns", pid))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let link = std::fs::read_link(entry.path())?;
+        namespaces.insert(name, link.to_string_lossy().to_string());
+    }
+    Ok(namespaces)
+}
 
-                if size != matching_map.size() {
-                    // Some Linux distros/versions seem to have 1 page vDSOs
-                    // and some have 2 pages I made this a non-critical error
-                    // so that you can telefork anyway and it might work,
-                    // especially if the program doesn't use any vDSO
-                    // syscalls. See later TODO comment on handling vDSOs.
+fn scan_fs_context(pid: i32) -> Result<FsContext> {
+    let root = std::fs::read_link(format!("/proc/{}/root", pid))?;
+    let fs = FsContext {
+        root: root.to_string_lossy().to_string(),
+        namespaces: read_namespaces(pid)?,
+    };
+    info!("process root: {}", fs.root);
+    let ours = read_namespaces(std::process::id() as i32)?;
+    if fs.namespaces.get("mnt") != ours.get("mnt") {
+        info!(
+            "process is in another mount namespace ({:?}), its fd paths are from inside it",
+            fs.namespaces.get("mnt")
+        );
+    }
+    Ok(fs)
+}
 
-                    // error("size mismatch in remap")?;
-                    eprintln!("size mismatch in remap for {}", name);
-                }
+/// The process's working directory, as seen from outside like its fd paths
+fn scan_cwd(pid: i32) -> Result<String> {
+    let cwd = std::fs::read_link(format!("/proc/{}/cwd", pid))?;
+    Ok(cwd.to_string_lossy().to_string())
+}
 
-                remote_mremap(
-                    child,
-                    vdso_syscall,
-                    matching_map.start(),
-                    matching_map.size(),
-                    addr,
-                )?;
+/// The process's umask, which `/proc/pid/status` has from Linux 4.7
+fn scan_umask(pid: i32) -> Result<u32> {
+    Ok(u32::from_str_radix(&read_status_field(pid, "Umask")?, 8)?)
+}
 
-                // When we remap the vDSO we have to change the address we're
-                // using for remote syscalls to the new location. It happens
-                // to still work to use a syscall in the vDSO to mremap the
-                // vDSO elsewhere even though it returns to unmapped space,
-                // because ptrace stops it before it executes anything from
-                // unmapped space.
-                if &name == "[vdso]" {
-                    vdso_syscall = SyscallLoc((addr + vdso_syscall_offset) as u64);
-                }
-            }
-            Command::Mapping(m) => {
-                let addr = remote_mmap_anon(child, vdso_syscall, Some(m.addr), m.size, prot_all)?;
-                // TODO set new area filenames
-                stream_memory(child, inp, addr, m.size)?;
-                // TODO remote mprotect to restore previous permissions
-            }
-            Command::FileDescriptors(cm) => {
-                restore_file_descriptors(child, vdso_syscall, cm)?;
-                let cm = scan_file_descriptors(child.as_raw())?;
-                tracing::debug!("restored file descriptors:");
-                for (fd, conn) in cm {
-                    tracing::debug!("fd = {}; {:?}", fd, conn);
-                }
-            }
-            Command::ResumeWithRegisters { len } => {
-                let mut reg_bytes = vec![0u8; len];
-                inp.read_exact(&mut reg_bytes[..])?;
-                // FIXME remove unwrap and use a proper error for bad serialization
-                let reg_info = RegInfo::from_bytes(&reg_bytes[..]).unwrap();
-                let mut regs = reg_info.regs;
-                // We'll be resuming from the "raise" syscall which checks for an i32 result in rax and libc passes along
-                regs.rax = pass_to_child as u64;
-                ptrace::setregs(child, regs)?;
-                break;
-            }
+/// Read the `/proc/pid/status` field with the given name
+fn read_status_field(pid: i32, field: &str) -> Result<String> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+    for line in status.lines() {
+        if let Some(value) = line.strip_prefix(field).and_then(|l| l.strip_prefix(':')) {
+            return Ok(value.trim().to_string());
         }
     }
+    error("field missing from /proc/pid/status")
+}
 
-    // TODO maybe use /proc/sys/kernel/ns_last_pid to restore with the same
-    // PID if possible? This might help thread local storage and other things work better.
-    // http://efiop-notes.blogspot.com/2014/06/how-to-set-pid-using-nslastpid.html
+/// Ask the stopped process for its `prctl` attributes by injecting `prctl`
+/// calls into it, since some of them aren't anywhere in `/proc`.
+fn scan_prctl_state(child: Pid) -> Result<PrctlState> {
+    let pid = child.as_raw();
+    let seccomp: u8 = read_status_field(pid, "Seccomp")?.parse()?;
+    if seccomp != 0 {
+        // Under seccomp our injected prctl could get the process killed
+        warn!("process is using seccomp, assuming it isn't a child subreaper");
+        return prctl_state_from_proc(pid, seccomp);
+    }
+    match query_prctl_state(child, seccomp) {
+        Ok(prctl) => {
+            info!("prctl attributes: {:?}", prctl);
+            Ok(prctl)
+        }
+        // Like when it's job control stopped, then it won't run our syscalls
+        Err(e) => {
+            warn!(
+                "couldn't inject prctl queries ({}), assuming it isn't a child subreaper",
+                e
+            );
+            prctl_state_from_proc(pid, seccomp)
+        }
+    }
+}
 
-    // TODO restore TLS: This seems to involve using the arch_prcntl syscall
-    // to save and restore the FS and GS registers ptrace does save/restore fs
-    // and gs though and TLS variables appear to work to me so maybe that
-    // isn't necessary? There's also something about how glibc caches the pid
-    // and tid which are wrong in the new process.
+/// Make do with what /proc has, which is all but the subreaper and dumpable
+/// flags
+fn prctl_state_from_proc(pid: i32, seccomp: u8) -> Result<PrctlState> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid))?;
+    let slack = std::fs::read_to_string(format!("/proc/{}/timerslack_ns", pid))?;
+    Ok(PrctlState {
+        name: comm.trim_end().to_string(),
+        timerslack_ns: slack.trim().parse()?,
+        child_subreaper: false,
+        dumpable: true,
+        no_new_privs: read_status_field(pid, "NoNewPrivs")? != "0",
+        seccomp,
+    })
+}
 
-    // TODO support using the vDSO of a different Linux kernel. Currently it
-    // just assumes the vDSO is the same and the program crashes if it tries
-    // to use the vDSO and it isn't the same. One idea for how to fix this is
-    // to do like rr (https://github.com/mozilla/rr/issues/1216) and put jump
-    // patches at all the entry points from the orginal processes's vDSO that
-    // jump to the correct places in the new vDSO as determined by reading the
-    // vDSO ELF header.
-    //
-    // Another possible solution is to do what rr does and patch all the vDSO
-    // entry points to just execute the normal syscalls.
+/// A syscall instruction for injecting queries into a process we're dumping
+fn find_syscall_loc(child: Pid) -> Result<SyscallLoc> {
+    let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
+    Ok(SyscallLoc::new(find_syscall(child, &maps)? as u64))
+}
 
-    // TODO restore or forward some types of file descriptors? Maybe basic
-    // files that also exist on the new system?
+/// Run `f` with a syscall location for injecting queries into a process
+/// we're dumping, with a `Scratch` region for their arguments and stack like
+/// a restore has. The process carries on afterwards, so nothing we do can be
+/// allowed near its real stack. The region is gone again once `f` returns.
+fn with_dump_syscall<T>(child: Pid, f: impl FnOnce(SyscallLoc) -> Result<T>) -> Result<T> {
+    let mut syscall = find_syscall_loc(child)?;
+    let scratch = Scratch::map(child, syscall, None)?;
+    scratch.install(&mut syscall);
+    let res = f(syscall);
+    remote_munmap(child, syscall, scratch.addr, SCRATCH_SIZE)?;
+    res
+}
 
-    // println!("========== recreated maps:");
-    // let maps = proc_maps::get_process_maps(child.as_raw() as proc_maps::Pid)?;
-    // _print_maps_info(&maps[..]);
+fn query_prctl_state(child: Pid, seccomp: u8) -> Result<PrctlState> {
+    with_dump_syscall(child, |syscall| {
+        query_prctl_state_with(child, syscall, seccomp)
+    })
+}
+
+fn query_prctl_state_with(child: Pid, syscall: SyscallLoc, seccomp: u8) -> Result<PrctlState> {
+    // The getters for these two write their answer through a pointer
+    let name = with_remote_bytes(child, syscall, &[0u8; 16], |addr| {
+        remote_prctl(child, syscall, libc::PR_GET_NAME, addr as u64)?;
+        read_memory(child, addr, 16)
+    })?;
+    let name = String::from_utf8_lossy(&name)
+        .trim_end_matches('\0')
+        .to_string();
+    let child_subreaper = with_remote_bytes(child, syscall, &[0u8; 4], |addr| {
+        remote_prctl(child, syscall, libc::PR_GET_CHILD_SUBREAPER, addr as u64)?;
+        read_memory(child, addr, 4)
+    })?;
 
-    // This lets the other process be stopped without triggering out waitpid,
-    // as well as to be debugged by a different ptrace-er
+    let prctl = PrctlState {
+        name,
+        timerslack_ns: remote_prctl(child, syscall, libc::PR_GET_TIMERSLACK, 0)? as u64,
+        child_subreaper: child_subreaper != [0u8; 4],
+        dumpable: remote_prctl(child, syscall, libc::PR_GET_DUMPABLE, 0)? != 0,
+        no_new_privs: remote_prctl(child, syscall, libc::PR_GET_NO_NEW_PRIVS, 0)? != 0,
+        seccomp,
+    };
+    Ok(prctl)
+}
 
-    for i in 1..10000 {
-        if i == 1 {
-            tracing::debug!("step {}", i);
-            let regs = ptrace::getregs(child)?;
-            tracing::debug!("regs = {:?}", regs);
+/// The non-default NUMA policies of the process's mappings, by start address.
+/// Only policies set per mapping with `mbind` show up here, one set for the
+/// whole process with `set_mempolicy` doesn't and isn't brought along.
+fn scan_memory_policies(pid: i32) -> HashMap<usize, (i32, Vec<u32>)> {
+    // Kernels built without NUMA support don't have this at all
+    let numa_maps = match std::fs::read_to_string(format!("/proc/{}/numa_maps", pid)) {
+        Ok(numa_maps) => numa_maps,
+        Err(_) => return HashMap::new(),
+    };
+    let mut policies = HashMap::new();
+    for line in numa_maps.lines() {
+        let (addr, rest) = match line.split_once(' ') {
+            Some(split) => split,
+            None => continue,
+        };
+        let addr = match usize::from_str_radix(addr, 16) {
+            Ok(addr) => addr,
+            Err(_) => continue,
+        };
+        // A couple of policy names have a space in them
+        let name_len = ["prefer (many)", "weighted interleave"]
+            .iter()
+            .find(|name| rest.starts_with(*name))
+            .map_or(0, |name| name.len());
+        let end = rest[name_len..]
+            .find(' ')
+            .map_or(rest.len(), |i| i + name_len);
+        if let Some(policy) = parse_memory_policy(&rest[..end]) {
+            info!("mapping at {:x} has NUMA policy {}", addr, &rest[..end]);
+            policies.insert(addr, policy);
         }
-        single_step(child)?;
     }
+    policies
+}
 
-    tracing::debug!("detaching from child");
-    ptrace::detach(child, None)?;
-
-    // Return the child pid so that we can do things or wait on it
-    Ok(child)
+/// Start addresses of the mappings using transparent huge pages, either
+/// because some of them already are or because the process asked for them
+/// with `madvise(MADV_HUGEPAGE)`, which shows up as `hg` in `VmFlags`.
+fn scan_huge_page_maps(pid: i32) -> Vec<usize> {
+    let smaps = match std::fs::read_to_string(format!("/proc/{}/smaps", pid)) {
+        Ok(smaps) => smaps,
+        Err(_) => return Vec::new(),
+    };
+    let mut huge = Vec::new();
+    let mut start = None;
+    for line in smaps.lines() {
+        // Each mapping starts with its line from `maps`, like `7f00-7f80 rw-p ...`
+        let range = line
+            .split_whitespace()
+            .next()
+            .and_then(|r| r.split_once('-'));
+        if let Some((addr, _)) = range {
+            start = usize::from_str_radix(addr, 16).ok();
+            continue;
+        }
+        let is_huge = match line.split_once(':') {
+            Some(("AnonHugePages", kb)) => kb.trim().trim_end_matches("kB").trim() != "0",
+            Some(("VmFlags", flags)) => flags.split_whitespace().any(|flag| flag == "hg"),
+            _ => false,
+        };
+        if let Some(addr) = start.filter(|_| is_huge) {
+            info!("mapping at {:x} uses transparent huge pages", addr);
+            huge.push(addr);
+            start = None;
+        }
+    }
+    huge
 }
 
-/// Utility to wait for the child process to exit, which is often what you
-/// want to do after using `telepad`.
-pub fn wait_for_exit(child: Pid) -> Result<i32> {
-    match waitpid(child, None)? {
-        WaitStatus::Exited(_, code) => Ok(code),
-        status => {
-            eprintln!("wait got: {:?}", status);
-            error("somehow got other wait status instead of exit")
+/// The mappings registered with a userfaultfd and in which modes, from the
+/// `um`, `uw` and `ui` in their `VmFlags`. Which userfaultfd isn't said
+/// anywhere, see `check_userfaultfds`.
+fn scan_userfault_regions(pid: i32) -> Result<Vec<UserfaultRegion>> {
+    let smaps = std::fs::read_to_string(format!("/proc/{}/smaps", pid))?;
+    let mut regions = Vec::new();
+    let mut range = None;
+    for line in smaps.lines() {
+        let bounds = line
+            .split_whitespace()
+            .next()
+            .and_then(|r| r.split_once('-'))
+            .and_then(|(start, end)| {
+                Some((
+                    usize::from_str_radix(start, 16).ok()?,
+                    usize::from_str_radix(end, 16).ok()?,
+                ))
+            });
+        if bounds.is_some() {
+            range = bounds;
+            continue;
+        }
+        let flags = match line.strip_prefix("VmFlags:") {
+            Some(flags) => flags,
+            None => continue,
+        };
+        let mut mode = 0;
+        for flag in flags.split_whitespace() {
+            mode |= match flag {
+                "um" => UFFDIO_REGISTER_MODE_MISSING,
+                "uw" => UFFDIO_REGISTER_MODE_WP,
+                "ui" => UFFDIO_REGISTER_MODE_MINOR,
+                _ => 0,
+            };
+        }
+        if let Some((start, end)) = range.take().filter(|_| mode != 0) {
+            let missing = if mode & UFFDIO_REGISTER_MODE_MISSING != 0 {
+                missing_pages(pid, start, end - start)?
+            } else {
+                Vec::new()
+            };
+            info!(
+                "mapping at {:x} is registered with a userfaultfd in mode {}",
+                start, mode
+            );
+            regions.push(UserfaultRegion {
+                addr: start,
+                size: end - start,
+                mode,
+                missing,
+            });
         }
     }
+    Ok(regions)
 }
 
-// Helper that magically executes a closure on a remote server, perhaps one
-// with way more processing power. See the `smallpt` example for a demo using
-// this to do ray tracing on a larger remote server. The closure can access
-// and modify any data in this process and after `yoyo` returns execution is
-// back on the original machine.
-//
-// To do this it teleforks to a server like the `teleserver` example, executes
-// closure `f`, then receives a telefork back. Only returns in the new process
-// that is teleforked back on the client, the original process waits for its
-// child to exit then exits with the same status.
-pub fn yoyo<A: ToSocketAddrs, F: FnOnce() -> ()>(dest: A, f: F) {
-    let mut stream = TcpStream::connect(dest).unwrap();
-    let loc = telefork(&mut stream).unwrap();
-    match loc {
-        TeleforkLocation::Child(fd) => {
-            let mut stream = unsafe { TcpStream::from_raw_fd(fd) };
+/// The pagemap bits for a page being in memory or swapped out, which are the
+/// ones a missing fault isn't raised for
+const PM_PRESENT: u64 = 1 << 63;
+const PM_SWAPPED: u64 = 1 << 62;
 
-            // Do some work on the remote server
-            f();
+/// Runs of pages in `addr..addr + size` that have never been faulted in
+fn missing_pages(pid: i32, addr: usize, size: usize) -> Result<Vec<(usize, usize)>> {
+    let mut pagemap = std::fs::File::open(format!("/proc/{}/pagemap", pid))?;
+    pagemap.seek(SeekFrom::Start((addr / PAGE_SIZE * 8) as u64))?;
+    let mut entries = vec![0u8; size / PAGE_SIZE * 8];
+    pagemap.read_exact(&mut entries)?;
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, entry) in entries.chunks_exact(8).enumerate() {
+        let entry = u64::from_ne_bytes(entry.try_into().unwrap());
+        if entry & (PM_PRESENT | PM_SWAPPED) != 0 {
+            continue;
+        }
+        let page = addr + i * PAGE_SIZE;
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == page => *len += PAGE_SIZE,
+            _ => runs.push((page, PAGE_SIZE)),
+        }
+    }
+    Ok(runs)
+}
 
-            let loc = telefork(&mut stream).unwrap();
-            std::mem::forget(stream); // parent drops stream not us
-            match loc {
-                // return normally in the child we teleforked back
-                TeleforkLocation::Child(_) => return,
-                // exit succesfully in the now unnecessary server process
-                TeleforkLocation::Parent => std::process::exit(0),
-            };
+/// Refuse to dump a process with memory registered with a userfaultfd we
+/// can't bring back along with it. With none the handler is in some other
+/// process, which the restored one can't be reconnected to, and with more
+/// than one there's no telling which region goes with which.
+fn check_userfaultfds(pid: i32, regions: &[UserfaultRegion]) -> Result<()> {
+    if regions.is_empty() {
+        return Ok(());
+    }
+    let mut uffds = 0;
+    for entry in std::fs::read_dir(format!("/proc/{}/fd", pid))? {
+        let target = std::fs::read_link(entry?.path()).unwrap_or_default();
+        if target.to_str() == Some("anon_inode:[userfaultfd]") {
+            uffds += 1;
         }
-        // teleforked succesfully, return out of match statement and wait to receive telefork back
-        TeleforkLocation::Parent => (),
+    }
+    match uffds {
+        1 => Ok(()),
+        0 => {
+            tracing::error!(
+                "{} regions are registered with a userfaultfd the process doesn't have open",
+                regions.len()
+            );
+            error("the process's memory is registered with a userfaultfd handled from another process, which can't come along")
+        }
+        _ => {
+            tracing::error!(
+                "{} regions are registered with one of {} userfaultfds",
+                regions.len(),
+                uffds
+            );
+            error("the process has memory registered with more than one userfaultfd, there's no telling which is which to restore them")
+        }
+    }
+}
+
+/// Give the regions registered with a userfaultfd to the userfaultfd, if
+/// there's only the one it could be
+fn attach_userfault_regions(cm: &mut ConnectionMap, regions: Vec<UserfaultRegion>) {
+    let mut uffds = cm.values_mut().filter_map(|conn| match conn {
+        Connection::Userfaultfd(uffd) => Some(uffd),
+        _ => None,
+    });
+    if let (Some(uffd), None) = (uffds.next(), uffds.next()) {
+        if !regions.is_empty() {
+            // Registering needs the API handshake done, whatever the fdinfo said
+            uffd.features.get_or_insert(0);
+        }
+        uffd.regions = regions;
+    }
+}
+
+/// The page size of each mapping made of hugetlbfs pages, by start address.
+/// Everything else, transparent huge pages included, has the base page size
+/// as its `KernelPageSize`.
+fn scan_hugetlb_maps(pid: i32) -> HashMap<usize, usize> {
+    let smaps = match std::fs::read_to_string(format!("/proc/{}/smaps", pid)) {
+        Ok(smaps) => smaps,
+        Err(_) => return HashMap::new(),
     };
+    let mut hugetlb = HashMap::new();
+    let mut start = None;
+    for line in smaps.lines() {
+        if let Some(kb) = line.strip_prefix("KernelPageSize:") {
+            let page_size = kb.trim().trim_end_matches("kB").trim().parse::<usize>();
+            match (start.take(), page_size) {
+                (Some(addr), Ok(kb)) if kb * 1024 > PAGE_SIZE => {
+                    hugetlb.insert(addr, kb * 1024);
+                }
+                _ => {}
+            }
+            continue;
+        }
+        // Each mapping starts with its line from `maps`, like `7f00-7f80 rw-p ...`
+        let range = line
+            .split_whitespace()
+            .next()
+            .and_then(|r| r.split_once('-'));
+        if let Some((addr, _)) = range {
+            start = usize::from_str_radix(addr, 16).ok().or(start);
+        }
+    }
+    hugetlb
+}
 
-    // receive the telefork back
-    let child = telepad(&mut stream, 0).unwrap();
-    // we don't return from this function in the original process, we let it
-    // return in the newly received process then just wait and exit with the
-    // same status
-    let status = wait_for_exit(child).unwrap();
-    std::process::exit(status);
+const MCL_ONFAULT: i32 = 4;
+
+/// The `VmFlags` of each of a process's mappings from `/proc/pid/smaps`, by
+/// start address
+fn scan_vm_flags(pid: i32) -> Result<Vec<(usize, Vec<String>)>> {
+    let smaps = std::fs::read_to_string(format!("/proc/{}/smaps", pid))?;
+    let mut maps = Vec::new();
+    let mut start = None;
+    for line in smaps.lines() {
+        if let Some(flags) = line.strip_prefix("VmFlags:") {
+            if let Some(addr) = start.take() {
+                maps.push((addr, flags.split_whitespace().map(String::from).collect()));
+            }
+            continue;
+        }
+        // Each mapping starts with its line from `maps`, like `7f00-7f80 rw-p ...`
+        let range = line
+            .split_whitespace()
+            .next()
+            .and_then(|r| r.split_once('-'));
+        if let Some((addr, _)) = range {
+            start = usize::from_str_radix(addr, 16).ok().or(start);
+        }
+    }
+    Ok(maps)
 }
 
-// Helper that attaches to a running process and dumps its state to a file
-// for later restore.
-pub fn teledump(pid: i32, out: &mut dyn Write, leave_running: bool) -> Result<()> {
-    let child = Pid::from_raw(pid);
-    // TODO: This is wrong! Just a copy-paste from telefork, but here we need to read the remote brk state.
-    // == 1. Record anything we can easily record within our own process
-    let proc_state = ProcessState {
-        // sbrk(0) returns current brk address and it won't change for child since we don't malloc before forking
-        brk_addr: unsafe { libc::sbrk(0) as usize },
+/// The flags the process called `mlockall` with, or 0 if it didn't. All of
+/// its mappings being locked (`lo`) means `MCL_CURRENT`, locked on fault
+/// (`lf`) means `MCL_ONFAULT` too, and a page we map in it coming out
+/// locked means `MCL_FUTURE`. The kernel won't lock the vDSO, device
+/// memory, huge pages or `[vsyscall]`, which is up in the kernel's half of
+/// the address space, so those don't count.
+fn scan_mlockall(child: Pid) -> Result<i32> {
+    let pid = child.as_raw();
+    let lockable = |addr: usize, flags: &[String]| {
+        addr < 1 << 63
+            && !flags
+                .iter()
+                .any(|f| ["io", "pf", "de", "mm", "ht"].contains(&f.as_str()))
+    };
+    let has = |flags: &[String], flag| flags.iter().any(|f| f == flag);
+    let future = if read_status_field(pid, "Seccomp")? != "0" {
+        None
+    } else {
+        // The scratch region is freshly mapped, so it's locked if
+        // everything new is
+        let res = with_dump_syscall(child, |syscall| {
+            with_remote_bytes(child, syscall, &[0], |addr| {
+                let flags = scan_vm_flags(pid)?;
+                Ok(flags.iter().any(|(a, f)| *a == addr && has(f, "lo")))
+            })
+        });
+        match res {
+            Ok(future) => Some(future),
+            Err(e) => {
+                tracing::debug!("couldn't map a page to see if it comes out locked: {}", e);
+                None
+            }
+        }
     };
+    let maps = scan_vm_flags(pid)?;
+    let mut lockable_maps = maps.iter().filter(|(a, f)| lockable(*a, f)).peekable();
+    let current = lockable_maps.peek().is_some() && lockable_maps.all(|(_, f)| has(f, "lo"));
+    let mut flags = 0;
+    if current {
+        flags |= libc::MCL_CURRENT;
+    }
+    // Without injecting a page we can't tell, but the two nearly always go
+    // together
+    if future.unwrap_or(current) {
+        flags |= libc::MCL_FUTURE;
+    }
+    if flags != 0 && maps.iter().any(|(_, f)| has(f, "lf")) {
+        flags |= MCL_ONFAULT;
+    }
+    if flags != 0 {
+        info!("process called mlockall with flags {:#x}", flags);
+    }
+    Ok(flags)
+}
 
-    if ptrace::attach(child).is_err() {
-        return error("failed to attach to process");
+/// Lock the restored process's memory the way `mlockall` had it. Not being
+/// allowed to lock that much here isn't worth failing over, it just pages
+/// like anything else. `MCL_FUTURE` on its own would be, since then the
+/// process's next allocation fails instead, so it's all or nothing.
+fn restore_mlockall(child: Pid, syscall: SyscallLoc, flags: i32) {
+    let res = remote_syscall(child, syscall, 151, [flags as u64, 0, 0, 0, 0, 0]) // mlockall
+        .and_then(|res| remote_result(res, || format!("mlockall({:#x})", flags)));
+    if let Err(e) = res {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe {
+            libc::prlimit(
+                child.as_raw(),
+                libc::RLIMIT_MEMLOCK,
+                std::ptr::null(),
+                &mut limit,
+            )
+        };
+        warn!(
+            "couldn't lock the process's memory, RLIMIT_MEMLOCK is {} bytes here: {}",
+            limit.rlim_cur, e
+        );
+    }
+}
+
+fn scan_scheduling(pid: i32) -> Result<Scheduling> {
+    let mut attr = [0u8; SCHED_ATTR_SIZE];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_sched_getattr,
+            pid,
+            attr.as_mut_ptr(),
+            SCHED_ATTR_SIZE,
+            0,
+        )
     };
-    write_state(out, child, proc_state)?;
+    Errno::result(res)?;
+    let sched = Scheduling::from_attr(&attr);
+    if sched.policy == SCHED_DEADLINE {
+        info!(
+            "process has SCHED_DEADLINE with runtime {}ns, deadline {}ns and period {}ns",
+            sched.runtime, sched.deadline, sched.period
+        );
+    }
+    Ok(sched)
+}
 
-    if leave_running {
-        ptrace::detach(child, None)?;
-    } else {
-        if ptrace::kill(child).is_err() {
-            return error("failed to kill the process");
+/// Give the restored process its scheduling back with `sched_setattr` from
+/// inside it. A real-time or deadline policy needs `CAP_SYS_NICE` here, and
+/// a deadline one has to be admitted too, which the kernel turns down when
+/// the CPUs it could run on already have too much of their time promised.
+/// Neither is worth failing the restore over, it runs like anything else.
+fn restore_scheduling(child: Pid, syscall: SyscallLoc, sched: &Scheduling, skips: &mut Skips) {
+    if scan_scheduling(child.as_raw()).ok().as_ref() == Some(sched) {
+        return;
+    }
+    let res = with_remote_bytes(child, syscall, &sched.to_attr(), |addr| {
+        let res = remote_syscall(child, syscall, 314, [0, addr as u64, 0, 0, 0, 0])?; // sched_setattr
+        remote_result(res, || format!("sched_setattr({:?})", sched))
+    });
+    if let Err(e) = res {
+        let errno = e.downcast_ref::<RemoteSyscallError>().map(|e| e.errno);
+        let why = match errno {
+            Some(Errno::EBUSY) => ", there isn't enough CPU time left here to admit it",
+            Some(Errno::EPERM) if sched.policy == SCHED_DEADLINE => {
+                ", it needs CAP_SYS_NICE and to be allowed on every CPU"
+            }
+            Some(Errno::EPERM) => {
+                ", it needs CAP_SYS_NICE or a higher RLIMIT_RTPRIO or RLIMIT_NICE"
+            }
+            _ => "",
+        };
+        skips.skip(sched.policy_name(), format!("{}{}", e, why));
+    }
+}
+
+/// The `PT_GNU_RELRO` regions of the ELF objects mapped into a process, as
+/// start address and size. They hold the GOT and other things the dynamic
+/// loader makes read-only once it's done relocating them, found from the
+/// program headers in the first page of each object's mapping.
+fn scan_relro_regions(child: Pid, maps: &[proc_maps::MapRange]) -> Vec<(usize, usize)> {
+    let mut regions = Vec::new();
+    let objects = maps.iter().filter(|m| {
+        m.offset == 0 && m.is_read() && m.filename().as_deref().is_some_and(|f| f.starts_with('/'))
+    });
+    for map in objects {
+        let page = match read_memory(child, map.start(), PAGE_SIZE) {
+            Ok(page) => page,
+            Err(_) => continue,
+        };
+        // Only 64 bit ELF, which is all we can restore anyways
+        if page[..4] != *b"\x7fELF" || page[4] != 2 {
+            continue;
+        }
+        let phoff = u64::from_ne_bytes(page[32..40].try_into().unwrap()) as usize;
+        let phentsize = u16::from_ne_bytes(page[54..56].try_into().unwrap()) as usize;
+        let phnum = u16::from_ne_bytes(page[56..58].try_into().unwrap()) as usize;
+        if phentsize < std::mem::size_of::<libc::Elf64_Phdr>()
+            || phoff.saturating_add(phnum * phentsize) > PAGE_SIZE
+        {
+            continue;
+        }
+        let headers: Vec<libc::Elf64_Phdr> = (0..phnum)
+            .map(|i| unsafe {
+                std::ptr::read_unaligned(page[phoff + i * phentsize..].as_ptr() as *const _)
+            })
+            .collect();
+        // The first page of the object is mapped where its lowest segment goes
+        let lowest = headers
+            .iter()
+            .filter(|h| h.p_type == libc::PT_LOAD)
+            .map(|h| h.p_vaddr as usize & !(PAGE_SIZE - 1))
+            .min();
+        let relro = headers.iter().find(|h| h.p_type == libc::PT_GNU_RELRO);
+        if let (Some(lowest), Some(relro)) = (lowest, relro) {
+            let bias = map.start().wrapping_sub(lowest);
+            // Rounded the same way the loader does it, the end of the last
+            // page is left writable if it's shared with .data
+            let start = bias.wrapping_add(relro.p_vaddr as usize) & !(PAGE_SIZE - 1);
+            let end =
+                bias.wrapping_add((relro.p_vaddr + relro.p_memsz) as usize) & !(PAGE_SIZE - 1);
+            if end > start {
+                regions.push((start, end - start));
+            }
         }
     }
+    regions
+}
 
-    Ok(())
+/// Turn a policy like `bind:0-1` or `interleave=static:0,2` into an `MPOL_*`
+/// mode and its nodes, or `None` for the default policy.
+fn parse_memory_policy(policy: &str) -> Option<(i32, Vec<u32>)> {
+    let (name, nodes) = policy.split_once(':').unwrap_or((policy, ""));
+    // Mode flags like `=static` go between the name and the nodes, we don't
+    // keep them since the nodes might get renumbered anyways
+    let name = name.split('=').next().unwrap_or(name);
+    let mode = match name {
+        "prefer" => MPOL_PREFERRED,
+        "bind" => MPOL_BIND,
+        "interleave" => MPOL_INTERLEAVE,
+        "local" => MPOL_LOCAL,
+        "prefer (many)" => MPOL_PREFERRED_MANY,
+        "weighted interleave" => MPOL_WEIGHTED_INTERLEAVE,
+        _ => return None,
+    };
+    Some((mode, parse_node_list(nodes)))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum Connection {
-    Invalid,
-    Tcp(TcpConnection),
-    File(FileConnection),
-    Stdio(StdioConnection),
+/// Parse a node list like `0-2,5` the way the kernel prints them in
+/// `numa_maps` and `/sys/devices/system/node/online`
+fn parse_node_list(list: &str) -> Vec<u32> {
+    let mut nodes = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        if let (Ok(first), Ok(last)) = (first.parse::<u32>(), last.parse::<u32>()) {
+            nodes.extend(first..=last);
+        }
+    }
+    nodes
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TcpConnection {
-    local_addr: String,
-    remote_addr: String,
+/// Read the environment and where its block is from `/proc`. The addresses
+/// are fields 50 and 51 of `stat`, counting from after the parenthesised
+/// command name since that can contain spaces.
+/// Field `n` of `/proc/pid/stat`, numbered from 1 like in `man proc`
+fn read_stat_field(pid: i32, n: usize) -> Result<usize> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let after_comm = match stat.rfind(')') {
+        Some(at) => &stat[at + 1..],
+        None => return error("couldn't parse /proc/pid/stat"),
+    };
+    // Field 3 is the first one after the command name
+    match after_comm.split_whitespace().nth(n - 3) {
+        Some(field) => Ok(field.parse()?),
+        None => error("/proc/pid/stat is missing fields"),
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct FileConnection {
-    path: String,
-    offset: u64,
+/// The data segment and heap bounds are in `/proc/pid/stat` but the break
+/// itself isn't, so we ask for it with an injected `brk(0)`. Under seccomp
+/// that could get the process killed, so there we make do with the top of
+/// the `[heap]` mapping.
+/// The rest of the layout from `/proc/pid/stat` to go with `heap`, which
+/// has the one field it doesn't show, the exact break
+fn scan_mm_layout(pid: i32, heap: &HeapLayout) -> Result<MmLayout> {
+    let auxv = std::fs::read(format!("/proc/{}/auxv", pid))?
+        .chunks_exact(8)
+        .map(|word| u64::from_ne_bytes(word.try_into().unwrap()))
+        .collect();
+    Ok(MmLayout {
+        start_code: read_stat_field(pid, 26)?,
+        end_code: read_stat_field(pid, 27)?,
+        start_data: heap.start_data,
+        end_data: heap.end_data,
+        start_brk: heap.start_brk,
+        brk: heap.brk,
+        start_stack: read_stat_field(pid, 28)?,
+        arg_start: read_stat_field(pid, 48)?,
+        arg_end: read_stat_field(pid, 49)?,
+        env_start: read_stat_field(pid, 50)?,
+        env_end: read_stat_field(pid, 51)?,
+        auxv,
+    })
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct StdioConnection {}
+/// Change the value for `key` in an auxv of pairs of words, if it's there
+fn set_auxv_entry(auxv: &mut [u64], key: u64, value: u64) {
+    for pair in auxv.chunks_exact_mut(2) {
+        if pair[0] == key {
+            pair[1] = value;
+        }
+    }
+}
 
-type ConnectionMap = HashMap<u32, Connection>;
+/// `PR_SET_MM_MAP`, also from `linux/prctl.h`
+const PR_SET_MM_MAP: i32 = 14;
+/// The size of `struct prctl_mm_map`, 11 addresses, the auxv pointer, its
+/// size and the exe fd
+const PRCTL_MM_MAP_SIZE: usize = 12 * 8 + 4 + 4;
 
-use std::os::unix::fs::FileTypeExt;
+/// Set the whole layout at once with `PR_SET_MM_MAP`, which unlike setting
+/// the fields one at a time doesn't need `CAP_SYS_RESOURCE` and can't trip
+/// over the fields having to stay in order with each other as they change.
+/// Needs a kernel built with `CONFIG_CHECKPOINT_RESTORE`, and every address
+/// in it has to be in a mapping we've already restored.
+fn restore_mm_layout(child: Pid, syscall: SyscallLoc, mm: &MmLayout) -> Result<()> {
+    let auxv_bytes = mm.auxv.len() * 8;
+    let mut map = Vec::with_capacity(PRCTL_MM_MAP_SIZE + auxv_bytes);
+    let fields = [
+        mm.start_code,
+        mm.end_code,
+        mm.start_data,
+        mm.end_data,
+        mm.start_brk,
+        mm.brk,
+        mm.start_stack,
+        mm.arg_start,
+        mm.arg_end,
+        mm.env_start,
+        mm.env_end,
+    ];
+    for field in fields {
+        map.extend_from_slice(&(field as u64).to_ne_bytes());
+    }
+    // The auxv pointer, filled in once we know where this lands
+    map.extend_from_slice(&0u64.to_ne_bytes());
+    map.extend_from_slice(&(auxv_bytes as u32).to_ne_bytes());
+    // Leave /proc/pid/exe alone, changing it needs more privileges
+    map.extend_from_slice(&u32::MAX.to_ne_bytes());
+    for word in &mm.auxv {
+        map.extend_from_slice(&word.to_ne_bytes());
+    }
+    let res = with_remote_bytes(child, syscall, &map, |addr| {
+        let auxv = ((addr + PRCTL_MM_MAP_SIZE) as u64).to_ne_bytes();
+        stream_memory(child, &mut &auxv[..], addr + 11 * 8, 8)?;
+        remote_syscall(
+            child,
+            syscall,
+            157, // prctl
+            [
+                libc::PR_SET_MM as u64,
+                PR_SET_MM_MAP as u64,
+                addr as u64,
+                PRCTL_MM_MAP_SIZE as u64,
+                0,
+                0,
+            ],
+        )
+    })?;
+    remote_result(res, || "prctl PR_SET_MM_MAP".to_string())?;
+    Ok(())
+}
 
-fn get_fd_offset(pid: i32, fd: u32) -> Result<Option<u64>> {
-    use std::io::BufRead;
-    let fdinfo_path = format!("/proc/{}/fdinfo/{}", pid, fd);
-    let file = match std::fs::File::open(&fdinfo_path) {
-        Ok(f) => f,
-        Err(_) => {
-            return error("failed to open fdinfo");
+fn scan_heap_layout(child: Pid) -> Result<HeapLayout> {
+    let pid = child.as_raw();
+    let start_brk = read_stat_field(pid, 47)?;
+    let query = || -> Result<usize> {
+        if read_status_field(pid, "Seccomp")? != "0" {
+            return error("process is using seccomp");
         }
+        with_dump_syscall(child, |syscall| remote_brk(child, syscall, 0))
     };
-    let reader = std::io::BufReader::new(file);
-
-    for line in reader.lines() {
-        let line = line?;
-        if line.starts_with("pos:") {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if let Some(offset_str) = parts.get(1) {
-                if let Ok(offset) = offset_str.parse::<u64>() {
-                    return Ok(Some(offset));
-                }
-            }
+    let brk = match query() {
+        Ok(brk) => brk,
+        Err(e) => {
+            tracing::debug!("couldn't ask for the program break ({}), using [heap]", e);
+            let maps = proc_maps::get_process_maps(pid as proc_maps::Pid)?;
+            find_map_named(&maps, "[heap]").map_or(start_brk, |m| m.start() + m.size())
         }
-    }
+    };
+    Ok(HeapLayout {
+        start_data: read_stat_field(pid, 45)?,
+        end_data: read_stat_field(pid, 46)?,
+        start_brk,
+        brk,
+    })
+}
 
-    Ok(None)
+fn scan_environment(pid: i32) -> Result<Environment> {
+    let start = read_stat_field(pid, 50)?;
+    let end = read_stat_field(pid, 51)?;
+    let environ = std::fs::read(format!("/proc/{}/environ", pid))?;
+    let vars = environ
+        .split(|b| *b == 0)
+        .filter(|var| !var.is_empty())
+        .map(|var| String::from_utf8_lossy(var).to_string())
+        .collect();
+    Ok(Environment { start, end, vars })
 }
 
-fn scan_file_descriptors(pid: i32) -> Result<ConnectionMap> {
-    let fd_dir: String = format!("/proc/{}/fd", pid);
-    let entries = std::fs::read_dir(fd_dir)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut cm: ConnectionMap = HashMap::new();
+    /// Where a process on a machine with 5-level paging can have its stack
+    const LA57_STACK: usize = 0x00ff_ffff_fff0_0000;
+    /// And where it goes with 4-level paging
+    const STACK: usize = 0x7fff_fff0_0000;
 
-    for entry in entries {
-        let entry = entry?;
-        let fd_path = entry.path();
-        let fd = fd_path.file_name().unwrap().to_string_lossy();
-        // Read the symbolic link to get the file descriptor target
-        let target = std::fs::read_link(&fd_path)?;
-        let metadata = std::fs::metadata(&target)?;
-        let file_type = metadata.file_type();
-        info!("file descriptor {}: {:?}", fd, target);
+    /// Our own mapping starting at `addr`, as `/proc/self/maps` has it now
+    fn own_map(addr: usize) -> proc_maps::MapRange {
+        let maps = proc_maps::get_process_maps(std::process::id() as proc_maps::Pid).unwrap();
+        maps.into_iter().find(|m| m.start() == addr).unwrap()
+    }
 
-        if file_type.is_file() {
-            let fd = fd.parse::<u32>().unwrap();
-            let offset = get_fd_offset(pid, fd)?.unwrap_or(0);
-            cm.insert(
-                fd,
-                Connection::File(FileConnection {
-                    path: target.to_string_lossy().to_string(),
-                    offset,
-                }),
-            );
-        } else if file_type.is_dir() {
-            cm.insert(
-                fd.parse::<u32>().unwrap(),
-                Connection::File(FileConnection {
-                    path: target.to_string_lossy().to_string(),
-                    offset: 0,
-                }),
-            );
-        } else if file_type.is_socket() {
-            cm.insert(
-                fd.parse::<u32>().unwrap(),
-                Connection::Tcp(TcpConnection {
-                    local_addr: target.to_string_lossy().to_string(),
-                    remote_addr: target.to_string_lossy().to_string(),
-                }),
-            );
-        } else if file_type.is_char_device() {
-            let fd = fd.parse::<u32>().unwrap();
-            if matches!(fd, 0..=2) {
-                cm.insert(fd, Connection::Stdio(StdioConnection {}));
-            } else {
-                warn!("saving unsupported file descriptor");
-                cm.insert(fd, Connection::Invalid);
-            }
-        } else {
-            warn!("saving unsupported file descriptor");
-            cm.insert(fd.parse::<u32>().unwrap(), Connection::Invalid);
-        }
+    /// Two reads of the maps with a mapping growing in between, like when the
+    /// process changes them while we're reading
+    #[test]
+    fn overlapping_maps() {
+        let len = 4 * PAGE_SIZE;
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let protect = |pages: usize| unsafe {
+            assert_eq!(libc::mprotect(addr, pages * PAGE_SIZE, libc::PROT_READ), 0);
+        };
+        let addr = addr as usize;
+        protect(2);
+        let before = own_map(addr);
+        let after_it = own_map(addr + 2 * PAGE_SIZE);
+        protect(3);
+        let grown = own_map(addr);
+        unsafe { libc::munmap(addr as *mut libc::c_void, len) };
+        assert_eq!(before.size(), 2 * PAGE_SIZE);
+        assert_eq!(grown.size(), 3 * PAGE_SIZE);
+
+        // Right up against each other is fine
+        let mut adjacent = vec![after_it.clone(), before.clone()];
+        check_overlapping_maps(&mut adjacent).unwrap();
+        assert_eq!(adjacent.len(), 2);
+        // The same one twice is just dropped
+        let mut duplicate = vec![before.clone(), after_it, before.clone()];
+        check_overlapping_maps(&mut duplicate).unwrap();
+        assert_eq!(duplicate.len(), 2);
+        // But there's no telling which of these is right
+        let mut overlapping = vec![before, grown];
+        assert!(check_overlapping_maps(&mut overlapping).is_err());
+    }
+
+    #[test]
+    fn address_space_fits() {
+        check_address_space(STACK, TASK_SIZE).unwrap();
+        check_address_space(TASK_SIZE, TASK_SIZE).unwrap();
+        check_address_space(STACK, TASK_SIZE_LA57).unwrap();
+        check_address_space(LA57_STACK, TASK_SIZE_LA57).unwrap();
+    }
+
+    #[test]
+    fn address_space_too_high() {
+        let err = check_address_space(LA57_STACK, TASK_SIZE).unwrap_err();
+        assert!(err.downcast_ref::<Unsupported>().is_some(), "{}", err);
+        let err = check_address_space(TASK_SIZE + PAGE_SIZE, TASK_SIZE).unwrap_err();
+        assert!(err.downcast_ref::<Unsupported>().is_some(), "{}", err);
     }
-    Ok(cm)
 }
@@ -2,7 +2,6 @@ use camino::Utf8PathBuf;
 
 use clap::{Args, Parser, Subcommand};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber;
 use tracing_subscriber::EnvFilter;
 
 use telefork::cmd;
@@ -22,8 +21,8 @@ pub struct App {
 #[derive(Debug, Args)]
 struct GlobalOpts {
     /// Verbosity level (can be specified multiple times)
-    #[clap(long, short, global = true, default_value_t = 0)]
-    verbose: usize,
+    #[clap(long, short, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
 }
 
 #[derive(Debug, Subcommand)]
@@ -37,19 +36,85 @@ enum Command {
         /// Restore the process running after dumping.
         #[clap(long)]
         leave_running: bool,
+        /// Checkpoint the process's GPU state with cuda-checkpoint first.
+        #[clap(long)]
+        cuda: bool,
+        /// Also write a JSON summary of the dump, to PATH.meta.json if no path is given.
+        #[clap(long, value_name = "META_PATH", num_args = 0..=1)]
+        meta: Option<Option<Utf8PathBuf>>,
+        /// Compress the dump, zstd or zstd:LEVEL.
+        #[clap(long, value_name = "ALGO", value_parser = cmd::parse_compression)]
+        compress: Option<i32>,
+        /// Encrypt the dump with the key in --key-file.
+        #[clap(long, requires = "key_file")]
+        encrypt: bool,
+        /// File with the encryption key, 32 raw bytes or 64 hex digits.
+        #[clap(long, value_name = "PATH", requires = "encrypt")]
+        key_file: Option<Utf8PathBuf>,
+        /// Copy memory in up to PASSES passes while it keeps running, so it's only stopped briefly at the end.
+        #[clap(long, value_name = "PASSES", default_value_t = 0)]
+        precopy: usize,
+        /// file for one file, or dir for a directory with a file per mapping that rsyncs well.
+        #[clap(long, value_name = "FORMAT", default_value = "file", value_parser = cmd::parse_format)]
+        format: cmd::Format,
     },
     /// Restore a process from a dumped file.
     Restore {
-        /// The dumped file to restore from.
+        /// The dumped file or directory to restore from.
         path: Utf8PathBuf,
+        /// Restore GPU state with cuda-checkpoint, for dumps made with --cuda.
+        #[clap(long)]
+        cuda: bool,
+        /// Give the restored process this file as its stdin.
+        #[clap(long, value_name = "FILE")]
+        stdin: Option<Utf8PathBuf>,
+        /// Write the restored process's stdout to this file.
+        #[clap(long, value_name = "FILE")]
+        stdout: Option<Utf8PathBuf>,
+        /// Write the restored process's stderr to this file.
+        #[clap(long, value_name = "FILE")]
+        stderr: Option<Utf8PathBuf>,
+        /// The key an encrypted dump was made with, compression is detected by itself.
+        #[clap(long, value_name = "PATH")]
+        key_file: Option<Utf8PathBuf>,
+        /// Attach shared memory segments that already exist here instead of recreating them.
+        #[clap(long)]
+        reattach_shm: bool,
+        /// Restore what can be and carry on without what can't, listing what was skipped.
+        #[clap(long)]
+        keep_going: bool,
+        /// Watch the restored process for a moment and report where it crashed if it does.
+        #[clap(long)]
+        debug_faults: bool,
+        /// Restore this many independent copies, each passed its number counting from 1.
+        #[clap(long, value_name = "N", default_value_t = 1)]
+        count: usize,
+        /// Restore inside this mount namespace, like /proc/PID/ns/mnt of a process in the
+        /// container the dump came from, so its file paths resolve the same.
+        #[clap(long, value_name = "PATH")]
+        mount_namespace: Option<Utf8PathBuf>,
+    },
+    /// List a process's file descriptors and whether they can be restored.
+    Fds {
+        /// The pid of the process to look at.
+        process_id: i32,
     },
+    /// Show which mappings, registers and fds differ between two dumps.
+    Diff {
+        /// The earlier dump, a file or directory.
+        a: Utf8PathBuf,
+        /// The later dump, a file or directory.
+        b: Utf8PathBuf,
+    },
+    /// Check telefork works on this machine by round tripping a canary.
+    Selftest,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = App::parse();
 
     let level = match cli.global_opts.verbose {
-        3.. => LevelFilter::DEBUG.into(),
+        3.. => LevelFilter::TRACE.into(),
         2 => LevelFilter::DEBUG.into(),
         1 => LevelFilter::INFO.into(),
         0 => LevelFilter::WARN.into(),
@@ -68,11 +133,70 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             process_id,
             path,
             leave_running,
+            cuda,
+            meta,
+            compress,
+            encrypt: _,
+            key_file,
+            precopy,
+            format,
+        } => {
+            let meta = match meta {
+                None => cmd::Meta::None,
+                Some(None) => cmd::Meta::Sibling,
+                Some(Some(p)) => cmd::Meta::Path(p.into()),
+            };
+            let transport = cmd::Transport {
+                compress,
+                key: key_file.map(cmd::read_key).transpose()?,
+                format,
+            };
+            cmd::dump(
+                process_id,
+                path,
+                leave_running,
+                cuda,
+                meta,
+                transport,
+                precopy,
+            )?;
+        }
+        Command::Restore {
+            path,
+            cuda,
+            stdin,
+            stdout,
+            stderr,
+            key_file,
+            reattach_shm,
+            keep_going,
+            debug_faults,
+            count,
+            mount_namespace,
         } => {
-            cmd::dump(process_id, path, leave_running)?;
+            let stdio = cmd::Stdio {
+                stdin: stdin.map(Into::into),
+                stdout: stdout.map(Into::into),
+                stderr: stderr.map(Into::into),
+            };
+            let key = key_file.map(cmd::read_key).transpose()?;
+            let config = telefork::Config {
+                reattach_shm,
+                keep_going,
+                debug_first_fault: debug_faults,
+                mount_namespace: mount_namespace.map(Into::into),
+                ..Default::default()
+            };
+            cmd::restore(path, cuda, stdio, key, config, count)?;
+        }
+        Command::Fds { process_id } => {
+            cmd::fds(process_id)?;
+        }
+        Command::Diff { a, b } => {
+            cmd::diff(a, b)?;
         }
-        Command::Restore { path } => {
-            cmd::restore(path)?;
+        Command::Selftest => {
+            cmd::selftest()?;
         }
     }
     Ok(())
@@ -0,0 +1,366 @@
+//! Dumping a process while it keeps running for most of it, the way live
+//! migration does it. Memory is copied in passes while the process runs,
+//! with the kernel's soft-dirty bits telling us which pages it wrote to since
+//! the last pass, and it's only stopped at the end for long enough to send
+//! whatever changed after that along with the registers and everything else.
+//! Between passes it's also stopped for just as long as it takes to read
+//! the dirty bits and clear them, so nothing it writes in between is missed.
+//!
+//! The first pass is the start of an ordinary dump, the layout and then each
+//! mapping's contents. Later passes send `DirtyPages` for the mappings that
+//! are still the same shape, and the final one also sends an `Unmap` for
+//! each one that isn't before carrying on like a normal dump, so anything
+//! new or changed gets sent in full.
+//!
+//! Shared mappings are left for the final pass, since whatever else has them
+//! mapped can write to them without setting our dirty bits. So are ones
+//! registered with a userfaultfd, which the final pass knows not to read the
+//! missing pages of.
+
+use crate::{
+    check_device_maps, describe_map, error, highest_address, scan_userfault_regions,
+    seize_and_stop, split_maps, write_command, write_layout, Command, Config, DumpHooks,
+    ProcessState, Result, PAGE_SIZE,
+};
+
+use nix::sys::{ptrace, uio};
+use nix::unistd::Pid;
+use tracing::info;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Stop doing passes once fewer than this many pages changed during one,
+/// another pass won't make the final stop much shorter
+const SETTLED_PAGES: usize = 256;
+
+/// The soft-dirty bit of a `/proc/pid/pagemap` entry
+const PM_SOFT_DIRTY: u64 = 1 << 55;
+
+/// How many pagemap entries to read at once
+const PAGEMAP_CHUNK: usize = 64 * 1024;
+
+/// A mapping an earlier pass sent the contents of
+struct SentMap {
+    size: usize,
+    flags: String,
+    /// If it was being unmapped while we read it the rest went as zeroes,
+    /// and it needs sending in full again
+    complete: bool,
+}
+
+/// What the passes so far have sent
+pub(crate) struct Precopy {
+    /// By start address
+    sent: HashMap<usize, SentMap>,
+    /// Bytes of memory contents sent, counting pages sent more than once
+    pub(crate) memory_bytes: usize,
+}
+
+impl Precopy {
+    /// Send the layout and a first copy of the running process's private
+    /// mappings, then passes of what changed until not much does or we've
+    /// done `Config::precopy_passes`.
+    pub(crate) fn run(
+        out: &mut dyn Write,
+        child: Pid,
+        proc_state: ProcessState,
+        config: &Config,
+        hooks: &mut DumpHooks,
+    ) -> Result<Precopy> {
+        let pid = child.as_raw();
+        check_soft_dirty()?;
+        let maps = proc_maps::get_process_maps(pid as proc_maps::Pid)?;
+        let highest = highest_address(&maps);
+        let (special_maps, regular_maps) = split_maps(maps, config)?;
+        let devices = check_device_maps(pid, &regular_maps, config)?;
+        write_layout(out, child, highest, proc_state, &special_maps, config)?;
+
+        // Anything written from here on shows up in the next pass
+        clear_soft_dirty(pid)?;
+        let mut precopy = Precopy {
+            sent: HashMap::new(),
+            memory_bytes: 0,
+        };
+        let userfaults: Vec<usize> = scan_userfault_regions(pid)?
+            .iter()
+            .map(|r| r.addr)
+            .collect();
+        for map in regular_maps.iter().filter(|m| {
+            is_private(m) && !userfaults.contains(&m.start()) && !devices.contains(&m.start())
+        }) {
+            let (written, complete) = write_running_map(out, child, map, hooks)?;
+            precopy.memory_bytes += written;
+            precopy.sent.insert(
+                map.start(),
+                SentMap {
+                    size: map.size(),
+                    flags: map.flags.clone(),
+                    complete,
+                },
+            );
+        }
+        info!("precopy pass 1 sent {} bytes", precopy.memory_bytes);
+
+        for pass in 2..=config.precopy_passes {
+            // A page written between reading its bit and clearing them all
+            // wouldn't be in this pass or show up in the next one
+            let dirty = with_stopped(pid, || {
+                let maps = proc_maps::get_process_maps(pid as proc_maps::Pid)?;
+                let mut dirty = Vec::new();
+                for map in maps.iter().filter(|m| precopy.has_current(m)) {
+                    let pages = dirty_pages(pid, map)?;
+                    if !pages.is_empty() {
+                        dirty.push((map.start(), pages));
+                    }
+                }
+                clear_soft_dirty(pid)?;
+                Ok(dirty)
+            })?;
+            let changed: usize = dirty.iter().map(|(_, pages)| pages.len()).sum();
+            for (start, pages) in dirty {
+                let (written, complete) = write_pages(out, child, start, &pages, hooks)?;
+                precopy.memory_bytes += written;
+                if !complete {
+                    if let Some(sent) = precopy.sent.get_mut(&start) {
+                        sent.complete = false;
+                    }
+                }
+            }
+            info!("precopy pass {} sent {} changed pages", pass, changed);
+            if changed < SETTLED_PAGES {
+                break;
+            }
+        }
+        Ok(precopy)
+    }
+
+    /// Whether we sent `map` in an earlier pass and it's still the same
+    /// shape, so sending what changed is enough
+    pub(crate) fn has_current(&self, map: &proc_maps::MapRange) -> bool {
+        matches!(self.sent.get(&map.start()), Some(sent)
+            if sent.complete && sent.size == map.size() && sent.flags == map.flags)
+    }
+
+    /// Once it's stopped, unmap everything we sent that isn't there anymore
+    /// or changed shape. Whatever replaced them gets sent in full after.
+    pub(crate) fn write_unmapped(
+        &self,
+        out: &mut dyn Write,
+        maps: &[proc_maps::MapRange],
+    ) -> Result<()> {
+        for (&addr, sent) in &self.sent {
+            if maps
+                .iter()
+                .any(|m| m.start() == addr && self.has_current(m))
+            {
+                continue;
+            }
+            info!("mapping at {:x} changed since it was copied", addr);
+            let comm = Command::Unmap {
+                addr,
+                size: sent.size,
+            };
+            write_command(out, &comm)?;
+        }
+        Ok(())
+    }
+
+    /// Send the pages of `map` changed since the last pass, for the final
+    /// one once it's stopped
+    pub(crate) fn write_dirty_pages(
+        &self,
+        out: &mut dyn Write,
+        child: Pid,
+        map: &proc_maps::MapRange,
+        hooks: &mut DumpHooks,
+    ) -> Result<usize> {
+        let pages = dirty_pages(child.as_raw(), map)?;
+        if pages.is_empty() {
+            return Ok(0);
+        }
+        let (written, complete) = write_pages(out, child, map.start(), &pages, hooks)?;
+        if !complete {
+            return error("failed to read from stopped process");
+        }
+        Ok(written)
+    }
+}
+
+/// Nothing but the process itself can write to its private mappings
+fn is_private(map: &proc_maps::MapRange) -> bool {
+    map.flags.get(3..4) == Some("p")
+}
+
+/// Keep every thread of the process stopped while `f` runs, so none of them
+/// can write to its memory meanwhile. Like for the dump itself they're
+/// stopped with `PTRACE_INTERRUPT`, which the process doesn't see.
+fn with_stopped<T>(pid: i32, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let mut stopped = Vec::new();
+    let res = stop_threads(pid, &mut stopped).and_then(|()| f());
+    for tid in stopped {
+        // It carries on, unless it's exited meanwhile
+        let _ = ptrace::detach(tid, None);
+    }
+    res
+}
+
+/// Stop the threads of `pid` until none are left running, since one we
+/// haven't got to yet can start more
+fn stop_threads(pid: i32, stopped: &mut Vec<Pid>) -> Result<()> {
+    loop {
+        let mut running = Vec::new();
+        for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+            if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+                let tid = Pid::from_raw(tid);
+                if !stopped.contains(&tid) {
+                    running.push(tid);
+                }
+            }
+        }
+        if running.is_empty() {
+            return Ok(());
+        }
+        for tid in running {
+            match seize_and_stop(tid) {
+                Ok(()) => stopped.push(tid),
+                // It exited since we listed it
+                Err(_) if !Path::new(&format!("/proc/{}/task/{}", pid, tid)).exists() => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Make sure the kernel tracks soft-dirty pages at all, which needs
+/// `CONFIG_MEM_SOFT_DIRTY`. Without it `clear_refs` still takes a 4 but no
+/// page ever comes up dirty, so every pass would find nothing changed. Where
+/// it works a page we've just mapped and written to always is.
+fn check_soft_dirty() -> Result<()> {
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            PAGE_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if page == libc::MAP_FAILED {
+        return Err(Box::new(std::io::Error::last_os_error()));
+    }
+    unsafe { std::ptr::write_volatile(page as *mut u8, 1) };
+    let mut entry = [0u8; 8];
+    let offset = (page as usize / PAGE_SIZE * 8) as u64;
+    let res = File::open("/proc/self/pagemap").and_then(|f| f.read_exact_at(&mut entry, offset));
+    unsafe { libc::munmap(page, PAGE_SIZE) };
+    res?;
+    if u64::from_ne_bytes(entry) & PM_SOFT_DIRTY == 0 {
+        tracing::error!("a page we just wrote to isn't soft-dirty, the kernel doesn't track them");
+        return error("soft-dirty tracking isn't available");
+    }
+    Ok(())
+}
+
+/// Reset the soft-dirty bits of all the process's pages
+pub(crate) fn clear_soft_dirty(pid: i32) -> Result<()> {
+    if let Err(e) = std::fs::write(format!("/proc/{}/clear_refs", pid), "4") {
+        tracing::error!("couldn't clear soft-dirty bits of {}: {}", pid, e);
+        return error("soft-dirty tracking isn't available");
+    }
+    Ok(())
+}
+
+/// The addresses of pages in `map` with their soft-dirty bit set
+pub(crate) fn dirty_pages(pid: i32, map: &proc_maps::MapRange) -> Result<Vec<usize>> {
+    let mut pagemap = File::open(format!("/proc/{}/pagemap", pid))?;
+    let first = map.start() / PAGE_SIZE;
+    let count = map.size() / PAGE_SIZE;
+    pagemap.seek(SeekFrom::Start((first * 8) as u64))?;
+    let mut pages = Vec::new();
+    let mut buf = vec![0u8; PAGEMAP_CHUNK * 8];
+    let mut done = 0;
+    while done < count {
+        let chunk = std::cmp::min(PAGEMAP_CHUNK, count - done);
+        pagemap.read_exact(&mut buf[..chunk * 8])?;
+        for (i, entry) in buf[..chunk * 8].chunks_exact(8).enumerate() {
+            let entry = u64::from_ne_bytes(entry.try_into().unwrap());
+            if entry & PM_SOFT_DIRTY != 0 {
+                pages.push(map.start() + (done + i) * PAGE_SIZE);
+            }
+        }
+        done += chunk;
+    }
+    Ok(pages)
+}
+
+/// Read a page of the child into `buf`, leaving it zeroed if it's not
+/// mapped anymore. Returns whether it was.
+fn read_page(child: Pid, addr: usize, buf: &mut [u8]) -> bool {
+    buf.fill(0);
+    let read = uio::process_vm_readv(
+        child,
+        &[uio::IoVec::from_mut_slice(buf)],
+        &[uio::RemoteIoVec {
+            base: addr,
+            len: PAGE_SIZE,
+        }],
+    );
+    matches!(read, Ok(len) if len == PAGE_SIZE)
+}
+
+/// Like `write_regular_map` but the process is running and can unmap it
+/// while we read, in which case we carry on with zeroes so the stream stays
+/// in one piece. Returns the bytes written and whether we read all of it.
+fn write_running_map(
+    out: &mut dyn Write,
+    child: Pid,
+    map: &proc_maps::MapRange,
+    hooks: &mut DumpHooks,
+) -> Result<(usize, bool)> {
+    write_command(out, &Command::Mapping(describe_map(map)))?;
+    let mut crc = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; PAGE_SIZE];
+    let mut complete = true;
+    for addr in (map.start()..map.start() + map.size()).step_by(PAGE_SIZE) {
+        complete &= read_page(child, addr, &mut buf);
+        hooks.transform(addr, &mut buf);
+        crc.update(&buf);
+        out.write_all(&buf)?;
+    }
+    let written = map.size();
+    write_command(out, &Command::MappingEnd { written })?;
+    let crc32 = crc.finalize();
+    write_command(out, &Command::MappingChecksum { crc32 })?;
+    Ok((written, complete))
+}
+
+/// Send the current contents of some pages of the mapping starting at `map`
+fn write_pages(
+    out: &mut dyn Write,
+    child: Pid,
+    map: usize,
+    pages: &[usize],
+    hooks: &mut DumpHooks,
+) -> Result<(usize, bool)> {
+    let comm = Command::DirtyPages {
+        map,
+        pages: pages.to_vec(),
+    };
+    write_command(out, &comm)?;
+    let mut buf = vec![0u8; PAGE_SIZE];
+    let mut complete = true;
+    for &page in pages {
+        complete &= read_page(child, page, &mut buf);
+        hooks.transform(page, &mut buf);
+        out.write_all(&buf)?;
+    }
+    let written = pages.len() * PAGE_SIZE;
+    write_command(out, &Command::MappingEnd { written })?;
+    Ok((written, complete))
+}
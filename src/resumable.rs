@@ -0,0 +1,373 @@
+//! A small framed protocol for sending a telefork over a connection that
+//! might drop. The stream is cut into numbered chunks and the receiver acks
+//! each one, so when a connection breaks the sender can reconnect, learn how
+//! far the receiver got, and carry on from there instead of from byte zero.
+//!
+//! Both ends just look like a normal `Write`/`Read` to `telefork`/`telepad`
+//! so nothing else needs to know this is going on.
+//!
+//! On the wire every (re)connection starts with the sender writing the magic
+//! and a session id, and the receiver answering with how many chunks it has
+//! already got. After that the sender writes frames of `seq: u64, len: u32,
+//! crc: u32` followed by `len` bytes, and the receiver writes back a `u64`
+//! count of chunks received after each one. An empty frame marks the end.
+//!
+//! A chunk that doesn't match its CRC32 gets the count of chunks received
+//! with `RESEND` set as the answer instead, and the sender sends everything
+//! from there again over the same connection. Senders from before the CRC
+//! was added say `OLD_MAGIC` and send frames without it.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use tracing::warn;
+
+const MAGIC: &[u8; 4] = b"TFRC";
+const OLD_MAGIC: &[u8; 4] = b"TFRS";
+/// Set in an ack to ask for everything from that chunk again
+const RESEND: u64 = 1 << 63;
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How many chunks we keep around waiting for an ack before we stop and wait
+const WINDOW: usize = 64;
+const MAX_RECONNECTS: usize = 10;
+
+fn write_u64(out: &mut dyn Write, v: u64) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+fn read_u64(inp: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    inp.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_frame(out: &mut dyn Write, seq: u64, data: &[u8]) -> io::Result<()> {
+    write_u64(out, seq)?;
+    out.write_all(&(data.len() as u32).to_le_bytes())?;
+    out.write_all(&crc32fast::hash(data).to_le_bytes())?;
+    out.write_all(data)?;
+    out.flush()
+}
+
+/// A frame and whether it matched its CRC, which is always true for frames
+/// from senders without one
+fn read_frame(inp: &mut dyn Read, checksums: bool) -> io::Result<(u64, Vec<u8>, bool)> {
+    let seq = read_u64(inp)?;
+    let mut len = [0u8; 4];
+    inp.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "oversized resumable frame",
+        ));
+    }
+    let mut crc = [0u8; 4];
+    if checksums {
+        inp.read_exact(&mut crc)?;
+    }
+    let mut data = vec![0u8; len];
+    inp.read_exact(&mut data)?;
+    let intact = !checksums || crc32fast::hash(&data) == u32::from_le_bytes(crc);
+    Ok((seq, data, intact))
+}
+
+fn backoff(attempt: usize) {
+    std::thread::sleep(Duration::from_millis(100 << attempt.min(6)));
+}
+
+/// The sending end. `connect` is called to make the first connection and
+/// again whenever the connection breaks.
+///
+/// Call `finish` once `telefork` returns in the parent, it waits for the
+/// receiver to ack everything. Dropping it doesn't do any I/O, which matters
+/// because the woken up child also ends up with a copy of it.
+pub struct ResumableWriter<S, C> {
+    connect: C,
+    stream: S,
+    session: u64,
+    next_seq: u64,
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    pending: Vec<u8>,
+}
+
+impl<S: Read + Write, C: FnMut() -> io::Result<S>> ResumableWriter<S, C> {
+    pub fn new(mut connect: C) -> io::Result<Self> {
+        let mut stream = connect()?;
+        // Just needs to be different from other transfers the receiver might be juggling
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let session = now.as_nanos() as u64 ^ ((std::process::id() as u64) << 32);
+        let received = Self::handshake(&mut stream, session)?;
+        if received != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "receiver thinks a new session is already underway",
+            ));
+        }
+        Ok(ResumableWriter {
+            connect,
+            stream,
+            session,
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            pending: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn handshake(stream: &mut S, session: u64) -> io::Result<u64> {
+        stream.write_all(MAGIC)?;
+        write_u64(stream, session)?;
+        stream.flush()?;
+        read_u64(stream)
+    }
+
+    /// Forget about chunks the receiver has told us it has
+    fn ack(&mut self, received: u64) {
+        while matches!(self.unacked.front(), Some((seq, _)) if *seq < received) {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Make a new connection, find out where the receiver got to and resend
+    /// everything after that.
+    fn reconnect(&mut self, cause: io::Error) -> io::Result<()> {
+        for attempt in 0..MAX_RECONNECTS {
+            warn!("telefork connection broke ({}), reconnecting", cause);
+            backoff(attempt);
+            let mut stream = match (self.connect)() {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let received = match Self::handshake(&mut stream, self.session) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            self.ack(received);
+            let resent = self
+                .unacked
+                .iter()
+                .try_for_each(|(seq, data)| write_frame(&mut stream, *seq, data));
+            if resent.is_ok() {
+                tracing::info!("resumed telefork from chunk {}", received);
+                self.stream = stream;
+                return Ok(());
+            }
+        }
+        Err(cause)
+    }
+
+    fn wait_for_ack(&mut self) -> io::Result<()> {
+        match read_u64(&mut self.stream) {
+            Ok(received) if received & RESEND != 0 => {
+                let received = received & !RESEND;
+                warn!("chunk {} arrived corrupted, resending from there", received);
+                self.ack(received);
+                let stream = &mut self.stream;
+                let resent = self
+                    .unacked
+                    .iter()
+                    .try_for_each(|(seq, data)| write_frame(stream, *seq, data));
+                match resent {
+                    Ok(()) => Ok(()),
+                    Err(e) => self.reconnect(e),
+                }
+            }
+            Ok(received) => {
+                self.ack(received);
+                Ok(())
+            }
+            Err(e) => self.reconnect(e),
+        }
+    }
+
+    fn send_chunk(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let res = write_frame(&mut self.stream, seq, &data);
+        self.unacked.push_back((seq, data));
+        if let Err(e) = res {
+            // This resends the chunk we just queued too
+            self.reconnect(e)?;
+        }
+        while self.unacked.len() >= WINDOW {
+            self.wait_for_ack()?;
+        }
+        Ok(())
+    }
+
+    /// Send whatever is left, mark the end of the stream and wait until the
+    /// receiver has acknowledged all of it.
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let chunk = std::mem::take(&mut self.pending);
+            self.send_chunk(chunk)?;
+        }
+        self.send_chunk(Vec::new())?;
+        while !self.unacked.is_empty() {
+            self.wait_for_ack()?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write, C: FnMut() -> io::Result<S>> Write for ResumableWriter<S, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), CHUNK_SIZE - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        if self.pending.len() == CHUNK_SIZE {
+            let chunk = std::mem::replace(&mut self.pending, Vec::with_capacity(CHUNK_SIZE));
+            self.send_chunk(chunk)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Chunks get sent as they fill up, flushing a partial one would just
+        // make the framing less efficient for no benefit
+        Ok(())
+    }
+}
+
+/// The receiving end. `accept` is called for the first connection and again
+/// to wait for the sender to come back if the connection breaks.
+pub struct ResumableReader<S, A> {
+    accept: A,
+    stream: S,
+    session: u64,
+    /// The next chunk number we want, which is also how many we've received
+    expected: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+    /// Whether the sender puts a CRC in each frame
+    checksums: bool,
+    /// We've asked for `expected` again, so whatever was already on its way
+    /// after it gets dropped until it comes
+    resending: bool,
+}
+
+impl<S: Read + Write, A: FnMut() -> io::Result<S>> ResumableReader<S, A> {
+    pub fn new(mut accept: A) -> io::Result<Self> {
+        let mut stream = accept()?;
+        let (session, checksums) = Self::read_handshake(&mut stream)?;
+        write_u64(&mut stream, 0)?;
+        stream.flush()?;
+        Ok(ResumableReader {
+            accept,
+            stream,
+            session,
+            expected: 0,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+            checksums,
+            resending: false,
+        })
+    }
+
+    /// The session id, and whether its frames have CRCs
+    fn read_handshake(stream: &mut S) -> io::Result<(u64, bool)> {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic)?;
+        let checksums = match &magic {
+            MAGIC => true,
+            OLD_MAGIC => false,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a resumable telefork stream",
+                ))
+            }
+        };
+        Ok((read_u64(stream)?, checksums))
+    }
+
+    fn reconnect(&mut self, cause: io::Error) -> io::Result<()> {
+        for attempt in 0..MAX_RECONNECTS {
+            warn!("telefork connection broke ({}), waiting for sender", cause);
+            let mut stream = match (self.accept)() {
+                Ok(stream) => stream,
+                Err(_) => {
+                    backoff(attempt);
+                    continue;
+                }
+            };
+            match Self::read_handshake(&mut stream) {
+                Ok((session, _)) if session == self.session => {}
+                // Some other transfer, not the one we're in the middle of
+                _ => continue,
+            }
+            if write_u64(&mut stream, self.expected)
+                .and_then(|_| stream.flush())
+                .is_ok()
+            {
+                self.stream = stream;
+                return Ok(());
+            }
+        }
+        Err(cause)
+    }
+
+    /// Get the next new chunk into `buf`
+    fn next_chunk(&mut self) -> io::Result<()> {
+        loop {
+            let (seq, data, intact) = match read_frame(&mut self.stream, self.checksums) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    self.reconnect(e)?;
+                    continue;
+                }
+            };
+            if seq < self.expected {
+                // Resent chunk we already had
+                continue;
+            }
+            if seq > self.expected {
+                if self.resending {
+                    continue;
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "resumable telefork stream skipped a chunk",
+                ));
+            }
+            if !intact {
+                warn!("chunk {} arrived corrupted, asking for it again", seq);
+                self.resending = true;
+                // If this fails the next read will notice and reconnect,
+                // which gets it resent too
+                let _ = write_u64(&mut self.stream, RESEND | self.expected)
+                    .and_then(|_| self.stream.flush());
+                continue;
+            }
+            self.resending = false;
+            self.expected += 1;
+            // If this fails the next read will notice and reconnect
+            let _ = write_u64(&mut self.stream, self.expected).and_then(|_| self.stream.flush());
+            if data.is_empty() {
+                self.done = true;
+            }
+            self.buf = data;
+            self.pos = 0;
+            return Ok(());
+        }
+    }
+}
+
+impl<S: Read + Write, A: FnMut() -> io::Result<S>> Read for ResumableReader<S, A> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
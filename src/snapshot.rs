@@ -0,0 +1,151 @@
+//! Restoring once and then putting the process back the way the restore
+//! left it as many times as you like, for fuzzers and tests that want to run
+//! something from the same point over and over without paying for a whole
+//! restore each time.
+//!
+//! `telepad_snapshot` restores the process and keeps it stopped, with a copy
+//! of its registers and of every private writable mapping. The soft-dirty
+//! bits are cleared then, so `Snapshot::reset` only has to copy back the
+//! pages the process wrote to since, the same tracking precopy uses.
+//!
+//! Only memory and registers are put back. If the process maps or unmaps
+//! anything `reset` refuses, and what it did to files, sockets and other
+//! processes stays done. Shared mappings aren't reset either, since whatever
+//! else has them could be relying on what was written.
+
+use crate::precopy::{clear_soft_dirty, dirty_pages};
+use crate::{
+    error, read_memory, telepad_attached, write_memory, Config, RestoredProcess, Result, PAGE_SIZE,
+};
+
+use nix::sys::ptrace;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use tracing::info;
+
+use std::io::Read;
+
+/// A restored process that can be put back the way it was restored, see
+/// the module docs
+pub struct Snapshot {
+    process: RestoredProcess,
+    registers: libc::user_regs_struct,
+    /// Its whole layout, to notice if it changed since
+    maps: Vec<proc_maps::MapRange>,
+    /// The private writable mappings and what was in them
+    memory: Vec<(proc_maps::MapRange, Vec<u8>)>,
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("process", &self.process)
+            .field("mappings", &self.memory.len())
+            .finish()
+    }
+}
+
+/// `telepad_attached` and take a `Snapshot` of it before it's run at all
+pub fn telepad_snapshot(
+    inp: &mut dyn Read,
+    pass_to_child: i32,
+    config: &Config,
+) -> Result<Snapshot> {
+    Snapshot::new(telepad_attached(inp, pass_to_child, config)?)
+}
+
+fn layout(pid: Pid) -> Result<Vec<proc_maps::MapRange>> {
+    Ok(proc_maps::get_process_maps(pid.as_raw() as proc_maps::Pid)?)
+}
+
+fn same_layout(a: &[proc_maps::MapRange], b: &[proc_maps::MapRange]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.start() == b.start() && a.size() == b.size() && a.flags == b.flags)
+}
+
+impl Snapshot {
+    /// Snapshot a restored process as it is now, which is usually before
+    /// it's run at all, but could be after getting it to some point first
+    pub fn new(process: RestoredProcess) -> Result<Snapshot> {
+        let pid = process.pid();
+        let registers = process.registers()?;
+        let maps = layout(pid)?;
+        let mut memory = Vec::new();
+        for map in &maps {
+            if map.is_write() && map.flags.get(3..4) == Some("p") {
+                let contents = read_memory(pid, map.start(), map.size())?;
+                memory.push((map.clone(), contents));
+            }
+        }
+        clear_soft_dirty(pid.as_raw())?;
+        let bytes: usize = memory.iter().map(|(map, _)| map.size()).sum();
+        info!(
+            "snapshot of {} has {} bytes in {} mappings",
+            pid,
+            bytes,
+            memory.len()
+        );
+        Ok(Snapshot {
+            process,
+            registers,
+            maps,
+            memory,
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.process.pid()
+    }
+
+    /// The process, for looking at its registers or running syscalls in it
+    /// between resets
+    pub fn process(&mut self) -> &mut RestoredProcess {
+        &mut self.process
+    }
+
+    /// Let it run until it stops, like at a breakpoint, or exits. A process
+    /// that exited can't be reset, it has to be restored again.
+    pub fn run(&mut self) -> Result<WaitStatus> {
+        ptrace::cont(self.pid(), None)?;
+        let status = waitpid(self.pid(), None)?;
+        if matches!(status, WaitStatus::Exited(..) | WaitStatus::Signaled(..)) {
+            self.process.attached = false;
+        }
+        Ok(status)
+    }
+
+    /// Put its memory and registers back to the snapshot, returning how many
+    /// pages had to be copied back. It has to be stopped.
+    pub fn reset(&mut self) -> Result<usize> {
+        let pid = self.pid();
+        if !self.process.attached {
+            return error("the process has exited, it can't be reset");
+        }
+        if !same_layout(&layout(pid)?, &self.maps) {
+            return error("its mappings have changed since the snapshot, it can't be reset");
+        }
+        let mut reset = 0;
+        for (map, contents) in &self.memory {
+            let pages = dirty_pages(pid.as_raw(), map)?;
+            reset += pages.len();
+            // Runs of pages next to each other go back in one write
+            let mut i = 0;
+            while i < pages.len() {
+                let mut end = i + 1;
+                while end < pages.len() && pages[end] == pages[end - 1] + PAGE_SIZE {
+                    end += 1;
+                }
+                let offset = pages[i] - map.start();
+                let len = (end - i) * PAGE_SIZE;
+                write_memory(pid, pages[i], &contents[offset..offset + len])?;
+                i = end;
+            }
+        }
+        ptrace::setregs(pid, self.registers)?;
+        clear_soft_dirty(pid.as_raw())?;
+        tracing::debug!("reset {} pages of {}", reset, pid);
+        Ok(reset)
+    }
+}
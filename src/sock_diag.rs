@@ -0,0 +1,239 @@
+//! Just enough of the `sock_diag` netlink interface to find out which unix
+//! sockets are connected to which. `/proc/net/unix` lists unix sockets but
+//! not their peers, and the peer is the only way to tell that two anonymous
+//! sockets came from the same `socketpair()` call.
+
+use crate::{error, Result};
+
+use std::collections::HashMap;
+
+const NETLINK_SOCK_DIAG: i32 = 4;
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const UDIAG_SHOW_NAME: u32 = 0x1;
+const UDIAG_SHOW_PEER: u32 = 0x4;
+const UNIX_DIAG_NAME: u16 = 0;
+const UNIX_DIAG_PEER: u16 = 2;
+
+/// What the kernel told us about one unix socket
+#[derive(Debug, Clone)]
+pub(crate) struct UnixSocketInfo {
+    /// `SOCK_STREAM`, `SOCK_DGRAM` or `SOCK_SEQPACKET`
+    pub sock_type: i32,
+    /// The bound path, `None` for anonymous sockets like `socketpair` ends
+    pub name: Option<String>,
+    /// Inode of the socket at the other end, if connected
+    pub peer: Option<u64>,
+}
+
+#[repr(C)]
+struct NlMsgHdr {
+    len: u32,
+    kind: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+#[repr(C)]
+struct UnixDiagReq {
+    family: u8,
+    protocol: u8,
+    pad: u16,
+    states: u32,
+    ino: u32,
+    show: u32,
+    cookie: [u32; 2],
+}
+
+#[repr(C)]
+struct Request {
+    hdr: NlMsgHdr,
+    req: UnixDiagReq,
+}
+
+/// Size of `struct unix_diag_msg`, which is followed by attributes
+const UNIX_DIAG_MSG_LEN: usize = 16;
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_ne_bytes([buf[at], buf[at + 1]])
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// Dump every unix socket in our network namespace, keyed by inode.
+pub(crate) fn unix_sockets() -> Result<HashMap<u64, UnixSocketInfo>> {
+    let sock = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            NETLINK_SOCK_DIAG,
+        )
+    };
+    if sock < 0 {
+        return error("couldn't open sock_diag netlink socket");
+    }
+    let res = dump_unix_sockets(sock);
+    unsafe { libc::close(sock) };
+    res
+}
+
+fn dump_unix_sockets(sock: i32) -> Result<HashMap<u64, UnixSocketInfo>> {
+    let req = Request {
+        hdr: NlMsgHdr {
+            len: std::mem::size_of::<Request>() as u32,
+            kind: SOCK_DIAG_BY_FAMILY,
+            flags: (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16,
+            seq: 1,
+            pid: 0,
+        },
+        req: UnixDiagReq {
+            family: libc::AF_UNIX as u8,
+            protocol: 0,
+            pad: 0,
+            states: !0,
+            ino: 0,
+            show: UDIAG_SHOW_NAME | UDIAG_SHOW_PEER,
+            cookie: [!0, !0],
+        },
+    };
+    let sent = unsafe {
+        libc::send(
+            sock,
+            &req as *const Request as *const libc::c_void,
+            std::mem::size_of::<Request>(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return error("couldn't send sock_diag request");
+    }
+
+    let mut sockets = HashMap::new();
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        let len = unsafe { libc::recv(sock, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if len <= 0 {
+            return error("couldn't read sock_diag response");
+        }
+        let msgs = &buf[..len as usize];
+        let mut at = 0;
+        while at + std::mem::size_of::<NlMsgHdr>() <= msgs.len() {
+            let msg_len = read_u32(msgs, at) as usize;
+            let kind = read_u16(msgs, at + 4);
+            if msg_len < std::mem::size_of::<NlMsgHdr>() || at + msg_len > msgs.len() {
+                return error("malformed sock_diag message");
+            }
+            match kind {
+                NLMSG_DONE => return Ok(sockets),
+                NLMSG_ERROR => return error("sock_diag request failed, is unix_diag available?"),
+                _ => {
+                    let body = &msgs[at + std::mem::size_of::<NlMsgHdr>()..at + msg_len];
+                    if let Some((ino, info)) = parse_unix_diag_msg(body) {
+                        sockets.insert(ino, info);
+                    }
+                }
+            }
+            at += align4(msg_len);
+        }
+    }
+}
+
+fn parse_unix_diag_msg(body: &[u8]) -> Option<(u64, UnixSocketInfo)> {
+    if body.len() < UNIX_DIAG_MSG_LEN {
+        return None;
+    }
+    let sock_type = body[1] as i32;
+    let ino = read_u32(body, 4) as u64;
+    let mut info = UnixSocketInfo {
+        sock_type,
+        name: None,
+        peer: None,
+    };
+
+    // Then a list of netlink attributes
+    let mut at = UNIX_DIAG_MSG_LEN;
+    while at + 4 <= body.len() {
+        let attr_len = read_u16(body, at) as usize;
+        let attr_type = read_u16(body, at + 2);
+        if attr_len < 4 || at + attr_len > body.len() {
+            break;
+        }
+        let payload = &body[at + 4..at + attr_len];
+        match attr_type {
+            UNIX_DIAG_NAME if !payload.is_empty() => {
+                // Abstract socket names start with a NUL byte
+                let name = String::from_utf8_lossy(payload);
+                info.name = Some(name.trim_end_matches('\0').to_string());
+            }
+            UNIX_DIAG_PEER if payload.len() >= 4 => {
+                info.peer = Some(read_u32(payload, 0) as u64);
+            }
+            _ => {}
+        }
+        at += align4(attr_len);
+    }
+    Some((ino, info))
+}
+
+/// Peek at the bytes waiting to be read on a socket in another process,
+/// without consuming them. We borrow the fd with `pidfd_getfd` so this needs
+/// ptrace-level access to the process, which we have anyways. Gives up and
+/// returns nothing if the kernel is too old for that.
+///
+/// Only a `SOCK_STREAM` socket's queue is one run of bytes. A peek only ever
+/// sees the first of the datagrams or packets queued on the other types, and
+/// writing them back as one would lose where each ended, so for those we
+/// just warn that there are some.
+pub(crate) fn peek_socket_buffer(pid: i32, fd: u32, sock_type: i32, limit: usize) -> Vec<u8> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if pidfd < 0 {
+        tracing::debug!("pidfd_open failed, not capturing buffered socket data");
+        return Vec::new();
+    }
+    let ours = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd, fd, 0) };
+    unsafe { libc::close(pidfd as i32) };
+    if ours < 0 {
+        tracing::debug!("pidfd_getfd failed, not capturing buffered socket data");
+        return Vec::new();
+    }
+
+    if sock_type != libc::SOCK_STREAM {
+        let waiting = unsafe {
+            libc::recv(
+                ours as i32,
+                std::ptr::null_mut(),
+                0,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT | libc::MSG_TRUNC,
+            )
+        };
+        unsafe { libc::close(ours as i32) };
+        if waiting >= 0 {
+            tracing::warn!(
+                "socket fd {} has messages waiting to be read, they won't be restored",
+                fd
+            );
+        }
+        return Vec::new();
+    }
+    let mut buf = vec![0u8; limit];
+    let len = unsafe {
+        libc::recv(
+            ours as i32,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    unsafe { libc::close(ours as i32) };
+    buf.truncate(len.max(0) as usize);
+    buf
+}
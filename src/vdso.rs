@@ -0,0 +1,214 @@
+//! Telling whether the vDSO of the kernel a dump was made on is one this
+//! kernel's can stand in for. The restored process has pointers into its
+//! old vDSO all over, libc looks up `__vdso_clock_gettime` and friends once
+//! at startup, so `telepad` moves this kernel's vDSO to where the old one
+//! was and hopes each function is at the same offset. The dump carries the
+//! old vDSO image to check that hope against instead of finding out with a
+//! crash the first time the process asks for the time.
+//!
+//! Only the dynamic symbols matter, the code behind them can be as
+//! different as it likes as long as it's entered in the same places.
+
+use crate::{error, Result};
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+/// Not in `libc`, the GNU symbol versioning sections
+const SHT_DYNSYM: u32 = 11;
+const SHT_GNU_VERDEF: u32 = 0x6fff_fffd;
+const SHT_GNU_VERSYM: u32 = 0x6fff_ffff;
+
+/// How well this kernel's vDSO can stand in for the one a dump was made with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VdsoCompat {
+    /// Exactly the same image, so the same kernel build
+    Compatible,
+    /// Different code but the same size with every function the old one had
+    /// at the same offset, so moving ours into its place works
+    RemappableSameSize,
+    /// Moving ours into its place leaves the process calling into the
+    /// middle of functions, the only hope is teleporting the old one along
+    /// with `Config::janky_vdso`
+    Incompatible(String),
+}
+
+/// Where each exported function or variable of a vDSO is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VdsoLayout {
+    pub size: usize,
+    /// By `name@version`, to offsets from the start of the image
+    pub symbols: BTreeMap<String, usize>,
+}
+
+impl VdsoLayout {
+    /// Read the dynamic symbol table out of a vDSO image
+    pub fn parse(image: &[u8]) -> Result<VdsoLayout> {
+        if image.len() < std::mem::size_of::<libc::Elf64_Ehdr>()
+            || image[..4] != *b"\x7fELF"
+            || image[4] != 2
+        {
+            return error("the vDSO isn't a 64 bit ELF image");
+        }
+        let header: libc::Elf64_Ehdr = read(image, 0)?;
+        // The vDSO is linked somewhere other than 0 on some kernels, its
+        // symbols are relative to that
+        let mut base = None;
+        for i in 0..header.e_phnum as usize {
+            let offset = header.e_phoff as usize + i * header.e_phentsize as usize;
+            let phdr: libc::Elf64_Phdr = read(image, offset)?;
+            if phdr.p_type == libc::PT_LOAD {
+                base = Some(phdr.p_vaddr.wrapping_sub(phdr.p_offset));
+                break;
+            }
+        }
+        let base = match base {
+            Some(base) => base,
+            None => return error("the vDSO has nothing to load"),
+        };
+        let sections: Vec<libc::Elf64_Shdr> = (0..header.e_shnum as usize)
+            .map(|i| {
+                read(
+                    image,
+                    header.e_shoff as usize + i * header.e_shentsize as usize,
+                )
+            })
+            .collect::<Result<_>>()?;
+        let find = |kind| sections.iter().find(|s| s.sh_type == kind);
+        let dynsym = match find(SHT_DYNSYM) {
+            Some(dynsym) => dynsym,
+            None => return error("the vDSO has no dynamic symbols"),
+        };
+        let strtab = section(image, sections.get(dynsym.sh_link as usize))?;
+        let versions = match (find(SHT_GNU_VERSYM), find(SHT_GNU_VERDEF)) {
+            (Some(versym), Some(verdef)) => Some((
+                section(image, Some(versym))?,
+                parse_verdefs(image, verdef, &sections)?,
+            )),
+            _ => None,
+        };
+
+        let mut symbols = BTreeMap::new();
+        let count = dynsym.sh_size as usize / std::mem::size_of::<libc::Elf64_Sym>();
+        for i in 0..count {
+            let offset = dynsym.sh_offset as usize + i * std::mem::size_of::<libc::Elf64_Sym>();
+            let sym: libc::Elf64_Sym = read(image, offset)?;
+            // Undefined, or the version definitions showing up as symbols
+            if sym.st_shndx == 0 || sym.st_value == 0 {
+                continue;
+            }
+            let name = string(strtab, sym.st_name as usize)?;
+            let version = versions.as_ref().and_then(|(versym, names)| {
+                let index = u16::from_ne_bytes(versym.get(i * 2..i * 2 + 2)?.try_into().ok()?);
+                names.get(&(index & 0x7fff))
+            });
+            let key = match version {
+                Some(version) => format!("{}@{}", name, version),
+                None => name.to_string(),
+            };
+            symbols.insert(key, sym.st_value.wrapping_sub(base) as usize);
+        }
+        Ok(VdsoLayout {
+            size: image.len(),
+            symbols,
+        })
+    }
+}
+
+/// Whether the vDSO image `ours` can be put where `theirs` was
+pub fn compare(theirs: &[u8], ours: &[u8]) -> VdsoCompat {
+    if theirs == ours {
+        return VdsoCompat::Compatible;
+    }
+    let (theirs, ours) = match (VdsoLayout::parse(theirs), VdsoLayout::parse(ours)) {
+        (Ok(theirs), Ok(ours)) => (theirs, ours),
+        (Err(e), _) => return VdsoCompat::Incompatible(format!("the dumped vDSO: {}", e)),
+        (_, Err(e)) => return VdsoCompat::Incompatible(format!("our vDSO: {}", e)),
+    };
+    for (name, offset) in &theirs.symbols {
+        match ours.symbols.get(name) {
+            None => return VdsoCompat::Incompatible(format!("this kernel's has no {}", name)),
+            Some(ours) if ours != offset => {
+                return VdsoCompat::Incompatible(format!(
+                    "{} is at {:#x} here instead of {:#x}",
+                    name, ours, offset
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    if theirs.size != ours.size {
+        return VdsoCompat::Incompatible(format!(
+            "it's {} bytes here instead of {}",
+            ours.size, theirs.size
+        ));
+    }
+    VdsoCompat::RemappableSameSize
+}
+
+/// A copy of this process's vDSO, which every process on this kernel shares
+pub fn ours() -> Result<Vec<u8>> {
+    let maps = proc_maps::get_process_maps(std::process::id() as proc_maps::Pid)?;
+    match maps
+        .iter()
+        .find(|m| m.filename().as_deref() == Some("[vdso]"))
+    {
+        Some(vdso) => {
+            let image =
+                unsafe { std::slice::from_raw_parts(vdso.start() as *const u8, vdso.size()) };
+            Ok(image.to_vec())
+        }
+        None => error("we don't have a vDSO"),
+    }
+}
+
+/// Version definition index to name
+fn parse_verdefs(
+    image: &[u8],
+    verdef: &libc::Elf64_Shdr,
+    sections: &[libc::Elf64_Shdr],
+) -> Result<BTreeMap<u16, String>> {
+    let strtab = section(image, sections.get(verdef.sh_link as usize))?;
+    let mut names = BTreeMap::new();
+    let mut offset = verdef.sh_offset as usize;
+    // `sh_info` is how many there are
+    for _ in 0..verdef.sh_info {
+        // vd_version, vd_flags, vd_ndx, vd_cnt, vd_hash, vd_aux, vd_next
+        let ndx = u16::from_ne_bytes(bytes(image, offset + 4, 2)?.try_into().unwrap());
+        let aux = u32::from_ne_bytes(bytes(image, offset + 12, 4)?.try_into().unwrap());
+        let next = u32::from_ne_bytes(bytes(image, offset + 16, 4)?.try_into().unwrap());
+        // The first Verdaux has the name, any others are parents
+        let name = u32::from_ne_bytes(bytes(image, offset + aux as usize, 4)?.try_into().unwrap());
+        names.insert(ndx, string(strtab, name as usize)?.to_string());
+        if next == 0 {
+            break;
+        }
+        offset += next as usize;
+    }
+    Ok(names)
+}
+
+fn bytes(image: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    match image.get(offset..offset.saturating_add(len)) {
+        Some(bytes) => Ok(bytes),
+        None => error("the vDSO image is cut short"),
+    }
+}
+
+fn read<T>(image: &[u8], offset: usize) -> Result<T> {
+    let bytes = bytes(image, offset, std::mem::size_of::<T>())?;
+    Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+fn section<'a>(image: &'a [u8], header: Option<&libc::Elf64_Shdr>) -> Result<&'a [u8]> {
+    match header {
+        Some(header) => bytes(image, header.sh_offset as usize, header.sh_size as usize),
+        None => error("the vDSO's section links are broken"),
+    }
+}
+
+fn string(strtab: &[u8], offset: usize) -> Result<&str> {
+    let rest = bytes(strtab, offset, 0).map(|_| &strtab[offset..])?;
+    let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+    Ok(std::str::from_utf8(&rest[..len])?)
+}